@@ -0,0 +1,663 @@
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// Bit offsets must address a string of at most 512MB, Redis's
+/// `proto-max-bulk-len`.
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8;
+
+fn offset_error() -> CommandError {
+    CommandError::Other("ERR bit offset is not an integer or out of range".into())
+}
+
+fn bit_offset(args: &mut Args) -> Result<u64, CommandError> {
+    args.string()?
+        .parse()
+        .ok()
+        .filter(|offset| *offset < MAX_BIT_OFFSET)
+        .ok_or_else(offset_error)
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    bytes
+        .get((offset / 8) as usize)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Sets the bit at `offset`, growing the string with zero bytes as needed,
+/// and returns what the bit was.
+fn set_bit(bytes: &mut Vec<u8>, offset: u64, value: bool) -> bool {
+    let index = (offset / 8) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    let old = bytes[index] & mask != 0;
+    if value {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+    old
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Unit {
+    Byte,
+    Bit,
+}
+
+/// A BITCOUNT or BITPOS range, with negative indexes counting from the end
+/// of the string.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Range {
+    pub start: i64,
+    pub end: Option<i64>,
+    pub unit: Unit,
+}
+
+impl Range {
+    fn parse_unit(args: &mut Args) -> Result<Unit, CommandError> {
+        if args.is_empty() || args.keyword("BYTE") {
+            Ok(Unit::Byte)
+        } else if args.keyword("BIT") {
+            Ok(Unit::Bit)
+        } else {
+            Err(CommandError::Syntax)
+        }
+    }
+
+    /// The first and last bit the range covers in a string of `len` bytes,
+    /// or `None` when it covers nothing.
+    fn bits(&self, len: usize) -> Option<(u64, u64)> {
+        let (mut start, mut end) = (self.start, self.end.unwrap_or(-1));
+        if start < 0 && end < 0 && start > end {
+            return None;
+        }
+        let total = match self.unit {
+            Unit::Byte => len as i64,
+            Unit::Bit => len as i64 * 8,
+        };
+        if start < 0 {
+            start = (total + start).max(0);
+        }
+        if end < 0 {
+            end = (total + end).max(0);
+        }
+        end = end.min(total - 1);
+        if start > end {
+            return None;
+        }
+        let (start, end) = (start as u64, end as u64);
+        match self.unit {
+            Unit::Byte => Some((start * 8, end * 8 + 7)),
+            Unit::Bit => Some((start, end)),
+        }
+    }
+}
+
+impl Default for Range {
+    fn default() -> Self {
+        Range {
+            start: 0,
+            end: None,
+            unit: Unit::Byte,
+        }
+    }
+}
+
+fn count_bits(bytes: &[u8], first: u64, last: u64) -> u64 {
+    let (first_byte, last_byte) = ((first / 8) as usize, (last / 8) as usize);
+    let head = 0xff >> (first % 8);
+    let tail = 0xff << (7 - last % 8);
+    bytes[first_byte..=last_byte]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            let mut byte = *byte;
+            if i == 0 {
+                byte &= head;
+            }
+            if i == last_byte - first_byte {
+                byte &= tail;
+            }
+            u64::from(byte.count_ones())
+        })
+        .sum()
+}
+
+fn find_bit(bytes: &[u8], bit: bool, first: u64, last: u64) -> Option<u64> {
+    // Whole bytes that can't hold the bit are skipped.
+    let skip = if bit { 0x00 } else { 0xff };
+    let mut offset = first;
+    while offset <= last {
+        if offset & 7 == 0 && offset + 7 <= last && bytes[(offset / 8) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bytes, offset) == bit {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+    /// The bits of the first source that are in none of the others.
+    Diff,
+    /// The bits set in exactly one source.
+    One,
+}
+
+impl BitOp {
+    fn apply(self, sources: &[&[u8]]) -> Vec<u8> {
+        let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+        let byte = |source: &[u8], i: usize| source.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| {
+                let mut bytes = sources.iter().map(|source| byte(source, i));
+                let first = bytes.next().unwrap_or(0);
+                match self {
+                    BitOp::And => bytes.fold(first, |acc, byte| acc & byte),
+                    BitOp::Or => bytes.fold(first, |acc, byte| acc | byte),
+                    BitOp::Xor => bytes.fold(first, |acc, byte| acc ^ byte),
+                    BitOp::Not => !first,
+                    BitOp::Diff => first & !bytes.fold(0, |acc, byte| acc | byte),
+                    BitOp::One => {
+                        let (once, twice) = bytes.fold((first, 0), |(once, twice), byte| {
+                            (once | byte, twice | (once & byte))
+                        });
+                        once & !twice
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// A BITFIELD integer type, such as `i5` or `u8`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl FieldType {
+    fn parse(s: &str) -> Result<FieldType, CommandError> {
+        let signed = match s.as_bytes().first() {
+            Some(b'i' | b'I') => Some(true),
+            Some(b'u' | b'U') => Some(false),
+            _ => None,
+        };
+        signed
+            .zip(s.get(1..).and_then(|bits| bits.parse().ok()))
+            .filter(|(signed, bits)| (1..=if *signed { 64 } else { 63 }).contains(bits))
+            .map(|(signed, bits)| FieldType { signed, bits })
+            .ok_or_else(|| {
+                CommandError::Other(
+                    "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not \
+                     supported but i64 is."
+                        .into(),
+                )
+            })
+    }
+
+    fn min(self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    fn read(self, bytes: &[u8], offset: u64) -> i64 {
+        let mut value: u64 = 0;
+        for i in 0..u64::from(self.bits) {
+            value = (value << 1) | u64::from(get_bit(bytes, offset + i));
+        }
+        let unused = 64 - self.bits;
+        if self.signed {
+            ((value << unused) as i64) >> unused
+        } else {
+            value as i64
+        }
+    }
+
+    fn write(self, bytes: &mut Vec<u8>, offset: u64, value: i64) {
+        let value = value as u64;
+        for i in 0..self.bits {
+            let bit = (value >> (self.bits - 1 - i)) & 1 == 1;
+            set_bit(bytes, offset + u64::from(i), bit);
+        }
+    }
+
+    /// Fits `value` into the type as `overflow` says, or `None` when it
+    /// doesn't fit and overflow is to fail.
+    fn fit(self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = (self.min(), self.max());
+        if (min..=max).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let modulus = 1 << self.bits;
+                let wrapped = value.rem_euclid(modulus);
+                Some(if wrapped > max {
+                    wrapped - modulus
+                } else {
+                    wrapped
+                } as i64)
+            }
+            Overflow::Sat if value > max => Some(max as i64),
+            Overflow::Sat => Some(min as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FieldOp {
+    Get(FieldType, u64),
+    Set(FieldType, u64, i64),
+    IncrBy(FieldType, u64, i64),
+    /// How SETs and INCRBYs after it handle values that don't fit.
+    Overflow(Overflow),
+}
+
+impl FieldOp {
+    fn is_write(&self) -> bool {
+        matches!(self, FieldOp::Set(..) | FieldOp::IncrBy(..))
+    }
+
+    fn parse(args: &mut Args, read_only: bool) -> Result<FieldOp, CommandError> {
+        let subcommand = args.string()?.to_uppercase();
+        let arity = match subcommand.as_str() {
+            "GET" => 2,
+            "SET" | "INCRBY" => 3,
+            "OVERFLOW" => 1,
+            _ => return Err(CommandError::Syntax),
+        };
+        if args.len() < arity {
+            return Err(CommandError::Syntax);
+        }
+        if subcommand == "OVERFLOW" {
+            let overflow = args.string()?;
+            return match overflow.to_uppercase().as_str() {
+                "WRAP" => Ok(FieldOp::Overflow(Overflow::Wrap)),
+                "SAT" => Ok(FieldOp::Overflow(Overflow::Sat)),
+                "FAIL" => Ok(FieldOp::Overflow(Overflow::Fail)),
+                _ => Err(CommandError::Other(
+                    "ERR Invalid OVERFLOW type specified".into(),
+                )),
+            };
+        }
+
+        let field_type = FieldType::parse(&args.string()?)?;
+        let offset = args.string()?;
+        // `#n` addresses the n-th field of this type.
+        let offset = match offset.strip_prefix('#') {
+            Some(index) => index
+                .parse::<u64>()
+                .ok()
+                .and_then(|index| index.checked_mul(u64::from(field_type.bits))),
+            None => offset.parse().ok(),
+        }
+        .filter(|offset| *offset < MAX_BIT_OFFSET)
+        .ok_or_else(offset_error)?;
+
+        if subcommand == "GET" {
+            return Ok(FieldOp::Get(field_type, offset));
+        }
+        if read_only {
+            return Err(CommandError::Other(
+                "ERR BITFIELD_RO only supports the GET subcommand".into(),
+            ));
+        }
+        let value = args.integer()?;
+        Ok(match subcommand.as_str() {
+            "SET" => FieldOp::Set(field_type, offset, value),
+            _ => FieldOp::IncrBy(field_type, offset, value),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BitmapCommand {
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    Count(String, Option<Range>),
+    /// BITPOS with the bit to look for.
+    Pos(String, bool, Option<Range>),
+    /// BITOP with the destination and sources.
+    Op(BitOp, String, Vec<String>),
+    Field(String, Vec<FieldOp>),
+}
+
+impl BitmapCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<BitmapCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "SETBIT" => {
+                let key = args.string()?;
+                let offset = bit_offset(&mut args)?;
+                let value = match args.string()?.as_str() {
+                    "0" => false,
+                    "1" => true,
+                    _ => {
+                        return Err(CommandError::Other(
+                            "ERR bit is not an integer or out of range".into(),
+                        ))
+                    }
+                };
+                BitmapCommand::SetBit(key, offset, value)
+            }
+            "GETBIT" => BitmapCommand::GetBit(args.string()?, bit_offset(&mut args)?),
+            "BITCOUNT" => {
+                let key = args.string()?;
+                let range = match args.len() {
+                    0 => None,
+                    1 => return Err(CommandError::Syntax),
+                    _ => Some(Range {
+                        start: args.integer()?,
+                        end: Some(args.integer()?),
+                        unit: Range::parse_unit(&mut args)?,
+                    }),
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                BitmapCommand::Count(key, range)
+            }
+            "BITPOS" => {
+                let key = args.string()?;
+                let bit = match args.integer()? {
+                    0 => false,
+                    1 => true,
+                    _ => {
+                        return Err(CommandError::Other(
+                            "ERR The bit argument must be 1 or 0.".into(),
+                        ))
+                    }
+                };
+                let range = if args.is_empty() {
+                    None
+                } else {
+                    let start = args.integer()?;
+                    let end = if args.is_empty() {
+                        None
+                    } else {
+                        Some(args.integer()?)
+                    };
+                    Some(Range {
+                        start,
+                        end,
+                        unit: Range::parse_unit(&mut args)?,
+                    })
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                BitmapCommand::Pos(key, bit, range)
+            }
+            "BITOP" => {
+                let op = match args.string()?.to_uppercase().as_str() {
+                    "AND" => BitOp::And,
+                    "OR" => BitOp::Or,
+                    "XOR" => BitOp::Xor,
+                    "NOT" => BitOp::Not,
+                    "DIFF" => BitOp::Diff,
+                    "ONE" => BitOp::One,
+                    _ => return Err(CommandError::Syntax),
+                };
+                let destination = args.string()?;
+                let sources = args.rest()?;
+                if op == BitOp::Not && sources.len() != 1 {
+                    return Err(CommandError::Other(
+                        "ERR BITOP NOT must be called with a single source key.".into(),
+                    ));
+                }
+                if op == BitOp::Diff && sources.len() < 2 {
+                    return Err(CommandError::Other(
+                        "ERR BITOP DIFF must be called with at least two source keys.".into(),
+                    ));
+                }
+                BitmapCommand::Op(op, destination, sources)
+            }
+            "BITFIELD" | "BITFIELD_RO" => {
+                let key = args.string()?;
+                let mut ops = Vec::new();
+                while !args.is_empty() {
+                    ops.push(FieldOp::parse(&mut args, name == "BITFIELD_RO")?);
+                }
+                BitmapCommand::Field(key, ops)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    /// Whether the command modifies the keyspace; a BITFIELD does only when
+    /// it sets or increments something.
+    pub fn is_write(&self) -> bool {
+        match self {
+            BitmapCommand::SetBit(..) | BitmapCommand::Op(..) => true,
+            BitmapCommand::Field(_, ops) => ops.iter().any(FieldOp::is_write),
+            _ => false,
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            BitmapCommand::SetBit(key, ..)
+            | BitmapCommand::GetBit(key, _)
+            | BitmapCommand::Count(key, _)
+            | BitmapCommand::Pos(key, ..)
+            | BitmapCommand::Field(key, _) => vec![key],
+            BitmapCommand::Op(_, destination, sources) => std::iter::once(destination)
+                .chain(sources)
+                .map(String::as_str)
+                .collect(),
+        }
+    }
+}
+
+/// Runs a BITFIELD's subcommands in order against `bytes`.
+fn bitfield(bytes: &mut Vec<u8>, ops: &[FieldOp]) -> RespData {
+    let mut overflow = Overflow::Wrap;
+    let mut replies = Vec::new();
+    for op in ops {
+        let (field_type, offset, value) = match *op {
+            FieldOp::Overflow(mode) => {
+                overflow = mode;
+                continue;
+            }
+            FieldOp::Get(field_type, offset) => {
+                replies.push(RespData::Integer(field_type.read(bytes, offset)));
+                continue;
+            }
+            FieldOp::Set(field_type, offset, value)
+            | FieldOp::IncrBy(field_type, offset, value) => (field_type, offset, value),
+        };
+        let old = field_type.read(bytes, offset);
+        let new = match op {
+            // Like Redis, an unsigned field takes a negative value as the
+            // unsigned 64-bit integer with the same bits.
+            FieldOp::Set(..) if !field_type.signed => i128::from(value as u64),
+            FieldOp::Set(..) => i128::from(value),
+            _ => i128::from(old) + i128::from(value),
+        };
+        match field_type.fit(new, overflow) {
+            Some(new) => {
+                field_type.write(bytes, offset, new);
+                let reply = if matches!(op, FieldOp::Set(..)) {
+                    old
+                } else {
+                    new
+                };
+                replies.push(RespData::Integer(reply));
+            }
+            None => replies.push(RespData::BulkStringNull),
+        }
+    }
+    RespData::Array(replies)
+}
+
+pub fn execute(db: &mut Db, command: BitmapCommand) -> Result<RespData, StoreError> {
+    match command {
+        BitmapCommand::SetBit(key, offset, value) => {
+            let bytes = db.string_or_create(&key)?;
+            let previous = set_bit(bytes, offset, value);
+            db.touch(&key);
+            Ok(RespData::Integer(previous as i64))
+        }
+        BitmapCommand::GetBit(key, offset) => {
+            let bytes = db.string(&key)?.unwrap_or_default();
+            Ok(RespData::Integer(get_bit(bytes, offset) as i64))
+        }
+        BitmapCommand::Count(key, range) => {
+            let bytes = db.string(&key)?.unwrap_or_default();
+            let count = range
+                .unwrap_or_default()
+                .bits(bytes.len())
+                .map_or(0, |(first, last)| count_bits(bytes, first, last));
+            Ok(RespData::Integer(count as i64))
+        }
+        BitmapCommand::Pos(key, bit, range) => {
+            let Some(bytes) = db.string(&key)? else {
+                return Ok(RespData::Integer(if bit { -1 } else { 0 }));
+            };
+            let range = range.unwrap_or_default();
+            let position = match range.bits(bytes.len()) {
+                None => -1,
+                Some((first, last)) => match find_bit(bytes, bit, first, last) {
+                    Some(position) => position as i64,
+                    // Past the end of the string all bits are clear, unless
+                    // the range was given an explicit end.
+                    None if !bit && range.end.is_none() => last as i64 + 1,
+                    None => -1,
+                },
+            };
+            Ok(RespData::Integer(position))
+        }
+        BitmapCommand::Op(op, destination, sources) => {
+            let mut bytes = Vec::new();
+            for key in &sources {
+                bytes.push(db.string(key)?.unwrap_or_default());
+            }
+            let result = op.apply(&bytes);
+            let len = result.len();
+            if result.is_empty() {
+                db.remove(&destination);
+            } else {
+                db.set(destination, Value::String(result), None);
+            }
+            Ok(RespData::Integer(len as i64))
+        }
+        BitmapCommand::Field(key, ops) => {
+            if ops.iter().any(FieldOp::is_write) {
+                let reply = bitfield(db.string_or_create(&key)?, &ops);
+                db.touch(&key);
+                return Ok(reply);
+            }
+            let mut bytes = db.string(&key)?.unwrap_or_default().to_vec();
+            Ok(bitfield(&mut bytes, &ops))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_numbered_from_the_most_significant() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 7, true));
+        assert!(!set_bit(&mut bytes, 9, true));
+        assert_eq!(bytes, [0x01, 0x40]);
+        assert!(set_bit(&mut bytes, 7, false));
+        assert!(get_bit(&bytes, 9));
+        assert!(!get_bit(&bytes, 100));
+    }
+
+    #[test]
+    fn ranges_count_from_either_end() {
+        let bytes = b"foobar";
+        let count = |start, end, unit| {
+            Range {
+                start,
+                end: Some(end),
+                unit,
+            }
+            .bits(bytes.len())
+            .map_or(0, |(first, last)| count_bits(bytes, first, last))
+        };
+        assert_eq!(count(0, -1, Unit::Byte), 26);
+        assert_eq!(count(1, 1, Unit::Byte), 6);
+        assert_eq!(count(5, 30, Unit::Bit), 17);
+        assert_eq!(count(-1, -2, Unit::Byte), 0);
+    }
+
+    #[test]
+    fn bitpos_pads_with_zeros_only_without_an_end() {
+        let bytes = [0xff, 0xf0, 0x00];
+        assert_eq!(find_bit(&bytes, false, 0, 23), Some(12));
+        assert_eq!(find_bit(&bytes, true, 2, 23), Some(2));
+        assert_eq!(find_bit(&[0xff], false, 0, 7), None);
+    }
+
+    #[test]
+    fn bitop_one_and_diff() {
+        let (a, b, c): (&[u8], &[u8], &[u8]) = (&[0b1100], &[0b1010], &[0b1001, 0xff]);
+        assert_eq!(BitOp::One.apply(&[a, b, c]), [0b0111, 0xff]);
+        assert_eq!(BitOp::Diff.apply(&[a, b]), [0b0100]);
+        assert_eq!(BitOp::Not.apply(&[a]), [!0b1100]);
+    }
+
+    #[test]
+    fn fields_wrap_saturate_or_fail() {
+        let u2 = FieldType {
+            signed: false,
+            bits: 2,
+        };
+        let i8 = FieldType {
+            signed: true,
+            bits: 8,
+        };
+        assert_eq!(u2.fit(5, Overflow::Wrap), Some(1));
+        assert_eq!(u2.fit(5, Overflow::Sat), Some(3));
+        assert_eq!(u2.fit(-1, Overflow::Fail), None);
+        assert_eq!(i8.fit(128, Overflow::Wrap), Some(-128));
+        assert_eq!(i8.fit(-200, Overflow::Sat), Some(-128));
+
+        let mut bytes = Vec::new();
+        i8.write(&mut bytes, 4, -2);
+        assert_eq!(bytes, [0x0f, 0xe0]);
+        assert_eq!(i8.read(&bytes, 4), -2);
+        assert_eq!(u2.read(&bytes, 4), 3);
+    }
+}
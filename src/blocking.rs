@@ -0,0 +1,348 @@
+use std::{
+    collections::VecDeque,
+    net::TcpStream,
+    sync::{mpsc::Sender, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    command::RedisCommand,
+    executor::{try_unblock, Context},
+    resp_parser::RespData,
+    store::{Store, Value},
+    tcp::peer_closed,
+};
+
+/// How often the store thread checks whether blocked clients have gone away.
+const DISCONNECT_POLL_PERIOD: Duration = Duration::from_millis(100);
+
+pub struct BlockedClient {
+    /// The client's connection, to tell whether it is still there before
+    /// handing it anything. Its own thread doesn't touch it while it waits.
+    pub stream: Arc<TcpStream>,
+    pub db: usize,
+    pub command: RedisCommand,
+    pub deadline: Option<Instant>,
+    pub reply: Sender<RespData>,
+}
+
+/// Clients parked on a blocking command, kept in the order they blocked so
+/// that whoever has waited longest on a key is served first.
+#[derive(Default)]
+pub struct BlockedClients {
+    clients: VecDeque<BlockedClient>,
+    last_disconnect_poll: Option<Instant>,
+}
+
+impl BlockedClients {
+    pub fn block(&mut self, client: BlockedClient) {
+        self.clients.push_back(client);
+    }
+
+    pub fn get(&self, index: usize) -> &BlockedClient {
+        &self.clients[index]
+    }
+
+    pub fn remove(&mut self, index: usize) -> BlockedClient {
+        self.clients.remove(index).unwrap()
+    }
+
+    /// Forgets the clients that went away while blocked, at most once per
+    /// poll period. Dropping one closes its reply channel, which lets its
+    /// connection thread finish.
+    pub fn remove_disconnected(&mut self, now: Instant) {
+        if self
+            .last_disconnect_poll
+            .is_some_and(|polled| now < polled + DISCONNECT_POLL_PERIOD)
+        {
+            return;
+        }
+        self.last_disconnect_poll = Some(now);
+        self.clients.retain(|client| !peer_closed(&client.stream));
+    }
+
+    /// The earliest moment one of the clients times out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.deadline)
+            .min()
+    }
+
+    /// When the store thread has to look at the clients again without being
+    /// sent anything: when one times out, or to check for disconnections.
+    pub fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        if self.clients.is_empty() {
+            return None;
+        }
+        let poll = now + DISCONNECT_POLL_PERIOD;
+        Some(
+            self.next_deadline()
+                .map_or(poll, |deadline| deadline.min(poll)),
+        )
+    }
+
+    pub fn take_timed_out(&mut self, now: Instant) -> Vec<BlockedClient> {
+        let (timed_out, waiting) = std::mem::take(&mut self.clients)
+            .into_iter()
+            .partition(|client| client.deadline.is_some_and(|deadline| deadline <= now));
+        self.clients = waiting;
+        timed_out.into()
+    }
+
+    /// The longest-waiting client blocked on `key` in `db`, starting the
+    /// search at position `from`.
+    pub fn position(&self, db: usize, key: &str, from: usize) -> Option<usize> {
+        self.clients
+            .iter()
+            .skip(from)
+            .position(|client| client.db == db && client.command.keys().contains(&key))
+            .map(|position| position + from)
+    }
+
+    /// Every key some client is blocked on.
+    pub fn keys(&self) -> Vec<(usize, String)> {
+        self.clients
+            .iter()
+            .flat_map(|client| {
+                client
+                    .command
+                    .keys()
+                    .into_iter()
+                    .map(|key| (client.db, key.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Serves clients blocked on keys that just became ready, longest-waiting
+/// first. Serving a client may itself make keys ready (`BLMOVE` pushes to its
+/// destination), so this keeps going until nothing is left. Returns what to
+/// feed replicas, with the database each command runs in.
+///
+/// A client that hung up is dropped rather than served, since nothing could
+/// deliver what was popped for it.
+pub fn serve(
+    store: &Store,
+    blocked_clients: &mut BlockedClients,
+    is_master: bool,
+    mut ready_keys: Vec<(usize, String)>,
+) -> Vec<(usize, Vec<String>)> {
+    let mut propagated = Vec::new();
+    while !ready_keys.is_empty() {
+        for (db, key) in ready_keys {
+            let mut from = 0;
+            while let Some(index) = blocked_clients.position(db, &key, from) {
+                let client = blocked_clients.get(index);
+                // Clients waiting for another type stay blocked, as the key
+                // may yet be replaced by one they can pop from.
+                match store.read(db, |db| db.get(&key).map(Value::type_name)) {
+                    Some(type_name) if type_name == client.command.awaited_type() => {}
+                    Some(_) => {
+                        from = index + 1;
+                        continue;
+                    }
+                    None => break,
+                }
+                if peer_closed(&client.stream) {
+                    blocked_clients.remove(index);
+                    from = index;
+                    continue;
+                }
+                let mut context = Context::new(store, client.db, is_master);
+                let Some(response) = try_unblock(&mut context, &client.command) else {
+                    from = index + 1;
+                    continue;
+                };
+
+                propagated.extend(context.also_propagate.into_iter().map(|args| (db, args)));
+                let client = blocked_clients.remove(index);
+                let _ = client.reply.send(response);
+                from = index;
+            }
+        }
+        ready_keys = store.take_ready_keys();
+    }
+    propagated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::mpsc::{self, Receiver},
+    };
+
+    use super::*;
+    use crate::{command::parse_command, executor::execute};
+
+    /// A connected pair: the server's end, as a blocked client holds it, and
+    /// the client's end, to keep the connection open or hang up.
+    fn connection(listener: &TcpListener) -> (Arc<TcpStream>, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Arc::new(server), client)
+    }
+
+    fn command(args: &[&str]) -> RedisCommand {
+        let args = args
+            .iter()
+            .map(|arg| RespData::BulkString(arg.to_string()))
+            .collect();
+        parse_command(&RespData::Array(args)).unwrap()
+    }
+
+    fn run(store: &Store, args: &[&str]) -> RespData {
+        execute(&mut Context::new(store, 0, true), command(args))
+    }
+
+    fn block(
+        blocked_clients: &mut BlockedClients,
+        stream: Arc<TcpStream>,
+        args: &[&str],
+        deadline: Option<Instant>,
+    ) -> Receiver<RespData> {
+        let (reply, replies) = mpsc::channel();
+        blocked_clients.block(BlockedClient {
+            stream,
+            db: 0,
+            command: command(args),
+            deadline,
+            reply,
+        });
+        replies
+    }
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn strings(values: &[&str]) -> RespData {
+        RespData::Array(
+            values
+                .iter()
+                .map(|value| RespData::BulkString(value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn longest_waiting_client_is_served_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let store = Store::new(1);
+        let mut blocked_clients = BlockedClients::default();
+        let mut peers = Vec::new();
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let (stream, peer) = connection(&listener);
+            peers.push(peer);
+            replies.push(block(
+                &mut blocked_clients,
+                stream,
+                &["BLPOP", "k", "0"],
+                None,
+            ));
+        }
+
+        run(&store, &["RPUSH", "k", "a", "b"]);
+        let propagated = serve(&store, &mut blocked_clients, true, store.take_ready_keys());
+
+        assert_eq!(replies[0].try_recv(), Ok(strings(&["k", "a"])));
+        assert_eq!(replies[1].try_recv(), Ok(strings(&["k", "b"])));
+        assert!(replies[2].try_recv().is_err());
+        assert_eq!(
+            propagated,
+            [(0, args(&["LPOP", "k"])), (0, args(&["LPOP", "k"]))]
+        );
+        assert_eq!(blocked_clients.keys(), [(0, "k".to_string())]);
+    }
+
+    #[test]
+    fn blmove_is_propagated_as_lmove_and_wakes_the_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let store = Store::new(1);
+        let mut blocked_clients = BlockedClients::default();
+        let (stream, _mover) = connection(&listener);
+        let moved = block(
+            &mut blocked_clients,
+            stream,
+            &["BLMOVE", "src", "dst", "LEFT", "RIGHT", "0"],
+            None,
+        );
+        let (stream, _popper) = connection(&listener);
+        let popped = block(&mut blocked_clients, stream, &["BRPOP", "dst", "0"], None);
+
+        run(&store, &["RPUSH", "src", "x"]);
+        let propagated = serve(&store, &mut blocked_clients, true, store.take_ready_keys());
+
+        assert_eq!(moved.try_recv(), Ok(RespData::BulkString("x".to_string())));
+        assert_eq!(popped.try_recv(), Ok(strings(&["dst", "x"])));
+        assert_eq!(
+            propagated,
+            [
+                (0, args(&["LMOVE", "src", "dst", "LEFT", "RIGHT"])),
+                (0, args(&["RPOP", "dst"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn client_that_hung_up_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let store = Store::new(1);
+        let mut blocked_clients = BlockedClients::default();
+        let (stream, peer) = connection(&listener);
+        let gone = block(&mut blocked_clients, stream, &["BLPOP", "k", "0"], None);
+        let (stream, _peer) = connection(&listener);
+        let waiting = block(&mut blocked_clients, stream, &["BLPOP", "k", "0"], None);
+        drop(peer);
+        std::thread::sleep(Duration::from_millis(50));
+
+        run(&store, &["RPUSH", "k", "a"]);
+        serve(&store, &mut blocked_clients, true, store.take_ready_keys());
+
+        assert!(gone.recv().is_err());
+        assert_eq!(waiting.try_recv(), Ok(strings(&["k", "a"])));
+    }
+
+    #[test]
+    fn clients_time_out_at_their_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut blocked_clients = BlockedClients::default();
+        let now = Instant::now();
+        assert_eq!(blocked_clients.next_wakeup(now), None);
+
+        let (stream, _forever) = connection(&listener);
+        block(&mut blocked_clients, stream, &["BLPOP", "a", "0"], None);
+        let (stream, _soon) = connection(&listener);
+        let soon = now + Duration::from_millis(10);
+        block(
+            &mut blocked_clients,
+            stream,
+            &["BLPOP", "b", "1"],
+            Some(soon),
+        );
+        let (stream, _later) = connection(&listener);
+        let later = now + Duration::from_secs(10);
+        block(
+            &mut blocked_clients,
+            stream,
+            &["BLPOP", "c", "1"],
+            Some(later),
+        );
+
+        assert_eq!(blocked_clients.next_deadline(), Some(soon));
+        assert_eq!(blocked_clients.next_wakeup(now), Some(soon));
+        assert!(blocked_clients.take_timed_out(now).is_empty());
+
+        let timed_out = blocked_clients.take_timed_out(soon);
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].command.timeout_reply(), RespData::ArrayNull);
+        assert_eq!(blocked_clients.next_deadline(), Some(later));
+        assert_eq!(
+            blocked_clients.next_wakeup(soon),
+            Some(soon + DISCONNECT_POLL_PERIOD)
+        );
+        assert_eq!(blocked_clients.keys().len(), 2);
+    }
+}
@@ -0,0 +1,434 @@
+use std::f64::consts::LN_2;
+
+use crate::{
+    command::{Args, CommandError},
+    hyperloglog::murmur_hash_64a,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// What BF.ADD creates a missing filter with, as in RedisBloom.
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+const DEFAULT_CAPACITY: u64 = 100;
+const DEFAULT_EXPANSION: u64 = 2;
+const MAX_EXPANSION: u64 = 32768;
+/// The most memory one sub-filter may take, whether asked for by BF.RESERVE
+/// or reached by scaling up.
+const MAX_FILTER_BYTES: u64 = 512 << 20;
+/// How much lower each sub-filter's error rate is than the one before, so
+/// that the error rates of them all add up to at most the one asked for.
+const TIGHTENING_RATIO: f64 = 0.5;
+
+/// The two hashes an item's bits are derived from.
+fn hashes(item: &str) -> (u64, u64) {
+    let a = murmur_hash_64a(item.as_bytes(), 0xc6a4a7935bd1e995);
+    (a, murmur_hash_64a(item.as_bytes(), a))
+}
+
+/// A classic Bloom filter sized for `capacity` items at `error_rate`.
+#[derive(Debug)]
+struct SubFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+    capacity: u64,
+    items: u64,
+}
+
+impl SubFilter {
+    /// Fails rather than allocate more than `MAX_FILTER_BYTES`, which a low
+    /// enough error rate does for any capacity.
+    fn new(capacity: u64, error_rate: f64) -> Result<Self, StoreError> {
+        let bits_per_item = -error_rate.ln() / (LN_2 * LN_2);
+        let bits = (capacity as f64 * bits_per_item).ceil();
+        if bits > (MAX_FILTER_BYTES * 8) as f64 {
+            return Err(StoreError::BloomTooLarge);
+        }
+        let bits = (bits as usize).max(8);
+        Ok(SubFilter {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes: (bits_per_item * LN_2).ceil() as u32,
+            capacity,
+            items: 0,
+        })
+    }
+
+    /// The bits an item sets, by double hashing.
+    fn positions(&self, (a, b): (u64, u64)) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        (0..u64::from(self.hashes)).map(move |i| (a.wrapping_add(i.wrapping_mul(b)) % len) as usize)
+    }
+
+    fn contains(&self, hashes: (u64, u64)) -> bool {
+        self.positions(hashes)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn insert(&mut self, hashes: (u64, u64)) {
+        let positions: Vec<usize> = self.positions(hashes).collect();
+        for bit in positions {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+        self.items += 1;
+    }
+}
+
+/// A scalable Bloom filter: once its newest sub-filter is full, it adds one
+/// `expansion` times larger with a lower error rate.
+#[derive(Debug)]
+pub struct BloomFilter {
+    filters: Vec<SubFilter>,
+    error_rate: f64,
+    /// `None` for a filter that refuses items once full.
+    expansion: Option<u64>,
+}
+
+impl BloomFilter {
+    fn new(error_rate: f64, capacity: u64, expansion: Option<u64>) -> Result<Self, StoreError> {
+        Ok(BloomFilter {
+            filters: vec![SubFilter::new(capacity, error_rate)?],
+            error_rate,
+            expansion,
+        })
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        let hashes = hashes(item);
+        self.filters.iter().any(|filter| filter.contains(hashes))
+    }
+
+    /// Adds `item`, returning whether it wasn't (probably) there already.
+    pub fn add(&mut self, item: &str) -> Result<bool, StoreError> {
+        let hashes = hashes(item);
+        if self.filters.iter().any(|filter| filter.contains(hashes)) {
+            return Ok(false);
+        }
+        let last = self.filters.last().unwrap();
+        if last.items >= last.capacity {
+            let expansion = self.expansion.ok_or(StoreError::BloomFull)?;
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.filters.len() as i32);
+            let capacity = last
+                .capacity
+                .checked_mul(expansion)
+                .ok_or(StoreError::BloomTooLarge)?;
+            let filter = SubFilter::new(capacity, error_rate)?;
+            self.filters.push(filter);
+        }
+        self.filters.last_mut().unwrap().insert(hashes);
+        Ok(true)
+    }
+
+    fn capacity(&self) -> u64 {
+        self.filters.iter().map(|filter| filter.capacity).sum()
+    }
+
+    fn size(&self) -> usize {
+        self.filters.iter().map(|filter| filter.bits.len()).sum()
+    }
+
+    fn items(&self) -> u64 {
+        self.filters.iter().map(|filter| filter.items).sum()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InfoField {
+    Capacity,
+    Size,
+    Filters,
+    Items,
+    Expansion,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BloomCommand {
+    Reserve {
+        key: String,
+        error_rate: f64,
+        capacity: u64,
+        expansion: Option<u64>,
+    },
+    /// BF.ADD and BF.MADD, with whether replies come as an array.
+    Add(String, Vec<String>, bool),
+    /// BF.EXISTS and BF.MEXISTS, with whether replies come as an array.
+    Exists(String, Vec<String>, bool),
+    Info(String, Option<InfoField>),
+}
+
+impl BloomCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<BloomCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "BF.RESERVE" => {
+                let key = args.string()?;
+                let error_rate = args
+                    .float()
+                    .map_err(|_| CommandError::Other("ERR bad error rate".into()))?;
+                if !(error_rate > 0.0 && error_rate < 1.0) {
+                    return Err(CommandError::Other("ERR (0 < error rate range < 1)".into()));
+                }
+                let capacity = args
+                    .integer()
+                    .map_err(|_| CommandError::Other("ERR bad capacity".into()))?;
+                if capacity == 0 {
+                    return Err(CommandError::Other(
+                        "ERR (capacity should be larger than 0)".into(),
+                    ));
+                }
+                let (mut expansion, mut scaling) = (DEFAULT_EXPANSION, true);
+                while !args.is_empty() {
+                    if args.keyword("EXPANSION") {
+                        expansion = args
+                            .integer()
+                            .map_err(|_| CommandError::Other("ERR bad expansion".into()))?;
+                        if expansion == 0 {
+                            return Err(CommandError::Other(
+                                "ERR expansion should be greater or equal to 1".into(),
+                            ));
+                        }
+                        if expansion > MAX_EXPANSION {
+                            return Err(CommandError::Other("ERR expansion is too large".into()));
+                        }
+                    } else if args.keyword("NONSCALING") {
+                        scaling = false;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                BloomCommand::Reserve {
+                    key,
+                    error_rate,
+                    capacity,
+                    expansion: scaling.then_some(expansion),
+                }
+            }
+            "BF.ADD" | "BF.EXISTS" => {
+                let (key, item) = (args.string()?, args.string()?);
+                if name == "BF.ADD" {
+                    BloomCommand::Add(key, vec![item], false)
+                } else {
+                    BloomCommand::Exists(key, vec![item], false)
+                }
+            }
+            "BF.MADD" | "BF.MEXISTS" => {
+                let key = args.string()?;
+                let items = args.rest()?;
+                if items.is_empty() {
+                    return Err(args.arity_error());
+                }
+                if name == "BF.MADD" {
+                    BloomCommand::Add(key, items, true)
+                } else {
+                    BloomCommand::Exists(key, items, true)
+                }
+            }
+            "BF.INFO" => {
+                let key = args.string()?;
+                let field = if args.is_empty() {
+                    None
+                } else {
+                    let field = args.string()?;
+                    Some(match field.to_uppercase().as_str() {
+                        "CAPACITY" => InfoField::Capacity,
+                        "SIZE" => InfoField::Size,
+                        "FILTERS" => InfoField::Filters,
+                        "ITEMS" => InfoField::Items,
+                        "EXPANSION" => InfoField::Expansion,
+                        _ => {
+                            return Err(CommandError::Other("ERR Invalid information value".into()))
+                        }
+                    })
+                };
+                BloomCommand::Info(key, field)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, BloomCommand::Reserve { .. } | BloomCommand::Add(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            BloomCommand::Reserve { key, .. }
+            | BloomCommand::Add(key, ..)
+            | BloomCommand::Exists(key, ..)
+            | BloomCommand::Info(key, _) => vec![key],
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: BloomCommand) -> Result<RespData, StoreError> {
+    match command {
+        BloomCommand::Reserve {
+            key,
+            error_rate,
+            capacity,
+            expansion,
+        } => {
+            if db.contains(&key) {
+                return Err(StoreError::BloomExists);
+            }
+            let filter = BloomFilter::new(error_rate, capacity, expansion)?;
+            db.set(key, Value::Bloom(filter), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        BloomCommand::Add(key, items, as_array) => {
+            if db.bloom(&key)?.is_none() {
+                let filter = BloomFilter::new(
+                    DEFAULT_ERROR_RATE,
+                    DEFAULT_CAPACITY,
+                    Some(DEFAULT_EXPANSION),
+                )?;
+                db.set(key.clone(), Value::Bloom(filter), None);
+            }
+            let filter = db.bloom_mut(&key)?.unwrap();
+            if !as_array {
+                let added = filter.add(&items[0])?;
+                if added {
+                    db.touch(&key);
+                }
+                return Ok(RespData::Integer(added.into()));
+            }
+            // BF.MADD goes on past a full filter, replying with an error for
+            // each item that didn't fit.
+            let replies: Vec<RespData> = items
+                .iter()
+                .map(|item| match filter.add(item) {
+                    Ok(added) => RespData::Integer(added.into()),
+                    Err(e) => RespData::Error(e.to_string()),
+                })
+                .collect();
+            if replies.contains(&RespData::Integer(1)) {
+                db.touch(&key);
+            }
+            Ok(RespData::Array(replies))
+        }
+        BloomCommand::Exists(key, items, as_array) => {
+            let filter = db.bloom(&key)?;
+            let mut replies = items.iter().map(|item| {
+                let found = filter.is_some_and(|filter| filter.contains(item));
+                RespData::Integer(found.into())
+            });
+            Ok(if as_array {
+                RespData::Array(replies.collect())
+            } else {
+                replies.next().unwrap()
+            })
+        }
+        BloomCommand::Info(key, field) => {
+            let filter = db.bloom(&key)?.ok_or(StoreError::BloomNotFound)?;
+            let expansion = filter
+                .expansion
+                .map_or(RespData::BulkStringNull, |expansion| {
+                    RespData::Integer(expansion as i64)
+                });
+            let fields = [
+                (
+                    InfoField::Capacity,
+                    "Capacity",
+                    RespData::Integer(filter.capacity() as i64),
+                ),
+                (
+                    InfoField::Size,
+                    "Size",
+                    RespData::Integer(filter.size() as i64),
+                ),
+                (
+                    InfoField::Filters,
+                    "Number of filters",
+                    RespData::Integer(filter.filters.len() as i64),
+                ),
+                (
+                    InfoField::Items,
+                    "Number of items inserted",
+                    RespData::Integer(filter.items() as i64),
+                ),
+                (InfoField::Expansion, "Expansion rate", expansion),
+            ];
+            Ok(RespData::Array(match field {
+                Some(field) => fields
+                    .into_iter()
+                    .filter(|(candidate, ..)| *candidate == field)
+                    .map(|(_, _, value)| value)
+                    .collect(),
+                None => fields
+                    .into_iter()
+                    .flat_map(|(_, name, value)| [RespData::SimpleString(name.to_string()), value])
+                    .collect(),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_what_was_added() {
+        let mut filter = BloomFilter::new(0.01, 100, None).unwrap();
+        assert!(filter.add("a").unwrap());
+        assert!(!filter.add("a").unwrap());
+        assert!(filter.contains("a"));
+        assert!(!filter.contains("b"));
+    }
+
+    #[test]
+    fn scales_by_adding_sub_filters() {
+        let mut filter = BloomFilter::new(0.01, 100, Some(2)).unwrap();
+        let added = (0..1000)
+            .filter(|i| filter.add(&i.to_string()).unwrap())
+            .count() as u64;
+        assert_eq!(filter.items(), added);
+        assert!((0..1000).all(|i| filter.contains(&i.to_string())));
+        assert_eq!(filter.filters.len(), 4);
+        assert_eq!(filter.capacity(), 100 + 200 + 400 + 800);
+        let false_positives = (1000..11000)
+            .filter(|i| filter.contains(&i.to_string()))
+            .count();
+        assert!(false_positives < 200, "{}", false_positives);
+    }
+
+    #[test]
+    fn non_scaling_filters_fill_up() {
+        let mut filter = BloomFilter::new(0.01, 10, None).unwrap();
+        let mut i = 0;
+        while filter.items() < 10 {
+            filter.add(&i.to_string()).unwrap();
+            i += 1;
+        }
+        assert_eq!(filter.add("another"), Err(StoreError::BloomFull));
+    }
+
+    #[test]
+    fn refuses_filters_too_large_to_allocate() {
+        assert_eq!(
+            BloomFilter::new(1e-300, 1_000_000_000, None).unwrap_err(),
+            StoreError::BloomTooLarge
+        );
+        let parse = |args: &[&str]| {
+            let args: Vec<RespData> = args
+                .iter()
+                .map(|arg| RespData::BulkString(arg.to_string()))
+                .collect();
+            BloomCommand::parse("BF.RESERVE", &args)
+        };
+        assert!(parse(&["bf", "0.01", "100", "EXPANSION", "32768"]).is_ok());
+        assert!(parse(&["bf", "0.01", "100", "EXPANSION", "9223372036854775807"]).is_err());
+
+        // Each sub-filter has half the error rate of the one before, so even
+        // one that doesn't grow needs more bits per item every time.
+        let mut filter = BloomFilter::new(1e-300, 1, Some(1)).unwrap();
+        let mut i = 0;
+        let error = loop {
+            if let Err(e) = filter.add(&i.to_string()) {
+                break e;
+            }
+            i += 1;
+        };
+        assert_eq!(error, StoreError::BloomTooLarge);
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{arg, Parser};
+use clap::Parser;
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
@@ -35,6 +35,9 @@ pub struct Args {
 
     #[arg(long, default_value = "")]
     pub dbfilename: String,
+
+    #[arg(long, default_value_t = 16)]
+    pub databases: usize,
 }
 
 pub fn parse_cli() -> Args {
@@ -0,0 +1,241 @@
+use crate::{
+    command::{Args, CommandError},
+    hyperloglog::murmur_hash_64a,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// The most counters a sketch may have, 512MB worth.
+const MAX_COUNTERS: usize = 64 << 20;
+
+/// A count-min sketch: `depth` rows of `width` counters, each row hashing
+/// items differently. An item's count is the smallest of its counters, which
+/// can only overestimate it.
+#[derive(Debug)]
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    counters: Vec<i64>,
+}
+
+impl CountMinSketch {
+    fn new(width: usize, depth: usize) -> Self {
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    /// The counter of each row that `item` hashes to.
+    fn cells(&self, item: &str) -> Vec<usize> {
+        (0..self.depth)
+            .map(|row| {
+                let column = murmur_hash_64a(item.as_bytes(), row as u64) % self.width as u64;
+                row * self.width + column as usize
+            })
+            .collect()
+    }
+
+    pub fn increment(&mut self, item: &str, by: i64) -> i64 {
+        for cell in self.cells(item) {
+            self.counters[cell] = self.counters[cell].saturating_add(by);
+        }
+        self.count(item)
+    }
+
+    pub fn count(&self, item: &str) -> i64 {
+        self.cells(item)
+            .into_iter()
+            .map(|cell| self.counters[cell])
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+fn cms_error(message: &str) -> CommandError {
+    CommandError::Other(format!("ERR CMS: {}", message))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CmsCommand {
+    /// CMS.INITBYDIM with the key, width and depth.
+    Init(String, usize, usize),
+    IncrBy(String, Vec<(String, i64)>),
+    Query(String, Vec<String>),
+    /// CMS.MERGE with the destination and the weighted sources.
+    Merge(String, Vec<(String, i64)>),
+}
+
+impl CmsCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<CmsCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "CMS.INITBYDIM" => {
+                let key = args.string()?;
+                let width = args
+                    .positive()
+                    .ok()
+                    .filter(|width| *width > 0)
+                    .ok_or_else(|| cms_error("invalid width"))?;
+                let depth = args
+                    .positive()
+                    .ok()
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(|| cms_error("invalid depth"))?;
+                if width
+                    .checked_mul(depth)
+                    .filter(|counters| *counters <= MAX_COUNTERS)
+                    .is_none()
+                {
+                    return Err(cms_error("width/depth is too large"));
+                }
+                CmsCommand::Init(key, width, depth)
+            }
+            "CMS.INCRBY" => {
+                let key = args.string()?;
+                if args.is_empty() || args.len() % 2 == 1 {
+                    return Err(args.arity_error());
+                }
+                let mut increments = Vec::new();
+                while !args.is_empty() {
+                    let item = args.string()?;
+                    let by = args
+                        .integer()
+                        .ok()
+                        .filter(|by| *by >= 0)
+                        .ok_or_else(|| cms_error("Cannot parse number"))?;
+                    increments.push((item, by));
+                }
+                CmsCommand::IncrBy(key, increments)
+            }
+            "CMS.QUERY" => {
+                let key = args.string()?;
+                let items = args.rest()?;
+                if items.is_empty() {
+                    return Err(args.arity_error());
+                }
+                CmsCommand::Query(key, items)
+            }
+            "CMS.MERGE" => {
+                let destination = args.string()?;
+                let count = args
+                    .positive()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| cms_error("invalid numkeys"))?;
+                let mut sources = Vec::new();
+                for _ in 0..count {
+                    sources.push((args.string()?, 1));
+                }
+                if args.keyword("WEIGHTS") {
+                    for (_, weight) in &mut sources {
+                        *weight = args
+                            .integer()
+                            .map_err(|_| cms_error("invalid weight value"))?;
+                    }
+                }
+                CmsCommand::Merge(destination, sources)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(self, CmsCommand::Query(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            CmsCommand::Init(key, ..) | CmsCommand::IncrBy(key, _) | CmsCommand::Query(key, _) => {
+                vec![key]
+            }
+            CmsCommand::Merge(destination, sources) => std::iter::once(destination.as_str())
+                .chain(sources.iter().map(|(key, _)| key.as_str()))
+                .collect(),
+        }
+    }
+}
+
+fn missing_key() -> StoreError {
+    StoreError::Cms("key does not exist")
+}
+
+pub fn execute(db: &mut Db, command: CmsCommand) -> Result<RespData, StoreError> {
+    match command {
+        CmsCommand::Init(key, width, depth) => {
+            if db.contains(&key) {
+                return Err(StoreError::Cms("key already exists"));
+            }
+            db.set(key, Value::Cms(CountMinSketch::new(width, depth)), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        CmsCommand::IncrBy(key, increments) => {
+            let sketch = db.cms_mut(&key)?.ok_or_else(missing_key)?;
+            let counts = increments
+                .iter()
+                .map(|(item, by)| RespData::Integer(sketch.increment(item, *by)))
+                .collect();
+            db.touch(&key);
+            Ok(RespData::Array(counts))
+        }
+        CmsCommand::Query(key, items) => {
+            let sketch = db.cms(&key)?.ok_or_else(missing_key)?;
+            Ok(RespData::Array(
+                items
+                    .iter()
+                    .map(|item| RespData::Integer(sketch.count(item)))
+                    .collect(),
+            ))
+        }
+        CmsCommand::Merge(destination, sources) => {
+            let merged = {
+                let target = db.cms(&destination)?.ok_or_else(missing_key)?;
+                let mut merged = vec![0i64; target.counters.len()];
+                for (key, weight) in &sources {
+                    let source = db.cms(key)?.ok_or_else(missing_key)?;
+                    if (source.width, source.depth) != (target.width, target.depth) {
+                        return Err(StoreError::Cms("width/depth is not equal"));
+                    }
+                    for (sum, counter) in merged.iter_mut().zip(&source.counters) {
+                        *sum = sum.saturating_add(counter.saturating_mul(*weight));
+                    }
+                }
+                merged
+            };
+            db.cms_mut(&destination)?.unwrap().counters = merged;
+            db.touch(&destination);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_underestimates() {
+        let mut sketch = CountMinSketch::new(20, 4);
+        for i in 0..100 {
+            sketch.increment(&i.to_string(), i);
+        }
+        assert!((0..100).all(|i| sketch.count(&i.to_string()) >= i));
+        assert_eq!(sketch.increment("99", 1), sketch.count("99"));
+        assert_eq!(CountMinSketch::new(1000, 5).count("missing"), 0);
+    }
+
+    #[test]
+    fn refuses_sketches_too_large_to_allocate() {
+        let parse = |width: &str, depth: &str| {
+            let args = ["cms", width, depth].map(|arg| RespData::BulkString(arg.to_string()));
+            CmsCommand::parse("CMS.INITBYDIM", &args)
+        };
+        assert!(parse("1048576", "64").is_ok());
+        let too_large = Err(cms_error("width/depth is too large"));
+        assert_eq!(parse("1048576", "65"), too_large);
+        assert_eq!(parse("9223372036854775807", "4"), too_large);
+    }
+}
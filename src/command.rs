@@ -1,4 +1,142 @@
-use crate::resp_parser::RespData;
+use std::str::FromStr;
+
+use crate::{
+    bitmap::BitmapCommand, bloom::BloomCommand, cms::CmsCommand, cuckoo::CuckooCommand,
+    geo::GeoCommand, hash::HashCommand, hyperloglog::HyperLogLogCommand, json::JsonCommand,
+    list::ListCommand, resp_parser::RespData, search::SearchCommand, set::SetCommand,
+    stream::StreamCommand, tdigest::TDigestCommand, timeseries::TimeSeriesCommand,
+    topk::TopKCommand, vector::VectorCommand, zset::SortedSetCommand,
+};
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum CommandError {
+    #[error("ERR unknown command '{0}'")]
+    UnknownCommand(String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("{0}")]
+    Other(String),
+}
+
+/// The most random picks a negative count may ask for.
+const MAX_REPEATED_PICKS: i64 = 1 << 24;
+
+/// A cursor over a command's arguments, for commands with more than a couple
+/// of positional arguments or with options.
+pub struct Args<'a> {
+    name: &'a str,
+    args: &'a [RespData],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(name: &'a str, args: &'a [RespData]) -> Self {
+        Args { name, args }
+    }
+
+    pub fn arity_error(&self) -> CommandError {
+        CommandError::WrongArity(self.name.to_lowercase())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn string(&mut self) -> Result<String, CommandError> {
+        let (first, rest) = self.args.split_first().ok_or_else(|| self.arity_error())?;
+        self.args = rest;
+        match first {
+            RespData::BulkString(s) | RespData::SimpleString(s) => Ok(s.clone()),
+            RespData::BulkBytes(bytes) => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            RespData::Integer(n) => Ok(n.to_string()),
+            _ => Err(CommandError::Syntax),
+        }
+    }
+
+    pub fn integer<T: FromStr>(&mut self) -> Result<T, CommandError> {
+        self.string()?.parse().map_err(|_| CommandError::NotInteger)
+    }
+
+    /// A count that must not be negative.
+    pub fn positive(&mut self) -> Result<usize, CommandError> {
+        let n: i64 = self.integer()?;
+        usize::try_from(n)
+            .map_err(|_| CommandError::Other("ERR value is out of range, must be positive".into()))
+    }
+
+    /// The count of HRANDFIELD, SRANDMEMBER or ZRANDMEMBER, where a negative
+    /// one asks for that many picks with repeats. Redis refuses counts below
+    /// `-(i64::MAX / 2)`; this stops at `MAX_REPEATED_PICKS`, as building a
+    /// reply anywhere near that size would stall the store thread for good.
+    pub fn random_count(&mut self) -> Result<i64, CommandError> {
+        let count: i64 = self.integer()?;
+        if count < -MAX_REPEATED_PICKS {
+            return Err(CommandError::Other("ERR value is out of range".into()));
+        }
+        Ok(count)
+    }
+
+    pub fn float(&mut self) -> Result<f64, CommandError> {
+        self.string()?.parse().map_err(|_| CommandError::NotFloat)
+    }
+
+    /// A blocking command's timeout in seconds, where 0 means forever.
+    pub fn timeout(&mut self) -> Result<f64, CommandError> {
+        let timeout: f64 = self
+            .string()?
+            .parse()
+            .ok()
+            .filter(|timeout: &f64| timeout.is_finite())
+            .ok_or_else(|| {
+                CommandError::Other("ERR timeout is not a float or out of range".into())
+            })?;
+        if timeout < 0.0 {
+            return Err(CommandError::Other("ERR timeout is negative".into()));
+        }
+        Ok(timeout)
+    }
+
+    /// Consumes the next argument if it is `keyword`, ignoring case.
+    pub fn keyword(&mut self, keyword: &str) -> bool {
+        match self.args.first() {
+            Some(RespData::BulkString(s)) if s.eq_ignore_ascii_case(keyword) => {
+                self.args = &self.args[1..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Consumes the remaining arguments, of which there must be at least one.
+    pub fn rest(&mut self) -> Result<Vec<String>, CommandError> {
+        if self.args.is_empty() {
+            return Err(self.arity_error());
+        }
+        let mut rest = Vec::new();
+        while !self.args.is_empty() {
+            rest.push(self.string()?);
+        }
+        Ok(rest)
+    }
+
+    /// Fails if any arguments are left over.
+    pub fn finish(&self) -> Result<(), CommandError> {
+        if self.args.is_empty() {
+            Ok(())
+        } else {
+            Err(self.arity_error())
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum ReplConf {
@@ -12,19 +150,182 @@ pub enum ReplConf {
 pub enum ConfigGet {
     Dir,
     Dbfilename,
+    Databases,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Scan {
+    pub cursor: u64,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub type_name: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum RedisCommand {
     Ping,
     Echo(String),
-    Set(String, String, Option<u64>),
+    Set(String, Vec<u8>, Option<u64>),
     Get(String),
+    Del(Vec<String>),
+    Keys(String),
+    Scan(Scan),
+    Select(i64),
+    Move(String, i64),
+    SwapDb(i64, i64),
     Info,
     ReplConf(ReplConf),
     PSync,
     Wait(u64, u64),
     ConfigGet(ConfigGet),
+    List(ListCommand),
+    Hash(HashCommand),
+    Sets(SetCommand),
+    SortedSet(SortedSetCommand),
+    Stream(StreamCommand),
+    HyperLogLog(HyperLogLogCommand),
+    Bitmap(BitmapCommand),
+    Geo(GeoCommand),
+    Json(JsonCommand),
+    TimeSeries(TimeSeriesCommand),
+    Bloom(BloomCommand),
+    Cuckoo(CuckooCommand),
+    Cms(CmsCommand),
+    TopK(TopKCommand),
+    TDigest(TDigestCommand),
+    Search(SearchCommand),
+    Vector(VectorCommand),
+    /// HELLO with the requested protocol version, if any.
+    Hello(Option<i64>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+    FlushAll,
+}
+
+impl RedisCommand {
+    /// Whether the command modifies the keyspace and so has to be fed to
+    /// replicas.
+    pub fn is_write(&self) -> bool {
+        match self {
+            RedisCommand::Set(..)
+            | RedisCommand::Del(_)
+            | RedisCommand::Move(..)
+            | RedisCommand::SwapDb(..)
+            | RedisCommand::FlushAll => true,
+            RedisCommand::List(command) => command.is_write(),
+            RedisCommand::Hash(command) => command.is_write(),
+            RedisCommand::Sets(command) => command.is_write(),
+            RedisCommand::SortedSet(command) => command.is_write(),
+            RedisCommand::Stream(command) => command.is_write(),
+            RedisCommand::HyperLogLog(command) => command.is_write(),
+            RedisCommand::Bitmap(command) => command.is_write(),
+            RedisCommand::Geo(command) => command.is_write(),
+            RedisCommand::Json(command) => command.is_write(),
+            RedisCommand::TimeSeries(command) => command.is_write(),
+            RedisCommand::Bloom(command) => command.is_write(),
+            RedisCommand::Cuckoo(command) => command.is_write(),
+            RedisCommand::Cms(command) => command.is_write(),
+            RedisCommand::TopK(command) => command.is_write(),
+            RedisCommand::TDigest(command) => command.is_write(),
+            RedisCommand::Search(command) => command.is_write(),
+            RedisCommand::Vector(command) => command.is_write(),
+            _ => false,
+        }
+    }
+
+    /// How long the command may block, in seconds (0 meaning forever), or
+    /// `None` for commands that never block.
+    pub fn timeout(&self) -> Option<f64> {
+        match self {
+            RedisCommand::List(command) => command.timeout(),
+            RedisCommand::SortedSet(command) => command.timeout(),
+            RedisCommand::Stream(command) => command.timeout(),
+            _ => None,
+        }
+    }
+
+    /// The reply for a blocking command that timed out.
+    pub fn timeout_reply(&self) -> RespData {
+        match self {
+            RedisCommand::List(command) => command.timeout_reply(),
+            _ => RespData::ArrayNull,
+        }
+    }
+
+    /// The type of value a blocking command waits for its keys to hold.
+    pub fn awaited_type(&self) -> &'static str {
+        match self {
+            RedisCommand::SortedSet(_) => "zset",
+            RedisCommand::Stream(_) => "stream",
+            _ => "list",
+        }
+    }
+
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            RedisCommand::Set(key, ..) | RedisCommand::Get(key) | RedisCommand::Move(key, _) => {
+                vec![key]
+            }
+            RedisCommand::Del(keys) => keys.iter().map(String::as_str).collect(),
+            RedisCommand::List(command) => command.keys(),
+            RedisCommand::Hash(command) => command.keys(),
+            RedisCommand::Sets(command) => command.keys(),
+            RedisCommand::SortedSet(command) => command.keys(),
+            RedisCommand::Stream(command) => command.keys(),
+            RedisCommand::HyperLogLog(command) => command.keys(),
+            RedisCommand::Bitmap(command) => command.keys(),
+            RedisCommand::Geo(command) => command.keys(),
+            RedisCommand::Json(command) => command.keys(),
+            RedisCommand::TimeSeries(command) => command.keys(),
+            RedisCommand::Bloom(command) => command.keys(),
+            RedisCommand::Cuckoo(command) => command.keys(),
+            RedisCommand::Cms(command) => command.keys(),
+            RedisCommand::TopK(command) => command.keys(),
+            RedisCommand::TDigest(command) => command.keys(),
+            RedisCommand::Vector(command) => command.keys(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A command's arguments as sent, which need not be UTF-8.
+pub type CommandArgs = Vec<Vec<u8>>;
+
+/// The command as sent by the client, for feeding it to replicas verbatim.
+pub fn command_args(data: &RespData) -> CommandArgs {
+    match data {
+        RespData::Array(elements) => elements
+            .iter()
+            .filter_map(|element| match element {
+                RespData::BulkString(s) | RespData::SimpleString(s) => Some(s.clone().into_bytes()),
+                RespData::BulkBytes(bytes) => Some(bytes.clone()),
+                RespData::Integer(n) => Some(n.to_string().into_bytes()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn as_text(data: &RespData) -> RespData {
+    match data {
+        RespData::BulkBytes(bytes) => {
+            RespData::BulkString(String::from_utf8_lossy(bytes).into_owned())
+        }
+        data => data.clone(),
+    }
+}
+
+fn bytes(data: &RespData) -> Option<Vec<u8>> {
+    match data {
+        RespData::BulkString(s) => Some(s.clone().into_bytes()),
+        RespData::BulkBytes(bytes) => Some(bytes.clone()),
+        _ => None,
+    }
 }
 
 fn parse_px(args: &[RespData]) -> Option<u64> {
@@ -36,19 +337,54 @@ fn parse_px(args: &[RespData]) -> Option<u64> {
     }
 }
 
-pub fn parse_command(data: &RespData) -> Option<RedisCommand> {
-    let array = match data {
-        RespData::Array(arr) => arr,
+fn parse_scan(args: &[RespData]) -> Option<Scan> {
+    let (cursor, mut options) = match args {
+        [RespData::BulkString(cursor), options @ ..] => (cursor.parse().ok()?, options),
         _ => return None,
     };
 
-    let (cmd, args) = array.split_first().unwrap();
+    let mut scan = Scan {
+        cursor,
+        pattern: None,
+        count: 10,
+        type_name: None,
+    };
+    while let [RespData::BulkString(option), RespData::BulkString(value), rest @ ..] = options {
+        match option.to_uppercase().as_str() {
+            "MATCH" => scan.pattern = Some(value.clone()),
+            "COUNT" => scan.count = value.parse().ok().filter(|count| *count > 0)?,
+            "TYPE" => scan.type_name = Some(value.clone()),
+            _ => return None,
+        }
+        options = rest;
+    }
+
+    options.is_empty().then_some(scan)
+}
+
+pub fn parse_command(data: &RespData) -> Result<RedisCommand, CommandError> {
+    let raw = match data {
+        RespData::Array(arr) => arr,
+        _ => return Err(CommandError::Syntax),
+    };
+    // Arguments are text, with bytes that aren't UTF-8 replaced, except
+    // for the value of SET: strings hold whatever bytes they are given.
+    let text: Vec<RespData>;
+    let array = if raw.iter().any(|arg| matches!(arg, RespData::BulkBytes(_))) {
+        text = raw.iter().map(as_text).collect();
+        &text
+    } else {
+        raw
+    };
+
+    let (cmd, args) = array.split_first().ok_or(CommandError::Syntax)?;
     let cmd = match cmd {
         RespData::BulkString(s) => s,
-        _ => return None,
+        _ => return Err(CommandError::Syntax),
     };
 
-    match cmd.to_uppercase().as_str() {
+    let name = cmd.to_uppercase();
+    let command = match name.as_str() {
         "PING" => match args {
             [] => Some(RedisCommand::Ping),
             _ => None,
@@ -58,9 +394,9 @@ pub fn parse_command(data: &RespData) -> Option<RedisCommand> {
             _ => None,
         },
         "SET" => match args {
-            [RespData::BulkString(key), RespData::BulkString(value), rest @ ..] => {
+            [RespData::BulkString(key), _, rest @ ..] => {
                 let px = parse_px(rest);
-                Some(RedisCommand::Set(key.clone(), value.clone(), px))
+                bytes(&raw[2]).map(|value| RedisCommand::Set(key.clone(), value, px))
             }
             _ => None,
         },
@@ -68,6 +404,42 @@ pub fn parse_command(data: &RespData) -> Option<RedisCommand> {
             [RespData::BulkString(key)] => Some(RedisCommand::Get(key.clone())),
             _ => None,
         },
+        "DEL" => match args {
+            [] => None,
+            _ => args
+                .iter()
+                .map(|arg| match arg {
+                    RespData::BulkString(key) => Some(key.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(RedisCommand::Del),
+        },
+        "KEYS" => match args {
+            [RespData::BulkString(pattern)] => Some(RedisCommand::Keys(pattern.clone())),
+            _ => None,
+        },
+        "SCAN" => parse_scan(args).map(RedisCommand::Scan),
+        "SELECT" => match args {
+            [RespData::BulkString(index)] => Some(RedisCommand::Select(
+                index.parse().map_err(|_| CommandError::NotInteger)?,
+            )),
+            _ => None,
+        },
+        "MOVE" => match args {
+            [RespData::BulkString(key), RespData::BulkString(db)] => Some(RedisCommand::Move(
+                key.clone(),
+                db.parse().map_err(|_| CommandError::NotInteger)?,
+            )),
+            _ => None,
+        },
+        "SWAPDB" => match args {
+            [RespData::BulkString(a), RespData::BulkString(b)] => Some(RedisCommand::SwapDb(
+                a.parse().map_err(|_| CommandError::NotInteger)?,
+                b.parse().map_err(|_| CommandError::NotInteger)?,
+            )),
+            _ => None,
+        },
         "INFO" => match args {
             [RespData::BulkString(role)] if role == "replication" => Some(RedisCommand::Info),
             _ => None,
@@ -76,13 +448,13 @@ pub fn parse_command(data: &RespData) -> Option<RedisCommand> {
             [RespData::BulkString(conf), RespData::BulkString(value)] => {
                 match conf.to_uppercase().as_str() {
                     "LISTENING-PORT" => {
-                        let port = value.parse().ok()?;
+                        let port = value.parse().map_err(|_| CommandError::Syntax)?;
                         Some(RedisCommand::ReplConf(ReplConf::ListeningPort(port)))
                     }
                     "CAPA" => Some(RedisCommand::ReplConf(ReplConf::Capa)),
                     "GETACK" => Some(RedisCommand::ReplConf(ReplConf::GetAck)),
                     "ACK" => {
-                        let offset = value.parse().ok()?;
+                        let offset = value.parse().map_err(|_| CommandError::Syntax)?;
                         Some(RedisCommand::ReplConf(ReplConf::Ack(offset)))
                     }
                     _ => None,
@@ -103,14 +475,114 @@ pub fn parse_command(data: &RespData) -> Option<RedisCommand> {
             {
                 if parameter == "dir" {
                     Some(RedisCommand::ConfigGet(ConfigGet::Dir))
-                } else if parameter == "dir" {
+                } else if parameter == "dbfilename" {
                     Some(RedisCommand::ConfigGet(ConfigGet::Dbfilename))
+                } else if parameter == "databases" {
+                    Some(RedisCommand::ConfigGet(ConfigGet::Databases))
                 } else {
                     None
                 }
             }
             _ => None,
         },
-        _ => None,
-    }
+        "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" | "LPOP" | "RPOP" | "LLEN" | "LRANGE"
+        | "LINDEX" | "LSET" | "LINSERT" | "LREM" | "LTRIM" | "LPOS" | "LMOVE" | "RPOPLPUSH"
+        | "LMPOP" | "BLPOP" | "BRPOP" | "BLMOVE" | "BLMPOP" => {
+            Some(RedisCommand::List(ListCommand::parse(&name, args)?))
+        }
+        "HSET" | "HSETNX" | "HGET" | "HMGET" | "HDEL" | "HEXISTS" | "HLEN" | "HKEYS" | "HVALS"
+        | "HGETALL" | "HINCRBY" | "HINCRBYFLOAT" | "HSTRLEN" | "HRANDFIELD" | "HEXPIRE"
+        | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" | "HTTL" | "HPTTL" | "HPERSIST" | "HGETEX"
+        | "HSETEX" => Some(RedisCommand::Hash(HashCommand::parse(&name, args)?)),
+        "SADD" | "SREM" | "SISMEMBER" | "SMISMEMBER" | "SMEMBERS" | "SCARD" | "SPOP"
+        | "SRANDMEMBER" | "SMOVE" | "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE"
+        | "SUNIONSTORE" | "SDIFFSTORE" | "SINTERCARD" => {
+            Some(RedisCommand::Sets(SetCommand::parse(&name, args)?))
+        }
+        "ZADD" | "ZREM" | "ZSCORE" | "ZMSCORE" | "ZINCRBY" | "ZCARD" | "ZCOUNT" | "ZRANK"
+        | "ZREVRANK" | "ZRANGE" | "ZRANGESTORE" | "ZPOPMIN" | "ZPOPMAX" | "ZRANDMEMBER"
+        | "ZUNION" | "ZINTER" | "ZDIFF" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE"
+        | "ZINTERCARD" | "BZPOPMIN" | "BZPOPMAX" | "ZMPOP" | "BZMPOP" => {
+            let command = SortedSetCommand::parse(&name, args)?;
+            Some(RedisCommand::SortedSet(command))
+        }
+        "XADD" | "XLEN" | "XRANGE" | "XREVRANGE" | "XDEL" | "XTRIM" | "XREAD" | "XINFO"
+        | "XGROUP" | "XREADGROUP" | "XACK" | "XPENDING" | "XCLAIM" | "XAUTOCLAIM" => {
+            Some(RedisCommand::Stream(StreamCommand::parse(&name, args)?))
+        }
+        "PFADD" | "PFCOUNT" | "PFMERGE" | "PFDEBUG" => Some(RedisCommand::HyperLogLog(
+            HyperLogLogCommand::parse(&name, args)?,
+        )),
+        "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITOP" | "BITFIELD" | "BITFIELD_RO" => {
+            Some(RedisCommand::Bitmap(BitmapCommand::parse(&name, args)?))
+        }
+        "GEOADD" | "GEOPOS" | "GEODIST" | "GEOHASH" | "GEOSEARCH" | "GEOSEARCHSTORE" => {
+            Some(RedisCommand::Geo(GeoCommand::parse(&name, args)?))
+        }
+        "JSON.SET" | "JSON.GET" | "JSON.DEL" | "JSON.NUMINCRBY" | "JSON.ARRAPPEND"
+        | "JSON.ARRPOP" | "JSON.OBJKEYS" | "JSON.STRAPPEND" | "JSON.TYPE" | "JSON.MGET" => {
+            Some(RedisCommand::Json(JsonCommand::parse(&name, args)?))
+        }
+        "TS.CREATE" | "TS.ADD" | "TS.MADD" | "TS.INCRBY" | "TS.GET" | "TS.RANGE"
+        | "TS.REVRANGE" | "TS.MRANGE" | "TS.CREATERULE" => Some(RedisCommand::TimeSeries(
+            TimeSeriesCommand::parse(&name, args)?,
+        )),
+        "BF.RESERVE" | "BF.ADD" | "BF.MADD" | "BF.EXISTS" | "BF.MEXISTS" | "BF.INFO" => {
+            Some(RedisCommand::Bloom(BloomCommand::parse(&name, args)?))
+        }
+        "CF.ADD" | "CF.EXISTS" | "CF.DEL" => {
+            Some(RedisCommand::Cuckoo(CuckooCommand::parse(&name, args)?))
+        }
+        "CMS.INITBYDIM" | "CMS.INCRBY" | "CMS.QUERY" | "CMS.MERGE" => {
+            Some(RedisCommand::Cms(CmsCommand::parse(&name, args)?))
+        }
+        "TOPK.RESERVE" | "TOPK.ADD" | "TOPK.LIST" | "TOPK.COUNT" => {
+            Some(RedisCommand::TopK(TopKCommand::parse(&name, args)?))
+        }
+        "TDIGEST.CREATE" | "TDIGEST.ADD" | "TDIGEST.QUANTILE" | "TDIGEST.CDF" | "TDIGEST.MERGE" => {
+            Some(RedisCommand::TDigest(TDigestCommand::parse(&name, args)?))
+        }
+        "FT.CREATE" | "FT.SEARCH" | "FT.DROPINDEX" => {
+            Some(RedisCommand::Search(SearchCommand::parse(&name, args)?))
+        }
+        "VADD" | "VSIM" | "VREM" => Some(RedisCommand::Vector(VectorCommand::parse(&name, args)?)),
+        "MULTI" | "EXEC" | "DISCARD" => {
+            Args::new(&name, args).finish()?;
+            Some(match name.as_str() {
+                "MULTI" => RedisCommand::Multi,
+                "EXEC" => RedisCommand::Exec,
+                _ => RedisCommand::Discard,
+            })
+        }
+        "WATCH" => Some(RedisCommand::Watch(Args::new(&name, args).rest()?)),
+        "UNWATCH" => {
+            Args::new(&name, args).finish()?;
+            Some(RedisCommand::Unwatch)
+        }
+        "FLUSHALL" => {
+            let mut args = Args::new(&name, args);
+            if !args.keyword("ASYNC") {
+                args.keyword("SYNC");
+            }
+            args.finish().map_err(|_| CommandError::Syntax)?;
+            Some(RedisCommand::FlushAll)
+        }
+        "HELLO" => {
+            let mut args = Args::new(&name, args);
+            let protover = if args.is_empty() {
+                None
+            } else {
+                Some(args.integer().map_err(|_| {
+                    CommandError::Other(
+                        "ERR Protocol version is not an integer or out of range".into(),
+                    )
+                })?)
+            };
+            args.finish().map_err(|_| CommandError::Syntax)?;
+            Some(RedisCommand::Hello(protover))
+        }
+        _ => return Err(CommandError::UnknownCommand(cmd.clone())),
+    };
+
+    command.ok_or(CommandError::Syntax)
 }
@@ -0,0 +1,522 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    executor::Rewritten,
+    resp_parser::RespData,
+    store::StoreError,
+    stream::{entry_reply, Stream, StreamId},
+};
+
+/// An entry delivered to a consumer that hasn't acknowledged it yet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: i64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Consumer {
+    /// Unix time in milliseconds of its last read or claim attempt.
+    pub seen_time: i64,
+    /// Unix time of its last read or claim that got something, if any.
+    pub active_time: Option<i64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+/// A consumer group of a stream: how far it has read, its consumers, and the
+/// pending entries list (PEL) of entries delivered but not acknowledged.
+pub struct ConsumerGroup {
+    pub last_delivered: StreamId,
+    /// How many entries the group has read, when known, for working out its
+    /// lag.
+    pub entries_read: Option<u64>,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup {
+            last_delivered,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
+
+    pub fn consumers(&self) -> &BTreeMap<String, Consumer> {
+        &self.consumers
+    }
+
+    /// Adds a consumer unless it exists, returning whether it was added.
+    pub fn create_consumer(&mut self, name: &str, now: i64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        let consumer = Consumer {
+            seen_time: now,
+            ..Consumer::default()
+        };
+        self.consumers.insert(name.to_string(), consumer);
+        true
+    }
+
+    /// Records a read or claim attempt by a consumer, which must exist.
+    pub fn see(&mut self, name: &str, now: i64, active: bool) {
+        let consumer = self.consumers.get_mut(name).unwrap();
+        consumer.seen_time = now;
+        if active {
+            consumer.active_time = Some(now);
+        }
+    }
+
+    /// Deletes a consumer and its pending entries, returning how many it
+    /// had.
+    pub fn remove_consumer(&mut self, name: &str) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Assigns a pending entry to a consumer, which must exist, taking it
+    /// from whichever consumer had it.
+    pub fn deliver(&mut self, id: StreamId, consumer: &str, time: i64, count: u64) {
+        if let Some(previous) = self.pending.get(&id) {
+            if let Some(previous) = self.consumers.get_mut(&previous.consumer) {
+                previous.pending.remove(&id);
+            }
+        }
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time: time,
+                delivery_count: count,
+            },
+        );
+        self.consumers.get_mut(consumer).unwrap().pending.insert(id);
+    }
+
+    /// Counts another delivery of a pending entry, as a history read does.
+    pub fn redeliver(&mut self, id: StreamId, time: i64) {
+        if let Some(entry) = self.pending.get_mut(&id) {
+            entry.delivery_time = time;
+            entry.delivery_count += 1;
+        }
+    }
+
+    /// Removes an entry from the PEL, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+        if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+            consumer.pending.remove(&id);
+        }
+        true
+    }
+}
+
+/// Where XCLAIM puts the delivery time of what it claims.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DeliveryTime {
+    /// `IDLE ms`: that long ago.
+    Idle(i64),
+    /// `TIME unix-time-ms`
+    At(i64),
+}
+
+/// XCLAIM's arguments.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Claim {
+    pub key: String,
+    pub group: String,
+    pub consumer: String,
+    pub min_idle: i64,
+    pub ids: Vec<StreamId>,
+    pub options: ClaimOptions,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ClaimOptions {
+    pub delivery_time: Option<DeliveryTime>,
+    pub retry_count: Option<u64>,
+    /// Creates pending entries for IDs that aren't pending yet.
+    pub force: bool,
+    /// Replies with IDs only, and doesn't count a delivery.
+    pub just_id: bool,
+    /// Moves the group's last delivered ID forward to this.
+    pub last_id: Option<StreamId>,
+}
+
+/// XAUTOCLAIM's arguments.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AutoClaim {
+    pub key: String,
+    pub group: String,
+    pub consumer: String,
+    pub min_idle: i64,
+    pub start: StreamId,
+    pub count: usize,
+    pub just_id: bool,
+}
+
+/// The extended form of XPENDING.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingRange {
+    pub min_idle: i64,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+/// What replicas are fed for an entry given to a consumer or found deleted:
+/// an XCLAIM forcing the pending entry into the same state, which drops it
+/// from the PEL if the stream entry no longer exists.
+pub fn claim_command(key: &str, name: &str, group: &ConsumerGroup, id: StreamId) -> Vec<String> {
+    let entry = &group.pending[&id];
+    [
+        "XCLAIM",
+        key,
+        name,
+        &entry.consumer,
+        "0",
+        &id.to_string(),
+        "TIME",
+        &entry.delivery_time.to_string(),
+        "RETRYCOUNT",
+        &entry.delivery_count.to_string(),
+        "FORCE",
+        "JUSTID",
+        "LASTID",
+        &group.last_delivered.to_string(),
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// What replicas are fed when the group's last delivered ID moved.
+pub fn set_id_command(key: &str, name: &str, group: &ConsumerGroup) -> Vec<String> {
+    let entries_read = group.entries_read.map_or(-1, |read| read as i64);
+    [
+        "XGROUP",
+        "SETID",
+        key,
+        name,
+        &group.last_delivered.to_string(),
+        "ENTRIESREAD",
+        &entries_read.to_string(),
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn group_mut<'a>(
+    stream: &'a mut Stream,
+    key: &str,
+    name: &str,
+) -> Result<&'a mut ConsumerGroup, StoreError> {
+    stream
+        .groups
+        .get_mut(name)
+        .ok_or_else(|| StoreError::NoGroup(key.to_string(), name.to_string()))
+}
+
+/// Runs XCLAIM. Returns the reply along with what to feed replicas.
+pub fn claim(stream: &mut Stream, claim: &Claim, now: i64) -> Result<Rewritten, StoreError> {
+    let Claim {
+        key,
+        group: name,
+        consumer,
+        min_idle,
+        ids,
+        options,
+    } = claim;
+    let time = match options.delivery_time {
+        Some(DeliveryTime::Idle(idle)) => now.saturating_sub(idle),
+        Some(DeliveryTime::At(time)) => time,
+        None => now,
+    };
+    // A time in the future is most likely the client's clock being off.
+    let time = if (0..=now).contains(&time) { time } else { now };
+
+    let group = group_mut(stream, key, name)?;
+    let mut last_id_moved = false;
+    if let Some(last_id) = options.last_id.filter(|id| *id > group.last_delivered) {
+        group.last_delivered = last_id;
+        last_id_moved = true;
+    }
+    if group.consumers.contains_key(consumer) {
+        group.see(consumer, now, false);
+    }
+
+    let mut claimed = Vec::new();
+    let mut propagate = Vec::new();
+    for &id in ids {
+        let entry = stream.entry(id);
+        let group = stream.groups.get_mut(name).unwrap();
+        let Some(entry) = entry else {
+            if group.pending.contains_key(&id) {
+                propagate.push(claim_command(key, name, group, id));
+                group.ack(id);
+            }
+            continue;
+        };
+        let count = match group.pending.get(&id) {
+            Some(pending) if now - pending.delivery_time < *min_idle => continue,
+            Some(pending) => pending.delivery_count,
+            None if options.force => 1,
+            None => continue,
+        };
+        let count = match options.retry_count {
+            Some(retry_count) => retry_count,
+            None if options.just_id => count,
+            None => count + 1,
+        };
+        group.create_consumer(consumer, now);
+        group.deliver(id, consumer, time, count);
+        group.see(consumer, now, true);
+        propagate.push(claim_command(key, name, group, id));
+        claimed.push(if options.just_id {
+            RespData::BulkString(id.to_string())
+        } else {
+            entry_reply(&entry)
+        });
+    }
+    // Each XCLAIM carries the last delivered ID along.
+    if last_id_moved && propagate.is_empty() {
+        propagate.push(set_id_command(key, name, &stream.groups[name]));
+    }
+    Ok((RespData::Array(claimed), propagate))
+}
+
+/// Runs XAUTOCLAIM, which claims up to `count` entries idle for at least
+/// `min_idle` from `start` on, looking at no more than ten times as many.
+/// Returns the reply along with what to feed replicas.
+pub fn auto_claim(
+    stream: &mut Stream,
+    claim: &AutoClaim,
+    now: i64,
+) -> Result<Rewritten, StoreError> {
+    let AutoClaim {
+        key,
+        group: name,
+        consumer,
+        min_idle,
+        start,
+        count,
+        just_id,
+    } = claim;
+    let group = group_mut(stream, key, name)?;
+    let mut attempts = count.saturating_mul(10);
+    let ids: Vec<StreamId> = group
+        .pending
+        .range(start..)
+        .map(|(id, _)| *id)
+        .take(attempts.saturating_add(1))
+        .collect();
+
+    let mut count = *count;
+    let mut examined = 0;
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut propagate = Vec::new();
+    for &id in &ids {
+        if attempts == 0 || count == 0 {
+            break;
+        }
+        attempts -= 1;
+        examined += 1;
+        let entry = stream.entry(id);
+        let group = stream.groups.get_mut(name).unwrap();
+        let Some(entry) = entry else {
+            propagate.push(claim_command(key, name, group, id));
+            group.ack(id);
+            deleted.push(RespData::BulkString(id.to_string()));
+            count -= 1;
+            continue;
+        };
+        let pending = &group.pending[&id];
+        if now - pending.delivery_time < *min_idle {
+            continue;
+        }
+        let delivery_count = pending.delivery_count + u64::from(!just_id);
+        group.create_consumer(consumer, now);
+        group.deliver(id, consumer, now, delivery_count);
+        group.see(consumer, now, true);
+        propagate.push(claim_command(key, name, group, id));
+        claimed.push(if *just_id {
+            RespData::BulkString(id.to_string())
+        } else {
+            entry_reply(&entry)
+        });
+        count -= 1;
+    }
+    let cursor = ids.get(examined).copied().unwrap_or(StreamId::MIN);
+    let reply = RespData::Array(vec![
+        RespData::BulkString(cursor.to_string()),
+        RespData::Array(claimed),
+        RespData::Array(deleted),
+    ]);
+    Ok((reply, propagate))
+}
+
+/// XPENDING's reply: a summary of the group's PEL, or the entries in
+/// `range`.
+pub fn pending_reply(group: &ConsumerGroup, range: Option<&PendingRange>, now: i64) -> RespData {
+    let id = |id: &StreamId| RespData::BulkString(id.to_string());
+    let Some(range) = range else {
+        let (Some((first, _)), Some((last, _))) = (
+            group.pending.first_key_value(),
+            group.pending.last_key_value(),
+        ) else {
+            return RespData::Array(vec![
+                RespData::Integer(0),
+                RespData::BulkStringNull,
+                RespData::BulkStringNull,
+                RespData::ArrayNull,
+            ]);
+        };
+        let consumers = group
+            .consumers
+            .iter()
+            .filter(|(_, consumer)| !consumer.pending.is_empty())
+            .map(|(name, consumer)| {
+                RespData::Array(vec![
+                    RespData::BulkString(name.clone()),
+                    RespData::BulkString(consumer.pending.len().to_string()),
+                ])
+            })
+            .collect();
+        return RespData::Array(vec![
+            RespData::Integer(group.pending.len() as i64),
+            id(first),
+            id(last),
+            RespData::Array(consumers),
+        ]);
+    };
+
+    if range.start > range.end {
+        return RespData::Array(Vec::new());
+    }
+    let ids: Box<dyn Iterator<Item = &StreamId>> = match &range.consumer {
+        Some(consumer) => match group.consumers.get(consumer) {
+            Some(consumer) => Box::new(consumer.pending.range(range.start..=range.end)),
+            None => Box::new(std::iter::empty()),
+        },
+        None => Box::new(
+            group
+                .pending
+                .range(range.start..=range.end)
+                .map(|(id, _)| id),
+        ),
+    };
+    let entries = ids
+        .map(|pending_id| (pending_id, &group.pending[pending_id]))
+        .filter(|(_, entry)| now - entry.delivery_time >= range.min_idle)
+        .take(range.count)
+        .map(|(pending_id, entry)| {
+            RespData::Array(vec![
+                id(pending_id),
+                RespData::BulkString(entry.consumer.clone()),
+                RespData::Integer(now - entry.delivery_time),
+                RespData::Integer(entry.delivery_count as i64),
+            ])
+        })
+        .collect();
+    RespData::Array(entries)
+}
+
+/// XINFO GROUPS's reply.
+pub fn groups_reply(stream: &Stream) -> RespData {
+    let field = |name: &str| RespData::BulkString(name.to_string());
+    let optional = |value: Option<u64>| {
+        value.map_or(RespData::BulkStringNull, |value| {
+            RespData::Integer(value as i64)
+        })
+    };
+    let groups = stream
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            RespData::Map(vec![
+                (field("name"), RespData::BulkString(name.clone())),
+                (
+                    field("consumers"),
+                    RespData::Integer(group.consumers.len() as i64),
+                ),
+                (
+                    field("pending"),
+                    RespData::Integer(group.pending.len() as i64),
+                ),
+                (
+                    field("last-delivered-id"),
+                    RespData::BulkString(group.last_delivered.to_string()),
+                ),
+                (field("entries-read"), optional(group.entries_read)),
+                (field("lag"), optional(stream.lag(group))),
+            ])
+        })
+        .collect();
+    RespData::Array(groups)
+}
+
+/// XINFO CONSUMERS's reply.
+pub fn consumers_reply(group: &ConsumerGroup, now: i64) -> RespData {
+    let field = |name: &str| RespData::BulkString(name.to_string());
+    let consumers = group
+        .consumers
+        .iter()
+        .map(|(name, consumer)| {
+            RespData::Map(vec![
+                (field("name"), RespData::BulkString(name.clone())),
+                (
+                    field("pending"),
+                    RespData::Integer(consumer.pending.len() as i64),
+                ),
+                (field("idle"), RespData::Integer(now - consumer.seen_time)),
+                (
+                    field("inactive"),
+                    RespData::Integer(consumer.active_time.map_or(-1, |time| now - time)),
+                ),
+            ])
+        })
+        .collect();
+    RespData::Array(consumers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64) -> StreamId {
+        StreamId { ms, seq: 0 }
+    }
+
+    #[test]
+    fn pending_entries_move_between_consumers() {
+        let mut group = ConsumerGroup::new(StreamId::MIN, None);
+        assert!(group.create_consumer("alice", 0));
+        assert!(!group.create_consumer("alice", 0));
+        group.create_consumer("bob", 0);
+
+        group.deliver(id(1), "alice", 10, 1);
+        group.deliver(id(2), "alice", 10, 1);
+        group.deliver(id(1), "bob", 20, 2);
+        assert_eq!(group.consumers()["alice"].pending.len(), 1);
+        assert_eq!(group.pending[&id(1)].consumer, "bob");
+
+        assert!(group.ack(id(1)));
+        assert!(!group.ack(id(1)));
+        assert!(group.consumers()["bob"].pending.is_empty());
+
+        assert_eq!(group.remove_consumer("alice"), Some(1));
+        assert!(group.pending.is_empty());
+        assert_eq!(group.remove_consumer("alice"), None);
+    }
+}
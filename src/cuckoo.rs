@@ -0,0 +1,244 @@
+use crate::{
+    command::{Args, CommandError},
+    hyperloglog::murmur_hash_64a,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// What CF.ADD creates a missing filter with, as in RedisBloom.
+const DEFAULT_CAPACITY: usize = 1024;
+const BUCKET_SIZE: usize = 2;
+/// How many fingerprints get kicked to other buckets before giving up on a
+/// sub-filter.
+const MAX_ITERATIONS: usize = 20;
+const MAX_FILTERS: usize = 32;
+
+/// An item's fingerprint, never 0 as that marks an empty slot, and the hash
+/// picking its first bucket.
+fn fingerprint(item: &str) -> (u8, u64) {
+    let hash = murmur_hash_64a(item.as_bytes(), 0);
+    ((hash % 255 + 1) as u8, hash)
+}
+
+#[derive(Debug)]
+struct SubFilter {
+    buckets: Vec<[u8; BUCKET_SIZE]>,
+}
+
+impl SubFilter {
+    fn new(capacity: usize) -> Self {
+        let buckets = (capacity / BUCKET_SIZE).max(1).next_power_of_two();
+        SubFilter {
+            buckets: vec![[0; BUCKET_SIZE]; buckets],
+        }
+    }
+
+    /// The other bucket a fingerprint may live in. With a power of two of
+    /// buckets, this leads from either bucket to the other.
+    fn alternate(&self, index: usize, fingerprint: u8) -> usize {
+        let mask = self.buckets.len() as u64 - 1;
+        ((index as u64 ^ u64::from(fingerprint).wrapping_mul(0x5bd1e995)) & mask) as usize
+    }
+
+    fn candidates(&self, (fingerprint, hash): (u8, u64)) -> [usize; 2] {
+        let first = (hash & (self.buckets.len() as u64 - 1)) as usize;
+        [first, self.alternate(first, fingerprint)]
+    }
+
+    fn contains(&self, item: (u8, u64)) -> bool {
+        self.candidates(item)
+            .iter()
+            .any(|&index| self.buckets[index].contains(&item.0))
+    }
+
+    /// Puts a fingerprint in a free slot of the bucket, if it has one.
+    fn place(&mut self, index: usize, fingerprint: u8) -> bool {
+        match self.buckets[index].iter_mut().find(|slot| **slot == 0) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Inserts an item, moving others to their alternate buckets to make
+    /// room if needed. The victims are picked in a fixed order rather than at
+    /// random, so that replicas end up with the same filter.
+    fn insert(&mut self, item: (u8, u64)) -> bool {
+        let [first, second] = self.candidates(item);
+        if self.place(first, item.0) || self.place(second, item.0) {
+            return true;
+        }
+
+        let (mut index, mut fingerprint) = (second, item.0);
+        let mut kicked = Vec::new();
+        for i in 0..MAX_ITERATIONS {
+            let slot = i % BUCKET_SIZE;
+            let victim = std::mem::replace(&mut self.buckets[index][slot], fingerprint);
+            kicked.push((index, slot, victim));
+            fingerprint = victim;
+            index = self.alternate(index, fingerprint);
+            if self.place(index, fingerprint) {
+                return true;
+            }
+        }
+        // No room turned up: put everything back as it was.
+        for (index, slot, victim) in kicked.into_iter().rev() {
+            self.buckets[index][slot] = victim;
+        }
+        false
+    }
+
+    fn delete(&mut self, item: (u8, u64)) -> bool {
+        for index in self.candidates(item) {
+            if let Some(slot) = self.buckets[index].iter_mut().find(|slot| **slot == item.0) {
+                *slot = 0;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A cuckoo filter, which unlike a Bloom filter can forget items. When an
+/// item doesn't fit, another sub-filter of the same size is added.
+#[derive(Debug)]
+pub struct CuckooFilter {
+    filters: Vec<SubFilter>,
+}
+
+impl Default for CuckooFilter {
+    fn default() -> Self {
+        CuckooFilter {
+            filters: vec![SubFilter::new(DEFAULT_CAPACITY)],
+        }
+    }
+}
+
+impl CuckooFilter {
+    pub fn add(&mut self, item: &str) -> Result<(), StoreError> {
+        let item = fingerprint(item);
+        if self.filters.iter_mut().any(|filter| filter.insert(item)) {
+            return Ok(());
+        }
+        if self.filters.len() == MAX_FILTERS {
+            return Err(StoreError::CuckooFull);
+        }
+        let mut filter = SubFilter::new(DEFAULT_CAPACITY);
+        filter.insert(item);
+        self.filters.push(filter);
+        Ok(())
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        let item = fingerprint(item);
+        self.filters.iter().any(|filter| filter.contains(item))
+    }
+
+    /// Removes one copy of `item`, returning whether there was one.
+    pub fn delete(&mut self, item: &str) -> bool {
+        let item = fingerprint(item);
+        self.filters
+            .iter_mut()
+            .rev()
+            .any(|filter| filter.delete(item))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CuckooCommand {
+    Add(String, String),
+    Exists(String, String),
+    Del(String, String),
+}
+
+impl CuckooCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<CuckooCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let (key, item) = (args.string()?, args.string()?);
+        let command = match name {
+            "CF.ADD" => CuckooCommand::Add(key, item),
+            "CF.EXISTS" => CuckooCommand::Exists(key, item),
+            "CF.DEL" => CuckooCommand::Del(key, item),
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(self, CuckooCommand::Exists(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            CuckooCommand::Add(key, _)
+            | CuckooCommand::Exists(key, _)
+            | CuckooCommand::Del(key, _) => vec![key],
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: CuckooCommand) -> Result<RespData, StoreError> {
+    match command {
+        CuckooCommand::Add(key, item) => {
+            if db.cuckoo(&key)?.is_none() {
+                db.set(key.clone(), Value::Cuckoo(CuckooFilter::default()), None);
+            }
+            db.cuckoo_mut(&key)?.unwrap().add(&item)?;
+            db.touch(&key);
+            Ok(RespData::Integer(1))
+        }
+        CuckooCommand::Exists(key, item) => {
+            let found = db
+                .cuckoo(&key)?
+                .is_some_and(|filter| filter.contains(&item));
+            Ok(RespData::Integer(found.into()))
+        }
+        CuckooCommand::Del(key, item) => {
+            let filter = db.cuckoo_mut(&key)?.ok_or(StoreError::CuckooNotFound)?;
+            let deleted = filter.delete(&item);
+            if deleted {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(deleted.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_and_deletes() {
+        let mut filter = CuckooFilter::default();
+        filter.add("a").unwrap();
+        filter.add("a").unwrap();
+        assert!(filter.contains("a"));
+        assert!(!filter.contains("b"));
+        assert!(filter.delete("a"));
+        assert!(filter.contains("a"));
+        assert!(filter.delete("a"));
+        assert!(!filter.contains("a"));
+        assert!(!filter.delete("a"));
+    }
+
+    #[test]
+    fn grows_when_full() {
+        let mut filter = CuckooFilter::default();
+        for i in 0..3000 {
+            filter.add(&i.to_string()).unwrap();
+        }
+        assert!(filter.filters.len() > 1);
+        assert!((0..3000).all(|i| filter.contains(&i.to_string())));
+        for i in 0..3000 {
+            assert!(filter.delete(&i.to_string()), "{}", i);
+        }
+        assert!(filter.filters.iter().all(|filter| filter
+            .buckets
+            .iter()
+            .all(|bucket| *bucket == [0; BUCKET_SIZE])));
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+};
+
+use crate::random;
+
+const INITIAL_SIZE: usize = 4;
+/// Shrink once fewer than this percentage of the buckets are in use.
+const MIN_FILL: usize = 10;
+/// Buckets moved to the new table by each write while rehashing.
+const REHASH_STEP: usize = 1;
+/// Empty buckets a rehash step may skip before giving up, so that a sparse
+/// table can't make a single step slow.
+const REHASH_EMPTY_VISITS: usize = 10 * REHASH_STEP;
+
+type Table<K, V> = Vec<Vec<(K, V)>>;
+
+fn new_table<K, V>(size: usize) -> Table<K, V> {
+    (0..size).map(|_| Vec::new()).collect()
+}
+
+/// Advances a scan cursor over a table with the given mask by incrementing
+/// its reversed bits.
+fn next_cursor(cursor: u64, mask: u64) -> u64 {
+    let mut cursor = cursor | !mask;
+    cursor = cursor.reverse_bits();
+    cursor = cursor.wrapping_add(1);
+    cursor.reverse_bits()
+}
+
+/// A chained hash table with a power-of-two number of buckets, modelled on
+/// Redis's `dict`. Unlike `std::collections::HashMap` it exposes its bucket
+/// layout, which is what makes a stateless `SCAN` cursor and cheap random
+/// sampling possible.
+///
+/// Like Redis it resizes incrementally: a resize allocates the new table and
+/// every later write moves a few buckets over, so that no single command
+/// pays for rehashing a large keyspace.
+pub struct Dict<K, V> {
+    table: Table<K, V>,
+    /// The table being rehashed into, along with the next bucket of `table`
+    /// to move. New entries go here while rehashing.
+    rehashing: Option<(Table<K, V>, usize)>,
+    len: usize,
+    hash_builder: RandomState,
+}
+
+impl<K: Hash + Eq, V> Default for Dict<K, V> {
+    fn default() -> Self {
+        Dict::new()
+    }
+}
+
+impl<K: Hash + Eq, V> Dict<K, V> {
+    pub fn new() -> Self {
+        Dict {
+            table: new_table(INITIAL_SIZE),
+            rehashing: None,
+            len: 0,
+            hash_builder: RandomState::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn bucket_in<Q: Hash + ?Sized>(&self, table: &Table<K, V>, key: &Q) -> usize {
+        self.hash_builder.hash_one(key) as usize & (table.len() - 1)
+    }
+
+    /// The tables to look a key up in: the new one only holds entries while
+    /// rehashing.
+    fn tables(&self) -> impl Iterator<Item = &Table<K, V>> {
+        std::iter::once(&self.table).chain(self.rehashing.as_ref().map(|(table, _)| table))
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tables().find_map(|table| {
+            table[self.bucket_in(table, key)]
+                .iter()
+                .find(|(k, _)| k.borrow() == key)
+                .map(|(_, v)| v)
+        })
+    }
+
+    /// Where `key` is stored: whether in the new table, its bucket and its
+    /// position in the bucket's chain.
+    fn locate<Q>(&self, key: &Q) -> Option<(bool, usize, usize)>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tables().enumerate().find_map(|(i, table)| {
+            let bucket = self.bucket_in(table, key);
+            table[bucket]
+                .iter()
+                .position(|(k, _)| k.borrow() == key)
+                .map(|position| (i == 1, bucket, position))
+        })
+    }
+
+    fn chain_mut(&mut self, rehashed: bool, bucket: usize) -> &mut Vec<(K, V)> {
+        match &mut self.rehashing {
+            Some((table, _)) if rehashed => &mut table[bucket],
+            _ => &mut self.table[bucket],
+        }
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash_step();
+        let (rehashed, bucket, position) = self.locate(key)?;
+        Some(&mut self.chain_mut(rehashed, bucket)[position].1)
+    }
+
+    /// Inserts a value, returning the previous one if the key was present.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.rehash_step();
+        if let Some((rehashed, bucket, position)) = self.locate(&key) {
+            let v = &mut self.chain_mut(rehashed, bucket)[position].1;
+            return Some(std::mem::replace(v, value));
+        }
+
+        match &mut self.rehashing {
+            Some((table, _)) => {
+                let bucket = self.hash_builder.hash_one(&key) as usize & (table.len() - 1);
+                table[bucket].push((key, value));
+            }
+            None => {
+                let bucket = self.bucket_in(&self.table, &key);
+                self.table[bucket].push((key, value));
+            }
+        }
+        self.len += 1;
+        if self.rehashing.is_none() && self.len > self.table.len() {
+            self.resize(self.table.len() * 2);
+        }
+        None
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.rehash_step();
+        let (rehashed, bucket, position) = self.locate(key)?;
+        let entry = self.chain_mut(rehashed, bucket).swap_remove(position);
+        self.len -= 1;
+
+        if self.rehashing.is_none()
+            && self.table.len() > INITIAL_SIZE
+            && self.len * 100 < self.table.len() * MIN_FILL
+        {
+            self.resize((self.len.max(1) * 2).next_power_of_two().max(INITIAL_SIZE));
+        }
+        Some(entry)
+    }
+
+    /// Starts rehashing into a table of `size` buckets.
+    fn resize(&mut self, size: usize) {
+        self.rehashing = Some((new_table(size), 0));
+    }
+
+    /// Moves the next few buckets to the new table, finishing the rehash once
+    /// the old table is empty.
+    fn rehash_step(&mut self) {
+        let Some((mut new, mut next)) = self.rehashing.take() else {
+            return;
+        };
+        let mut moved = 0;
+        let mut empty_visits = 0;
+        while moved < REHASH_STEP && next < self.table.len() {
+            if self.table[next].is_empty() {
+                next += 1;
+                empty_visits += 1;
+                if empty_visits == REHASH_EMPTY_VISITS {
+                    break;
+                }
+                continue;
+            }
+            for (key, value) in std::mem::take(&mut self.table[next]) {
+                let bucket = self.bucket_in(&new, &key);
+                new[bucket].push((key, value));
+            }
+            next += 1;
+            moved += 1;
+        }
+
+        if next == self.table.len() {
+            self.table = new;
+        } else {
+            self.rehashing = Some((new, next));
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.tables().flatten().flatten().map(|(k, v)| (k, v))
+    }
+
+    /// Picks a random entry: a random non-empty bucket of either table, then
+    /// a random element of its chain. Like Redis this is not perfectly
+    /// uniform, but it is cheap.
+    pub fn random_entry(&self) -> Option<(&K, &V)> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let buckets = self.tables().map(Vec::len).sum();
+        loop {
+            let index = random::below(buckets);
+            let bucket = match self.table.get(index) {
+                Some(bucket) => bucket,
+                None => &self.rehashing.as_ref().unwrap().0[index - self.table.len()],
+            };
+            if !bucket.is_empty() {
+                let (k, v) = &bucket[random::below(bucket.len())];
+                return Some((k, v));
+            }
+        }
+    }
+
+    pub fn random_key(&self) -> Option<&K> {
+        self.random_entry().map(|(k, _)| k)
+    }
+
+    /// Visits one bucket and returns the cursor for the next call; a returned
+    /// cursor of 0 means the iteration is complete.
+    ///
+    /// The cursor is advanced by incrementing its *reversed* bits, so the
+    /// high-order bits of the bucket index change first. Growing the table
+    /// splits bucket `i` into `i` and `i | old_size`, and shrinking merges them
+    /// back; either way every bucket not yet visited maps to a cursor that is
+    /// still ahead of us. Every element present for the whole iteration is
+    /// therefore returned at least once, even if the table resizes between
+    /// calls. Elements may be returned more than once.
+    ///
+    /// While rehashing, the cursor's bucket in the smaller table is visited
+    /// along with every bucket of the larger table that it expands to.
+    pub fn scan<F: FnMut(&K, &V)>(&self, cursor: u64, mut f: F) -> u64 {
+        if self.len == 0 {
+            return 0;
+        }
+
+        let Some((new, _)) = &self.rehashing else {
+            let mask = (self.table.len() - 1) as u64;
+            for (k, v) in &self.table[(cursor & mask) as usize] {
+                f(k, v);
+            }
+            return next_cursor(cursor, mask);
+        };
+
+        let (small, large) = if self.table.len() <= new.len() {
+            (&self.table, new)
+        } else {
+            (new, &self.table)
+        };
+        let small_mask = (small.len() - 1) as u64;
+        let large_mask = (large.len() - 1) as u64;
+        for (k, v) in &small[(cursor & small_mask) as usize] {
+            f(k, v);
+        }
+        let mut cursor = cursor;
+        loop {
+            for (k, v) in &large[(cursor & large_mask) as usize] {
+                f(k, v);
+            }
+            cursor = next_cursor(cursor, large_mask);
+            if cursor & (small_mask ^ large_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn scan_all(dict: &Dict<String, ()>) -> HashSet<String> {
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            cursor = dict.scan(cursor, |k, _| {
+                seen.insert(k.clone());
+            });
+            if cursor == 0 {
+                return seen;
+            }
+        }
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut dict = Dict::new();
+        assert_eq!(dict.insert("a".to_string(), 1), None);
+        assert_eq!(dict.insert("a".to_string(), 2), Some(1));
+        assert_eq!(dict.get("a"), Some(&2));
+        assert_eq!(dict.remove("a"), Some(2));
+        assert_eq!(dict.len(), 0);
+    }
+
+    #[test]
+    fn scan_returns_every_key() {
+        let mut dict = Dict::new();
+        for i in 0..1000 {
+            dict.insert(format!("key:{}", i), ());
+        }
+        assert_eq!(scan_all(&dict).len(), 1000);
+    }
+
+    #[test]
+    fn scan_survives_resizing() {
+        let mut dict = Dict::new();
+        for i in 0..100 {
+            dict.insert(format!("key:{}", i), ());
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut step = 0;
+        loop {
+            cursor = dict.scan(cursor, |k, _| {
+                seen.insert(k.clone());
+            });
+            step += 1;
+            if step == 5 {
+                for i in 100..2000 {
+                    dict.insert(format!("key:{}", i), ());
+                }
+            }
+            if step == 40 {
+                for i in 100..2000 {
+                    dict.remove(&format!("key:{}", i));
+                }
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        for i in 0..100 {
+            assert!(seen.contains(&format!("key:{}", i)));
+        }
+    }
+
+    #[test]
+    fn resizing_is_incremental() {
+        let mut dict = Dict::new();
+        for i in 0..=INITIAL_SIZE {
+            dict.insert(i, i);
+        }
+        assert!(dict.rehashing.is_some());
+        for i in 0..=INITIAL_SIZE {
+            assert_eq!(dict.get(&i), Some(&i));
+        }
+        assert_eq!(dict.iter().count(), INITIAL_SIZE + 1);
+
+        for _ in 0..INITIAL_SIZE {
+            dict.get_mut(&0);
+        }
+        assert!(dict.rehashing.is_none());
+        assert_eq!(dict.table.len(), INITIAL_SIZE * 2);
+        for i in 0..=INITIAL_SIZE {
+            assert_eq!(dict.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn scan_while_rehashing() {
+        let mut dict = Dict::new();
+        for i in 0..1000 {
+            dict.insert(format!("key:{}", i), ());
+            if dict.rehashing.is_some() && i > 600 {
+                break;
+            }
+        }
+        assert!(dict.rehashing.is_some());
+        let len = dict.len();
+        assert_eq!(scan_all(&dict).len(), len);
+        assert!(dict.random_key().is_some());
+    }
+}
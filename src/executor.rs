@@ -0,0 +1,706 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    bitmap, bloom, cms,
+    command::{CommandArgs, RedisCommand},
+    cuckoo, geo,
+    hash::{self, HashCommand},
+    hyperloglog, json, list,
+    resp_parser::{Protocol, RespData},
+    search,
+    set::{self, SetCommand},
+    store::{unix_time_ms, Store, StoreError, Value},
+    stream::{self, StreamCommand},
+    tdigest, timeseries, topk, vector, zset,
+};
+
+/// State a command runs against: the store, the client's selected database
+/// and whatever the command wants fed to replicas besides itself.
+pub struct Context<'a> {
+    pub store: &'a Store,
+    pub db: usize,
+    pub is_master: bool,
+    /// Commands to propagate before the executed one, e.g. the `DEL` for a key
+    /// that was found expired while running it.
+    pub also_propagate: Vec<Vec<String>>,
+    /// Set when the command was rewritten into `also_propagate` and must not
+    /// be fed to replicas as sent, e.g. `BLPOP` becoming `LPOP`.
+    pub prevent_propagation: bool,
+    /// Whether the caller can wait for a blocking command; replicated
+    /// commands never block.
+    pub can_block: bool,
+    /// Set when the command found nothing to serve and the client has to
+    /// wait instead of getting a reply.
+    pub blocked: Option<Blocked>,
+}
+
+/// A reply together with the commands to feed replicas in place of the
+/// command that produced it.
+pub type Rewritten = (RespData, Vec<Vec<String>>);
+
+pub struct Blocked {
+    pub command: RedisCommand,
+    pub deadline: Option<Instant>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(store: &'a Store, db: usize, is_master: bool) -> Self {
+        Context {
+            store,
+            db,
+            is_master,
+            also_propagate: Vec::new(),
+            prevent_propagation: false,
+            can_block: false,
+            blocked: None,
+        }
+    }
+
+    /// Lazily deletes `key` if it has expired, or else the expired fields of
+    /// the hash it holds. Replicas never expire anything on their own; they
+    /// wait for the master's `DEL` or `HDEL`.
+    pub fn expire_if_needed(&mut self, key: &str) {
+        if !self.is_master {
+            return;
+        }
+        if self.store.expire_if_needed(self.db, key) {
+            self.also_propagate
+                .push(vec!["DEL".to_string(), key.to_string()]);
+            return;
+        }
+
+        let fields = self.store.expire_hash_fields(self.db, key);
+        if !fields.is_empty() {
+            let mut args = vec!["HDEL".to_string(), key.to_string()];
+            args.extend(fields);
+            self.also_propagate.push(args);
+        }
+    }
+
+    /// What to feed replicas for a command that just ran: whatever it asked
+    /// for, then the command as sent if it was a write that succeeded and
+    /// wasn't rewritten.
+    pub fn propagated(
+        &mut self,
+        is_write: bool,
+        args: CommandArgs,
+        response: &RespData,
+    ) -> Vec<CommandArgs> {
+        let mut propagated: Vec<CommandArgs> = std::mem::take(&mut self.also_propagate)
+            .into_iter()
+            .map(|args| args.into_iter().map(String::into_bytes).collect())
+            .collect();
+        if is_write && !self.prevent_propagation && !matches!(response, RespData::Error(_)) {
+            propagated.push(args);
+        }
+        propagated
+    }
+
+    fn db_index(&self, index: i64) -> Option<usize> {
+        usize::try_from(index)
+            .ok()
+            .filter(|index| *index < self.store.databases())
+    }
+}
+
+fn ok() -> RespData {
+    RespData::SimpleString("OK".to_string())
+}
+
+fn error(message: &str) -> RespData {
+    RespData::Error(message.to_string())
+}
+
+fn bulk_strings(items: Vec<String>) -> RespData {
+    RespData::Array(items.into_iter().map(RespData::BulkString).collect())
+}
+
+fn reply(result: Result<RespData, StoreError>) -> RespData {
+    result.unwrap_or_else(|e| RespData::Error(e.to_string()))
+}
+
+/// Runs a blocking command as if it didn't block, returning `None` when there
+/// is nothing to serve it yet. What it did is propagated in its non-blocking
+/// form, since replicas must never block.
+pub fn try_unblock(ctx: &mut Context, command: &RedisCommand) -> Option<RespData> {
+    for key in command.keys() {
+        ctx.expire_if_needed(key);
+    }
+
+    let result = match command {
+        RedisCommand::List(command) => ctx.store.write(ctx.db, |db| list::try_pop(db, command)),
+        RedisCommand::SortedSet(command) => {
+            ctx.store.write(ctx.db, |db| zset::try_pop(db, command))
+        }
+        RedisCommand::Stream(command) => {
+            ctx.store.write(ctx.db, |db| stream::try_read(db, command))
+        }
+        _ => Ok(None),
+    };
+    ctx.prevent_propagation = true;
+    match result {
+        Ok(Some((response, propagate))) => {
+            ctx.also_propagate.extend(propagate);
+            Some(response)
+        }
+        Ok(None) => None,
+        Err(e) => Some(RespData::Error(e.to_string())),
+    }
+}
+
+pub fn execute(ctx: &mut Context, command: RedisCommand) -> RespData {
+    for key in command.keys() {
+        ctx.expire_if_needed(key);
+    }
+
+    // XREADGROUP adds its consumer whether or not it gets to read.
+    if let RedisCommand::Stream(command) = &command {
+        match ctx
+            .store
+            .write(ctx.db, |db| stream::create_consumers(db, command))
+        {
+            Ok(created) => ctx.also_propagate.extend(created),
+            Err(e) => return RespData::Error(e.to_string()),
+        }
+    }
+
+    if let Some(timeout) = command.timeout() {
+        return match try_unblock(ctx, &command) {
+            Some(response) => response,
+            None if ctx.can_block => {
+                let mut command = command;
+                if let RedisCommand::Stream(command) = &mut command {
+                    ctx.store.read(ctx.db, |db| command.resolve_read_ids(db));
+                }
+                let response = command.timeout_reply();
+                // A timeout too large for an `Instant` is as good as forever.
+                let deadline = Duration::try_from_secs_f64(timeout)
+                    .ok()
+                    .filter(|timeout| !timeout.is_zero())
+                    .and_then(|timeout| Instant::now().checked_add(timeout));
+                ctx.blocked = Some(Blocked { command, deadline });
+                response
+            }
+            None => command.timeout_reply(),
+        };
+    }
+
+    match command {
+        RedisCommand::Set(key, value, px) => {
+            let expires_at = px.map(|px| Instant::now() + Duration::from_millis(px));
+            ctx.store
+                .write(ctx.db, |db| db.set(key, Value::String(value), expires_at));
+            ok()
+        }
+        RedisCommand::Get(key) => reply(ctx.store.read(ctx.db, |db| {
+            Ok(db
+                .string(&key)?
+                .map(|value| RespData::BulkBytes(value.to_vec()))
+                .unwrap_or(RespData::BulkStringNull))
+        })),
+        RedisCommand::Del(keys) => {
+            let deleted = ctx.store.write(ctx.db, |db| {
+                keys.iter().filter(|key| db.remove(key).is_some()).count()
+            });
+            // Replicas have nothing to delete either.
+            ctx.prevent_propagation = deleted == 0;
+            RespData::Integer(deleted as i64)
+        }
+        RedisCommand::Keys(pattern) => bulk_strings(ctx.store.keys(ctx.db, &pattern)),
+        RedisCommand::Scan(scan) => {
+            let result = ctx.store.scan(
+                ctx.db,
+                scan.cursor,
+                scan.pattern.as_deref(),
+                scan.count,
+                scan.type_name.as_deref(),
+            );
+            RespData::Array(vec![
+                RespData::BulkString(result.cursor.to_string()),
+                bulk_strings(result.keys),
+            ])
+        }
+        RedisCommand::Move(key, db) => {
+            let Some(db) = ctx.db_index(db) else {
+                return error("ERR DB index is out of range");
+            };
+            if db == ctx.db {
+                return error("ERR source and destination objects are the same");
+            }
+
+            RespData::Integer(ctx.store.move_key(ctx.db, db, &key) as i64)
+        }
+        RedisCommand::SwapDb(a, b) => match (ctx.db_index(a), ctx.db_index(b)) {
+            (Some(a), Some(b)) => {
+                ctx.store.swap_dbs(a, b);
+                ok()
+            }
+            _ => error("ERR DB index is out of range"),
+        },
+        RedisCommand::FlushAll => {
+            ctx.store.flush_all();
+            ok()
+        }
+        RedisCommand::List(command) => {
+            reply(ctx.store.write(ctx.db, |db| list::execute(db, command)))
+        }
+        RedisCommand::Hash(mut command) => {
+            command.resolve_expiry(unix_time_ms());
+            let replicated = command.replicated_form();
+            let incremented = match &command {
+                HashCommand::IncrByFloat(key, field, _) => Some((key.clone(), field.clone())),
+                _ => None,
+            };
+            let written = command.is_write().then(|| command.keys()[0].to_string());
+            let response = reply(ctx.store.write(ctx.db, |db| {
+                let result = hash::execute(db, command);
+                if let Some(key) = written {
+                    db.reindex(&key);
+                }
+                result
+            }));
+            if let (Some(args), false) = (replicated, matches!(response, RespData::Error(_))) {
+                ctx.prevent_propagation = true;
+                ctx.also_propagate.push(args);
+            }
+            // Replicas get the resulting value rather than the increment, so
+            // that float rounding can't make them drift, set without touching
+            // the field's TTL as HSET would.
+            if let (Some((key, field)), RespData::BulkString(value)) = (incremented, &response) {
+                ctx.prevent_propagation = true;
+                ctx.also_propagate.push(vec![
+                    "HSETEX".to_string(),
+                    key,
+                    "KEEPTTL".to_string(),
+                    "FIELDS".to_string(),
+                    "1".to_string(),
+                    field,
+                    value.clone(),
+                ]);
+            }
+            response
+        }
+        RedisCommand::SortedSet(command) => {
+            reply(ctx.store.write(ctx.db, |db| zset::execute(db, command)))
+        }
+        // Without BLOCK, XREADGROUP still moves its group along the same way
+        // and has to tell replicas.
+        RedisCommand::Stream(StreamCommand::ReadGroup { .. }) => {
+            try_unblock(ctx, &command).unwrap_or(RespData::ArrayNull)
+        }
+        RedisCommand::Stream(command @ (StreamCommand::Claim(_) | StreamCommand::AutoClaim(_))) => {
+            ctx.prevent_propagation = true;
+            match ctx.store.write(ctx.db, |db| stream::claim(db, &command)) {
+                Ok((response, propagate)) => {
+                    ctx.also_propagate.extend(propagate);
+                    response
+                }
+                Err(e) => RespData::Error(e.to_string()),
+            }
+        }
+        RedisCommand::Stream(command) => {
+            let original = matches!(command, StreamCommand::Add { .. } | StreamCommand::Trim(..))
+                .then(|| command.clone());
+            let response = reply(ctx.store.write(ctx.db, |db| stream::execute(db, command)));
+            if let (Some(command), false) = (original, matches!(response, RespData::Error(_))) {
+                let len = ctx.store.read(ctx.db, |db| {
+                    db.stream(command.keys()[0])
+                        .ok()
+                        .flatten()
+                        .map_or(0, |stream| stream.len())
+                });
+                ctx.prevent_propagation = true;
+                ctx.also_propagate
+                    .extend(command.replicated_form(&response, len));
+            }
+            response
+        }
+        RedisCommand::HyperLogLog(command) => reply(
+            ctx.store
+                .write(ctx.db, |db| hyperloglog::execute(db, command)),
+        ),
+        RedisCommand::Bitmap(command) => {
+            reply(ctx.store.write(ctx.db, |db| bitmap::execute(db, command)))
+        }
+        RedisCommand::Geo(command) => {
+            reply(ctx.store.write(ctx.db, |db| geo::execute(db, command)))
+        }
+        RedisCommand::Json(command) => {
+            reply(ctx.store.write(ctx.db, |db| json::execute(db, command)))
+        }
+        RedisCommand::Bloom(command) => {
+            reply(ctx.store.write(ctx.db, |db| bloom::execute(db, command)))
+        }
+        RedisCommand::Cuckoo(command) => {
+            reply(ctx.store.write(ctx.db, |db| cuckoo::execute(db, command)))
+        }
+        RedisCommand::Cms(command) => {
+            reply(ctx.store.write(ctx.db, |db| cms::execute(db, command)))
+        }
+        RedisCommand::TopK(command) => {
+            reply(ctx.store.write(ctx.db, |db| topk::execute(db, command)))
+        }
+        RedisCommand::TDigest(command) => {
+            reply(ctx.store.write(ctx.db, |db| tdigest::execute(db, command)))
+        }
+        RedisCommand::Vector(command) => {
+            reply(ctx.store.write(ctx.db, |db| vector::execute(db, command)))
+        }
+        RedisCommand::Search(command) => {
+            reply(ctx.store.write(ctx.db, |db| search::execute(db, command)))
+        }
+        RedisCommand::TimeSeries(mut command) => {
+            command.resolve_timestamps(unix_time_ms());
+            let replicated = command.replicated_form();
+            let response = reply(
+                ctx.store
+                    .write(ctx.db, |db| timeseries::execute(db, command)),
+            );
+            if let (Some(args), false) = (replicated, matches!(response, RespData::Error(_))) {
+                ctx.prevent_propagation = true;
+                ctx.also_propagate.push(args);
+            }
+            response
+        }
+        RedisCommand::Sets(command) => {
+            let popped_from = match &command {
+                SetCommand::Pop { key, .. } => Some(key.clone()),
+                _ => None,
+            };
+            let response = reply(ctx.store.write(ctx.db, |db| set::execute(db, command)));
+            // SPOP picks members at random, so replicas are told which ones
+            // went instead.
+            if let Some(key) = popped_from {
+                ctx.prevent_propagation = true;
+                let popped: Vec<String> = match &response {
+                    RespData::BulkString(member) => vec![member.clone()],
+                    RespData::Array(members) => members
+                        .iter()
+                        .filter_map(|member| match member {
+                            RespData::BulkString(member) => Some(member.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                if !popped.is_empty() {
+                    let mut args = vec!["SREM".to_string(), key];
+                    args.extend(popped);
+                    ctx.also_propagate.push(args);
+                }
+            }
+            response
+        }
+        _ => error("ERR command not supported here"),
+    }
+}
+
+/// What HELLO replies with, along with the protocol the client speaks from
+/// then on: `protover` if given and supported, else `protocol` unchanged.
+pub fn hello(protover: Option<i64>, protocol: Protocol, is_master: bool) -> (RespData, Protocol) {
+    let protocol = match protover {
+        None => protocol,
+        Some(2) => Protocol::Resp2,
+        Some(3) => Protocol::Resp3,
+        Some(_) => return (error("NOPROTO unsupported protocol version"), protocol),
+    };
+    let proto = match protocol {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    };
+    let role = if is_master { "master" } else { "replica" };
+    let field = |name: &str| RespData::BulkString(name.to_string());
+    let reply = RespData::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field("7.4.0")),
+        (field("proto"), RespData::Integer(proto)),
+        (field("mode"), field("standalone")),
+        (field("role"), field(role)),
+        (field("modules"), RespData::Array(Vec::new())),
+    ]);
+    (reply, protocol)
+}
+
+/// Runs the commands a client queued after MULTI one after the other, with
+/// nothing else getting in between. Blocking commands don't block, as in
+/// Redis. Returns the replies along with what to feed replicas, wrapped in
+/// MULTI and EXEC so that they apply it at once too, and each with the
+/// database it runs in.
+pub fn execute_transaction(
+    store: &Store,
+    mut db: usize,
+    mut protocol: Protocol,
+    is_master: bool,
+    commands: Vec<(RedisCommand, CommandArgs)>,
+) -> (RespData, Vec<(usize, CommandArgs)>) {
+    let mut replies = Vec::new();
+    let mut propagated = Vec::new();
+    for (command, args) in commands {
+        let response = match command {
+            RedisCommand::Ping => RespData::SimpleString("PONG".to_string()),
+            RedisCommand::Echo(message) => RespData::BulkString(message),
+            // The watched keys were already checked by the time EXEC runs.
+            RedisCommand::Unwatch => ok(),
+            RedisCommand::Hello(protover) => {
+                let response;
+                (response, protocol) = hello(protover, protocol, is_master);
+                response
+            }
+            RedisCommand::Select(index) => {
+                let ctx = Context::new(store, db, is_master);
+                match ctx.db_index(index) {
+                    Some(index) => {
+                        db = index;
+                        ok()
+                    }
+                    None => error("ERR DB index is out of range"),
+                }
+            }
+            command => {
+                let is_write = command.is_write();
+                let mut ctx = Context::new(store, db, is_master);
+                let response = execute(&mut ctx, command);
+                for args in ctx.propagated(is_write, args, &response) {
+                    propagated.push((db, args));
+                }
+                response
+            }
+        };
+        replies.push(response);
+    }
+
+    if let (Some((first, _)), Some((last, _))) = (propagated.first(), propagated.last()) {
+        let (first, last) = (*first, *last);
+        propagated.insert(0, (first, vec![b"MULTI".to_vec()]));
+        propagated.push((last, vec![b"EXEC".to_vec()]));
+    }
+    (RespData::Array(replies), propagated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{command_args, parse_command},
+        resp_parser::parse_resp,
+    };
+
+    fn parse(args: &[&str]) -> (RedisCommand, CommandArgs) {
+        let resp = RespData::Array(
+            args.iter()
+                .map(|arg| RespData::BulkString(arg.to_string()))
+                .collect(),
+        );
+        (parse_command(&resp).unwrap(), command_args(&resp))
+    }
+
+    fn text(args: &[Vec<u8>]) -> Vec<&str> {
+        args.iter()
+            .map(|arg| std::str::from_utf8(arg).unwrap())
+            .collect()
+    }
+
+    /// Runs a command on a master, returning its reply and what it feeds
+    /// replicas.
+    fn run(store: &Store, args: &[&str]) -> (RespData, Vec<Vec<String>>) {
+        let (command, args) = parse(args);
+        let is_write = command.is_write();
+        let mut ctx = Context::new(store, 0, true);
+        let response = execute(&mut ctx, command);
+        let propagated = ctx
+            .propagated(is_write, args, &response)
+            .iter()
+            .map(|args| text(args).into_iter().map(str::to_string).collect())
+            .collect();
+        (response, propagated)
+    }
+
+    #[test]
+    fn string_values_are_binary_safe() {
+        let store = Store::new(1);
+        let request = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\n\xff\x00\r\n";
+        let (_, resp) = parse_resp(request).unwrap();
+        let mut ctx = Context::new(&store, 0, true);
+        let response = execute(&mut ctx, parse_command(&resp).unwrap());
+        assert_eq!(response, ok());
+        assert_eq!(
+            ctx.propagated(true, command_args(&resp), &response),
+            [[b"SET".to_vec(), b"k".to_vec(), vec![0xff, 0x00]]]
+        );
+        assert_eq!(
+            run(&store, &["GET", "k"]).0,
+            RespData::BulkBytes(vec![0xff, 0x00])
+        );
+    }
+
+    #[test]
+    fn hyperloglogs_read_back_from_their_strings() {
+        let store = Store::new(1);
+        run(&store, &["PFADD", "h", "a", "b", "c"]);
+        let (RespData::BulkBytes(bytes), _) = run(&store, &["GET", "h"]) else {
+            panic!("GET should return the encoded HyperLogLog");
+        };
+        let set = RespData::Array(vec![
+            RespData::BulkString("SET".to_string()),
+            RespData::BulkString("copy".to_string()),
+            RespData::BulkBytes(bytes),
+        ]);
+        execute(
+            &mut Context::new(&store, 0, true),
+            parse_command(&set).unwrap(),
+        );
+        assert_eq!(run(&store, &["PFCOUNT", "copy"]).0, RespData::Integer(3));
+        assert_eq!(
+            run(&store, &["PFCOUNT", "h", "copy"]).0,
+            RespData::Integer(3)
+        );
+        assert_eq!(run(&store, &["PFADD", "copy", "d"]).0, RespData::Integer(1));
+
+        run(&store, &["SETBIT", "h", "0", "0"]);
+        assert_eq!(run(&store, &["PFCOUNT", "h"]).0, RespData::Integer(3));
+        run(&store, &["SET", "s", "HYLL, but not really"]);
+        assert_eq!(
+            run(&store, &["PFCOUNT", "s"]).0,
+            RespData::Error(StoreError::NotHyperLogLog.to_string())
+        );
+    }
+
+    #[test]
+    fn del_is_propagated_only_when_it_deletes() {
+        let store = Store::new(1);
+        run(&store, &["SET", "a", "1"]);
+        assert_eq!(run(&store, &["DEL", "a", "b"]).1, [["DEL", "a", "b"]]);
+        let (response, propagated) = run(&store, &["DEL", "a", "b"]);
+        assert_eq!(response, RespData::Integer(0));
+        assert!(propagated.is_empty());
+    }
+
+    #[test]
+    fn lazy_expiry_propagates_del() {
+        let store = Store::new(1);
+        store.write(0, |db| {
+            db.set(
+                "a".to_string(),
+                Value::String(b"1".to_vec()),
+                Some(Instant::now()),
+            );
+        });
+        let (response, propagated) = run(&store, &["GET", "a"]);
+        assert_eq!(response, RespData::BulkStringNull);
+        assert_eq!(propagated, [["DEL", "a"]]);
+    }
+
+    #[test]
+    fn hincrbyfloat_replicates_its_result_keeping_the_ttl() {
+        let store = Store::new(1);
+        run(&store, &["HSET", "h", "f", "1e308"]);
+        run(&store, &["HPEXPIRE", "h", "60000", "FIELDS", "1", "f"]);
+        let (response, propagated) = run(&store, &["HINCRBYFLOAT", "h", "f", "1"]);
+        assert_eq!(response, RespData::BulkString("1e+308".to_string()));
+        assert_eq!(
+            propagated,
+            [["HSETEX", "h", "KEEPTTL", "FIELDS", "1", "f", "1e+308"]]
+        );
+
+        let replica = Store::new(1);
+        run(&replica, &["HSET", "h", "f", "1"]);
+        run(&replica, &["HPEXPIRE", "h", "60000", "FIELDS", "1", "f"]);
+        let replicated: Vec<&str> = propagated[0].iter().map(String::as_str).collect();
+        run(&replica, &replicated);
+        let (ttl, _) = run(&replica, &["HPTTL", "h", "FIELDS", "1", "f"]);
+        assert!(matches!(
+            ttl,
+            RespData::Array(ref ttls) if matches!(ttls[..], [RespData::Integer(ms)] if ms > 0)
+        ));
+    }
+
+    #[test]
+    fn transactions_run_in_the_selected_db_and_replicate_atomically() {
+        let store = Store::new(2);
+        let commands = [
+            &["SET", "a", "0"][..],
+            &["SELECT", "1"],
+            &["SET", "a", "1"],
+            &["HELLO", "3"],
+            &["SELECT", "2"],
+            &["GET", "a"],
+        ]
+        .into_iter()
+        .map(parse)
+        .collect();
+        let (response, propagated) =
+            execute_transaction(&store, 0, Protocol::Resp2, true, commands);
+
+        let RespData::Array(replies) = response else {
+            panic!("{:?}", response);
+        };
+        assert_eq!(replies[..3], [ok(), ok(), ok()]);
+        assert!(matches!(
+            &replies[3],
+            RespData::Map(fields) if fields[2].1 == RespData::Integer(3)
+        ));
+        assert_eq!(
+            replies[4..],
+            [
+                error("ERR DB index is out of range"),
+                RespData::BulkBytes(b"1".to_vec()),
+            ]
+        );
+        let propagated: Vec<(usize, Vec<&str>)> = propagated
+            .iter()
+            .map(|(db, args)| (*db, text(args)))
+            .collect();
+        assert_eq!(
+            propagated,
+            [
+                (0, vec!["MULTI"]),
+                (0, vec!["SET", "a", "0"]),
+                (1, vec!["SET", "a", "1"]),
+                (1, vec!["EXEC"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_only_transactions_are_not_replicated() {
+        let store = Store::new(1);
+        let commands = [&["GET", "a"][..], &["PING"]]
+            .into_iter()
+            .map(parse)
+            .collect();
+        let (_, propagated) = execute_transaction(&store, 0, Protocol::Resp2, true, commands);
+        assert!(propagated.is_empty());
+    }
+
+    #[test]
+    fn writes_that_change_nothing_leave_watchers_alone() {
+        let store = Store::new(1);
+        run(&store, &["HSET", "h", "f", "1"]);
+        run(&store, &["SADD", "s", "m"]);
+        run(&store, &["RPUSH", "l", "x"]);
+        let client = std::net::SocketAddr::from(([127, 0, 0, 1], 1));
+        let keys = ["h", "s", "l", "z", "n"].map(String::from);
+        store.watch(0, &keys, client);
+        store.take_ready_keys();
+        let watched: Vec<(usize, String)> = keys.iter().map(|key| (0, key.clone())).collect();
+
+        run(&store, &["HDEL", "h", "missing"]);
+        run(&store, &["SREM", "s", "missing"]);
+        run(&store, &["SADD", "s", "m"]);
+        run(&store, &["LREM", "l", "0", "missing"]);
+        run(&store, &["HGET", "h", "f"]);
+        run(&store, &["HINCRBYFLOAT", "h", "f", "inf"]);
+        run(&store, &["ZADD", "z", "XX", "1", "m"]);
+        run(&store, &["ZADD", "z", "XX", "INCR", "1", "m"]);
+        run(&store, &["HINCRBYFLOAT", "n", "f", "inf"]);
+        run(
+            &store,
+            &["HSETEX", "n", "PXAT", "1", "FIELDS", "1", "f", "v"],
+        );
+        assert!(!store.watched_keys_modified(client, &watched));
+        assert!(store.take_ready_keys().is_empty());
+
+        run(&store, &["RPUSH", "l", "y"]);
+        assert!(store.watched_keys_modified(client, &watched));
+    }
+}
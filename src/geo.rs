@@ -0,0 +1,733 @@
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+    zset::{self, format_score, AddOptions, SortedSet, SortedSetCommand},
+};
+
+/// Bits per coordinate in a geohash score, 52 bits in all, which a double
+/// holds exactly.
+const STEP: u32 = 26;
+const LONG_MAX: f64 = 180.0;
+/// The latitudes that Web Mercator, and so Redis, can index.
+const LAT_MAX: f64 = 85.05112878;
+/// The latitudes of a standard geohash, as GEOHASH reports them.
+const STANDARD_LAT_MAX: f64 = 90.0;
+const EARTH_RADIUS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Spreads the bits of `lat` over the even positions and those of `lon`
+/// over the odd ones.
+fn interleave(lat: u32, lon: u32) -> u64 {
+    (0..32).fold(0, |bits, i| {
+        bits | (u64::from(lat >> i & 1) << (2 * i)) | (u64::from(lon >> i & 1) << (2 * i + 1))
+    })
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    (0..32).fold((0, 0), |(lat, lon), i| {
+        (
+            lat | ((bits >> (2 * i) & 1) as u32) << i,
+            lon | ((bits >> (2 * i + 1) & 1) as u32) << i,
+        )
+    })
+}
+
+/// A geohash cell: `step` bits of each coordinate.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Cell {
+    bits: u64,
+    step: u32,
+}
+
+/// The bounds of a cell, as (min, max) pairs.
+struct Area {
+    lon: (f64, f64),
+    lat: (f64, f64),
+}
+
+impl Cell {
+    fn encode(lon: f64, lat: f64, lat_max: f64, step: u32) -> Cell {
+        let cells = (1u64 << step) as f64;
+        let lat_offset = (lat + lat_max) / (2.0 * lat_max) * cells;
+        let lon_offset = (lon + LONG_MAX) / (2.0 * LONG_MAX) * cells;
+        Cell {
+            bits: interleave(lat_offset as u32, lon_offset as u32),
+            step,
+        }
+    }
+
+    fn area(self, lat_max: f64) -> Area {
+        let (lat, lon) = deinterleave(self.bits);
+        let cells = (1u64 << self.step) as f64;
+        let bounds = |index: u32, max: f64| {
+            (
+                -max + f64::from(index) / cells * 2.0 * max,
+                -max + (f64::from(index) + 1.0) / cells * 2.0 * max,
+            )
+        };
+        Area {
+            lon: bounds(lon, LONG_MAX),
+            lat: bounds(lat, lat_max),
+        }
+    }
+
+    /// The cell `dx` cells east and `dy` cells north of this one, wrapping
+    /// around.
+    fn moved(self, dx: i64, dy: i64) -> Cell {
+        let (lat, lon) = deinterleave(self.bits);
+        let mask = (1i64 << self.step) - 1;
+        let lat = ((i64::from(lat) + dy) & mask) as u32;
+        let lon = ((i64::from(lon) + dx) & mask) as u32;
+        Cell {
+            bits: interleave(lat, lon),
+            step: self.step,
+        }
+    }
+
+    /// The scores of the members inside the cell, from the first up to but
+    /// excluding the second.
+    fn score_range(self) -> (f64, f64) {
+        let shift = 2 * (STEP - self.step);
+        (
+            (self.bits << shift) as f64,
+            ((self.bits + 1) << shift) as f64,
+        )
+    }
+}
+
+/// The longitude and latitude a score stands for: the center of its cell.
+fn decode(score: f64) -> (f64, f64) {
+    let area = Cell {
+        bits: score as u64,
+        step: STEP,
+    }
+    .area(LAT_MAX);
+    (
+        ((area.lon.0 + area.lon.1) / 2.0).clamp(-LONG_MAX, LONG_MAX),
+        ((area.lat.0 + area.lat.1) / 2.0).clamp(-LAT_MAX, LAT_MAX),
+    )
+}
+
+fn encode(lon: f64, lat: f64) -> f64 {
+    Cell::encode(lon, lat, LAT_MAX, STEP).bits as f64
+}
+
+/// The 11-character standard geohash of a point, with the 53rd bit it would
+/// need taken as zero.
+fn geohash_string(lon: f64, lat: f64) -> String {
+    let bits = Cell::encode(lon, lat, STANDARD_LAT_MAX, STEP).bits;
+    (0..11)
+        .map(|i| {
+            let index = if i == 10 {
+                0
+            } else {
+                (bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            char::from(GEOHASH_ALPHABET[index as usize])
+        })
+        .collect()
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+/// The haversine distance in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lat2r) = (lat1.to_radians(), lat2.to_radians());
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+fn format_distance(distance: f64) -> RespData {
+    RespData::BulkString(format!("{:.4}", distance))
+}
+
+fn parse_coordinates(args: &mut Args) -> Result<(f64, f64), CommandError> {
+    let mut coordinate = || {
+        args.float()
+            .ok()
+            .filter(|value| !value.is_nan())
+            .ok_or(CommandError::NotFloat)
+    };
+    let (lon, lat) = (coordinate()?, coordinate()?);
+    if !(-LONG_MAX..=LONG_MAX).contains(&lon) || !(-LAT_MAX..=LAT_MAX).contains(&lat) {
+        return Err(CommandError::Other(format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            lon, lat
+        )));
+    }
+    Ok((lon, lat))
+}
+
+/// Meters per unit.
+fn parse_unit(args: &mut Args) -> Result<f64, CommandError> {
+    match args.string()?.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(CommandError::Other(
+            "ERR unsupported unit provided. please use M, KM, FT, MI".into(),
+        )),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Origin {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+/// The area to search, in meters.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Shape {
+    Radius(f64),
+    /// A box with its width and height.
+    Box(f64, f64),
+}
+
+impl Shape {
+    /// The distance from `center` to `point` if the point is inside.
+    fn distance_if_inside(self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match self {
+            Shape::Radius(radius) => {
+                let distance = distance(center.0, center.1, point.0, point.1);
+                (distance <= radius).then_some(distance)
+            }
+            Shape::Box(width, height) => {
+                if lat_distance(point.1, center.1) > height / 2.0
+                    || distance(point.0, point.1, center.0, point.1) > width / 2.0
+                {
+                    return None;
+                }
+                Some(distance(center.0, center.1, point.0, point.1))
+            }
+        }
+    }
+
+    /// The cells to look for members in: the one around `center` and those
+    /// of its neighbors that reach into the shape, with cells small enough to
+    /// cover it.
+    fn cells(self, center: (f64, f64)) -> Vec<Cell> {
+        let (lon, lat) = center;
+        let (half_width, half_height, radius) = match self {
+            Shape::Radius(radius) => (radius, radius, radius),
+            Shape::Box(width, height) => {
+                (width / 2.0, height / 2.0, (width / 2.0).hypot(height / 2.0))
+            }
+        };
+        let lat_delta = (half_height / EARTH_RADIUS).to_degrees();
+        let lon_delta =
+            |lat: f64| (half_width / EARTH_RADIUS / lat.to_radians().cos()).to_degrees();
+        // Longitudes spread apart towards the pole, so the box takes the
+        // delta of its edge nearer to it.
+        let lon_delta = if lat < 0.0 {
+            lon_delta(lat - lat_delta)
+        } else {
+            lon_delta(lat + lat_delta)
+        };
+        let (min_lon, max_lon) = (lon - lon_delta, lon + lon_delta);
+        let (min_lat, max_lat) = (lat - lat_delta, lat + lat_delta);
+
+        let mut step = estimate_step(radius, lat);
+        let mut cell = Cell::encode(lon, lat, LAT_MAX, step);
+        // Near the edge of its cell, a neighbor may not reach as far as the
+        // shape does.
+        let too_small = cell.moved(0, 1).area(LAT_MAX).lat.1 < max_lat
+            || cell.moved(0, -1).area(LAT_MAX).lat.0 > min_lat
+            || cell.moved(1, 0).area(LAT_MAX).lon.1 < max_lon
+            || cell.moved(-1, 0).area(LAT_MAX).lon.0 > min_lon;
+        if step > 1 && too_small {
+            step -= 1;
+            cell = Cell::encode(lon, lat, LAT_MAX, step);
+        }
+
+        let area = cell.area(LAT_MAX);
+        let mut cells = Vec::new();
+        for dy in [0, 1, -1] {
+            for dx in [0, 1, -1] {
+                let useless = step >= 2
+                    && ((dy == -1 && area.lat.0 < min_lat)
+                        || (dy == 1 && area.lat.1 > max_lat)
+                        || (dx == -1 && area.lon.0 < min_lon)
+                        || (dx == 1 && area.lon.1 > max_lon));
+                let neighbor = cell.moved(dx, dy);
+                // Cells wrap around, so with a large enough shape some
+                // neighbors are one and the same.
+                if !useless && !cells.contains(&neighbor) {
+                    cells.push(neighbor);
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// The largest cells that a search of `radius` meters around `lat` can
+/// look at without missing members.
+fn estimate_step(mut radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return STEP;
+    }
+    let mut step: i32 = 1;
+    while radius < MERCATOR_MAX {
+        radius *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    // Meridians get closer towards the poles.
+    if lat.abs() > 66.0 {
+        step -= 1;
+        if lat.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP as i32) as u32
+}
+
+/// GEOSEARCH's and GEOSEARCHSTORE's options.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Search {
+    pub origin: Origin,
+    pub shape: Shape,
+    /// Meters per unit of the shape and of distances in the reply.
+    pub unit: f64,
+    pub descending: Option<bool>,
+    pub count: Option<usize>,
+    pub any: bool,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+    pub store_dist: bool,
+}
+
+impl Search {
+    fn parse(name: &str, args: &mut Args, store: bool) -> Result<Search, CommandError> {
+        let mut origin = None;
+        let mut origins = 0;
+        let mut shape = None;
+        let mut shapes = 0;
+        let mut unit = 1.0;
+        let mut search = Search {
+            origin: Origin::LonLat(0.0, 0.0),
+            shape: Shape::Radius(0.0),
+            unit,
+            descending: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+            store_dist: false,
+        };
+        while !args.is_empty() {
+            let option = args.string()?.to_uppercase();
+            match option.as_str() {
+                "WITHCOORD" if !store => search.with_coord = true,
+                "WITHDIST" if !store => search.with_dist = true,
+                "WITHHASH" if !store => search.with_hash = true,
+                "STOREDIST" if store => search.store_dist = true,
+                "ASC" => search.descending = Some(false),
+                "DESC" => search.descending = Some(true),
+                "COUNT" if !args.is_empty() => {
+                    let count: i64 = args.integer()?;
+                    if count <= 0 {
+                        return Err(CommandError::Other("ERR COUNT must be > 0".into()));
+                    }
+                    search.count = Some(count as usize);
+                    search.any = args.keyword("ANY");
+                }
+                "FROMMEMBER" if !args.is_empty() => {
+                    origin = Some(Origin::Member(args.string()?));
+                    origins += 1;
+                }
+                "FROMLONLAT" if args.len() >= 2 => {
+                    let (lon, lat) = parse_coordinates(args)?;
+                    origin = Some(Origin::LonLat(lon, lat));
+                    origins += 1;
+                }
+                "BYRADIUS" if args.len() >= 2 => {
+                    let radius = args.float()?;
+                    if radius < 0.0 {
+                        return Err(CommandError::Other("ERR radius cannot be negative".into()));
+                    }
+                    unit = parse_unit(args)?;
+                    shape = Some(Shape::Radius(radius));
+                    shapes += 1;
+                }
+                "BYBOX" if args.len() >= 3 => {
+                    let (width, height) = (args.float()?, args.float()?);
+                    if width < 0.0 || height < 0.0 {
+                        return Err(CommandError::Other(
+                            "ERR height or width cannot be negative".into(),
+                        ));
+                    }
+                    unit = parse_unit(args)?;
+                    shape = Some(Shape::Box(width, height));
+                    shapes += 1;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let (Some(origin), 1) = (origin, origins) else {
+            return Err(CommandError::Other(format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                name
+            )));
+        };
+        let (Some(shape), 1) = (shape, shapes) else {
+            return Err(CommandError::Other(format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                name
+            )));
+        };
+        search.origin = origin;
+        search.unit = unit;
+        search.shape = match shape {
+            Shape::Radius(radius) => Shape::Radius(radius * unit),
+            Shape::Box(width, height) => Shape::Box(width * unit, height * unit),
+        };
+        Ok(search)
+    }
+}
+
+/// A member found by a search.
+struct Found<'a> {
+    member: &'a String,
+    score: f64,
+    /// In the search's unit.
+    distance: f64,
+    lon: f64,
+    lat: f64,
+}
+
+/// The members of `zset` inside the search's shape, sorted and limited as
+/// it asks.
+fn search<'a>(zset: &'a SortedSet, search: &Search) -> Result<Vec<Found<'a>>, StoreError> {
+    let center = match &search.origin {
+        Origin::LonLat(lon, lat) => (*lon, *lat),
+        Origin::Member(member) => decode(zset.score(member).ok_or(StoreError::UnknownGeoMember)?),
+    };
+    // Without ANY, COUNT means the closest members.
+    let descending = match search.descending {
+        None if search.count.is_some() && !search.any => Some(false),
+        descending => descending,
+    };
+
+    let mut found = Vec::new();
+    'cells: for cell in search.shape.cells(center) {
+        let (min, max) = cell.score_range();
+        for (member, score) in zset.score_range(min, max) {
+            let (lon, lat) = decode(score);
+            if let Some(distance) = search.shape.distance_if_inside(center, (lon, lat)) {
+                found.push(Found {
+                    member,
+                    score,
+                    distance: distance / search.unit,
+                    lon,
+                    lat,
+                });
+                if search.any && Some(found.len()) == search.count {
+                    break 'cells;
+                }
+            }
+        }
+    }
+    if let Some(descending) = descending {
+        found.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        if descending {
+            found.reverse();
+        }
+    }
+    found.truncate(search.count.unwrap_or(usize::MAX));
+    Ok(found)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GeoCommand {
+    Add {
+        key: String,
+        options: AddOptions,
+        /// Longitude, latitude and member.
+        points: Vec<(f64, f64, String)>,
+    },
+    Pos(String, Vec<String>),
+    /// GEODIST with the two members and meters per unit.
+    Dist(String, String, String, f64),
+    Hash(String, Vec<String>),
+    Search(String, Box<Search>),
+    /// GEOSEARCHSTORE with the destination and source.
+    SearchStore(String, String, Box<Search>),
+}
+
+impl GeoCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<GeoCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "GEOADD" => {
+                let key = args.string()?;
+                if args.len() < 3 {
+                    return Err(args.arity_error());
+                }
+                let mut options = AddOptions::default();
+                loop {
+                    if args.keyword("NX") {
+                        options.nx = true;
+                    } else if args.keyword("XX") {
+                        options.xx = true;
+                    } else if args.keyword("CH") {
+                        options.ch = true;
+                    } else {
+                        break;
+                    }
+                }
+                let points = args.len() / 3;
+                if points == 0 || points * 3 != args.len() || (options.nx && options.xx) {
+                    return Err(CommandError::Syntax);
+                }
+                let mut points = Vec::with_capacity(points);
+                while !args.is_empty() {
+                    let (lon, lat) = parse_coordinates(&mut args)?;
+                    points.push((lon, lat, args.string()?));
+                }
+                GeoCommand::Add {
+                    key,
+                    options,
+                    points,
+                }
+            }
+            "GEOPOS" => {
+                let key = args.string()?;
+                let mut members = Vec::new();
+                while !args.is_empty() {
+                    members.push(args.string()?);
+                }
+                GeoCommand::Pos(key, members)
+            }
+            "GEODIST" => {
+                let (key, from, to) = (args.string()?, args.string()?, args.string()?);
+                let unit = if args.is_empty() {
+                    1.0
+                } else {
+                    parse_unit(&mut args)?
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                GeoCommand::Dist(key, from, to, unit)
+            }
+            "GEOHASH" => {
+                let key = args.string()?;
+                let mut members = Vec::new();
+                while !args.is_empty() {
+                    members.push(args.string()?);
+                }
+                GeoCommand::Hash(key, members)
+            }
+            "GEOSEARCH" => {
+                let key = args.string()?;
+                if args.is_empty() {
+                    return Err(args.arity_error());
+                }
+                GeoCommand::Search(key, Box::new(Search::parse(name, &mut args, false)?))
+            }
+            "GEOSEARCHSTORE" => {
+                let (destination, source) = (args.string()?, args.string()?);
+                if args.is_empty() {
+                    return Err(args.arity_error());
+                }
+                GeoCommand::SearchStore(
+                    destination,
+                    source,
+                    Box::new(Search::parse(name, &mut args, true)?),
+                )
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, GeoCommand::Add { .. } | GeoCommand::SearchStore(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            GeoCommand::Add { key, .. }
+            | GeoCommand::Pos(key, _)
+            | GeoCommand::Dist(key, ..)
+            | GeoCommand::Hash(key, _)
+            | GeoCommand::Search(key, _) => vec![key],
+            GeoCommand::SearchStore(destination, source, _) => vec![destination, source],
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: GeoCommand) -> Result<RespData, StoreError> {
+    match command {
+        // Members are kept in a sorted set, scored by their geohash.
+        GeoCommand::Add {
+            key,
+            options,
+            points,
+        } => zset::execute(
+            db,
+            SortedSetCommand::Add {
+                key,
+                options,
+                elements: points
+                    .into_iter()
+                    .map(|(lon, lat, member)| (encode(lon, lat), member))
+                    .collect(),
+            },
+        ),
+        GeoCommand::Pos(key, members) => {
+            let zset = db.sorted_set(&key)?;
+            Ok(RespData::Array(
+                members
+                    .iter()
+                    .map(|member| match zset.and_then(|zset| zset.score(member)) {
+                        Some(score) => {
+                            let (lon, lat) = decode(score);
+                            RespData::Array(vec![
+                                RespData::BulkString(format_score(lon)),
+                                RespData::BulkString(format_score(lat)),
+                            ])
+                        }
+                        None => RespData::ArrayNull,
+                    })
+                    .collect(),
+            ))
+        }
+        GeoCommand::Dist(key, from, to, unit) => {
+            let zset = db.sorted_set(&key)?;
+            let scores = zset.and_then(|zset| Some((zset.score(&from)?, zset.score(&to)?)));
+            Ok(match scores {
+                Some((from, to)) => {
+                    let ((lon1, lat1), (lon2, lat2)) = (decode(from), decode(to));
+                    format_distance(distance(lon1, lat1, lon2, lat2) / unit)
+                }
+                None => RespData::BulkStringNull,
+            })
+        }
+        GeoCommand::Hash(key, members) => {
+            let zset = db.sorted_set(&key)?;
+            Ok(RespData::Array(
+                members
+                    .iter()
+                    .map(|member| match zset.and_then(|zset| zset.score(member)) {
+                        Some(score) => {
+                            let (lon, lat) = decode(score);
+                            RespData::BulkString(geohash_string(lon, lat))
+                        }
+                        None => RespData::BulkStringNull,
+                    })
+                    .collect(),
+            ))
+        }
+        GeoCommand::Search(key, options) => {
+            let Some(zset) = db.sorted_set(&key)? else {
+                return Ok(RespData::Array(Vec::new()));
+            };
+            let found = search(zset, &options)?;
+            let with_any = options.with_dist || options.with_hash || options.with_coord;
+            Ok(RespData::Array(
+                found
+                    .into_iter()
+                    .map(|found| {
+                        let member = RespData::BulkString(found.member.clone());
+                        if !with_any {
+                            return member;
+                        }
+                        let mut reply = vec![member];
+                        if options.with_dist {
+                            reply.push(format_distance(found.distance));
+                        }
+                        if options.with_hash {
+                            reply.push(RespData::Integer(found.score as i64));
+                        }
+                        if options.with_coord {
+                            reply.push(RespData::Array(vec![
+                                RespData::BulkString(format_score(found.lon)),
+                                RespData::BulkString(format_score(found.lat)),
+                            ]));
+                        }
+                        RespData::Array(reply)
+                    })
+                    .collect(),
+            ))
+        }
+        GeoCommand::SearchStore(destination, source, options) => {
+            let mut result = SortedSet::default();
+            if let Some(zset) = db.sorted_set(&source)? {
+                for found in search(zset, &options)? {
+                    let score = if options.store_dist {
+                        found.distance
+                    } else {
+                        found.score
+                    };
+                    result.insert(found.member.clone(), score);
+                }
+            }
+            let len = result.len();
+            db.remove(&destination);
+            if len > 0 {
+                db.set(destination, Value::SortedSet(result), None);
+            }
+            Ok(RespData::Integer(len as i64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_round_trip_to_the_cell_center() {
+        let score = encode(13.361389, 38.115556);
+        assert_eq!(score, 3479099956230698.0);
+        let (lon, lat) = decode(score);
+        assert!((lon - 13.361389).abs() < 1e-5 && (lat - 38.115556).abs() < 1e-5);
+        assert_eq!(geohash_string(lon, lat), "sqc8b49rny0");
+    }
+
+    #[test]
+    fn distances_match_redis() {
+        let (palermo, catania) = (
+            decode(encode(13.361389, 38.115556)),
+            decode(encode(15.087269, 37.502669)),
+        );
+        let meters = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert_eq!(format!("{:.4}", meters), "166274.1516");
+    }
+
+    #[test]
+    fn neighbors_wrap_around() {
+        let cell = Cell::encode(179.9, 0.0, LAT_MAX, 4);
+        let east = cell.moved(1, 0).area(LAT_MAX);
+        assert_eq!(east.lon.0, -LONG_MAX);
+        assert_eq!(cell.moved(1, 0).moved(-1, 0), cell);
+    }
+
+    #[test]
+    fn box_search_checks_both_sides() {
+        let shape = Shape::Box(200_000.0, 20_000.0);
+        assert!(shape
+            .distance_if_inside((15.0, 37.0), (15.5, 37.0))
+            .is_some());
+        assert!(shape
+            .distance_if_inside((15.0, 37.0), (15.0, 37.5))
+            .is_none());
+    }
+}
@@ -0,0 +1,135 @@
+/// Glob-style matching with the same rules as Redis's `stringmatchlen`:
+///
+/// - `*` matches any sequence of bytes, including none
+/// - `?` matches exactly one byte
+/// - `[abc]`, `[a-z]` and `[^abc]` match one byte from (or not from) a set
+/// - `\x` matches `x` literally
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+
+    let mut p = 0;
+    let mut s = 0;
+    // Where to resume after the most recent `*` if the rest fails to match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let mut matched = false;
+        let mut next_p = p + 1;
+
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while next_p < pattern.len() && pattern[next_p] == b'*' {
+                        next_p += 1;
+                    }
+                    if next_p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((next_p, s));
+                    p = next_p;
+                    continue;
+                }
+                b'?' => matched = true,
+                b'[' => {
+                    let (class_matched, end) = match_class(pattern, p + 1, string[s], nocase);
+                    matched = class_matched;
+                    next_p = end;
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    matched = eq(pattern[p + 1], string[s]);
+                    next_p = p + 2;
+                }
+                c => matched = eq(c, string[s]),
+            }
+        }
+
+        if matched {
+            p = next_p;
+            s += 1;
+        } else if let Some((star_p, star_s)) = backtrack {
+            p = star_p;
+            s = star_s + 1;
+            backtrack = Some((star_p, s));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class starting right after `[`. Returns whether it
+/// matched and the pattern index just past the closing `]`.
+fn match_class(pattern: &[u8], mut p: usize, c: u8, nocase: bool) -> (bool, usize) {
+    let fold = |b: u8| if nocase { b.to_ascii_lowercase() } else { b };
+    let c = fold(c);
+
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= fold(pattern[p + 1]) == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (start, end) = (fold(pattern[p]), fold(pattern[p + 2]));
+            let (start, end) = (start.min(end), start.max(end));
+            matched |= (start..=end).contains(&c);
+            p += 3;
+        } else {
+            matched |= fold(pattern[p]) == c;
+            p += 1;
+        }
+    }
+
+    // An unterminated class is treated as ending at the end of the pattern.
+    let end = (p + 1).min(pattern.len());
+    (matched != negate, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn star_and_question_mark() {
+        assert!(m("*", ""));
+        assert!(m("h?llo", "hello"));
+        assert!(m("h*llo", "heeeello"));
+        assert!(m("h*llo", "hllo"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("*:*:*", "user:1:name"));
+        assert!(!m("*:*:*", "user:1"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-b]llo", "hbllo"));
+        assert!(m("h[b-a]llo", "hallo"));
+    }
+
+    #[test]
+    fn escapes_and_nocase() {
+        assert!(m("h\\*llo", "h*llo"));
+        assert!(!m("h\\*llo", "hello"));
+        assert!(glob_match(b"HELLO*", b"hello world", true));
+        assert!(!glob_match(b"HELLO*", b"hello world", false));
+    }
+}
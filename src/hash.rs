@@ -0,0 +1,968 @@
+use crate::{
+    command::{Args, CommandError},
+    dict::Dict,
+    random,
+    resp_parser::RespData,
+    store::{unix_time_ms, Db, StoreError},
+    zset::format_score,
+};
+
+/// A hash value. Fields may carry their own TTL, kept as a Unix time in
+/// milliseconds; like keys, expired fields read as missing until they are
+/// reclaimed.
+#[derive(Default)]
+pub struct Hash {
+    fields: Dict<String, String>,
+    expires: Dict<String, i64>,
+}
+
+impl Hash {
+    fn is_expired(&self, field: &str, now: i64) -> bool {
+        self.expires.get(field).is_some_and(|at| *at <= now)
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        let now = unix_time_ms();
+        self.fields
+            .get(field)
+            .filter(|_| !self.is_expired(field, now))
+    }
+
+    /// Sets a field, clearing its TTL. Returns the previous value.
+    pub fn insert(&mut self, field: String, value: String) -> Option<String> {
+        self.expires.remove(&field);
+        self.fields.insert(field, value)
+    }
+
+    /// Sets a field, keeping its TTL.
+    pub fn replace(&mut self, field: String, value: String) {
+        self.fields.insert(field, value);
+    }
+
+    pub fn remove(&mut self, field: &str) -> Option<String> {
+        self.expires.remove(field);
+        self.fields.remove(field)
+    }
+
+    pub fn len(&self) -> usize {
+        let now = unix_time_ms();
+        let expired = self.expires.iter().filter(|(_, at)| **at <= now).count();
+        self.fields.len() - expired
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        let now = unix_time_ms();
+        self.fields
+            .iter()
+            .filter(move |(field, _)| !self.is_expired(field, now))
+    }
+
+    pub fn random_entry(&self) -> Option<(&String, &String)> {
+        (self.len() > 0).then(|| self.random_live_entry(unix_time_ms()))
+    }
+
+    /// A random field that hasn't expired by `now`, of which there must be
+    /// at least one.
+    fn random_live_entry(&self, now: i64) -> (&String, &String) {
+        loop {
+            let (field, value) = self.fields.random_entry().unwrap();
+            if !self.is_expired(field, now) {
+                return (field, value);
+            }
+        }
+    }
+
+    pub fn expires_at(&self, field: &str) -> Option<i64> {
+        self.expires.get(field).copied()
+    }
+
+    pub fn set_expires_at(&mut self, field: &str, at: Option<i64>) {
+        match at {
+            Some(at) => {
+                self.expires.insert(field.to_string(), at);
+            }
+            None => {
+                self.expires.remove(field);
+            }
+        }
+    }
+
+    pub fn has_volatile_fields(&self) -> bool {
+        self.expires.len() > 0
+    }
+
+    /// Deletes the fields whose TTL has passed, returning them.
+    pub fn remove_expired(&mut self, now: i64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .expires
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(field, _)| field.clone())
+            .collect();
+        for field in &expired {
+            self.remove(field);
+        }
+        expired
+    }
+}
+
+/// An expiry time as given by the client, in milliseconds: relative for EX,
+/// PX and HEXPIRE, a Unix time for EXAT, PXAT and HEXPIREAT.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Expiry {
+    In(i64),
+    At(i64),
+}
+
+impl Expiry {
+    fn parse(
+        args: &mut Args,
+        name: &str,
+        unit_ms: i64,
+        absolute: bool,
+    ) -> Result<Expiry, CommandError> {
+        let time: i64 = args.integer()?;
+        let ms = time
+            .checked_mul(unit_ms)
+            .filter(|_| time >= 0)
+            .ok_or_else(|| {
+                CommandError::Other(format!(
+                    "ERR invalid expire time in '{}' command",
+                    name.to_lowercase()
+                ))
+            })?;
+        Ok(if absolute {
+            Expiry::At(ms)
+        } else {
+            Expiry::In(ms)
+        })
+    }
+
+    /// Parses an EX, PX, EXAT or PXAT option, if that is what comes next.
+    fn parse_option(args: &mut Args, name: &str) -> Result<Option<Expiry>, CommandError> {
+        for (keyword, unit_ms, absolute) in [
+            ("EX", 1000, false),
+            ("PX", 1, false),
+            ("EXAT", 1000, true),
+            ("PXAT", 1, true),
+        ] {
+            if args.keyword(keyword) {
+                return Expiry::parse(args, name, unit_ms, absolute).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The Unix time in milliseconds this expiry stands for.
+    fn resolve(self, now: i64) -> i64 {
+        match self {
+            Expiry::In(ms) => now.saturating_add(ms),
+            Expiry::At(ms) => ms,
+        }
+    }
+}
+
+/// What a command does to the TTL of the fields it touches.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TtlChange {
+    Set(Expiry),
+    Persist,
+    Keep,
+}
+
+/// The NX, XX, GT and LT options of HEXPIRE. A field without a TTL counts as
+/// expiring never.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExpireCondition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+impl ExpireCondition {
+    fn name(self) -> &'static str {
+        match self {
+            ExpireCondition::Nx => "NX",
+            ExpireCondition::Xx => "XX",
+            ExpireCondition::Gt => "GT",
+            ExpireCondition::Lt => "LT",
+        }
+    }
+
+    fn allows(self, current: Option<i64>, at: i64) -> bool {
+        match self {
+            ExpireCondition::Nx => current.is_none(),
+            ExpireCondition::Xx => current.is_some(),
+            ExpireCondition::Gt => current.is_some_and(|current| at > current),
+            ExpireCondition::Lt => match current {
+                Some(current) => at < current,
+                None => true,
+            },
+        }
+    }
+}
+
+/// The FNX and FXX options of HSETEX: set the fields only if none or all of
+/// them exist.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FieldCondition {
+    Fnx,
+    Fxx,
+}
+
+/// Parses `FIELDS numfields ...`, returning how many fields follow.
+fn parse_numfields(args: &mut Args, args_per_field: usize) -> Result<usize, CommandError> {
+    if !args.keyword("FIELDS") {
+        return Err(CommandError::Other(
+            "ERR Mandatory argument FIELDS is missing or not at the right position".into(),
+        ));
+    }
+    let numfields: i64 = args.integer()?;
+    if numfields <= 0 {
+        return Err(CommandError::Other(
+            "ERR Parameter `numFields` should be greater than 0".into(),
+        ));
+    }
+    let numfields = numfields as usize;
+    if numfields.checked_mul(args_per_field) != Some(args.len()) {
+        return Err(CommandError::Other(
+            "ERR The `numfields` parameter must match the number of arguments".into(),
+        ));
+    }
+    Ok(numfields)
+}
+
+fn parse_fields(args: &mut Args) -> Result<Vec<String>, CommandError> {
+    parse_numfields(args, 1)?;
+    args.rest()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HashCommand {
+    Set(String, Vec<(String, String)>),
+    SetNx(String, String, String),
+    Get(String, String),
+    MGet(String, Vec<String>),
+    Del(String, Vec<String>),
+    Exists(String, String),
+    Len(String),
+    Keys(String),
+    Vals(String),
+    GetAll(String),
+    IncrBy(String, String, i64),
+    IncrByFloat(String, String, f64),
+    StrLen(String, String),
+    RandField {
+        key: String,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    /// HEXPIRE, HPEXPIRE, HEXPIREAT and HPEXPIREAT.
+    Expire {
+        key: String,
+        expiry: Expiry,
+        condition: Option<ExpireCondition>,
+        fields: Vec<String>,
+    },
+    /// HTTL, or HPTTL in milliseconds.
+    Ttl {
+        key: String,
+        fields: Vec<String>,
+        millis: bool,
+    },
+    Persist(String, Vec<String>),
+    GetEx {
+        key: String,
+        ttl: TtlChange,
+        fields: Vec<String>,
+    },
+    SetEx {
+        key: String,
+        condition: Option<FieldCondition>,
+        ttl: TtlChange,
+        fields: Vec<(String, String)>,
+    },
+}
+
+impl HashCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<HashCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "HSET" => {
+                let key = args.string()?;
+                if args.is_empty() || args.len() % 2 == 1 {
+                    return Err(args.arity_error());
+                }
+                let mut fields = Vec::new();
+                while !args.is_empty() {
+                    fields.push((args.string()?, args.string()?));
+                }
+                HashCommand::Set(key, fields)
+            }
+            "HSETNX" => HashCommand::SetNx(args.string()?, args.string()?, args.string()?),
+            "HGET" => HashCommand::Get(args.string()?, args.string()?),
+            "HMGET" => HashCommand::MGet(args.string()?, args.rest()?),
+            "HDEL" => HashCommand::Del(args.string()?, args.rest()?),
+            "HEXISTS" => HashCommand::Exists(args.string()?, args.string()?),
+            "HLEN" => HashCommand::Len(args.string()?),
+            "HKEYS" => HashCommand::Keys(args.string()?),
+            "HVALS" => HashCommand::Vals(args.string()?),
+            "HGETALL" => HashCommand::GetAll(args.string()?),
+            "HINCRBY" => HashCommand::IncrBy(args.string()?, args.string()?, args.integer()?),
+            "HINCRBYFLOAT" => {
+                HashCommand::IncrByFloat(args.string()?, args.string()?, args.float()?)
+            }
+            "HSTRLEN" => HashCommand::StrLen(args.string()?, args.string()?),
+            "HRANDFIELD" => {
+                let key = args.string()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.random_count()?)
+                };
+                let with_values = count.is_some() && args.keyword("WITHVALUES");
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                HashCommand::RandField {
+                    key,
+                    count,
+                    with_values,
+                }
+            }
+            "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => {
+                let key = args.string()?;
+                let unit_ms = if name.starts_with("HP") { 1 } else { 1000 };
+                let expiry = Expiry::parse(&mut args, name, unit_ms, name.ends_with("AT"))?;
+                let condition = [
+                    ExpireCondition::Nx,
+                    ExpireCondition::Xx,
+                    ExpireCondition::Gt,
+                    ExpireCondition::Lt,
+                ]
+                .into_iter()
+                .find(|condition| args.keyword(condition.name()));
+                HashCommand::Expire {
+                    key,
+                    expiry,
+                    condition,
+                    fields: parse_fields(&mut args)?,
+                }
+            }
+            "HTTL" | "HPTTL" => HashCommand::Ttl {
+                key: args.string()?,
+                fields: parse_fields(&mut args)?,
+                millis: name == "HPTTL",
+            },
+            "HPERSIST" => HashCommand::Persist(args.string()?, parse_fields(&mut args)?),
+            "HGETEX" => {
+                let key = args.string()?;
+                let ttl = if args.keyword("PERSIST") {
+                    TtlChange::Persist
+                } else {
+                    Expiry::parse_option(&mut args, name)?.map_or(TtlChange::Keep, TtlChange::Set)
+                };
+                HashCommand::GetEx {
+                    key,
+                    ttl,
+                    fields: parse_fields(&mut args)?,
+                }
+            }
+            "HSETEX" => {
+                let key = args.string()?;
+                let mut condition = None;
+                let mut ttl = None;
+                loop {
+                    let parsed_condition = if args.keyword("FNX") {
+                        Some(FieldCondition::Fnx)
+                    } else if args.keyword("FXX") {
+                        Some(FieldCondition::Fxx)
+                    } else {
+                        None
+                    };
+                    if let Some(parsed) = parsed_condition {
+                        if condition.replace(parsed).is_some() {
+                            return Err(CommandError::Syntax);
+                        }
+                        continue;
+                    }
+
+                    let parsed_ttl = if args.keyword("KEEPTTL") {
+                        Some(TtlChange::Keep)
+                    } else {
+                        Expiry::parse_option(&mut args, name)?.map(TtlChange::Set)
+                    };
+                    if let Some(parsed) = parsed_ttl {
+                        if ttl.replace(parsed).is_some() {
+                            return Err(CommandError::Syntax);
+                        }
+                        continue;
+                    }
+                    break;
+                }
+
+                let numfields = parse_numfields(&mut args, 2)?;
+                let fields = (0..numfields)
+                    .map(|_| Ok((args.string()?, args.string()?)))
+                    .collect::<Result<Vec<_>, CommandError>>()?;
+                HashCommand::SetEx {
+                    key,
+                    condition,
+                    ttl: ttl.unwrap_or(TtlChange::Persist),
+                    fields,
+                }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            HashCommand::Set(..)
+                | HashCommand::SetNx(..)
+                | HashCommand::Del(..)
+                | HashCommand::IncrBy(..)
+                | HashCommand::IncrByFloat(..)
+                | HashCommand::Expire { .. }
+                | HashCommand::Persist(..)
+                | HashCommand::SetEx { .. }
+        ) || matches!(self, HashCommand::GetEx { ttl, .. } if *ttl != TtlChange::Keep)
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            HashCommand::Set(key, _)
+            | HashCommand::SetNx(key, ..)
+            | HashCommand::Get(key, _)
+            | HashCommand::MGet(key, _)
+            | HashCommand::Del(key, _)
+            | HashCommand::Exists(key, _)
+            | HashCommand::Len(key)
+            | HashCommand::Keys(key)
+            | HashCommand::Vals(key)
+            | HashCommand::GetAll(key)
+            | HashCommand::IncrBy(key, ..)
+            | HashCommand::IncrByFloat(key, ..)
+            | HashCommand::StrLen(key, _)
+            | HashCommand::RandField { key, .. }
+            | HashCommand::Expire { key, .. }
+            | HashCommand::Ttl { key, .. }
+            | HashCommand::Persist(key, _)
+            | HashCommand::GetEx { key, .. }
+            | HashCommand::SetEx { key, .. } => vec![key],
+        }
+    }
+
+    /// Turns relative expiry times into Unix times, so that the command can
+    /// be fed to replicas with the same deadline it had here.
+    pub fn resolve_expiry(&mut self, now: i64) {
+        match self {
+            HashCommand::Expire { expiry, .. }
+            | HashCommand::GetEx {
+                ttl: TtlChange::Set(expiry),
+                ..
+            }
+            | HashCommand::SetEx {
+                ttl: TtlChange::Set(expiry),
+                ..
+            } => *expiry = Expiry::At(expiry.resolve(now)),
+            _ => {}
+        }
+    }
+
+    /// The command to feed replicas in place of one that sets field TTLs,
+    /// using absolute times only. Meant to be called after `resolve_expiry`.
+    pub fn replicated_form(&self) -> Option<Vec<String>> {
+        let fields_arg = |fields: &[String]| {
+            let mut args = vec!["FIELDS".to_string(), fields.len().to_string()];
+            args.extend(fields.iter().cloned());
+            args
+        };
+        let now = unix_time_ms();
+        match self {
+            HashCommand::Expire {
+                key,
+                expiry,
+                condition,
+                fields,
+            } => {
+                let mut args = vec![
+                    "HPEXPIREAT".to_string(),
+                    key.clone(),
+                    expiry.resolve(now).to_string(),
+                ];
+                args.extend(condition.map(|condition| condition.name().to_string()));
+                args.extend(fields_arg(fields));
+                Some(args)
+            }
+            HashCommand::GetEx {
+                key,
+                ttl: TtlChange::Set(expiry),
+                fields,
+            } => {
+                let mut args = vec![
+                    "HPEXPIREAT".to_string(),
+                    key.clone(),
+                    expiry.resolve(now).to_string(),
+                ];
+                args.extend(fields_arg(fields));
+                Some(args)
+            }
+            HashCommand::GetEx {
+                key,
+                ttl: TtlChange::Persist,
+                fields,
+            } => {
+                let mut args = vec!["HPERSIST".to_string(), key.clone()];
+                args.extend(fields_arg(fields));
+                Some(args)
+            }
+            HashCommand::SetEx {
+                key,
+                condition,
+                ttl,
+                fields,
+            } => {
+                let mut args = vec!["HSETEX".to_string(), key.clone()];
+                match condition {
+                    Some(FieldCondition::Fnx) => args.push("FNX".to_string()),
+                    Some(FieldCondition::Fxx) => args.push("FXX".to_string()),
+                    None => {}
+                }
+                match ttl {
+                    TtlChange::Set(expiry) => {
+                        args.push("PXAT".to_string());
+                        args.push(expiry.resolve(now).to_string());
+                    }
+                    TtlChange::Keep => args.push("KEEPTTL".to_string()),
+                    TtlChange::Persist => {}
+                }
+                args.push("FIELDS".to_string());
+                args.push(fields.len().to_string());
+                for (field, value) in fields {
+                    args.push(field.clone());
+                    args.push(value.clone());
+                }
+                Some(args)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Applies a TTL change to an existing field. A deadline that has already
+/// passed deletes the field instead; returns whether that happened.
+fn change_ttl(hash: &mut Hash, field: &str, ttl: TtlChange, now: i64) -> bool {
+    match ttl {
+        TtlChange::Set(expiry) => {
+            let at = expiry.resolve(now);
+            if at <= now {
+                hash.remove(field);
+                return true;
+            }
+            hash.set_expires_at(field, Some(at));
+        }
+        TtlChange::Persist => hash.set_expires_at(field, None),
+        TtlChange::Keep => {}
+    }
+    false
+}
+
+/// Housekeeping after changing field TTLs: watchers of the hash have to
+/// learn if anything `changed`, the expire cycle has to know about the hash,
+/// and deleting fields may have left it empty.
+fn after_ttl_change(db: &mut Db, key: &str, changed: bool) {
+    if changed {
+        db.touch(key);
+    }
+    if db
+        .hash(key)
+        .ok()
+        .flatten()
+        .is_some_and(|hash| hash.has_volatile_fields())
+    {
+        db.track_volatile_hash(key);
+    }
+    db.remove_if_empty(key);
+}
+
+fn bulk_string_or_null(value: Option<&String>) -> RespData {
+    value
+        .cloned()
+        .map(RespData::BulkString)
+        .unwrap_or(RespData::BulkStringNull)
+}
+
+/// Picks `count` random fields the way HRANDFIELD does: distinct fields for a
+/// positive count, possibly repeated ones for a negative count.
+fn random_fields(hash: &Hash, count: i64) -> Vec<(&String, &String)> {
+    let len = hash.len();
+    if len == 0 {
+        return Vec::new();
+    }
+    let now = unix_time_ms();
+    if count < 0 {
+        return (0..count.unsigned_abs())
+            .map(|_| hash.random_live_entry(now))
+            .collect();
+    }
+    random::sample_distinct(
+        len,
+        count as usize,
+        || hash.iter().collect(),
+        || hash.random_live_entry(now),
+    )
+}
+
+pub fn execute(db: &mut Db, command: HashCommand) -> Result<RespData, StoreError> {
+    match command {
+        HashCommand::Set(key, fields) => {
+            let hash = db.hash_or_create(&key)?;
+            let added = fields
+                .into_iter()
+                .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+                .count();
+            db.touch(&key);
+            Ok(RespData::Integer(added as i64))
+        }
+        HashCommand::SetNx(key, field, value) => {
+            let hash = db.hash_or_create(&key)?;
+            if hash.get(&field).is_some() {
+                return Ok(RespData::Integer(0));
+            }
+            hash.insert(field, value);
+            db.touch(&key);
+            Ok(RespData::Integer(1))
+        }
+        HashCommand::Get(key, field) => Ok(bulk_string_or_null(
+            db.hash(&key)?.and_then(|hash| hash.get(&field)),
+        )),
+        HashCommand::MGet(key, fields) => {
+            let hash = db.hash(&key)?;
+            Ok(RespData::Array(
+                fields
+                    .iter()
+                    .map(|field| bulk_string_or_null(hash.and_then(|hash| hash.get(field))))
+                    .collect(),
+            ))
+        }
+        HashCommand::Del(key, fields) => {
+            let Some(hash) = db.hash_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let deleted = fields
+                .iter()
+                .filter(|field| hash.remove(field).is_some())
+                .count();
+            if deleted > 0 {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(RespData::Integer(deleted as i64))
+        }
+        HashCommand::Exists(key, field) => Ok(RespData::Integer(
+            db.hash(&key)?
+                .is_some_and(|hash| hash.get(&field).is_some()) as i64,
+        )),
+        HashCommand::Len(key) => Ok(RespData::Integer(
+            db.hash(&key)?.map_or(0, |hash| hash.len() as i64),
+        )),
+        HashCommand::Keys(key) => Ok(RespData::Array(
+            db.hash(&key)?
+                .into_iter()
+                .flat_map(|hash| hash.iter())
+                .map(|(field, _)| RespData::BulkString(field.clone()))
+                .collect(),
+        )),
+        HashCommand::Vals(key) => Ok(RespData::Array(
+            db.hash(&key)?
+                .into_iter()
+                .flat_map(|hash| hash.iter())
+                .map(|(_, value)| RespData::BulkString(value.clone()))
+                .collect(),
+        )),
+        HashCommand::GetAll(key) => Ok(RespData::Map(
+            db.hash(&key)?
+                .into_iter()
+                .flat_map(|hash| hash.iter())
+                .map(|(field, value)| {
+                    (
+                        RespData::BulkString(field.clone()),
+                        RespData::BulkString(value.clone()),
+                    )
+                })
+                .collect(),
+        )),
+        HashCommand::IncrBy(key, field, increment) => {
+            let current: i64 = match db.hash(&key)?.and_then(|hash| hash.get(&field)) {
+                Some(value) => value.parse().map_err(|_| StoreError::HashValueNotInteger)?,
+                None => 0,
+            };
+            let value = current.checked_add(increment).ok_or(StoreError::Overflow)?;
+            db.hash_or_create(&key)?.replace(field, value.to_string());
+            db.touch(&key);
+            Ok(RespData::Integer(value))
+        }
+        HashCommand::IncrByFloat(key, field, increment) => {
+            let current: f64 = match db.hash(&key)?.and_then(|hash| hash.get(&field)) {
+                Some(value) => value
+                    .parse()
+                    .ok()
+                    .filter(|value: &f64| !value.is_nan())
+                    .ok_or(StoreError::HashValueNotFloat)?,
+                None => 0.0,
+            };
+            let value = current + increment;
+            if !value.is_finite() {
+                return Err(StoreError::NanOrInfinity);
+            }
+            let value = format_score(value);
+            db.hash_or_create(&key)?.replace(field, value.clone());
+            db.touch(&key);
+            Ok(RespData::BulkString(value))
+        }
+        HashCommand::StrLen(key, field) => Ok(RespData::Integer(
+            db.hash(&key)?
+                .and_then(|hash| hash.get(&field))
+                .map_or(0, |value| value.len() as i64),
+        )),
+        HashCommand::RandField {
+            key,
+            count,
+            with_values,
+        } => {
+            let hash = db.hash(&key)?;
+            let Some(count) = count else {
+                return Ok(bulk_string_or_null(
+                    hash.and_then(|hash| hash.random_entry())
+                        .map(|(field, _)| field),
+                ));
+            };
+            let Some(hash) = hash else {
+                return Ok(RespData::Array(Vec::new()));
+            };
+            Ok(RespData::Array(
+                random_fields(hash, count)
+                    .into_iter()
+                    .flat_map(|(field, value)| {
+                        let value = with_values.then(|| RespData::BulkString(value.clone()));
+                        std::iter::once(RespData::BulkString(field.clone())).chain(value)
+                    })
+                    .collect(),
+            ))
+        }
+        HashCommand::Expire {
+            key,
+            expiry,
+            condition,
+            fields,
+        } => {
+            let Some(hash) = db.hash_mut(&key)? else {
+                return Ok(RespData::Array(vec![RespData::Integer(-2); fields.len()]));
+            };
+            let now = unix_time_ms();
+            let at = expiry.resolve(now);
+            let results = fields
+                .iter()
+                .map(|field| {
+                    if hash.get(field).is_none() {
+                        return -2;
+                    }
+                    if condition
+                        .is_some_and(|condition| !condition.allows(hash.expires_at(field), at))
+                    {
+                        return 0;
+                    }
+                    if change_ttl(hash, field, TtlChange::Set(Expiry::At(at)), now) {
+                        2
+                    } else {
+                        1
+                    }
+                })
+                .collect::<Vec<i64>>();
+            after_ttl_change(db, &key, results.iter().any(|result| *result > 0));
+            Ok(RespData::Array(
+                results.into_iter().map(RespData::Integer).collect(),
+            ))
+        }
+        HashCommand::Ttl {
+            key,
+            fields,
+            millis,
+        } => {
+            let hash = db.hash(&key)?;
+            let now = unix_time_ms();
+            Ok(RespData::Array(
+                fields
+                    .iter()
+                    .map(
+                        |field| match hash.filter(|hash| hash.get(field).is_some()) {
+                            None => -2,
+                            Some(hash) => match hash.expires_at(field) {
+                                None => -1,
+                                Some(at) if millis => (at - now).max(0),
+                                Some(at) => ((at - now).max(0) + 999) / 1000,
+                            },
+                        },
+                    )
+                    .map(RespData::Integer)
+                    .collect(),
+            ))
+        }
+        HashCommand::Persist(key, fields) => {
+            let Some(hash) = db.hash_mut(&key)? else {
+                return Ok(RespData::Array(vec![RespData::Integer(-2); fields.len()]));
+            };
+            let results = fields
+                .iter()
+                .map(|field| {
+                    if hash.get(field).is_none() {
+                        -2
+                    } else if hash.expires_at(field).is_none() {
+                        -1
+                    } else {
+                        hash.set_expires_at(field, None);
+                        1
+                    }
+                })
+                .collect::<Vec<i64>>();
+            if results.contains(&1) {
+                db.touch(&key);
+            }
+            Ok(RespData::Array(
+                results.into_iter().map(RespData::Integer).collect(),
+            ))
+        }
+        HashCommand::GetEx { key, ttl, fields } => {
+            let Some(hash) = db.hash_mut(&key)? else {
+                return Ok(RespData::Array(vec![
+                    RespData::BulkStringNull;
+                    fields.len()
+                ]));
+            };
+            let now = unix_time_ms();
+            let values: Vec<RespData> = fields
+                .iter()
+                .map(|field| {
+                    let value = bulk_string_or_null(hash.get(field));
+                    if value != RespData::BulkStringNull {
+                        change_ttl(hash, field, ttl, now);
+                    }
+                    value
+                })
+                .collect();
+            let changed = ttl != TtlChange::Keep
+                && values
+                    .iter()
+                    .any(|value| *value != RespData::BulkStringNull);
+            after_ttl_change(db, &key, changed);
+            Ok(RespData::Array(values))
+        }
+        HashCommand::SetEx {
+            key,
+            condition,
+            ttl,
+            fields,
+        } => {
+            let existing = db.hash(&key)?.map_or(0, |hash| {
+                fields
+                    .iter()
+                    .filter(|(field, _)| hash.get(field).is_some())
+                    .count()
+            });
+            match condition {
+                Some(FieldCondition::Fnx) if existing > 0 => return Ok(RespData::Integer(0)),
+                Some(FieldCondition::Fxx) if existing < fields.len() => {
+                    return Ok(RespData::Integer(0))
+                }
+                _ => {}
+            }
+
+            let now = unix_time_ms();
+            if let TtlChange::Set(expiry) = ttl {
+                // The fields would expire as soon as they are set, so a
+                // missing hash stays missing.
+                if expiry.resolve(now) <= now && db.hash(&key)?.is_none() {
+                    return Ok(RespData::Integer(1));
+                }
+            }
+            let hash = db.hash_or_create(&key)?;
+            for (field, value) in fields {
+                match ttl {
+                    TtlChange::Keep => hash.replace(field.clone(), value),
+                    _ => {
+                        hash.insert(field.clone(), value);
+                    }
+                }
+                change_ttl(hash, &field, ttl, now);
+            }
+            after_ttl_change(db, &key, true);
+            Ok(RespData::Integer(1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(len: usize) -> Hash {
+        let mut hash = Hash::default();
+        for i in 0..len {
+            hash.insert(format!("field:{}", i), i.to_string());
+        }
+        hash
+    }
+
+    #[test]
+    fn random_fields_are_distinct_for_positive_count() {
+        let hash = hash(100);
+        for count in [0, 10, 50, 99, 100, 200] {
+            let fields = random_fields(&hash, count);
+            let distinct: std::collections::HashSet<_> =
+                fields.iter().map(|(field, _)| *field).collect();
+            assert_eq!(fields.len(), (count as usize).min(100));
+            assert_eq!(distinct.len(), fields.len());
+        }
+    }
+
+    #[test]
+    fn random_fields_may_repeat_for_negative_count() {
+        assert_eq!(random_fields(&hash(3), -10).len(), 10);
+    }
+
+    #[test]
+    fn hrandfield_refuses_huge_negative_counts() {
+        let parse = |count: &str| {
+            let args = ["h", count].map(|arg| RespData::BulkString(arg.to_string()));
+            HashCommand::parse("HRANDFIELD", &args)
+        };
+        assert!(parse("-16777216").is_ok());
+        assert_eq!(
+            parse("-9223372036854775808"),
+            Err(CommandError::Other("ERR value is out of range".into()))
+        );
+    }
+
+    #[test]
+    fn expired_fields_read_as_missing_until_reclaimed() {
+        let mut hash = hash(3);
+        hash.set_expires_at("field:0", Some(unix_time_ms() - 1));
+        hash.set_expires_at("field:1", Some(unix_time_ms() + 60_000));
+        assert_eq!(hash.get("field:0"), None);
+        assert_eq!(hash.len(), 2);
+        assert_eq!(hash.iter().count(), 2);
+
+        assert_eq!(
+            hash.remove_expired(unix_time_ms()),
+            vec!["field:0".to_string()]
+        );
+        assert!(hash.has_volatile_fields());
+        hash.insert("field:1".to_string(), "new".to_string());
+        assert!(!hash.has_volatile_fields());
+    }
+}
@@ -0,0 +1,763 @@
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError},
+};
+
+/// Bits of the hash that pick a register.
+const P: u32 = 14;
+/// Bits of the hash left for counting trailing zeros.
+const Q: u32 = 64 - P;
+const REGISTERS: usize = 1 << P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * REGISTER_BITS).div_ceil(8);
+
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+/// Where the encoding sits in the header, after the `HYLL` magic.
+const ENCODING: usize = 4;
+/// Where the cached cardinality sits: 8 bytes, little endian, with the top
+/// bit set when it is stale.
+const CARDINALITY: usize = 8;
+
+/// Size in bytes past which a sparse HyperLogLog is made dense (Redis's
+/// `hll-sparse-max-bytes`).
+const SPARSE_MAX_BYTES: usize = 3000;
+
+// Sparse opcodes: ZERO `00xxxxxx` covers 1 to 64 zero registers, XZERO
+// `01xxxxxx xxxxxxxx` up to 16384, and VAL `1vvvvvxx` 1 to 4 registers
+// holding a value from 1 to 32.
+const XZERO_BIT: u8 = 0x40;
+const VAL_BIT: u8 = 0x80;
+const ZERO_MAX_LEN: usize = 64;
+const XZERO_MAX_LEN: usize = 16384;
+const VAL_MAX_VALUE: u8 = 32;
+const VAL_MAX_LEN: usize = 4;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Opcode {
+    Zero(usize),
+    XZero(usize),
+    Val(u8, usize),
+}
+
+impl Opcode {
+    fn read(bytes: &[u8]) -> Opcode {
+        let op = bytes[0];
+        if op & VAL_BIT != 0 {
+            Opcode::Val(((op >> 2) & 0x1f) + 1, usize::from(op & 0x3) + 1)
+        } else if op & XZERO_BIT != 0 {
+            Opcode::XZero(((usize::from(op & 0x3f) << 8) | usize::from(bytes[1])) + 1)
+        } else {
+            Opcode::Zero(usize::from(op & 0x3f) + 1)
+        }
+    }
+
+    /// Registers covered.
+    fn span(self) -> usize {
+        match self {
+            Opcode::Zero(len) | Opcode::XZero(len) | Opcode::Val(_, len) => len,
+        }
+    }
+
+    /// Size in bytes.
+    fn size(self) -> usize {
+        match self {
+            Opcode::XZero(_) => 2,
+            _ => 1,
+        }
+    }
+
+    fn write(self, out: &mut Vec<u8>) {
+        match self {
+            Opcode::Zero(len) => out.push((len - 1) as u8),
+            Opcode::XZero(len) => {
+                let len = len - 1;
+                out.push((len >> 8) as u8 | XZERO_BIT);
+                out.push((len & 0xff) as u8);
+            }
+            Opcode::Val(value, len) => out.push(((value - 1) << 2 | (len - 1) as u8) | VAL_BIT),
+        }
+    }
+
+    /// A run of zero registers, as ZERO if it fits and XZERO otherwise.
+    fn zeros(len: usize) -> Opcode {
+        if len > ZERO_MAX_LEN {
+            Opcode::XZero(len)
+        } else {
+            Opcode::Zero(len)
+        }
+    }
+}
+
+/// Redis's MurmurHash64A, which picks the register and run length for an
+/// element, and which Bloom and cuckoo filters hash their items with too.
+pub fn murmur_hash_64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= u64::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The register an element falls into, and the length of the run of zeros
+/// ending its hash, plus one.
+fn register_and_count(element: &str) -> (usize, u8) {
+    let hash = murmur_hash_64a(element.as_bytes(), 0xadc83b19);
+    let index = hash as usize & (REGISTERS - 1);
+    // The extra bit caps the count at Q + 1.
+    let hash = (hash >> P) | (1 << Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn dense_register(registers: &[u8], index: usize) -> u8 {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let low = u16::from(registers[byte]);
+    let high = u16::from(registers.get(byte + 1).copied().unwrap_or(0));
+    (((low | high << 8) >> shift) as u8) & REGISTER_MAX
+}
+
+fn set_dense_register(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * REGISTER_BITS / 8;
+    let shift = index * REGISTER_BITS % 8;
+    let mask = u16::from(REGISTER_MAX) << shift;
+    let value = u16::from(value) << shift;
+    registers[byte] = (registers[byte] & !mask as u8) | value as u8;
+    // The last register ends exactly at the last byte.
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next = (*next & !(mask >> 8) as u8) | (value >> 8) as u8;
+    }
+}
+
+/// The cardinality estimate for registers with the given histogram of
+/// values, after Otmar Ertl's "New cardinality estimation algorithms for
+/// HyperLogLog sketches", as Redis does.
+fn estimate(histogram: &[u32; 64]) -> u64 {
+    fn sigma(mut x: f64) -> f64 {
+        if x == 1.0 {
+            return f64::INFINITY;
+        }
+        let mut y = 1.0;
+        let mut z = x;
+        loop {
+            x *= x;
+            let previous = z;
+            z += x * y;
+            y += y;
+            if previous == z {
+                return z;
+            }
+        }
+    }
+
+    fn tau(mut x: f64) -> f64 {
+        if x == 0.0 || x == 1.0 {
+            return 0.0;
+        }
+        let mut y = 1.0;
+        let mut z = 1.0 - x;
+        loop {
+            x = x.sqrt();
+            let previous = z;
+            y *= 0.5;
+            z -= (1.0 - x).powi(2) * y;
+            if previous == z {
+                return z / 3.0;
+            }
+        }
+    }
+
+    let m = REGISTERS as f64;
+    let q = Q as usize;
+    let mut z = m * tau((m - f64::from(histogram[q + 1])) / m);
+    for count in histogram[1..=q].iter().rev() {
+        z += f64::from(*count);
+        z *= 0.5;
+    }
+    z += m * sigma(f64::from(histogram[0]) / m);
+    // 0.5 / ln(2), the bias correction for an unbounded number of registers.
+    const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+/// A HyperLogLog laid out byte for byte like Redis's: a 16-byte header
+/// (`HYLL`, the encoding, 3 unused bytes and the cached cardinality)
+/// followed by the registers, either run-length encoded (sparse) or packed
+/// 6 bits each (dense). Redis keeps it in a string; here it is a value of its
+/// own that still reports itself as a string.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.resize(HEADER_SIZE, 0);
+        bytes[ENCODING] = SPARSE;
+        Opcode::XZero(XZERO_MAX_LEN).write(&mut bytes);
+        HyperLogLog { bytes }
+    }
+}
+
+impl HyperLogLog {
+    /// Reads a HyperLogLog back from the string it was encoded in, e.g.
+    /// after GET and SET or a restore. Returns `None` unless the header is
+    /// valid and the registers cover exactly `REGISTERS`, as Redis checks.
+    pub fn from_bytes(bytes: &[u8]) -> Option<HyperLogLog> {
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(b"HYLL") {
+            return None;
+        }
+        let valid = match bytes[ENCODING] {
+            DENSE => bytes.len() == DENSE_SIZE,
+            SPARSE => {
+                let mut pos = HEADER_SIZE;
+                let mut registers = 0;
+                while pos < bytes.len() && registers <= REGISTERS {
+                    let truncated =
+                        bytes[pos] & (VAL_BIT | XZERO_BIT) == XZERO_BIT && pos + 1 == bytes.len();
+                    if truncated {
+                        return None;
+                    }
+                    let op = Opcode::read(&bytes[pos..]);
+                    pos += op.size();
+                    registers += op.span();
+                }
+                registers == REGISTERS
+            }
+            _ => false,
+        };
+        valid.then(|| HyperLogLog {
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.bytes[ENCODING] == SPARSE
+    }
+
+    fn opcodes(&self) -> impl Iterator<Item = Opcode> + '_ {
+        let mut pos = HEADER_SIZE;
+        std::iter::from_fn(move || {
+            if pos >= self.bytes.len() {
+                return None;
+            }
+            let op = Opcode::read(&self.bytes[pos..]);
+            pos += op.size();
+            Some(op)
+        })
+    }
+
+    fn cached_count(&self) -> Option<u64> {
+        let cached = u64::from_le_bytes(self.bytes[CARDINALITY..HEADER_SIZE].try_into().unwrap());
+        (cached >> 63 == 0).then_some(cached)
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.bytes[HEADER_SIZE - 1] |= 0x80;
+    }
+
+    /// Adds an element, returning whether a register changed.
+    pub fn add(&mut self, element: &str) -> bool {
+        let (index, count) = register_and_count(element);
+        let changed = self.set_register(index, count);
+        if changed {
+            self.invalidate_cache();
+        }
+        changed
+    }
+
+    /// Raises a register to `count` if it is lower, returning whether it
+    /// was.
+    fn set_register(&mut self, index: usize, count: u8) -> bool {
+        if self.is_sparse() && !self.set_sparse_register(index, count) {
+            return false;
+        }
+        if self.is_sparse() {
+            return true;
+        }
+        let registers = &mut self.bytes[HEADER_SIZE..];
+        if dense_register(registers, index) >= count {
+            return false;
+        }
+        set_dense_register(registers, index, count);
+        true
+    }
+
+    /// Raises a register of the sparse encoding, splitting the opcode that
+    /// covers it. Returns false when the register was already high enough;
+    /// when the result wouldn't fit, the HyperLogLog is made dense instead,
+    /// leaving the register for the caller to set.
+    fn set_sparse_register(&mut self, index: usize, count: u8) -> bool {
+        if count > VAL_MAX_VALUE {
+            self.make_dense();
+            return true;
+        }
+
+        // Find the opcode covering the register, and the one before it.
+        let mut pos = HEADER_SIZE;
+        let mut prev = None;
+        let mut first = 0;
+        let op = loop {
+            let op = Opcode::read(&self.bytes[pos..]);
+            if index < first + op.span() {
+                break op;
+            }
+            prev = Some(pos);
+            pos += op.size();
+            first += op.span();
+        };
+        let last = first + op.span() - 1;
+
+        let mut seq = Vec::with_capacity(5);
+        match op {
+            Opcode::Val(value, _) if value >= count => return false,
+            Opcode::Val(value, _) => {
+                if index != first {
+                    Opcode::Val(value, index - first).write(&mut seq);
+                }
+                Opcode::Val(count, 1).write(&mut seq);
+                if index != last {
+                    Opcode::Val(value, last - index).write(&mut seq);
+                }
+            }
+            Opcode::Zero(_) | Opcode::XZero(_) => {
+                if index != first {
+                    Opcode::zeros(index - first).write(&mut seq);
+                }
+                Opcode::Val(count, 1).write(&mut seq);
+                if index != last {
+                    Opcode::zeros(last - index).write(&mut seq);
+                }
+            }
+        }
+        if seq.len() > op.size() && self.bytes.len() + seq.len() - op.size() > SPARSE_MAX_BYTES {
+            self.make_dense();
+            return true;
+        }
+        self.bytes.splice(pos..pos + op.size(), seq);
+
+        // Merge adjacent VAL opcodes with the same value, looking at up to
+        // five opcodes from the one before the change.
+        let mut pos = prev.unwrap_or(HEADER_SIZE);
+        for _ in 0..5 {
+            if pos >= self.bytes.len() {
+                break;
+            }
+            let op = Opcode::read(&self.bytes[pos..]);
+            let Opcode::Val(value, len) = op else {
+                pos += op.size();
+                continue;
+            };
+            let next = (pos + 1 < self.bytes.len()).then(|| Opcode::read(&self.bytes[pos + 1..]));
+            if let Some(Opcode::Val(next_value, next_len)) = next {
+                if next_value == value && len + next_len <= VAL_MAX_LEN {
+                    let mut merged = Vec::with_capacity(1);
+                    Opcode::Val(value, len + next_len).write(&mut merged);
+                    self.bytes.splice(pos..pos + 2, merged);
+                    // Try merging the result with what follows, too.
+                    continue;
+                }
+            }
+            pos += 1;
+        }
+        true
+    }
+
+    /// Switches to the dense encoding, returning whether it was sparse.
+    pub fn make_dense(&mut self) -> bool {
+        if !self.is_sparse() {
+            return false;
+        }
+        let mut registers = [0; REGISTERS];
+        self.merge_into(&mut registers);
+        let mut dense = self.bytes[..HEADER_SIZE].to_vec();
+        dense[ENCODING] = DENSE;
+        dense.resize(DENSE_SIZE, 0);
+        for (index, value) in registers.into_iter().enumerate() {
+            if value > 0 {
+                set_dense_register(&mut dense[HEADER_SIZE..], index, value);
+            }
+        }
+        self.bytes = dense;
+        true
+    }
+
+    /// Raises each of `max` to the matching register's value.
+    pub fn merge_into(&self, max: &mut [u8; REGISTERS]) {
+        if !self.is_sparse() {
+            for (index, max) in max.iter_mut().enumerate() {
+                *max = (*max).max(dense_register(&self.bytes[HEADER_SIZE..], index));
+            }
+            return;
+        }
+        let mut index = 0;
+        for op in self.opcodes() {
+            if let Opcode::Val(value, len) = op {
+                for max in &mut max[index..index + len] {
+                    *max = (*max).max(value);
+                }
+            }
+            index += op.span();
+        }
+    }
+
+    /// Raises each register to the matching value of `max`, as PFMERGE
+    /// does.
+    fn raise_to(&mut self, max: &[u8; REGISTERS]) {
+        for (index, value) in max.iter().enumerate() {
+            if *value > 0 {
+                self.set_register(index, *value);
+            }
+        }
+        self.invalidate_cache();
+    }
+
+    fn histogram(&self) -> [u32; 64] {
+        let mut histogram = [0; 64];
+        if self.is_sparse() {
+            for op in self.opcodes() {
+                let value = match op {
+                    Opcode::Val(value, _) => value,
+                    _ => 0,
+                };
+                histogram[usize::from(value)] += op.span() as u32;
+            }
+        } else {
+            for index in 0..REGISTERS {
+                histogram[usize::from(dense_register(&self.bytes[HEADER_SIZE..], index))] += 1;
+            }
+        }
+        histogram
+    }
+
+    /// The estimated cardinality, cached in the header until the next change.
+    pub fn count(&mut self) -> u64 {
+        if let Some(count) = self.cached_count() {
+            return count;
+        }
+        let count = estimate(&self.histogram());
+        self.bytes[CARDINALITY..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+        count
+    }
+
+    /// The sparse opcodes spelled out, as PFDEBUG DECODE shows them.
+    fn decode(&self) -> String {
+        self.opcodes()
+            .map(|op| match op {
+                Opcode::Zero(len) => format!("z:{}", len),
+                Opcode::XZero(len) => format!("Z:{}", len),
+                Opcode::Val(value, len) => format!("v:{},{}", value, len),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The cardinality of the union of several HyperLogLogs.
+fn count_union<'a>(hlls: impl Iterator<Item = &'a HyperLogLog>) -> u64 {
+    let mut max = [0; REGISTERS];
+    for hll in hlls {
+        hll.merge_into(&mut max);
+    }
+    let mut histogram = [0; 64];
+    for value in max {
+        histogram[usize::from(value)] += 1;
+    }
+    estimate(&histogram)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DebugSubcommand {
+    GetReg,
+    Decode,
+    Encoding,
+    ToDense,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HyperLogLogCommand {
+    Add(String, Vec<String>),
+    Count(Vec<String>),
+    /// PFMERGE with the destination and sources.
+    Merge(String, Vec<String>),
+    Debug(DebugSubcommand, String),
+}
+
+impl HyperLogLogCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<HyperLogLogCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "PFADD" => {
+                let key = args.string()?;
+                let mut elements = Vec::new();
+                while !args.is_empty() {
+                    elements.push(args.string()?);
+                }
+                HyperLogLogCommand::Add(key, elements)
+            }
+            "PFCOUNT" => HyperLogLogCommand::Count(args.rest()?),
+            "PFMERGE" => {
+                let destination = args.string()?;
+                let mut sources = Vec::new();
+                while !args.is_empty() {
+                    sources.push(args.string()?);
+                }
+                HyperLogLogCommand::Merge(destination, sources)
+            }
+            "PFDEBUG" => {
+                let subcommand = args.string()?;
+                let parsed = match subcommand.to_lowercase().as_str() {
+                    "getreg" => DebugSubcommand::GetReg,
+                    "decode" => DebugSubcommand::Decode,
+                    "encoding" => DebugSubcommand::Encoding,
+                    "todense" => DebugSubcommand::ToDense,
+                    _ => {
+                        return Err(CommandError::Other(format!(
+                            "ERR Unknown PFDEBUG subcommand '{}'",
+                            subcommand
+                        )))
+                    }
+                };
+                let key = args.string()?;
+                if !args.is_empty() {
+                    return Err(CommandError::Other(format!(
+                        "ERR Wrong number of arguments for the '{}' subcommand",
+                        subcommand
+                    )));
+                }
+                HyperLogLogCommand::Debug(parsed, key)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(self, HyperLogLogCommand::Count(_))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            HyperLogLogCommand::Add(key, _) | HyperLogLogCommand::Debug(_, key) => vec![key],
+            HyperLogLogCommand::Count(keys) => keys.iter().map(String::as_str).collect(),
+            HyperLogLogCommand::Merge(destination, sources) => std::iter::once(destination)
+                .chain(sources)
+                .map(String::as_str)
+                .collect(),
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: HyperLogLogCommand) -> Result<RespData, StoreError> {
+    match command {
+        HyperLogLogCommand::Add(key, elements) => {
+            let created = db.hyperloglog(&key)?.is_none();
+            let hll = db.hyperloglog_or_create(&key)?;
+            let mut changed = created;
+            for element in &elements {
+                changed |= hll.add(element);
+            }
+            if changed {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(changed as i64))
+        }
+        HyperLogLogCommand::Count(keys) => {
+            if let [key] = keys.as_slice() {
+                return Ok(RespData::Integer(
+                    db.hyperloglog_mut(key)?.map_or(0, |hll| hll.count() as i64),
+                ));
+            }
+            let mut hlls = Vec::new();
+            for key in &keys {
+                hlls.extend(db.hyperloglog(key)?);
+            }
+            Ok(RespData::Integer(
+                count_union(hlls.iter().map(|hll| &**hll)) as i64,
+            ))
+        }
+        HyperLogLogCommand::Merge(destination, sources) => {
+            let mut max = [0; REGISTERS];
+            let mut any_dense = false;
+            for key in std::iter::once(&destination).chain(&sources) {
+                if let Some(hll) = db.hyperloglog(key)? {
+                    any_dense |= !hll.is_sparse();
+                    hll.merge_into(&mut max);
+                }
+            }
+            let hll = db.hyperloglog_or_create(&destination)?;
+            if any_dense {
+                hll.make_dense();
+            }
+            hll.raise_to(&max);
+            db.touch(&destination);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        HyperLogLogCommand::Debug(subcommand, key) => {
+            let hll = db.hyperloglog_mut(&key)?.ok_or(StoreError::MissingKey)?;
+            match subcommand {
+                DebugSubcommand::GetReg => {
+                    hll.make_dense();
+                    let registers = &hll.bytes[HEADER_SIZE..];
+                    Ok(RespData::Array(
+                        (0..REGISTERS)
+                            .map(|index| RespData::Integer(dense_register(registers, index).into()))
+                            .collect(),
+                    ))
+                }
+                DebugSubcommand::Decode if hll.is_sparse() => {
+                    Ok(RespData::BulkString(hll.decode()))
+                }
+                DebugSubcommand::Decode => Err(StoreError::HllNotSparse),
+                DebugSubcommand::Encoding => Ok(RespData::SimpleString(
+                    if hll.is_sparse() { "sparse" } else { "dense" }.to_string(),
+                )),
+                DebugSubcommand::ToDense => Ok(RespData::Integer(hll.make_dense() as i64)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registers(hll: &HyperLogLog) -> [u8; REGISTERS] {
+        let mut max = [0; REGISTERS];
+        hll.merge_into(&mut max);
+        max
+    }
+
+    #[test]
+    fn starts_as_one_xzero_opcode() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.bytes.len(), HEADER_SIZE + 2);
+        assert_eq!(&hll.bytes[..4], b"HYLL");
+        assert_eq!(hll.decode(), "Z:16384");
+        assert_eq!(hll.count(), 0);
+    }
+
+    #[test]
+    fn sparse_and_dense_agree() {
+        let mut sparse = HyperLogLog::default();
+        for i in 0..200 {
+            sparse.add(&format!("element:{}", i));
+        }
+        assert!(sparse.is_sparse());
+        let mut dense = sparse.clone();
+        assert!(dense.make_dense());
+        assert_eq!(dense.bytes.len(), DENSE_SIZE);
+        assert_eq!(registers(&sparse), registers(&dense));
+        assert_eq!(sparse.count(), dense.count());
+
+        for i in 200..300 {
+            assert_eq!(
+                sparse.add(&format!("element:{}", i)),
+                dense.add(&format!("element:{}", i))
+            );
+        }
+        assert_eq!(registers(&sparse), registers(&dense));
+        assert!(!sparse.add("element:0"));
+    }
+
+    #[test]
+    fn reads_back_only_valid_encodings() {
+        let mut sparse = HyperLogLog::default();
+        for i in 0..100 {
+            sparse.add(&format!("element:{}", i));
+        }
+        let mut dense = sparse.clone();
+        dense.make_dense();
+        for hll in [&sparse, &dense] {
+            assert_eq!(HyperLogLog::from_bytes(hll.as_bytes()).as_ref(), Some(hll));
+        }
+
+        let mut bad_magic = sparse.bytes.clone();
+        bad_magic[0] = b'h';
+        let mut bad_encoding = sparse.bytes.clone();
+        bad_encoding[ENCODING] = 2;
+        let mut too_few_registers = HyperLogLog::default().bytes;
+        too_few_registers[HEADER_SIZE] = XZERO_BIT;
+        let truncated = &HyperLogLog::default().bytes[..HEADER_SIZE + 1];
+        let mut extra_opcode = sparse.bytes.clone();
+        extra_opcode.push(0);
+        for bytes in [
+            &bad_magic[..],
+            &bad_encoding,
+            &too_few_registers,
+            truncated,
+            &extra_opcode,
+            &dense.bytes[..DENSE_SIZE - 1],
+            b"HYLL",
+        ] {
+            assert_eq!(HyperLogLog::from_bytes(bytes), None);
+        }
+    }
+
+    #[test]
+    fn sparse_runs_merge_and_split() {
+        let mut hll = HyperLogLog::default();
+        assert!(hll.set_register(10, 3));
+        assert_eq!(hll.decode(), "z:10 v:3,1 Z:16373");
+        assert!(hll.set_register(11, 3));
+        assert_eq!(hll.decode(), "z:10 v:3,2 Z:16372");
+        assert!(hll.set_register(11, 5));
+        assert_eq!(hll.decode(), "z:10 v:3,1 v:5,1 Z:16372");
+        assert!(!hll.set_register(11, 4));
+        // Values above what VAL can hold need the dense encoding.
+        assert!(hll.set_register(0, 40));
+        assert!(!hll.is_sparse());
+        assert_eq!(registers(&hll)[..12], [40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 5]);
+    }
+
+    #[test]
+    fn estimates_within_the_standard_error() {
+        let mut hll = HyperLogLog::default();
+        for i in 0..100_000 {
+            hll.add(&i.to_string());
+        }
+        assert!(!hll.is_sparse());
+        let count = hll.count() as f64;
+        assert!((count - 100_000.0).abs() / 100_000.0 < 0.05, "{}", count);
+
+        let mut other = HyperLogLog::default();
+        for i in 50_000..150_000 {
+            other.add(&i.to_string());
+        }
+        let union = count_union([&hll, &other].into_iter()) as f64;
+        assert!((union - 150_000.0).abs() / 150_000.0 < 0.05, "{}", union);
+    }
+}
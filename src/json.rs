@@ -0,0 +1,1457 @@
+use std::cmp::Ordering;
+
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// How deep documents and path filters may nest, as in RedisJSON.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// An object, keeping its keys in insertion order.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, CommandError> {
+        let mut parser = Parser::new(text);
+        let value = parser.value(0);
+        parser.skip_whitespace();
+        match value {
+            Some(value) if parser.at_end() => Ok(value),
+            _ => Err(parser.error()),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "boolean",
+            Json::Integer(_) => "integer",
+            Json::Number(_) => "number",
+            Json::String(_) => "string",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The children of an array or object, with where they are.
+    fn children(&self) -> Vec<(Step, &Json)> {
+        match self {
+            Json::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (Step::Index(i), item))
+                .collect(),
+            Json::Object(fields) => fields
+                .iter()
+                .map(|(key, value)| (Step::Field(key.clone()), value))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn get_mut(&mut self, location: &[Step]) -> Option<&mut Json> {
+        let Some((step, rest)) = location.split_first() else {
+            return Some(self);
+        };
+        let child = match (self, step) {
+            (Json::Array(items), Step::Index(i)) => items.get_mut(*i)?,
+            (Json::Object(fields), Step::Field(name)) => {
+                &mut fields.iter_mut().find(|(key, _)| key == name)?.1
+            }
+            _ => return None,
+        };
+        child.get_mut(rest)
+    }
+
+    /// Removes the value at `location`, which must not be the root.
+    fn remove(&mut self, location: &[Step]) -> bool {
+        let Some((step, parent)) = location.split_last() else {
+            return false;
+        };
+        match (self.get_mut(parent), step) {
+            (Some(Json::Array(items)), Step::Index(i)) if *i < items.len() => {
+                items.remove(*i);
+                true
+            }
+            (Some(Json::Object(fields)), Step::Field(name)) => {
+                let len = fields.len();
+                fields.retain(|(key, _)| key != name);
+                fields.len() < len
+            }
+            _ => false,
+        }
+    }
+
+    pub fn serialize(&self, format: &Format) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, format: &Format, level: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Json::Integer(value) => out.push_str(&value.to_string()),
+            Json::Number(value) => out.push_str(&format_number(*value)),
+            Json::String(value) => write_string(out, value),
+            Json::Array(items) => {
+                write_container(out, format, level, ('[', ']'), items, |out, item| {
+                    item.write(out, format, level + 1)
+                })
+            }
+            Json::Object(fields) => write_container(
+                out,
+                format,
+                level,
+                ('{', '}'),
+                fields,
+                |out, (key, value)| {
+                    write_string(out, key);
+                    out.push(':');
+                    out.push_str(&format.space);
+                    value.write(out, format, level + 1);
+                },
+            ),
+        }
+    }
+}
+
+/// Formats a float the way RedisJSON does: always with a fraction or an
+/// exponent, so that it reads back as a float.
+fn format_number(value: f64) -> String {
+    let magnitude = value.abs();
+    if magnitude != 0.0 && !(1e-5..1e16).contains(&magnitude) {
+        return format!("{:e}", value);
+    }
+    let formatted = value.to_string();
+    if formatted.contains('.') {
+        formatted
+    } else {
+        format!("{}.0", formatted)
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_container<T>(
+    out: &mut String,
+    format: &Format,
+    level: usize,
+    (open, close): (char, char),
+    items: &[T],
+    mut write_item: impl FnMut(&mut String, &T),
+) {
+    out.push(open);
+    if items.is_empty() {
+        out.push(close);
+        return;
+    }
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(&format.newline);
+        out.push_str(&format.indent.repeat(level + 1));
+        write_item(out, item);
+    }
+    out.push_str(&format.newline);
+    out.push_str(&format.indent.repeat(level));
+    out.push(close);
+}
+
+/// JSON.GET's INDENT, NEWLINE and SPACE.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Format {
+    pub indent: String,
+    pub newline: String,
+    pub space: String,
+}
+
+/// A recursive descent parser over JSON text, shared by documents and the
+/// literals in path filters.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser { text, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.text.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start_matches([' ', '\t', '\n', '\r']);
+        self.pos = self.text.len() - trimmed.len();
+    }
+
+    /// Consumes `token` if the input continues with it.
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self) -> CommandError {
+        let consumed = &self.text[..self.pos];
+        let line = consumed.matches('\n').count() + 1;
+        let column = consumed.len() - consumed.rfind('\n').map_or(0, |i| i + 1) + 1;
+        CommandError::Other(format!(
+            "ERR invalid JSON at line {} column {}",
+            line, column
+        ))
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+        match self.peek()? {
+            '{' => self.object(depth),
+            '[' => self.array(depth),
+            '"' => self.string('"').map(Json::String),
+            't' if self.eat("true") => Some(Json::Bool(true)),
+            'f' if self.eat("false") => Some(Json::Bool(false)),
+            'n' if self.eat("null") => Some(Json::Null),
+            '-' | '0'..='9' => self.number(),
+            _ => None,
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Option<Json> {
+        self.eat("[");
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.eat("]") {
+            return Some(Json::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                return Some(Json::Array(items));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Option<Json> {
+        self.eat("{");
+        let mut fields: Vec<(String, Json)> = Vec::new();
+        self.skip_whitespace();
+        if self.eat("}") {
+            return Some(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return None;
+            }
+            let key = self.string('"')?;
+            self.skip_whitespace();
+            if !self.eat(":") {
+                return None;
+            }
+            let value = self.value(depth + 1)?;
+            // A repeated key keeps its first place and its last value.
+            match fields.iter_mut().find(|(existing, _)| *existing == key) {
+                Some(field) => field.1 = value,
+                None => fields.push((key, value)),
+            }
+            self.skip_whitespace();
+            if self.eat("}") {
+                return Some(Json::Object(fields));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    /// A string delimited by `quote`, which paths allow to be `'`.
+    fn string(&mut self, quote: char) -> Option<String> {
+        self.eat(&quote.to_string());
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        loop {
+            let (i, c) = chars.next()?;
+            match c {
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Some(value);
+                }
+                '\\' => {
+                    let (_, escaped) = chars.next()?;
+                    value.push(match escaped {
+                        '"' | '\\' | '/' | '\'' => escaped,
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'b' => '\u{08}',
+                        'f' => '\u{0c}',
+                        'u' => {
+                            let mut code = hex4(&mut chars)?;
+                            // A high surrogate must be followed by its low half.
+                            if (0xd800..0xdc00).contains(&code) {
+                                if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                                    return None;
+                                }
+                                let low = hex4(&mut chars)?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return None;
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code)?
+                        }
+                        _ => return None,
+                    });
+                }
+                c if c < ' ' => return None,
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let rest = self.rest();
+        let bytes = rest.as_bytes();
+        let digits = |from: usize| {
+            from + bytes[from..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count()
+        };
+        let mut end = usize::from(bytes.first() == Some(&b'-'));
+        let int_end = digits(end);
+        // No leading zeros, and at least one digit.
+        if int_end == end || (bytes[end] == b'0' && int_end > end + 1) {
+            return None;
+        }
+        end = int_end;
+        let mut is_float = false;
+        if bytes.get(end) == Some(&b'.') {
+            let frac_end = digits(end + 1);
+            if frac_end == end + 1 {
+                return None;
+            }
+            end = frac_end;
+            is_float = true;
+        }
+        if matches!(bytes.get(end), Some(b'e' | b'E')) {
+            end += 1;
+            if matches!(bytes.get(end), Some(b'+' | b'-')) {
+                end += 1;
+            }
+            let exp_end = digits(end);
+            if exp_end == end {
+                return None;
+            }
+            end = exp_end;
+            is_float = true;
+        }
+        let literal = &rest[..end];
+        self.pos += end;
+        if !is_float {
+            if let Ok(value) = literal.parse() {
+                return Some(Json::Integer(value));
+            }
+        }
+        literal
+            .parse()
+            .ok()
+            .filter(|value: &f64| value.is_finite())
+            .map(Json::Number)
+    }
+}
+
+fn hex4(chars: &mut std::str::CharIndices) -> Option<u32> {
+    (0..4).try_fold(0, |code, _| Some(code * 16 + chars.next()?.1.to_digit(16)?))
+}
+
+/// One step from a value to one of its children.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+enum Step {
+    Index(usize),
+    Field(String),
+}
+
+/// Where a value sits in a document, from the root.
+type Location = Vec<Step>;
+
+#[derive(Debug, PartialEq, Clone)]
+enum Selector {
+    Name(String),
+    Wildcard,
+    Index(i64),
+    /// `[a,b]`: names and indexes.
+    Union(Vec<Selector>),
+    Slice(Option<i64>, Option<i64>, i64),
+    Filter(Box<Filter>),
+    /// `..x`: `x` applied to a value and all its descendants.
+    Descendants(Box<Selector>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Operand {
+    /// A path from the element being filtered, `@`.
+    Current(Vec<Selector>),
+    Root(Vec<Selector>),
+    Literal(Json),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Filter {
+    Exists(Operand),
+    Compare(Operand, Comparison, Operand),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+/// A path into a document: JSONPath when it starts with `$`, which matches
+/// any number of values, or else the legacy syntax, which stands for a
+/// single value.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Path {
+    text: String,
+    legacy: bool,
+    selectors: Vec<Selector>,
+}
+
+fn path_error(text: &str) -> CommandError {
+    CommandError::Other(format!("ERR invalid JSON path '{}'", text))
+}
+
+impl Path {
+    pub fn parse(text: &str) -> Result<Path, CommandError> {
+        let legacy = !text.starts_with('$');
+        let selectors = if legacy && (text.is_empty() || text == ".") {
+            Vec::new()
+        } else {
+            let source = match text.strip_prefix('$') {
+                Some(rest) => rest.to_string(),
+                None if text.starts_with(['.', '[']) => text.to_string(),
+                None => format!(".{}", text),
+            };
+            let mut parser = Parser::new(&source);
+            let selectors = parser.selectors(0);
+            match selectors {
+                Some(selectors) if parser.at_end() => selectors,
+                _ => return Err(path_error(text)),
+            }
+        };
+        Ok(Path {
+            text: text.to_string(),
+            legacy,
+            selectors,
+        })
+    }
+
+    /// The legacy path to the whole document, for commands whose path is
+    /// optional.
+    pub fn root() -> Path {
+        Path {
+            text: ".".to_string(),
+            legacy: true,
+            selectors: Vec::new(),
+        }
+    }
+
+    fn is_root(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    fn select<'a>(&self, document: &'a Json) -> Vec<(Location, &'a Json)> {
+        select(&self.selectors, document, document)
+    }
+
+    fn locations(&self, document: &Json) -> Vec<Location> {
+        self.select(document)
+            .into_iter()
+            .map(|(location, _)| location)
+            .collect()
+    }
+
+    fn missing(&self) -> StoreError {
+        StoreError::JsonPathMissing(self.text.clone())
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn selectors(&mut self, depth: usize) -> Option<Vec<Selector>> {
+        let mut selectors = Vec::new();
+        loop {
+            if self.eat("..") {
+                let selector = if self.eat("*") {
+                    Selector::Wildcard
+                } else if self.eat("[") {
+                    self.bracket(depth)?
+                } else {
+                    Selector::Name(self.name()?)
+                };
+                selectors.push(Selector::Descendants(Box::new(selector)));
+            } else if self.eat(".") {
+                selectors.push(if self.eat("*") {
+                    Selector::Wildcard
+                } else {
+                    Selector::Name(self.name()?)
+                });
+            } else if self.eat("[") {
+                selectors.push(self.bracket(depth)?);
+            } else {
+                return Some(selectors);
+            }
+        }
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '$'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return None;
+        }
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        Some(name)
+    }
+
+    fn integer(&mut self) -> Option<i64> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest
+            .char_indices()
+            .take_while(|(i, c)| c.is_ascii_digit() || (*i == 0 && *c == '-'))
+            .count();
+        let value = rest[..len].parse().ok()?;
+        self.pos += len;
+        Some(value)
+    }
+
+    /// What follows a `[`, up to and including the `]`.
+    fn bracket(&mut self, depth: usize) -> Option<Selector> {
+        self.skip_whitespace();
+        let selector = if self.eat("*") {
+            Selector::Wildcard
+        } else if self.eat("?") {
+            self.skip_whitespace();
+            if !self.eat("(") {
+                return None;
+            }
+            let filter = self.filter(depth + 1)?;
+            self.skip_whitespace();
+            if !self.eat(")") {
+                return None;
+            }
+            Selector::Filter(Box::new(filter))
+        } else {
+            let first = self.integer();
+            self.skip_whitespace();
+            if self.eat(":") {
+                let end = self.integer();
+                self.skip_whitespace();
+                let step = if self.eat(":") {
+                    self.integer().unwrap_or(1)
+                } else {
+                    1
+                };
+                if step <= 0 {
+                    return None;
+                }
+                Selector::Slice(first, end, step)
+            } else {
+                let mut items = Vec::new();
+                let mut next = first.map(Selector::Index);
+                loop {
+                    let item = match next.take() {
+                        Some(item) => item,
+                        None => match self.peek()? {
+                            quote @ ('\'' | '"') => Selector::Name(self.string(quote)?),
+                            _ => Selector::Index(self.integer()?),
+                        },
+                    };
+                    items.push(item);
+                    self.skip_whitespace();
+                    if !self.eat(",") {
+                        break;
+                    }
+                    self.skip_whitespace();
+                }
+                if items.len() == 1 {
+                    items.pop()?
+                } else {
+                    Selector::Union(items)
+                }
+            }
+        };
+        self.skip_whitespace();
+        self.eat("]").then_some(selector)
+    }
+
+    fn filter(&mut self, depth: usize) -> Option<Filter> {
+        let mut filters = vec![self.conjunction(depth)?];
+        loop {
+            self.skip_whitespace();
+            if !self.eat("||") {
+                return Some(if filters.len() == 1 {
+                    filters.pop()?
+                } else {
+                    Filter::Or(filters)
+                });
+            }
+            filters.push(self.conjunction(depth)?);
+        }
+    }
+
+    fn conjunction(&mut self, depth: usize) -> Option<Filter> {
+        let mut filters = vec![self.unary(depth)?];
+        loop {
+            self.skip_whitespace();
+            if !self.eat("&&") {
+                return Some(if filters.len() == 1 {
+                    filters.pop()?
+                } else {
+                    Filter::And(filters)
+                });
+            }
+            filters.push(self.unary(depth)?);
+        }
+    }
+
+    fn unary(&mut self, depth: usize) -> Option<Filter> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+        if self.eat("!") {
+            return Some(Filter::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        if self.eat("(") {
+            let filter = self.filter(depth + 1)?;
+            self.skip_whitespace();
+            return self.eat(")").then_some(filter);
+        }
+        let left = self.operand(depth)?;
+        self.skip_whitespace();
+        let comparison = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ]
+        .into_iter()
+        .find(|(token, _)| self.eat(token));
+        match comparison {
+            Some((_, comparison)) => Some(Filter::Compare(left, comparison, self.operand(depth)?)),
+            None => Some(Filter::Exists(left)),
+        }
+    }
+
+    fn operand(&mut self, depth: usize) -> Option<Operand> {
+        self.skip_whitespace();
+        if self.eat("@") {
+            return Some(Operand::Current(self.selectors(depth)?));
+        }
+        if self.eat("$") {
+            return Some(Operand::Root(self.selectors(depth)?));
+        }
+        if self.peek() == Some('\'') {
+            return Some(Operand::Literal(Json::String(self.string('\'')?)));
+        }
+        self.value(0).map(Operand::Literal)
+    }
+}
+
+fn select<'a>(
+    selectors: &[Selector],
+    value: &'a Json,
+    root: &'a Json,
+) -> Vec<(Location, &'a Json)> {
+    let mut matches = vec![(Vec::new(), value)];
+    for selector in selectors {
+        let mut next = Vec::new();
+        for (location, value) in matches {
+            apply(selector, &location, value, root, &mut next);
+        }
+        matches = next;
+    }
+    matches
+}
+
+fn apply<'a>(
+    selector: &Selector,
+    location: &Location,
+    value: &'a Json,
+    root: &'a Json,
+    out: &mut Vec<(Location, &'a Json)>,
+) {
+    let mut push = |step: Step, child: &'a Json| {
+        let mut location = location.clone();
+        location.push(step);
+        out.push((location, child));
+    };
+    match (selector, value) {
+        (Selector::Name(name), Json::Object(_)) => {
+            if let Some(child) = value.field(name) {
+                push(Step::Field(name.clone()), child);
+            }
+        }
+        (Selector::Wildcard, _) => {
+            for (step, child) in value.children() {
+                push(step, child);
+            }
+        }
+        (Selector::Index(index), Json::Array(items)) => {
+            let index = if *index < 0 {
+                items.len() as i64 + index
+            } else {
+                *index
+            };
+            if let Some(child) = usize::try_from(index).ok().and_then(|i| items.get(i)) {
+                push(Step::Index(index as usize), child);
+            }
+        }
+        (Selector::Union(items), _) => {
+            for item in items {
+                apply(item, location, value, root, out);
+            }
+        }
+        (Selector::Slice(start, end, step), Json::Array(items)) => {
+            let len = items.len() as i64;
+            let clamp = |bound: i64| if bound < 0 { len + bound } else { bound }.clamp(0, len);
+            let start = start.map_or(0, clamp);
+            let end = end.map_or(len, clamp);
+            for i in (start..end).step_by(*step as usize) {
+                push(Step::Index(i as usize), &items[i as usize]);
+            }
+        }
+        (Selector::Filter(filter), _) => {
+            for (step, child) in value.children() {
+                if filter.matches(child, root) {
+                    push(step, child);
+                }
+            }
+        }
+        (Selector::Descendants(selector), _) => {
+            apply(selector, location, value, root, out);
+            for (step, child) in value.children() {
+                let mut location = location.clone();
+                location.push(step);
+                apply(
+                    &Selector::Descendants(selector.clone()),
+                    &location,
+                    child,
+                    root,
+                    out,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Operand {
+    fn resolve<'a>(&'a self, current: &'a Json, root: &'a Json) -> Option<&'a Json> {
+        match self {
+            Operand::Current(selectors) => select(selectors, current, root).first().map(|m| m.1),
+            Operand::Root(selectors) => select(selectors, root, root).first().map(|m| m.1),
+            Operand::Literal(value) => Some(value),
+        }
+    }
+}
+
+fn compare(a: &Json, b: &Json) -> Option<Ordering> {
+    match (a, b) {
+        (Json::Integer(a), Json::Integer(b)) => Some(a.cmp(b)),
+        (Json::Integer(_) | Json::Number(_), Json::Integer(_) | Json::Number(_)) => {
+            as_float(a)?.partial_cmp(&as_float(b)?)
+        }
+        (Json::String(a), Json::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn as_float(value: &Json) -> Option<f64> {
+    match value {
+        Json::Integer(value) => Some(*value as f64),
+        Json::Number(value) => Some(*value),
+        _ => None,
+    }
+}
+
+impl Filter {
+    fn matches(&self, current: &Json, root: &Json) -> bool {
+        match self {
+            Filter::Exists(operand) => operand.resolve(current, root).is_some(),
+            Filter::Compare(left, comparison, right) => {
+                let (Some(left), Some(right)) =
+                    (left.resolve(current, root), right.resolve(current, root))
+                else {
+                    return false;
+                };
+                let ordering = compare(left, right);
+                match comparison {
+                    Comparison::Eq => ordering == Some(Ordering::Equal),
+                    Comparison::Ne => ordering != Some(Ordering::Equal),
+                    Comparison::Lt => ordering == Some(Ordering::Less),
+                    Comparison::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                    Comparison::Gt => ordering == Some(Ordering::Greater),
+                    Comparison::Ge => {
+                        matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+                    }
+                }
+            }
+            Filter::Not(filter) => !filter.matches(current, root),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(current, root)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(current, root)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Condition {
+    /// Only set paths that don't exist yet.
+    Nx,
+    /// Only set paths that already exist.
+    Xx,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonCommand {
+    Set {
+        key: String,
+        path: Path,
+        value: Json,
+        condition: Option<Condition>,
+    },
+    Get {
+        key: String,
+        format: Format,
+        paths: Vec<Path>,
+    },
+    Del(String, Path),
+    NumIncrBy(String, Path, Json),
+    ArrAppend(String, Path, Vec<Json>),
+    /// JSON.ARRPOP with the index to pop.
+    ArrPop(String, Path, i64),
+    ObjKeys(String, Path),
+    StrAppend(String, Path, String),
+    Type(String, Path),
+    /// JSON.MGET with the keys and the path to get from each.
+    MGet(Vec<String>, Path),
+}
+
+fn optional_path(args: &mut Args) -> Result<Path, CommandError> {
+    if args.is_empty() {
+        Ok(Path::root())
+    } else {
+        Path::parse(&args.string()?)
+    }
+}
+
+impl JsonCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<JsonCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "JSON.SET" => {
+                let key = args.string()?;
+                let path = Path::parse(&args.string()?)?;
+                let value = Json::parse(&args.string()?)?;
+                let condition = if args.keyword("NX") {
+                    Some(Condition::Nx)
+                } else if args.keyword("XX") {
+                    Some(Condition::Xx)
+                } else {
+                    None
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                JsonCommand::Set {
+                    key,
+                    path,
+                    value,
+                    condition,
+                }
+            }
+            "JSON.GET" => {
+                let key = args.string()?;
+                let mut format = Format::default();
+                let mut paths = Vec::new();
+                while !args.is_empty() {
+                    if args.keyword("INDENT") {
+                        format.indent = args.string()?;
+                    } else if args.keyword("NEWLINE") {
+                        format.newline = args.string()?;
+                    } else if args.keyword("SPACE") {
+                        format.space = args.string()?;
+                    } else {
+                        paths.push(Path::parse(&args.string()?)?);
+                    }
+                }
+                if paths.is_empty() {
+                    paths.push(Path::root());
+                }
+                JsonCommand::Get { key, format, paths }
+            }
+            "JSON.DEL" => JsonCommand::Del(args.string()?, optional_path(&mut args)?),
+            "JSON.NUMINCRBY" => {
+                let (key, path) = (args.string()?, Path::parse(&args.string()?)?);
+                let number = Json::parse(&args.string()?)?;
+                if as_float(&number).is_none() {
+                    return Err(CommandError::Other("ERR expected a number".into()));
+                }
+                JsonCommand::NumIncrBy(key, path, number)
+            }
+            "JSON.ARRAPPEND" => {
+                let (key, path) = (args.string()?, Path::parse(&args.string()?)?);
+                let mut values = Vec::new();
+                for value in args.rest()? {
+                    values.push(Json::parse(&value)?);
+                }
+                JsonCommand::ArrAppend(key, path, values)
+            }
+            "JSON.ARRPOP" => {
+                let (key, path) = (args.string()?, optional_path(&mut args)?);
+                let index = if args.is_empty() { -1 } else { args.integer()? };
+                JsonCommand::ArrPop(key, path, index)
+            }
+            "JSON.OBJKEYS" => JsonCommand::ObjKeys(args.string()?, optional_path(&mut args)?),
+            "JSON.STRAPPEND" => {
+                let key = args.string()?;
+                let path = if args.len() > 1 {
+                    Path::parse(&args.string()?)?
+                } else {
+                    Path::root()
+                };
+                let Json::String(value) = Json::parse(&args.string()?)? else {
+                    return Err(CommandError::Other("ERR expected a JSON string".into()));
+                };
+                JsonCommand::StrAppend(key, path, value)
+            }
+            "JSON.TYPE" => JsonCommand::Type(args.string()?, optional_path(&mut args)?),
+            "JSON.MGET" => {
+                let mut keys = args.rest()?;
+                if keys.len() < 2 {
+                    return Err(args.arity_error());
+                }
+                let path = Path::parse(&keys.pop().unwrap())?;
+                JsonCommand::MGet(keys, path)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            JsonCommand::Set { .. }
+                | JsonCommand::Del(..)
+                | JsonCommand::NumIncrBy(..)
+                | JsonCommand::ArrAppend(..)
+                | JsonCommand::ArrPop(..)
+                | JsonCommand::StrAppend(..)
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            JsonCommand::Set { key, .. }
+            | JsonCommand::Get { key, .. }
+            | JsonCommand::Del(key, _)
+            | JsonCommand::NumIncrBy(key, ..)
+            | JsonCommand::ArrAppend(key, ..)
+            | JsonCommand::ArrPop(key, ..)
+            | JsonCommand::ObjKeys(key, _)
+            | JsonCommand::StrAppend(key, ..)
+            | JsonCommand::Type(key, _) => vec![key],
+            JsonCommand::MGet(keys, _) => keys.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// The serialized values `path` matches: an array of them for JSONPath, the
+/// first one for a legacy path.
+fn get(document: &Json, path: &Path) -> Result<Json, StoreError> {
+    let matches = path.select(document);
+    if path.legacy {
+        let (_, value) = matches.into_iter().next().ok_or_else(|| path.missing())?;
+        Ok(value.clone())
+    } else {
+        Ok(Json::Array(
+            matches
+                .into_iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        ))
+    }
+}
+
+/// Runs `update` on every value `path` matches. A JSONPath gets a reply for
+/// each, and a legacy path the reply for the first, with values `update`
+/// doesn't apply to failing with a type error.
+fn update_each(
+    document: &mut Json,
+    path: &Path,
+    expected: &'static str,
+    mut update: impl FnMut(&mut Json) -> Result<Option<RespData>, StoreError>,
+) -> Result<RespData, StoreError> {
+    let mut replies = Vec::new();
+    for location in path.locations(document) {
+        let value = document.get_mut(&location).unwrap();
+        let found = value.type_name();
+        match update(value)? {
+            Some(reply) => replies.push(reply),
+            None if path.legacy => {
+                return Err(StoreError::JsonWrongType(expected, found));
+            }
+            None => replies.push(RespData::BulkStringNull),
+        }
+        if path.legacy {
+            break;
+        }
+    }
+    if path.legacy {
+        replies.pop().ok_or_else(|| path.missing())
+    } else {
+        Ok(RespData::Array(replies))
+    }
+}
+
+fn add_numbers(a: &Json, b: &Json) -> Result<Json, StoreError> {
+    if let (Json::Integer(a), Json::Integer(b)) = (a, b) {
+        if let Some(sum) = a.checked_add(*b) {
+            return Ok(Json::Integer(sum));
+        }
+    }
+    let sum = as_float(a).unwrap_or_default() + as_float(b).unwrap_or_default();
+    if !sum.is_finite() {
+        return Err(StoreError::NanOrInfinity);
+    }
+    Ok(Json::Number(sum))
+}
+
+pub fn execute(db: &mut Db, command: JsonCommand) -> Result<RespData, StoreError> {
+    let compact = Format::default();
+    match command {
+        JsonCommand::Set {
+            key,
+            path,
+            value,
+            condition,
+        } => {
+            let Some(document) = db.json_mut(&key)? else {
+                if !path.is_root() {
+                    return Err(StoreError::JsonNewAtRoot);
+                }
+                if condition == Some(Condition::Xx) {
+                    return Ok(RespData::BulkStringNull);
+                }
+                db.set(key, Value::Json(value), None);
+                return Ok(RespData::SimpleString("OK".to_string()));
+            };
+
+            let locations = path.locations(document);
+            if !locations.is_empty() {
+                if condition == Some(Condition::Nx) {
+                    return Ok(RespData::BulkStringNull);
+                }
+                for location in locations {
+                    *document.get_mut(&location).unwrap() = value.clone();
+                }
+                db.touch(&key);
+                return Ok(RespData::SimpleString("OK".to_string()));
+            }
+            if condition == Some(Condition::Xx) {
+                return Ok(RespData::BulkStringNull);
+            }
+
+            // A missing name is added to the objects that would hold it.
+            let Some((Selector::Name(name), parents)) = path.selectors.split_last() else {
+                return Ok(RespData::BulkStringNull);
+            };
+            let parents: Vec<Location> = select(parents, document, document)
+                .into_iter()
+                .filter(|(_, parent)| matches!(parent, Json::Object(_)))
+                .map(|(location, _)| location)
+                .collect();
+            if parents.is_empty() {
+                return if path.legacy {
+                    Err(StoreError::JsonWrongStaticPath)
+                } else {
+                    Ok(RespData::BulkStringNull)
+                };
+            }
+            for location in parents {
+                if let Some(Json::Object(fields)) = document.get_mut(&location) {
+                    fields.push((name.clone(), value.clone()));
+                }
+            }
+            db.touch(&key);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        JsonCommand::Get { key, format, paths } => {
+            let Some(document) = db.json(&key)? else {
+                return Ok(RespData::BulkStringNull);
+            };
+            let result = if let [path] = paths.as_slice() {
+                get(document, path)?
+            } else {
+                // Several paths give an object keyed by path, with every value
+                // an array as soon as one of the paths is a JSONPath.
+                let legacy = paths.iter().all(|path| path.legacy);
+                let mut fields = Vec::new();
+                for path in &paths {
+                    let value = if legacy {
+                        get(document, path)?
+                    } else {
+                        Json::Array(
+                            path.select(document)
+                                .into_iter()
+                                .map(|(_, value)| value.clone())
+                                .collect(),
+                        )
+                    };
+                    fields.push((path.text.clone(), value));
+                }
+                Json::Object(fields)
+            };
+            Ok(RespData::BulkString(result.serialize(&format)))
+        }
+        JsonCommand::Del(key, path) => {
+            let Some(document) = db.json_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            if path.is_root() {
+                db.remove(&key);
+                return Ok(RespData::Integer(1));
+            }
+            // Later siblings go first, so that earlier indexes stay valid.
+            let mut locations = path.locations(document);
+            locations.sort();
+            let deleted = locations
+                .iter()
+                .rev()
+                .filter(|location| document.remove(location))
+                .count();
+            if deleted > 0 {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(deleted as i64))
+        }
+        JsonCommand::NumIncrBy(key, path, number) => {
+            let document = db.json_mut(&key)?.ok_or(StoreError::JsonMissingKey)?;
+            let mut results = Vec::new();
+            let reply = update_each(document, &path, "a number", |value| {
+                if as_float(value).is_none() {
+                    results.push(Json::Null);
+                    return Ok(None);
+                }
+                *value = add_numbers(value, &number)?;
+                results.push(value.clone());
+                Ok(Some(RespData::Integer(0)))
+            });
+            if results.iter().any(|result| *result != Json::Null) {
+                db.touch(&key);
+            }
+            let reply = reply?;
+            // The new values come back as JSON, not one reply each.
+            Ok(match reply {
+                RespData::Array(_) => {
+                    RespData::BulkString(Json::Array(results).serialize(&compact))
+                }
+                _ => RespData::BulkString(results[0].serialize(&compact)),
+            })
+        }
+        JsonCommand::ArrAppend(key, path, values) => {
+            let document = db.json_mut(&key)?.ok_or(StoreError::JsonMissingKey)?;
+            let mut changed = false;
+            let reply = update_each(document, &path, "array", |value| match value {
+                Json::Array(items) => {
+                    items.extend(values.iter().cloned());
+                    changed = true;
+                    Ok(Some(RespData::Integer(items.len() as i64)))
+                }
+                _ => Ok(None),
+            });
+            if changed {
+                db.touch(&key);
+            }
+            reply
+        }
+        JsonCommand::ArrPop(key, path, index) => {
+            let document = db.json_mut(&key)?.ok_or(StoreError::JsonMissingKey)?;
+            let mut changed = false;
+            let reply = update_each(document, &path, "array", |value| match value {
+                Json::Array(items) if items.is_empty() => Ok(Some(RespData::BulkStringNull)),
+                Json::Array(items) => {
+                    let len = items.len() as i64;
+                    let index = if index < 0 { len + index } else { index };
+                    let popped = items.remove(index.clamp(0, len - 1) as usize);
+                    changed = true;
+                    Ok(Some(RespData::BulkString(popped.serialize(&compact))))
+                }
+                _ => Ok(None),
+            });
+            if changed {
+                db.touch(&key);
+            }
+            reply
+        }
+        JsonCommand::ObjKeys(key, path) => {
+            let Some(document) = db.json(&key)? else {
+                return Ok(RespData::BulkStringNull);
+            };
+            let keys = |value: &Json| match value {
+                Json::Object(fields) => Some(RespData::Array(
+                    fields
+                        .iter()
+                        .map(|(key, _)| RespData::BulkString(key.clone()))
+                        .collect(),
+                )),
+                _ => None,
+            };
+            let matches = path.select(document);
+            if path.legacy {
+                return match matches.first() {
+                    None => Ok(RespData::BulkStringNull),
+                    Some((_, value)) => {
+                        keys(value).ok_or(StoreError::JsonWrongType("object", value.type_name()))
+                    }
+                };
+            }
+            Ok(RespData::Array(
+                matches
+                    .into_iter()
+                    .map(|(_, value)| keys(value).unwrap_or(RespData::BulkStringNull))
+                    .collect(),
+            ))
+        }
+        JsonCommand::StrAppend(key, path, suffix) => {
+            let document = db.json_mut(&key)?.ok_or(StoreError::JsonMissingKey)?;
+            let mut changed = false;
+            let reply = update_each(document, &path, "string", |value| match value {
+                Json::String(string) => {
+                    string.push_str(&suffix);
+                    changed = true;
+                    Ok(Some(RespData::Integer(string.len() as i64)))
+                }
+                _ => Ok(None),
+            });
+            if changed {
+                db.touch(&key);
+            }
+            reply
+        }
+        JsonCommand::Type(key, path) => {
+            let Some(document) = db.json(&key)? else {
+                return Ok(RespData::BulkStringNull);
+            };
+            let matches = path.select(document);
+            if path.legacy {
+                return Ok(matches
+                    .first()
+                    .map_or(RespData::BulkStringNull, |(_, value)| {
+                        RespData::SimpleString(value.type_name().to_string())
+                    }));
+            }
+            Ok(RespData::Array(
+                matches
+                    .into_iter()
+                    .map(|(_, value)| RespData::BulkString(value.type_name().to_string()))
+                    .collect(),
+            ))
+        }
+        JsonCommand::MGet(keys, path) => Ok(RespData::Array(
+            keys.iter()
+                .map(|key| {
+                    db.json(key)
+                        .ok()
+                        .flatten()
+                        .and_then(|document| get(document, &path).ok())
+                        .map_or(RespData::BulkStringNull, |value| {
+                            RespData::BulkString(value.serialize(&compact))
+                        })
+                })
+                .collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Json {
+        Json::parse(
+            r#"{"store":{"book":[{"title":"A","price":8.95},{"title":"B","price":22,"isbn":"x"}],
+                "bicycle":{"color":"red","price":19.95}}}"#,
+        )
+        .unwrap()
+    }
+
+    fn query(path: &str) -> String {
+        let document = doc();
+        let values = Path::parse(path)
+            .unwrap()
+            .select(&document)
+            .into_iter()
+            .map(|(_, value)| value.clone())
+            .collect();
+        Json::Array(values).serialize(&Format::default())
+    }
+
+    #[test]
+    fn parses_and_serializes() {
+        let text = r#"{"a":[1,-2.5,1e+30,true,null],"b":"\"\u00e9\ud83d\ude00\n","c":{}}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(
+            value.serialize(&Format::default()),
+            r#"{"a":[1,-2.5,1e30,true,null],"b":"\"é😀\n","c":{}}"#
+        );
+        assert_eq!(
+            Json::parse("3.0").unwrap().serialize(&Format::default()),
+            "3.0"
+        );
+        for bad in ["", "[1,]", "{\"a\"}", "01", "\"\\x\"", "[1] 2", "nul"] {
+            assert!(Json::parse(bad).is_err(), "{}", bad);
+        }
+        assert!(Json::parse(&"[".repeat(200)).is_err());
+    }
+
+    #[test]
+    fn pretty_prints() {
+        let format = Format {
+            indent: "  ".into(),
+            newline: "\n".into(),
+            space: " ".into(),
+        };
+        let value = Json::parse(r#"{"a":[1,{}]}"#).unwrap();
+        assert_eq!(
+            value.serialize(&format),
+            "{\n  \"a\": [\n    1,\n    {}\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn jsonpath_selects() {
+        assert_eq!(query("$.store.book[0].title"), r#"["A"]"#);
+        assert_eq!(query("$..price"), "[8.95,22,19.95]");
+        assert_eq!(query("$.store.book[*].title"), r#"["A","B"]"#);
+        assert_eq!(query("$.store.book[-1].title"), r#"["B"]"#);
+        assert_eq!(query("$['store']['bicycle'].color"), r#"["red"]"#);
+        assert_eq!(query("$.store.book[0,1].title"), r#"["A","B"]"#);
+        assert_eq!(query("$.store.book[:1].title"), r#"["A"]"#);
+        assert_eq!(query("$..book[?(@.price < 10)].title"), r#"["A"]"#);
+        assert_eq!(query("$..book[?(@.isbn)].title"), r#"["B"]"#);
+        assert_eq!(
+            query("$..book[?(@.price > 10 && @.title == 'B' || @.title == \"A\")].title"),
+            r#"["A","B"]"#
+        );
+        assert_eq!(query("$.missing"), "[]");
+        assert!(Path::parse("$.store[").is_err());
+    }
+
+    #[test]
+    fn refuses_deeply_nested_filters() {
+        assert_eq!(query("$..book[?(!!(@.isbn))].title"), r#"["B"]"#);
+        for path in [
+            format!("$[?({}@.a)]", "!".repeat(300_000)),
+            format!("$[?({}@.a{})]", "(".repeat(300_000), ")".repeat(300_000)),
+            format!("$[?({}{})]", "@[?(".repeat(100_000), ")]".repeat(100_000)),
+        ] {
+            assert!(Path::parse(&path).is_err());
+        }
+        let chain = vec!["@.a"; 300_000];
+        let path = Path::parse(&format!("$[?({})]", chain.join(" || "))).unwrap();
+        let document = Json::parse(r#"[{"a":1},{"b":2}]"#).unwrap();
+        assert_eq!(path.select(&document).len(), 1);
+    }
+
+    #[test]
+    fn legacy_paths_start_at_the_root() {
+        let document = doc();
+        for path in [
+            ".store.bicycle.color",
+            "store.bicycle.color",
+            "[\"store\"].bicycle.color",
+        ] {
+            let path = Path::parse(path).unwrap();
+            assert!(path.legacy);
+            assert_eq!(get(&document, &path).unwrap(), Json::String("red".into()));
+        }
+        assert!(Path::parse(".").unwrap().is_root());
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::VecDeque;
+
+use crate::{
+    command::{Args, CommandError},
+    executor::Rewritten,
+    resp_parser::RespData,
+    store::{Db, StoreError},
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn name(self) -> &'static str {
+        match self {
+            End::Left => "LEFT",
+            End::Right => "RIGHT",
+        }
+    }
+
+    fn pop_command(self) -> &'static str {
+        match self {
+            End::Left => "LPOP",
+            End::Right => "RPOP",
+        }
+    }
+
+    fn parse(args: &mut Args) -> Result<End, CommandError> {
+        if args.keyword("LEFT") {
+            Ok(End::Left)
+        } else if args.keyword("RIGHT") {
+            Ok(End::Right)
+        } else {
+            Err(CommandError::Syntax)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ListCommand {
+    Push {
+        key: String,
+        end: End,
+        elements: Vec<String>,
+        only_if_exists: bool,
+    },
+    Pop {
+        key: String,
+        end: End,
+        count: Option<usize>,
+    },
+    Len(String),
+    Range(String, i64, i64),
+    Index(String, i64),
+    Set(String, i64, String),
+    Insert {
+        key: String,
+        before: bool,
+        pivot: String,
+        element: String,
+    },
+    Rem {
+        key: String,
+        count: i64,
+        element: String,
+    },
+    Trim(String, i64, i64),
+    Pos {
+        key: String,
+        element: String,
+        rank: i64,
+        count: Option<usize>,
+        max_len: usize,
+    },
+    Move {
+        source: String,
+        destination: String,
+        from: End,
+        to: End,
+        timeout: Option<f64>,
+    },
+    /// BLPOP and BRPOP.
+    BlockingPop {
+        keys: Vec<String>,
+        end: End,
+        timeout: f64,
+    },
+    /// LMPOP, or BLMPOP when there is a timeout.
+    MPop {
+        keys: Vec<String>,
+        end: End,
+        count: usize,
+        timeout: Option<f64>,
+    },
+}
+
+fn parse_mpop(args: &mut Args, timeout: Option<f64>) -> Result<ListCommand, CommandError> {
+    let numkeys: i64 = args.integer()?;
+    if numkeys <= 0 {
+        return Err(CommandError::Other(
+            "ERR numkeys should be greater than 0".into(),
+        ));
+    }
+    let keys = (0..numkeys)
+        .map(|_| args.string())
+        .collect::<Result<Vec<_>, _>>()?;
+    let end = End::parse(args)?;
+    let count = if args.keyword("COUNT") {
+        let count: i64 = args.integer()?;
+        if count <= 0 {
+            return Err(CommandError::Other(
+                "ERR count should be greater than 0".into(),
+            ));
+        }
+        count as usize
+    } else {
+        1
+    };
+    Ok(ListCommand::MPop {
+        keys,
+        end,
+        count,
+        timeout,
+    })
+}
+
+impl ListCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<ListCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "LPUSH" | "RPUSH" | "LPUSHX" | "RPUSHX" => ListCommand::Push {
+                key: args.string()?,
+                end: if name.starts_with('L') {
+                    End::Left
+                } else {
+                    End::Right
+                },
+                elements: args.rest()?,
+                only_if_exists: name.ends_with('X'),
+            },
+            "LPOP" | "RPOP" => ListCommand::Pop {
+                key: args.string()?,
+                end: if name == "LPOP" {
+                    End::Left
+                } else {
+                    End::Right
+                },
+                count: if args.is_empty() {
+                    None
+                } else {
+                    Some(args.positive()?)
+                },
+            },
+            "LLEN" => ListCommand::Len(args.string()?),
+            "LRANGE" => ListCommand::Range(args.string()?, args.integer()?, args.integer()?),
+            "LINDEX" => ListCommand::Index(args.string()?, args.integer()?),
+            "LSET" => ListCommand::Set(args.string()?, args.integer()?, args.string()?),
+            "LINSERT" => {
+                let key = args.string()?;
+                let before = if args.keyword("BEFORE") {
+                    true
+                } else if args.keyword("AFTER") {
+                    false
+                } else {
+                    return Err(CommandError::Syntax);
+                };
+                ListCommand::Insert {
+                    key,
+                    before,
+                    pivot: args.string()?,
+                    element: args.string()?,
+                }
+            }
+            "LREM" => ListCommand::Rem {
+                key: args.string()?,
+                count: args.integer()?,
+                element: args.string()?,
+            },
+            "LTRIM" => ListCommand::Trim(args.string()?, args.integer()?, args.integer()?),
+            "LPOS" => {
+                let key = args.string()?;
+                let element = args.string()?;
+                let mut rank = 1;
+                let mut count = None;
+                let mut max_len = 0;
+                while !args.is_empty() {
+                    if args.keyword("RANK") {
+                        rank = args.integer()?;
+                        if rank == 0 || rank == i64::MIN {
+                            return Err(CommandError::Other(
+                                "ERR RANK can't be zero: use 1 to start from the first match, \
+                                 2 from the second ... or use negative to start from the end \
+                                 of the list"
+                                    .into(),
+                            ));
+                        }
+                    } else if args.keyword("COUNT") {
+                        count = Some(args.integer::<i64>()?.try_into().map_err(|_| {
+                            CommandError::Other("ERR COUNT can't be negative".into())
+                        })?);
+                    } else if args.keyword("MAXLEN") {
+                        max_len = args.integer::<i64>()?.try_into().map_err(|_| {
+                            CommandError::Other("ERR MAXLEN can't be negative".into())
+                        })?;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                ListCommand::Pos {
+                    key,
+                    element,
+                    rank,
+                    count,
+                    max_len,
+                }
+            }
+            "LMOVE" => ListCommand::Move {
+                source: args.string()?,
+                destination: args.string()?,
+                from: End::parse(&mut args)?,
+                to: End::parse(&mut args)?,
+                timeout: None,
+            },
+            "RPOPLPUSH" => ListCommand::Move {
+                source: args.string()?,
+                destination: args.string()?,
+                from: End::Right,
+                to: End::Left,
+                timeout: None,
+            },
+            "BLMOVE" => ListCommand::Move {
+                source: args.string()?,
+                destination: args.string()?,
+                from: End::parse(&mut args)?,
+                to: End::parse(&mut args)?,
+                timeout: Some(args.timeout()?),
+            },
+            "BLPOP" | "BRPOP" => {
+                if args.len() < 2 {
+                    return Err(args.arity_error());
+                }
+                let keys = (1..args.len())
+                    .map(|_| args.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                ListCommand::BlockingPop {
+                    keys,
+                    end: if name == "BLPOP" {
+                        End::Left
+                    } else {
+                        End::Right
+                    },
+                    timeout: args.timeout()?,
+                }
+            }
+            "LMPOP" => parse_mpop(&mut args, None)?,
+            "BLMPOP" => {
+                let timeout = args.timeout()?;
+                parse_mpop(&mut args, Some(timeout))?
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(
+            self,
+            ListCommand::Len(_)
+                | ListCommand::Range(..)
+                | ListCommand::Index(..)
+                | ListCommand::Pos { .. }
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            ListCommand::Push { key, .. }
+            | ListCommand::Pop { key, .. }
+            | ListCommand::Len(key)
+            | ListCommand::Range(key, ..)
+            | ListCommand::Index(key, _)
+            | ListCommand::Set(key, ..)
+            | ListCommand::Insert { key, .. }
+            | ListCommand::Rem { key, .. }
+            | ListCommand::Trim(key, ..)
+            | ListCommand::Pos { key, .. } => vec![key],
+            ListCommand::Move {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+            ListCommand::BlockingPop { keys, .. } | ListCommand::MPop { keys, .. } => {
+                keys.iter().map(String::as_str).collect()
+            }
+        }
+    }
+
+    /// How long the command may block, in seconds (0 meaning forever), or
+    /// `None` for commands that never block.
+    pub fn timeout(&self) -> Option<f64> {
+        match self {
+            ListCommand::BlockingPop { timeout, .. } => Some(*timeout),
+            ListCommand::Move { timeout, .. } | ListCommand::MPop { timeout, .. } => *timeout,
+            _ => None,
+        }
+    }
+
+    /// The reply for a blocking command that timed out.
+    pub fn timeout_reply(&self) -> RespData {
+        match self {
+            ListCommand::Move { .. } => RespData::BulkStringNull,
+            _ => RespData::ArrayNull,
+        }
+    }
+}
+
+pub fn push(list: &mut VecDeque<String>, end: End, element: String) {
+    match end {
+        End::Left => list.push_front(element),
+        End::Right => list.push_back(element),
+    }
+}
+
+pub fn pop(list: &mut VecDeque<String>, end: End) -> Option<String> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+/// Resolves a possibly negative index against a list of length `len`.
+fn index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { index + len as i64 } else { index };
+    usize::try_from(index).ok().filter(|index| *index < len)
+}
+
+/// Resolves an inclusive `start..=stop` range the way LRANGE and LTRIM do:
+/// negative indexes count from the end and out-of-range ends are clamped.
+pub fn range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+fn bulk_strings<'a>(elements: impl Iterator<Item = &'a String>) -> RespData {
+    RespData::Array(elements.cloned().map(RespData::BulkString).collect())
+}
+
+/// Pops up to `count` elements (one when `count` is `None`) from the list at
+/// `key`, deleting the key once it is empty.
+pub fn pop_from(
+    db: &mut Db,
+    key: &str,
+    end: End,
+    count: Option<usize>,
+) -> Result<Option<Vec<String>>, StoreError> {
+    let Some(list) = db.list_mut(key)? else {
+        return Ok(None);
+    };
+    let count = count.unwrap_or(1).min(list.len());
+    let popped: Vec<String> = (0..count).filter_map(|_| pop(list, end)).collect();
+    if !popped.is_empty() {
+        db.touch(key);
+    }
+    db.remove_if_empty(key);
+    Ok(Some(popped))
+}
+
+/// Pops from `source` and pushes onto `destination`, returning the element.
+pub fn move_element(
+    db: &mut Db,
+    source: &str,
+    destination: &str,
+    from: End,
+    to: End,
+) -> Result<Option<String>, StoreError> {
+    // Check the destination's type before touching the source.
+    db.list(destination)?;
+    let Some(list) = db.list_mut(source)? else {
+        return Ok(None);
+    };
+    let Some(element) = pop(list, from) else {
+        return Ok(None);
+    };
+    db.touch(source);
+    db.remove_if_empty(source);
+    push(db.list_or_create(destination)?, to, element.clone());
+    db.touch(destination);
+    Ok(Some(element))
+}
+
+/// Runs one of the pops that may block (BLPOP, BLMOVE, LMPOP and friends)
+/// without blocking. Returns the reply together with the non-blocking commands
+/// to feed replicas in its place, or `None` when every key is empty.
+pub fn try_pop(db: &mut Db, command: &ListCommand) -> Result<Option<Rewritten>, StoreError> {
+    match command {
+        ListCommand::BlockingPop { keys, end, .. } => {
+            for key in keys {
+                if let Some(mut popped) = pop_from(db, key, *end, None)? {
+                    let element = popped.pop().unwrap();
+                    return Ok(Some((
+                        bulk_strings([key.clone(), element].iter()),
+                        vec![vec![end.pop_command().to_string(), key.clone()]],
+                    )));
+                }
+            }
+            Ok(None)
+        }
+        ListCommand::MPop {
+            keys, end, count, ..
+        } => {
+            for key in keys {
+                if let Some(popped) = pop_from(db, key, *end, Some(*count))? {
+                    let propagate = vec![vec![
+                        end.pop_command().to_string(),
+                        key.clone(),
+                        popped.len().to_string(),
+                    ]];
+                    return Ok(Some((
+                        RespData::Array(vec![
+                            RespData::BulkString(key.clone()),
+                            bulk_strings(popped.iter()),
+                        ]),
+                        propagate,
+                    )));
+                }
+            }
+            Ok(None)
+        }
+        ListCommand::Move {
+            source,
+            destination,
+            from,
+            to,
+            ..
+        } => Ok(
+            move_element(db, source, destination, *from, *to)?.map(|element| {
+                (
+                    RespData::BulkString(element),
+                    vec![vec![
+                        "LMOVE".to_string(),
+                        source.clone(),
+                        destination.clone(),
+                        from.name().to_string(),
+                        to.name().to_string(),
+                    ]],
+                )
+            }),
+        ),
+        _ => Ok(None),
+    }
+}
+
+pub fn execute(db: &mut Db, command: ListCommand) -> Result<RespData, StoreError> {
+    match command {
+        ListCommand::Push {
+            key,
+            end,
+            elements,
+            only_if_exists,
+        } => {
+            if only_if_exists && db.list(&key)?.is_none() {
+                return Ok(RespData::Integer(0));
+            }
+            let list = db.list_or_create(&key)?;
+            for element in elements {
+                push(list, end, element);
+            }
+            let len = list.len();
+            db.touch(&key);
+            Ok(RespData::Integer(len as i64))
+        }
+        ListCommand::Pop { key, end, count } => Ok(match pop_from(db, &key, end, count)? {
+            None if count.is_some() => RespData::ArrayNull,
+            None => RespData::BulkStringNull,
+            Some(popped) if count.is_some() => bulk_strings(popped.iter()),
+            Some(mut popped) => popped
+                .pop()
+                .map(RespData::BulkString)
+                .unwrap_or(RespData::BulkStringNull),
+        }),
+        ListCommand::Len(key) => Ok(RespData::Integer(
+            db.list(&key)?.map_or(0, |list| list.len() as i64),
+        )),
+        ListCommand::Range(key, start, stop) => {
+            let Some(list) = db.list(&key)? else {
+                return Ok(RespData::Array(Vec::new()));
+            };
+            Ok(match range(list.len(), start, stop) {
+                Some((start, stop)) => bulk_strings(list.range(start..=stop)),
+                None => RespData::Array(Vec::new()),
+            })
+        }
+        ListCommand::Index(key, i) => Ok(db
+            .list(&key)?
+            .and_then(|list| index(list.len(), i).map(|i| list[i].clone()))
+            .map(RespData::BulkString)
+            .unwrap_or(RespData::BulkStringNull)),
+        ListCommand::Set(key, i, element) => {
+            let list = db.list_mut(&key)?.ok_or(StoreError::NoSuchKey)?;
+            let i = index(list.len(), i).ok_or(StoreError::IndexOutOfRange)?;
+            list[i] = element;
+            db.touch(&key);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        ListCommand::Insert {
+            key,
+            before,
+            pivot,
+            element,
+        } => {
+            let Some(list) = db.list_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let Some(position) = list.iter().position(|e| *e == pivot) else {
+                return Ok(RespData::Integer(-1));
+            };
+            list.insert(if before { position } else { position + 1 }, element);
+            let len = list.len();
+            db.touch(&key);
+            Ok(RespData::Integer(len as i64))
+        }
+        ListCommand::Rem {
+            key,
+            count,
+            element,
+        } => {
+            let Some(list) = db.list_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let limit = if count == 0 {
+                usize::MAX
+            } else {
+                count.unsigned_abs() as usize
+            };
+            let mut removed = 0;
+            let mut kept = VecDeque::with_capacity(list.len());
+            if count >= 0 {
+                for e in list.drain(..) {
+                    if removed < limit && e == element {
+                        removed += 1;
+                    } else {
+                        kept.push_back(e);
+                    }
+                }
+            } else {
+                while let Some(e) = list.pop_back() {
+                    if removed < limit && e == element {
+                        removed += 1;
+                    } else {
+                        kept.push_front(e);
+                    }
+                }
+            }
+            *list = kept;
+            if removed > 0 {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(RespData::Integer(removed as i64))
+        }
+        ListCommand::Trim(key, start, stop) => {
+            if let Some(list) = db.list_mut(&key)? {
+                let len = list.len();
+                match range(len, start, stop) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                }
+                if list.len() != len {
+                    db.touch(&key);
+                }
+                db.remove_if_empty(&key);
+            }
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        ListCommand::Pos {
+            key,
+            element,
+            rank,
+            count,
+            max_len,
+        } => {
+            let list = db.list(&key)?;
+            let len = list.map_or(0, |list| list.len());
+            let max_len = if max_len == 0 { len } else { max_len.min(len) };
+            let wanted = match count {
+                Some(0) => usize::MAX,
+                Some(count) => count,
+                None => 1,
+            };
+
+            let positions: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                Box::new(0..max_len)
+            } else {
+                Box::new((len - max_len..len).rev())
+            };
+            let matches: Vec<i64> = match list {
+                Some(list) => positions
+                    .filter(|&i| list[i] == element)
+                    .skip(rank.unsigned_abs() as usize - 1)
+                    .take(wanted)
+                    .map(|i| i as i64)
+                    .collect(),
+                None => Vec::new(),
+            };
+
+            Ok(match count {
+                Some(_) => RespData::Array(matches.into_iter().map(RespData::Integer).collect()),
+                None => matches
+                    .first()
+                    .map(|i| RespData::Integer(*i))
+                    .unwrap_or(RespData::BulkStringNull),
+            })
+        }
+        ListCommand::Move {
+            source,
+            destination,
+            from,
+            to,
+            ..
+        } => Ok(move_element(db, &source, &destination, from, to)?
+            .map(RespData::BulkString)
+            .unwrap_or(RespData::BulkStringNull)),
+        ListCommand::BlockingPop { .. } | ListCommand::MPop { .. } => {
+            Ok(try_pop(db, &command)?.map_or_else(|| command.timeout_reply(), |(reply, _)| reply))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_normalizes_indexes() {
+        assert_eq!(range(5, 0, -1), Some((0, 4)));
+        assert_eq!(range(5, -2, 100), Some((3, 4)));
+        assert_eq!(range(5, -100, 1), Some((0, 1)));
+        assert_eq!(range(5, 3, 1), None);
+        assert_eq!(range(5, 5, 10), None);
+        assert_eq!(range(0, 0, -1), None);
+    }
+
+    #[test]
+    fn index_counts_from_the_end() {
+        assert_eq!(index(3, -1), Some(2));
+        assert_eq!(index(3, 3), None);
+        assert_eq!(index(3, -4), None);
+    }
+}
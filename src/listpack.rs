@@ -0,0 +1,110 @@
+/// A compact run of integers and strings packed into one allocation, in the
+/// spirit of Redis's listpack. Integers are LEB128 varints and strings are a
+/// varint length followed by their bytes; readers have to know the layout.
+#[derive(Default)]
+pub struct ListPack {
+    bytes: Vec<u8>,
+}
+
+impl ListPack {
+    /// Size in bytes.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn push_int(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    pub fn push_str(&mut self, value: &str) {
+        self.push_int(value.len() as u64);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    /// Overwrites the integer at `pos`, which has to have been pushed as a
+    /// value below 128 and is replaced by another one.
+    pub fn set_small_int(&mut self, pos: usize, value: u8) {
+        debug_assert!(self.bytes[pos] < 0x80 && value < 0x80);
+        self.bytes[pos] = value;
+    }
+
+    pub fn reader(&self) -> Reader<'_> {
+        Reader {
+            bytes: &self.bytes,
+            pos: 0,
+        }
+    }
+}
+
+/// A cursor over a `ListPack`.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn is_at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn int(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.bytes[self.pos];
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn str(&mut self) -> &'a str {
+        let len = self.int() as usize;
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        // Only whole strings are ever pushed, so this can't split a char.
+        std::str::from_utf8(bytes).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_was_pushed() {
+        let mut pack = ListPack::default();
+        pack.push_int(0);
+        pack.push_int(300);
+        pack.push_str("héllo");
+        pack.push_int(u64::MAX);
+        pack.push_str("");
+
+        let mut reader = pack.reader();
+        let flag = reader.pos();
+        assert_eq!(reader.int(), 0);
+        assert_eq!(reader.int(), 300);
+        assert_eq!(reader.str(), "héllo");
+        assert_eq!(reader.int(), u64::MAX);
+        assert_eq!(reader.str(), "");
+        assert!(reader.is_at_end());
+
+        pack.set_small_int(flag, 1);
+        assert_eq!(pack.reader().int(), 1);
+    }
+}
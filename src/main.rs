@@ -1,38 +1,168 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use blocking::{BlockedClient, BlockedClients};
 use cli::parse_cli;
-use command::{parse_command, ConfigGet, RedisCommand, ReplConf};
+use command::{command_args, parse_command, CommandArgs, ConfigGet, RedisCommand, ReplConf};
+use executor::{execute, execute_transaction, hello, Context};
 use replica::main_of_replica;
-use resp_parser::parse_resp;
+use resp_parser::{encode_resp, parse_frame, FrameError, Protocol, RespData};
 use store::Store;
 use tcp::send_message_to_client;
+use transaction::Transaction;
 
+mod bitmap;
+mod blocking;
+mod bloom;
 mod cli;
+mod cms;
 mod command;
+mod consumer_group;
+mod cuckoo;
+mod dict;
+mod executor;
+mod geo;
+mod glob;
+mod hash;
+mod hyperloglog;
+mod json;
+mod list;
+mod listpack;
+mod random;
 mod replica;
 mod resp_parser;
+mod search;
+mod set;
+mod skiplist;
 mod store;
+mod stream;
 mod tcp;
+mod tdigest;
+mod timeseries;
+mod topk;
+mod transaction;
+mod vector;
+mod zset;
+
+/// How often the active expire cycle runs, like Redis's default `hz 10`.
+const ACTIVE_EXPIRE_CYCLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// The client a command came from, and where its reply goes.
+struct Caller {
+    addr: SocketAddr,
+    stream: Arc<TcpStream>,
+    reply: Sender<RespData>,
+}
 
 enum Message {
     NewConnection(TcpStream),
     DisconnectReplica(TcpStream),
     Data(Vec<u8>),
-    Set(String, String, Option<u64>),
-    Get(TcpStream, String),
+    /// A keyspace command to run against the selected database. Commands
+    /// coming from our master have no caller to reply to.
+    Command(usize, RedisCommand, CommandArgs, Option<Caller>),
+    /// A transaction's queued commands, with their arguments as sent, to run
+    /// at once starting in the given database and protocol, unless one of
+    /// the keys the caller watches was modified.
+    Exec(
+        usize,
+        Protocol,
+        Vec<(RedisCommand, CommandArgs)>,
+        Vec<(usize, String)>,
+        Option<Caller>,
+    ),
+    /// A client starts watching keys of the given database.
+    Watch(SocketAddr, usize, Vec<String>),
+    /// A client stops watching the keys it watches.
+    Unwatch(SocketAddr, Vec<(usize, String)>),
+    ActiveExpireCycle,
     WaitHandshake(TcpStream, u64, u64),
     UpdateOffset(TcpStream, u64),
 }
 
+/// Stops a client watching the keys it watches, if any.
+fn unwatch(tx: &Sender<Message>, addr: SocketAddr, watched: &mut Vec<(usize, String)>) {
+    if !watched.is_empty() {
+        tx.send(Message::Unwatch(addr, std::mem::take(watched)))
+            .unwrap();
+    }
+}
+
+/// Sends a client its reply. A client that went away is only logged; its
+/// connection loop finds out on the next read.
+fn reply_to_client(stream: &TcpStream, message: impl AsRef<[u8]>) {
+    if let Err(e) = send_message_to_client(stream, message) {
+        eprintln!("Error handling client: {}", e);
+    }
+}
+
 fn make_bulk_string(data: &str) -> String {
     format!("${}\r\n{}\r\n", data.len(), data)
 }
 
+fn make_command<S: AsRef<[u8]>>(args: &[S]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+type Replicas = Arc<Mutex<HashMap<SocketAddr, (TcpStream, u64)>>>;
+
+struct Replication {
+    replicas: Replicas,
+    last_write_bytes: Arc<RwLock<usize>>,
+    total_write_bytes: Arc<RwLock<usize>>,
+    /// The database the replication stream last switched to with `SELECT`.
+    selected_db: Option<usize>,
+}
+
+impl Replication {
+    fn new() -> Self {
+        Replication {
+            replicas: Arc::new(Mutex::new(HashMap::new())),
+            last_write_bytes: Arc::new(RwLock::new(0)),
+            total_write_bytes: Arc::new(RwLock::new(0)),
+            selected_db: None,
+        }
+    }
+
+    fn feed(&self, data: &[u8]) {
+        let mut replicas = self.replicas.lock().unwrap();
+
+        for (replica, _) in replicas.values_mut() {
+            replica.write_all(data).unwrap();
+            replica.flush().unwrap();
+        }
+
+        let mut last_write_bytes = self.last_write_bytes.write().unwrap();
+        *last_write_bytes = data.len();
+
+        let mut total_write_bytes = self.total_write_bytes.write().unwrap();
+        *total_write_bytes += data.len();
+    }
+
+    /// Feeds a command to replicas, preceded by a `SELECT` when it runs in a
+    /// different database than the previous one.
+    fn propagate<S: AsRef<[u8]>>(&mut self, db: usize, args: &[S]) {
+        if self.selected_db != Some(db) {
+            self.feed(&make_command(&["SELECT", &db.to_string()]));
+            self.selected_db = Some(db);
+        }
+        self.feed(&make_command(args));
+    }
+}
+
 fn decode_hex(s: &str) -> Result<Vec<u8>, Error> {
-    if s.len() % 2 != 0 {
+    if s.len() % 2 == 1 {
         return Err(Error::new(
             ErrorKind::InvalidInput,
             "Odd number of hex digits",
@@ -54,79 +184,164 @@ fn main() {
 
     main_of_replica(&tx);
 
-    let _ = {
+    let is_master = parse_cli().replicaof.is_none();
+
+    if is_master {
+        let tx = tx.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(ACTIVE_EXPIRE_CYCLE_PERIOD);
+            if tx.send(Message::ActiveExpireCycle).is_err() {
+                break;
+            }
+        });
+    }
+
+    {
         let tx = tx.clone();
         std::thread::spawn(move || {
-            let replicas: Arc<Mutex<HashMap<core::net::SocketAddr, (TcpStream, u64)>>> =
-                Arc::new(Mutex::new(HashMap::new()));
-            let store = Store::new();
+            let mut replication = Replication::new();
+            let store = Store::new(parse_cli().databases);
+
+            let mut blocked_clients = BlockedClients::default();
 
-            let last_write_bytes = Arc::new(RwLock::new(0));
-            let total_write_bytes = Arc::new(RwLock::new(0));
+            loop {
+                let message = match blocked_clients.next_wakeup(Instant::now()) {
+                    Some(wakeup) => {
+                        match rx.recv_timeout(wakeup.saturating_duration_since(Instant::now())) {
+                            Ok(message) => Some(message),
+                            Err(RecvTimeoutError::Timeout) => None,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                    None => match rx.recv() {
+                        Ok(message) => Some(message),
+                        Err(_) => break,
+                    },
+                };
+
+                let now = Instant::now();
+                for client in blocked_clients.take_timed_out(now) {
+                    let _ = client.reply.send(client.command.timeout_reply());
+                }
+                blocked_clients.remove_disconnected(now);
+
+                let Some(message) = message else {
+                    continue;
+                };
 
-            for message in rx {
                 match message {
                     Message::NewConnection(stream) => {
                         println!("New connection established");
-                        let mut replicas = replicas.lock().unwrap();
+                        let mut replicas = replication.replicas.lock().unwrap();
                         replicas.insert(stream.peer_addr().unwrap(), (stream, 0));
+                        // The new replica has no database selected yet.
+                        replication.selected_db = None;
                     }
                     Message::DisconnectReplica(stream) => {
-                        let mut replicas = replicas.lock().unwrap();
+                        let mut replicas = replication.replicas.lock().unwrap();
                         replicas.remove(&stream.peer_addr().unwrap());
                     }
                     Message::Data(data) => {
-                        let mut replicas = replicas.lock().unwrap();
+                        replication.feed(&data);
+                    }
+                    Message::Command(db, command, args, caller) => {
+                        let is_write = command.is_write();
+                        let swaps_dbs = matches!(command, RedisCommand::SwapDb(..));
+                        let mut context = Context::new(&store, db, is_master);
+                        context.can_block = caller.is_some();
+                        let response = execute(&mut context, command);
 
-                        for (replica, _) in replicas.values_mut() {
-                            replica.write_all(&data).unwrap();
-                            replica.flush().unwrap();
+                        for args in context.propagated(is_write, args, &response) {
+                            replication.propagate(db, &args);
+                        }
+
+                        match (caller, context.blocked) {
+                            (Some(caller), Some(blocked)) => {
+                                blocked_clients.block(BlockedClient {
+                                    stream: caller.stream,
+                                    db,
+                                    command: blocked.command,
+                                    deadline: blocked.deadline,
+                                    reply: caller.reply,
+                                });
+                            }
+                            (Some(caller), None) => {
+                                let _ = caller.reply.send(response);
+                            }
+                            (None, _) => {}
                         }
 
-                        let mut last_write_bytes = last_write_bytes.write().unwrap();
-                        *last_write_bytes = data.len();
+                        let mut ready_keys = store.take_ready_keys();
+                        if swaps_dbs {
+                            // Any blocked key may now have something to serve.
+                            ready_keys.extend(blocked_clients.keys());
+                        }
+                        for (db, args) in
+                            blocking::serve(&store, &mut blocked_clients, is_master, ready_keys)
+                        {
+                            replication.propagate(db, &args);
+                        }
+                    }
+                    Message::Exec(db, protocol, commands, watched, caller) => {
+                        if let Some(caller) = &caller {
+                            let modified = store.watched_keys_modified(caller.addr, &watched);
+                            store.unwatch(caller.addr, &watched);
+                            if modified {
+                                let _ = caller.reply.send(RespData::ArrayNull);
+                                continue;
+                            }
+                        }
+                        let swaps_dbs = commands
+                            .iter()
+                            .any(|(command, _)| matches!(command, RedisCommand::SwapDb(..)));
+                        let (response, propagated) =
+                            execute_transaction(&store, db, protocol, is_master, commands);
+                        for (db, args) in propagated {
+                            replication.propagate(db, &args);
+                        }
+                        if let Some(caller) = caller {
+                            let _ = caller.reply.send(response);
+                        }
 
-                        let mut total_write_bytes = total_write_bytes.write().unwrap();
-                        *total_write_bytes += data.len();
+                        let mut ready_keys = store.take_ready_keys();
+                        if swaps_dbs {
+                            ready_keys.extend(blocked_clients.keys());
+                        }
+                        for (db, args) in
+                            blocking::serve(&store, &mut blocked_clients, is_master, ready_keys)
+                        {
+                            replication.propagate(db, &args);
+                        }
                     }
-                    Message::Set(key, value, px) => {
-                        store.set(key, value, px);
+                    Message::Watch(addr, db, keys) => {
+                        store.watch(db, &keys, addr);
                     }
-                    Message::Get(stream, key) => {
-                        let message = store
-                            .get(&key)
-                            .map(|v| format!("${}\r\n{}\r\n", v.len(), v))
-                            .unwrap_or("$-1\r\n".to_string());
-                        if let Err(e) = send_message_to_client(&stream, &message) {
-                            eprintln!("Error handling client: {}", e);
+                    Message::Unwatch(addr, watched) => {
+                        store.unwatch(addr, &watched);
+                    }
+                    Message::ActiveExpireCycle => {
+                        for (db, args) in store.active_expire_cycle() {
+                            replication.propagate(db, &args);
                         }
                     }
                     Message::WaitHandshake(stream, numreplicas, timeout) => {
-                        if *total_write_bytes.read().unwrap() != 0 {
-                            let data_of_getack = format!(
-                                "*3\r\n{}{}{}",
-                                make_bulk_string("REPLCONF"),
-                                make_bulk_string("GETACK"),
-                                make_bulk_string("*")
-                            );
-                            tx.send(Message::Data(data_of_getack.as_bytes().to_vec()))
-                                .unwrap();
+                        if *replication.total_write_bytes.read().unwrap() != 0 {
+                            let data_of_getack = make_command(&["REPLCONF", "GETACK", "*"]);
+                            tx.send(Message::Data(data_of_getack)).unwrap();
                         }
 
                         if numreplicas == 0 {
                             let message = format!(":{}\r\n", 0);
-                            if let Err(e) = send_message_to_client(&stream, &message) {
-                                eprintln!("Error handling client: {}", e);
-                            }
+                            reply_to_client(&stream, &message);
 
-                            break;
+                            continue;
                         }
 
-                        let replicas = Arc::clone(&replicas);
-                        let last_write_bytes = Arc::clone(&last_write_bytes);
-                        let total_write_bytes = Arc::clone(&total_write_bytes);
+                        let replicas = Arc::clone(&replication.replicas);
+                        let last_write_bytes = Arc::clone(&replication.last_write_bytes);
+                        let total_write_bytes = Arc::clone(&replication.total_write_bytes);
                         std::thread::spawn(move || {
-                            std::thread::sleep(std::time::Duration::from_millis(timeout));
+                            std::thread::sleep(Duration::from_millis(timeout));
 
                             let replicas = replicas.lock().unwrap();
                             let last_write_bytes = last_write_bytes.read().unwrap();
@@ -139,13 +354,11 @@ fn main() {
                                 .count();
 
                             let message = format!(":{}\r\n", synced_replica_count);
-                            if let Err(e) = send_message_to_client(&stream, &message) {
-                                eprintln!("Error handling client: {}", e);
-                            }
+                            reply_to_client(&stream, &message);
                         });
                     }
                     Message::UpdateOffset(stream, offset) => {
-                        let mut replicas = replicas.lock().unwrap();
+                        let mut replicas = replication.replicas.lock().unwrap();
                         replicas
                             .entry(stream.peer_addr().unwrap())
                             .and_modify(|v| v.1 = offset);
@@ -153,7 +366,7 @@ fn main() {
                 }
             }
         });
-    };
+    }
 
     for stream in listener.incoming() {
         match stream {
@@ -163,53 +376,160 @@ fn main() {
                 std::thread::spawn(move || {
                     println!("accepted new connection");
 
+                    let addr = stream.peer_addr().unwrap();
                     let mut is_replica = false;
-                    loop {
-                        let mut buf = [0; 1024];
-                        let size = stream.read(&mut buf).unwrap_or(0);
-                        if size == 0 {
-                            break;
-                        }
+                    let mut db = 0;
+                    let mut protocol = Protocol::Resp2;
+                    let mut transaction: Option<Transaction> = None;
+                    // The keys WATCHed since the last EXEC, with their database.
+                    let mut watched: Vec<(usize, String)> = Vec::new();
+                    // The connection as blocked commands hold it, and a way
+                    // to get the reply to a command sent to the store thread.
+                    let peer = Arc::new(stream.try_clone().unwrap());
+                    let caller = || {
+                        let (reply, replies) = mpsc::channel();
+                        let caller = Caller {
+                            addr,
+                            stream: Arc::clone(&peer),
+                            reply,
+                        };
+                        (caller, replies)
+                    };
+                    let mut input = Vec::new();
+                    'connection: loop {
+                        let (rest, resp) = match parse_frame(&input) {
+                            Ok((rest, resp)) => (rest.to_vec(), resp),
+                            Err(FrameError::Protocol(e)) => {
+                                // Like Redis, there is no telling where the next
+                                // request starts, so the connection is closed.
+                                let message = format!("-ERR Protocol error: {}\r\n", e);
+                                reply_to_client(&stream, &message);
+                                break;
+                            }
+                            Err(FrameError::Incomplete) => {
+                                let mut buf = [0; 1024];
+                                let size = stream.read(&mut buf).unwrap_or(0);
+                                if size == 0 {
+                                    break;
+                                }
 
-                        let resp = parse_resp(&buf[..size]).unwrap().1;
-                        let command = parse_command(&resp).map_or_else(
-                            || {
-                                println!(
-                                    "Invalid command: {}",
-                                    String::from_utf8_lossy(&buf[..size])
-                                );
-                                None
-                            },
-                            Some,
-                        );
+                                input.extend_from_slice(&buf[..size]);
+                                continue;
+                            }
+                        };
+                        input = rest;
 
-                        if command.is_none() {
-                            continue;
-                        }
+                        let command = match parse_command(&resp) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                if let Some(transaction) = &mut transaction {
+                                    transaction.aborted = true;
+                                }
+                                let message =
+                                    encode_resp(&RespData::Error(e.to_string()), protocol);
+                                reply_to_client(&stream, &message);
+                                continue;
+                            }
+                        };
+
+                        let command = match (&mut transaction, command) {
+                            (
+                                Some(_),
+                                command @ (RedisCommand::Multi
+                                | RedisCommand::Exec
+                                | RedisCommand::Discard),
+                            ) => command,
+                            (Some(transaction), command) => {
+                                let message = transaction.queue(command, command_args(&resp));
+                                reply_to_client(&stream, message);
+                                continue;
+                            }
+                            (None, command) => command,
+                        };
 
-                        match command.unwrap() {
+                        match command {
+                            RedisCommand::Multi => {
+                                let message = if transaction.is_some() {
+                                    "-ERR MULTI calls can not be nested\r\n"
+                                } else {
+                                    transaction = Some(Transaction::default());
+                                    "+OK\r\n"
+                                };
+                                reply_to_client(&stream, message);
+                            }
+                            RedisCommand::Discard => {
+                                let message = match transaction.take() {
+                                    Some(_) => {
+                                        unwatch(&tx, addr, &mut watched);
+                                        "+OK\r\n"
+                                    }
+                                    None => "-ERR DISCARD without MULTI\r\n",
+                                };
+                                reply_to_client(&stream, message);
+                            }
+                            RedisCommand::Exec => {
+                                let message = match transaction.take() {
+                                    None => "-ERR EXEC without MULTI\r\n",
+                                    Some(transaction) if transaction.aborted => {
+                                        unwatch(&tx, addr, &mut watched);
+                                        "-EXECABORT Transaction discarded because of previous errors.\r\n"
+                                    }
+                                    Some(transaction) => {
+                                        let selected_db =
+                                            transaction.selected_db(parse_cli().databases);
+                                        let selected_protocol = transaction.selected_protocol();
+                                        let (caller, replies) = caller();
+                                        tx.send(Message::Exec(
+                                            db,
+                                            protocol,
+                                            transaction.commands,
+                                            std::mem::take(&mut watched),
+                                            Some(caller),
+                                        ))
+                                        .unwrap();
+                                        let response = replies.recv().unwrap();
+                                        if response != RespData::ArrayNull {
+                                            db = selected_db.unwrap_or(db);
+                                            protocol = selected_protocol.unwrap_or(protocol);
+                                        }
+                                        reply_to_client(&stream, encode_resp(&response, protocol));
+                                        continue;
+                                    }
+                                };
+                                reply_to_client(&stream, message);
+                            }
+                            RedisCommand::Watch(keys) => {
+                                watched.extend(keys.iter().map(|key| (db, key.clone())));
+                                tx.send(Message::Watch(addr, db, keys)).unwrap();
+                                reply_to_client(&stream, "+OK\r\n");
+                            }
+                            RedisCommand::Unwatch => {
+                                unwatch(&tx, addr, &mut watched);
+                                reply_to_client(&stream, "+OK\r\n");
+                            }
                             RedisCommand::Ping => {
-                                if let Err(e) = send_message_to_client(&stream, "+PONG\r\n") {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, "+PONG\r\n");
                             }
                             RedisCommand::Echo(message) => {
                                 let message = format!("+{}\r\n", message);
-                                if let Err(e) = send_message_to_client(&stream, &message) {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, &message);
                             }
-                            RedisCommand::Set(key, value, px) => {
-                                tx.send(Message::Set(key, value, px)).unwrap();
-                                if let Err(e) = send_message_to_client(&stream, "+OK\r\n") {
-                                    eprintln!("Error handling client: {}", e);
-                                }
-
-                                tx.send(Message::Data(buf[..size].to_vec())).unwrap();
+                            RedisCommand::Select(index) => {
+                                let message = match usize::try_from(index) {
+                                    Ok(index) if index < parse_cli().databases => {
+                                        db = index;
+                                        "+OK\r\n"
+                                    }
+                                    _ => "-ERR DB index is out of range\r\n",
+                                };
+                                reply_to_client(&stream, message);
                             }
-                            RedisCommand::Get(key) => {
-                                tx.send(Message::Get(stream.try_clone().unwrap(), key))
-                                    .unwrap();
+                            RedisCommand::Hello(protover) => {
+                                let is_master = parse_cli().replicaof.is_none();
+                                let response;
+                                (response, protocol) = hello(protover, protocol, is_master);
+                                let message = encode_resp(&response, protocol);
+                                reply_to_client(&stream, &message);
                             }
                             RedisCommand::Info => {
                                 let role = match parse_cli().replicaof {
@@ -225,32 +545,26 @@ fn main() {
                                 ]
                                 .join("\r\n");
                                 let message = make_bulk_string(&info);
-                                if let Err(e) = send_message_to_client(&stream, &message) {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, &message);
                             }
                             RedisCommand::ReplConf(ReplConf::Ack(offset)) => {
                                 tx.send(Message::UpdateOffset(stream.try_clone().unwrap(), offset))
                                     .unwrap();
                             }
                             RedisCommand::ReplConf(_) => {
-                                if let Err(e) = send_message_to_client(&stream, "+OK\r\n") {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, "+OK\r\n");
                             }
                             RedisCommand::PSync => {
                                 let message =
                                     "+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n";
-                                if let Err(e) = send_message_to_client(&stream, &message) {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, message);
 
                                 let file_content = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
                                 let file_content = decode_hex(file_content).unwrap();
                                 stream
-                                    .write(format!("${}\r\n", file_content.len()).as_bytes())
+                                    .write_all(format!("${}\r\n", file_content.len()).as_bytes())
                                     .unwrap();
-                                stream.write(&file_content).unwrap();
+                                stream.write_all(&file_content).unwrap();
                                 stream.flush().unwrap();
 
                                 is_replica = true;
@@ -272,9 +586,7 @@ fn main() {
                                     make_bulk_string("dir"),
                                     make_bulk_string(&args.dir)
                                 );
-                                if let Err(e) = send_message_to_client(&stream, &message) {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, &message);
                             }
                             RedisCommand::ConfigGet(ConfigGet::Dbfilename) => {
                                 let args = parse_cli();
@@ -283,13 +595,35 @@ fn main() {
                                     make_bulk_string("dbfilename"),
                                     make_bulk_string(&args.dbfilename)
                                 );
-                                if let Err(e) = send_message_to_client(&stream, &message) {
-                                    eprintln!("Error handling client: {}", e);
-                                }
+                                reply_to_client(&stream, &message);
+                            }
+                            RedisCommand::ConfigGet(ConfigGet::Databases) => {
+                                let args = parse_cli();
+                                let message = format!(
+                                    "*2\r\n{}{}",
+                                    make_bulk_string("databases"),
+                                    make_bulk_string(&args.databases.to_string())
+                                );
+                                reply_to_client(&stream, &message);
+                            }
+                            command => {
+                                let args = command_args(&resp);
+                                let (caller, replies) = caller();
+                                tx.send(Message::Command(db, command, args, Some(caller)))
+                                    .unwrap();
+
+                                // A blocking command may keep us waiting for a long
+                                // time. If the client hangs up meanwhile, the store
+                                // thread drops it, closing the reply channel.
+                                let Ok(response) = replies.recv() else {
+                                    break 'connection;
+                                };
+                                reply_to_client(&stream, encode_resp(&response, protocol));
                             }
                         }
                     }
 
+                    unwatch(&tx, addr, &mut watched);
                     if is_replica {
                         tx.send(Message::DisconnectReplica(stream.try_clone().unwrap()))
                             .unwrap();
@@ -0,0 +1,92 @@
+use std::{
+    cell::Cell,
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hash, Hasher},
+};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+fn seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default(),
+    );
+    hasher.finish() | 1
+}
+
+/// One step of xorshift64*, returning the new state and the number drawn.
+fn step(mut x: u64) -> (u64, u64) {
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    (x, x.wrapping_mul(0x2545_f491_4f6c_dd1d))
+}
+
+/// xorshift64* — good enough for sampling keys, not for anything secret.
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let (x, drawn) = step(state.get());
+        state.set(x);
+        drawn
+    })
+}
+
+/// A xorshift64* generator with a state of its own, for values whose random
+/// choices have to come out the same on replicas that run the same commands.
+#[derive(Debug, Clone)]
+pub struct Seeded(u64);
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Seeded(seed | 1)
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        let (x, drawn) = step(self.0);
+        self.0 = x;
+        (drawn >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Returns a number in `0..n`. `n` must not be zero.
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
+
+/// Picks `count` distinct items out of a collection of `len`, given a way to
+/// list them all and a way to pick one at random. When most of the items are
+/// wanted, dropping random ones from the full list is cheaper than drawing
+/// until enough distinct ones came up.
+pub fn sample_distinct<T: Hash + Eq + Copy>(
+    len: usize,
+    count: usize,
+    all: impl FnOnce() -> Vec<T>,
+    mut pick: impl FnMut() -> T,
+) -> Vec<T> {
+    if count >= len {
+        return all();
+    }
+    if count * 3 > len {
+        let mut items = all();
+        while items.len() > count {
+            items.swap_remove(below(items.len()));
+        }
+        return items;
+    }
+
+    let mut seen = HashSet::new();
+    let mut items = Vec::with_capacity(count);
+    while items.len() < count {
+        let item = pick();
+        if seen.insert(item) {
+            items.push(item);
+        }
+    }
+    items
+}
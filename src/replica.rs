@@ -2,9 +2,10 @@ use std::{io::Read, net::TcpStream, sync::mpsc::Sender};
 
 use crate::{
     cli::parse_cli,
-    command::{parse_command, RedisCommand, ReplConf},
-    resp_parser::parse_resp,
+    command::{command_args, parse_command, RedisCommand, ReplConf},
+    resp_parser::{parse_frame, parse_resp, FrameError, Protocol},
     tcp::send_message_to_client,
+    transaction::Transaction,
     Message,
 };
 
@@ -13,7 +14,7 @@ fn run_client(tx: &Sender<Message>, host: &str, port: u16) {
 
     let mut stream = TcpStream::connect(format!("{}:{}", host, port)).unwrap();
     let message = "*1\r\n$4\r\nPING\r\n";
-    send_message_to_client(&stream, &message).unwrap();
+    send_message_to_client(&stream, message).unwrap();
     let _ = stream.read(&mut [0; 128]);
 
     let message = format!(
@@ -24,12 +25,12 @@ fn run_client(tx: &Sender<Message>, host: &str, port: u16) {
     send_message_to_client(&stream, &message).unwrap();
     let _ = stream.read(&mut [0; 128]);
 
-    let message = format!("*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n");
-    send_message_to_client(&stream, &message).unwrap();
+    let message = "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n";
+    send_message_to_client(&stream, message).unwrap();
     let _ = stream.read(&mut [0; 128]);
 
     let message = "*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n";
-    send_message_to_client(&stream, &message).unwrap();
+    send_message_to_client(&stream, message).unwrap();
 
     let mut input = Vec::new();
 
@@ -49,7 +50,7 @@ fn run_client(tx: &Sender<Message>, host: &str, port: u16) {
         };
 
         let (next_input, resp) = parse_resp(&current_input).unwrap();
-        if parse_command(&resp).is_some() {
+        if parse_command(&resp).is_ok() {
             input = current_input;
             break;
         } else {
@@ -58,28 +59,37 @@ fn run_client(tx: &Sender<Message>, host: &str, port: u16) {
     }
 
     let mut offset = 0;
+    let mut db = 0;
+    // A transaction being fed to us, with the database it started in, to
+    // apply at once on EXEC.
+    let mut transaction: Option<(usize, Transaction)> = None;
 
     loop {
-        let current_input = if input.is_empty() {
-            let mut buf = [0; 1024];
-            let bytes_read = stream.read(&mut buf).unwrap();
-
-            if bytes_read == 0 {
-                println!("Server closed the connection");
+        let (rest, resp) = match parse_frame(&input) {
+            Ok((rest, resp)) => (rest.to_vec(), resp),
+            Err(FrameError::Protocol(e)) => {
+                println!("Protocol error from master: {}", e);
                 break;
             }
-
-            buf[..bytes_read].to_vec()
-        } else {
-            input
+            Err(FrameError::Incomplete) => {
+                // The frame is split across reads; wait for the rest of it.
+                let mut buf = [0; 1024];
+                let bytes_read = stream.read(&mut buf).unwrap();
+
+                if bytes_read == 0 {
+                    println!("Server closed the connection");
+                    break;
+                }
+
+                input.extend_from_slice(&buf[..bytes_read]);
+                continue;
+            }
         };
+        let consumed = input.len() - rest.len();
+        input = rest;
 
-        let (next_input, resp) = parse_resp(&current_input).unwrap();
-        input = next_input.to_vec();
-
-        let command = parse_command(&resp).unwrap();
-        match command {
-            RedisCommand::ReplConf(ReplConf::GetAck) => {
+        match parse_command(&resp).ok() {
+            Some(RedisCommand::ReplConf(ReplConf::GetAck)) => {
                 let message = format!(
                     "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n${}\r\n{}\r\n",
                     offset.to_string().len(),
@@ -88,13 +98,45 @@ fn run_client(tx: &Sender<Message>, host: &str, port: u16) {
 
                 send_message_to_client(&stream, &message).unwrap();
             }
-            RedisCommand::Set(key, value, px) => {
-                tx.send(Message::Set(key, value, px)).unwrap();
+            Some(RedisCommand::Multi) => {
+                transaction = Some((db, Transaction::default()));
+            }
+            Some(RedisCommand::Exec) => {
+                if let Some((db, transaction)) = transaction.take() {
+                    tx.send(Message::Exec(
+                        db,
+                        Protocol::Resp2,
+                        transaction.commands,
+                        Vec::new(),
+                        None,
+                    ))
+                    .unwrap();
+                }
             }
+            Some(RedisCommand::Select(index)) => {
+                match usize::try_from(index) {
+                    Ok(index) if index < args.databases => db = index,
+                    _ => {
+                        println!("Master selected database {} which we don't have", index);
+                        break;
+                    }
+                }
+                if let Some((_, transaction)) = &mut transaction {
+                    transaction
+                        .commands
+                        .push((RedisCommand::Select(index), command_args(&resp)));
+                }
+            }
+            Some(command) if command.is_write() => match &mut transaction {
+                Some((_, transaction)) => transaction.commands.push((command, command_args(&resp))),
+                None => tx
+                    .send(Message::Command(db, command, command_args(&resp), None))
+                    .unwrap(),
+            },
             _ => {}
         }
 
-        offset += current_input.len() - input.len();
+        offset += consumed;
     }
 }
 
@@ -103,7 +145,8 @@ pub fn main_of_replica(tx: &Sender<Message>) {
     std::thread::spawn(move || {
         let args = parse_cli();
 
-        args.replicaof
-            .map(|replica| run_client(&tx, &replica.host, replica.port));
+        if let Some(replica) = args.replicaof {
+            run_client(&tx, &replica.host, replica.port);
+        }
     });
 }
@@ -8,14 +8,28 @@ use nom::{
     IResult,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum RespData {
     SimpleString(String),
     Error(String),
     Integer(i64),
     BulkString(String),
+    /// A bulk string that need not be valid UTF-8, such as a bitmap.
+    /// Requests are parsed into it only when they aren't valid UTF-8.
+    BulkBytes(Vec<u8>),
     BulkStringNull,
     Array(Vec<RespData>),
+    ArrayNull,
+    /// A RESP3 map. RESP2 clients get it flattened into an array of
+    /// alternating keys and values.
+    Map(Vec<(RespData, RespData)>),
+}
+
+/// The protocol version a client speaks, as negotiated with HELLO.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
 }
 
 fn parse_simple_string(input: &[u8]) -> IResult<&[u8], RespData> {
@@ -62,10 +76,11 @@ fn parse_bulk_string(input: &[u8]) -> IResult<&[u8], RespData> {
     let (input, is_string) = opt(crlf)(input)?;
 
     if is_string.is_some() {
-        Ok((
-            input,
-            RespData::BulkString(String::from_utf8_lossy(data).into_owned()),
-        ))
+        let data = match String::from_utf8(data.to_vec()) {
+            Ok(string) => RespData::BulkString(string),
+            Err(e) => RespData::BulkBytes(e.into_bytes()),
+        };
+        Ok((input, data))
     } else {
         Ok((input, RespData::BulkStringNull))
     }
@@ -80,6 +95,10 @@ fn parse_array(input: &[u8]) -> IResult<&[u8], RespData> {
         nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Digit))
     })?;
 
+    if len == -1 {
+        return Ok((input, RespData::ArrayNull));
+    }
+
     let (input, elements) = count(parse_resp, len as usize)(input)?;
     Ok((input, RespData::Array(elements)))
 }
@@ -94,6 +113,125 @@ pub fn parse_resp(input: &[u8]) -> IResult<&[u8], RespData> {
     ))(input)
 }
 
+/// Longest header line we wait for before calling the input malformed, as
+/// Redis's inline request limit.
+const MAX_HEADER_LEN: usize = 64 * 1024;
+/// Longest bulk string a client may send, as Redis's `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// Why no value could be read off a connection.
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    /// The value is still being received.
+    Incomplete,
+    /// The input can never form a valid value, so the connection is unusable.
+    Protocol(String),
+}
+
+/// Parses the value at the start of a connection's input, telling a value
+/// that is only cut short from one that is malformed.
+pub fn parse_frame(input: &[u8]) -> Result<(&[u8], RespData), FrameError> {
+    match frame_len(input).map_err(FrameError::Protocol)? {
+        None => Err(FrameError::Incomplete),
+        Some(_) => {
+            parse_resp(input).map_err(|_| FrameError::Protocol("invalid request".to_string()))
+        }
+    }
+}
+
+/// The length of the value at the start of `input`, or `None` if more of it
+/// is needed.
+fn frame_len(input: &[u8]) -> Result<Option<usize>, String> {
+    let Some(&kind) = input.first() else {
+        return Ok(None);
+    };
+    let Some(end) = input.windows(2).position(|window| window == b"\r\n") else {
+        return if input.len() > MAX_HEADER_LEN {
+            Err("too big request header".to_string())
+        } else {
+            Ok(None)
+        };
+    };
+    let header = end + 2;
+    let length = |what: &str| {
+        core::str::from_utf8(&input[1..end])
+            .ok()
+            .and_then(|line| line.parse::<i64>().ok())
+            .ok_or_else(|| format!("invalid {}", what))
+    };
+
+    match kind {
+        b'+' | b'-' => Ok(Some(header)),
+        b':' => length("integer").map(|_| Some(header)),
+        b'$' => match length("bulk length")? {
+            -1 => Ok(Some(header)),
+            len => {
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|len| *len <= MAX_BULK_LEN)
+                    .ok_or("invalid bulk length")?;
+                let Some(terminator) = input.get(header + len..header + len + 2) else {
+                    return Ok(None);
+                };
+                if terminator != b"\r\n" {
+                    return Err("expected CRLF after bulk string".to_string());
+                }
+                Ok(Some(header + len + 2))
+            }
+        },
+        b'*' => match length("multibulk length")? {
+            -1 => Ok(Some(header)),
+            len => {
+                let len = usize::try_from(len).map_err(|_| "invalid multibulk length")?;
+                let mut total = header;
+                for _ in 0..len {
+                    match frame_len(&input[total..])? {
+                        Some(element) => total += element,
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(total))
+            }
+        },
+        kind => Err(format!("unexpected '{}'", kind.escape_ascii())),
+    }
+}
+
+pub fn encode_resp(data: &RespData, protocol: Protocol) -> Vec<u8> {
+    let encode_all = |elements: &mut dyn Iterator<Item = &RespData>| {
+        elements
+            .flat_map(|element| encode_resp(element, protocol))
+            .collect::<Vec<u8>>()
+    };
+    match data {
+        RespData::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+        RespData::Error(e) => format!("-{}\r\n", e).into_bytes(),
+        RespData::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+        RespData::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+        RespData::BulkBytes(bytes) => {
+            [format!("${}\r\n", bytes.len()).as_bytes(), bytes, b"\r\n"].concat()
+        }
+        RespData::BulkStringNull | RespData::ArrayNull if protocol == Protocol::Resp3 => {
+            b"_\r\n".to_vec()
+        }
+        RespData::BulkStringNull => b"$-1\r\n".to_vec(),
+        RespData::Array(elements) => [
+            format!("*{}\r\n", elements.len()).into_bytes(),
+            encode_all(&mut elements.iter()),
+        ]
+        .concat(),
+        RespData::ArrayNull => b"*-1\r\n".to_vec(),
+        RespData::Map(entries) => {
+            let mut flattened = entries.iter().flat_map(|(k, v)| [k, v]);
+            let header = match protocol {
+                Protocol::Resp2 => format!("*{}\r\n", entries.len() * 2),
+                Protocol::Resp3 => format!("%{}\r\n", entries.len()),
+            };
+            [header.into_bytes(), encode_all(&mut flattened)].concat()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,4 +318,68 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn encode_round_trip() {
+        let data = RespData::Array(vec![
+            RespData::BulkString("SET".to_string()),
+            RespData::Integer(-1),
+            RespData::BulkStringNull,
+            RespData::SimpleString("OK".to_string()),
+            RespData::Error("ERR oops".to_string()),
+            RespData::ArrayNull,
+        ]);
+        let encoded = encode_resp(&data, Protocol::Resp2);
+        assert_eq!(parse_resp(&encoded), Ok((&b""[..], data)));
+    }
+
+    #[test]
+    fn bulk_bytes_need_not_be_utf8() {
+        let data = RespData::BulkBytes(vec![0xff, 0x00, b'a']);
+        assert_eq!(encode_resp(&data, Protocol::Resp2), b"$3\r\n\xff\x00a\r\n");
+        assert_eq!(parse_resp(b"$3\r\n\xff\x00a\r\n"), Ok((&b""[..], data)));
+    }
+
+    #[test]
+    fn map_depends_on_protocol() {
+        let data = RespData::Map(vec![(
+            RespData::BulkString("a".to_string()),
+            RespData::BulkStringNull,
+        )]);
+        assert_eq!(
+            encode_resp(&data, Protocol::Resp2),
+            b"*2\r\n$1\r\na\r\n$-1\r\n"
+        );
+        assert_eq!(
+            encode_resp(&data, Protocol::Resp3),
+            b"%1\r\n$1\r\na\r\n_\r\n"
+        );
+    }
+
+    #[test]
+    fn frame_tells_incomplete_from_malformed() {
+        let frame = b"*2\r\n$4\r\nECHO\r\n$5\r\nHello\r\n";
+        for cut in 0..frame.len() {
+            assert_eq!(parse_frame(&frame[..cut]), Err(FrameError::Incomplete));
+        }
+        assert!(parse_frame(frame).is_ok());
+
+        for malformed in [
+            &b"hello\r\n"[..],
+            b"*x\r\n",
+            b"*-2\r\n",
+            b"*1\r\n$-5\r\n",
+            b"*1\r\n$2\r\nabc\r\n",
+            b"*1\r\n:1x\r\n",
+        ] {
+            assert!(matches!(
+                parse_frame(malformed),
+                Err(FrameError::Protocol(_))
+            ));
+        }
+        assert_eq!(
+            parse_frame(&[b'*'; MAX_HEADER_LEN + 1]),
+            Err(FrameError::Protocol("too big request header".to_string()))
+        );
+    }
 }
@@ -0,0 +1,901 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    command::{Args, CommandError},
+    hash::Hash,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+    zset::SortedSet,
+};
+
+/// What FT.SEARCH returns without LIMIT.
+const DEFAULT_LIMIT: usize = 10;
+
+/// How deeply parentheses and field scopes may nest in a query.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FieldType {
+    /// Words, matched case-insensitively.
+    Text,
+    /// Exact values, split on the separator.
+    Tag(char),
+    Numeric,
+}
+
+impl FieldType {
+    fn name(self) -> &'static str {
+        match self {
+            FieldType::Text => "TEXT",
+            FieldType::Tag(_) => "TAG",
+            FieldType::Numeric => "NUMERIC",
+        }
+    }
+}
+
+/// A schema field: the hash field it indexes and the name queries use.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Field {
+    name: String,
+    alias: String,
+    kind: FieldType,
+}
+
+impl Field {
+    /// What a text or tag field's value is indexed under: its lowercased
+    /// words, or its tags.
+    fn terms(&self, value: &str) -> Vec<String> {
+        let terms: Vec<&str> = match self.kind {
+            FieldType::Tag(separator) => value.split(separator).map(str::trim).collect(),
+            _ => value
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .collect(),
+        };
+        terms
+            .into_iter()
+            .filter(|term| !term.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+}
+
+enum Postings {
+    /// Documents by the words or tags in the field.
+    Terms(BTreeMap<String, BTreeSet<String>>),
+    /// Documents scored by the field's value.
+    Numbers(SortedSet),
+}
+
+/// A secondary index over the hashes whose keys start with one of its
+/// prefixes. It is kept up to date as hashes change, see `Db::reindex`.
+pub struct Index {
+    prefixes: Vec<String>,
+    fields: Vec<Field>,
+    /// The indexed values of each document, so it can be taken out of the
+    /// postings when it changes.
+    documents: HashMap<String, Vec<Option<String>>>,
+    postings: Vec<Postings>,
+}
+
+impl Index {
+    fn new(prefixes: Vec<String>, fields: Vec<Field>) -> Self {
+        let postings = fields
+            .iter()
+            .map(|field| match field.kind {
+                FieldType::Numeric => Postings::Numbers(SortedSet::default()),
+                _ => Postings::Terms(BTreeMap::new()),
+            })
+            .collect();
+        Index {
+            prefixes,
+            fields,
+            documents: HashMap::new(),
+            postings,
+        }
+    }
+
+    /// Forgets every document, keeping the definition.
+    pub fn clear(&mut self) {
+        let fields = std::mem::take(&mut self.fields);
+        *self = Index::new(std::mem::take(&mut self.prefixes), fields);
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Brings the document at `key` up to date with the hash now stored
+    /// there, if any.
+    pub fn update(&mut self, key: &str, hash: Option<&Hash>) {
+        self.remove(key);
+        if let Some(hash) = hash.filter(|_| self.covers(key)) {
+            self.add(key, hash);
+        }
+    }
+
+    fn add(&mut self, key: &str, hash: &Hash) {
+        let values: Vec<Option<String>> = self
+            .fields
+            .iter()
+            .map(|field| hash.get(&field.name).cloned())
+            .collect();
+        for ((field, postings), value) in self.fields.iter().zip(&mut self.postings).zip(&values) {
+            let Some(value) = value else { continue };
+            match postings {
+                Postings::Terms(terms) => {
+                    for term in field.terms(value) {
+                        terms.entry(term).or_default().insert(key.to_string());
+                    }
+                }
+                // A value that isn't a number leaves the document out of
+                // numeric filters on the field.
+                Postings::Numbers(numbers) => {
+                    if let Some(number) = value.parse().ok().filter(|n: &f64| !n.is_nan()) {
+                        numbers.insert(key.to_string(), number);
+                    }
+                }
+            }
+        }
+        self.documents.insert(key.to_string(), values);
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(values) = self.documents.remove(key) else {
+            return;
+        };
+        for ((field, postings), value) in self.fields.iter().zip(&mut self.postings).zip(&values) {
+            let Some(value) = value else { continue };
+            match postings {
+                Postings::Terms(terms) => {
+                    for term in field.terms(value) {
+                        if let Some(documents) = terms.get_mut(&term) {
+                            documents.remove(key);
+                            if documents.is_empty() {
+                                terms.remove(&term);
+                            }
+                        }
+                    }
+                }
+                Postings::Numbers(numbers) => {
+                    numbers.remove(key);
+                }
+            }
+        }
+    }
+
+    fn field(&self, alias: &str) -> Result<usize, StoreError> {
+        self.fields
+            .iter()
+            .position(|field| field.alias == alias)
+            .ok_or_else(|| StoreError::UnknownField(alias.to_string()))
+    }
+
+    /// The postings of a field that queries use as the given type.
+    fn postings(&self, alias: &str, kind: &'static str) -> Result<&Postings, StoreError> {
+        let field = self.field(alias)?;
+        if self.fields[field].kind.name() != kind {
+            return Err(StoreError::FieldType(alias.to_string(), kind));
+        }
+        Ok(&self.postings[field])
+    }
+
+    fn words(postings: &Postings, word: &str, prefix: bool) -> BTreeSet<String> {
+        let Postings::Terms(terms) = postings else {
+            return BTreeSet::new();
+        };
+        if !prefix {
+            return terms.get(word).cloned().unwrap_or_default();
+        }
+        terms
+            .range(word.to_string()..)
+            .take_while(|(term, _)| term.starts_with(word))
+            .flat_map(|(_, documents)| documents.iter().cloned())
+            .collect()
+    }
+
+    /// The keys of the documents matching `query`, in order.
+    fn search(&self, query: &Query) -> Result<BTreeSet<String>, StoreError> {
+        Ok(match query {
+            Query::All => self.documents.keys().cloned().collect(),
+            Query::Word {
+                field: Some(alias),
+                word,
+                prefix,
+            } => Self::words(self.postings(alias, "TEXT")?, word, *prefix),
+            Query::Word {
+                field: None,
+                word,
+                prefix,
+            } => self
+                .fields
+                .iter()
+                .zip(&self.postings)
+                .filter(|(field, _)| field.kind == FieldType::Text)
+                .flat_map(|(_, postings)| Self::words(postings, word, *prefix))
+                .collect(),
+            Query::Tags(alias, wanted) => {
+                let Postings::Terms(terms) = self.postings(alias, "TAG")? else {
+                    unreachable!()
+                };
+                wanted
+                    .iter()
+                    .filter_map(|tag| terms.get(tag))
+                    .flatten()
+                    .cloned()
+                    .collect()
+            }
+            Query::Range(alias, min, max) => {
+                let Postings::Numbers(numbers) = self.postings(alias, "NUMERIC")? else {
+                    unreachable!()
+                };
+                numbers
+                    .iter()
+                    .skip_while(|(_, value)| !min.below(*value))
+                    .take_while(|(_, value)| max.above(*value))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            }
+            Query::And(queries) => {
+                let mut matches = self.search(&queries[0])?;
+                for query in &queries[1..] {
+                    let other = self.search(query)?;
+                    matches.retain(|key| other.contains(key));
+                }
+                matches
+            }
+            Query::Or(queries) => {
+                let mut matches = BTreeSet::new();
+                for query in queries {
+                    matches.extend(self.search(query)?);
+                }
+                matches
+            }
+            Query::Not(query) => {
+                let excluded = self.search(query)?;
+                self.documents
+                    .keys()
+                    .filter(|key| !excluded.contains(*key))
+                    .cloned()
+                    .collect()
+            }
+        })
+    }
+
+    /// Sorts documents by a field, numerically for numeric fields. Documents
+    /// without the field come last.
+    fn sort(&self, keys: &mut [String], alias: &str, descending: bool) -> Result<(), StoreError> {
+        let field = self.field(alias)?;
+        let numeric = self.fields[field].kind == FieldType::Numeric;
+        let value = |key: &String| self.documents[key][field].as_deref();
+        keys.sort_by(|a, b| {
+            let ordering = match (value(a), value(b)) {
+                (Some(a), Some(b)) if numeric => {
+                    let number = |value: &str| value.parse().unwrap_or(f64::NAN);
+                    number(a).total_cmp(&number(b))
+                }
+                (Some(a), Some(b)) => a.cmp(b),
+                (a, b) => return b.is_some().cmp(&a.is_some()),
+            };
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        Ok(())
+    }
+}
+
+/// One end of a numeric range.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Bound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl Bound {
+    fn parse(bound: &str) -> Option<Bound> {
+        let (bound, exclusive) = match bound.strip_prefix('(') {
+            Some(bound) => (bound, true),
+            None => (bound, false),
+        };
+        let value = match bound.to_lowercase().as_str() {
+            "inf" | "+inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            bound => bound.parse().ok().filter(|value: &f64| !value.is_nan())?,
+        };
+        Some(Bound { value, exclusive })
+    }
+
+    /// Whether `value` is past this lower bound.
+    fn below(self, value: f64) -> bool {
+        if self.exclusive {
+            self.value < value
+        } else {
+            self.value <= value
+        }
+    }
+
+    /// Whether `value` is within this upper bound.
+    fn above(self, value: f64) -> bool {
+        if self.exclusive {
+            value < self.value
+        } else {
+            value <= self.value
+        }
+    }
+}
+
+/// A parsed FT.SEARCH query.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Query {
+    /// `*`, every document in the index.
+    All,
+    /// A word in the given TEXT field, or in any of them. A trailing `*`
+    /// matches any word it is a prefix of.
+    Word {
+        field: Option<String>,
+        word: String,
+        prefix: bool,
+    },
+    /// `@field:{a | b}`, documents with any of the tags.
+    Tags(String, Vec<String>),
+    /// `@field:[min max]`, with `(` for an exclusive bound.
+    Range(String, Bound, Bound),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    /// `-query`, documents not matching it.
+    Not(Box<Query>),
+}
+
+fn syntax_error(offset: usize) -> CommandError {
+    CommandError::Other(format!("ERR Syntax error at offset {}", offset))
+}
+
+/// A recursive descent parser for the query language. As in RediSearch,
+/// `|` binds tighter than the implicit AND between terms, so `a b | c` means
+/// `a (b | c)`. Inside `@field:(...)` bare words apply to that field.
+struct Parser<'a> {
+    query: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(query: &str) -> Result<Query, CommandError> {
+        let mut parser = Parser { query, offset: 0 };
+        let parsed = parser.intersection(None, 0)?;
+        parser.skip_spaces();
+        if parser.offset < query.len() {
+            return Err(syntax_error(parser.offset));
+        }
+        Ok(parsed)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.query[self.offset..].chars().next()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.offset += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.peek() == Some(c) {
+            self.offset += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), CommandError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(syntax_error(self.offset))
+        }
+    }
+
+    /// Consumes characters while `accept` holds, with `\` escaping the next
+    /// one.
+    fn take_while(&mut self, accept: impl Fn(char) -> bool) -> String {
+        let mut taken = String::new();
+        let mut chars = self.query[self.offset..].chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                let Some(escaped) = chars.next() else { break };
+                taken.push(escaped);
+                self.offset += 1 + escaped.len_utf8();
+            } else if accept(c) {
+                taken.push(c);
+                self.offset += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+        taken
+    }
+
+    fn intersection(&mut self, field: Option<&str>, depth: usize) -> Result<Query, CommandError> {
+        let mut queries = Vec::new();
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                None | Some(')') => break,
+                _ => queries.push(self.union(field, depth)?),
+            }
+        }
+        match queries.len() {
+            0 => Err(syntax_error(self.offset)),
+            1 => Ok(queries.pop().unwrap()),
+            _ => Ok(Query::And(queries)),
+        }
+    }
+
+    fn union(&mut self, field: Option<&str>, depth: usize) -> Result<Query, CommandError> {
+        let mut queries = vec![self.term(field, depth)?];
+        while self.eat('|') {
+            queries.push(self.term(field, depth)?);
+        }
+        Ok(if queries.len() == 1 {
+            queries.pop().unwrap()
+        } else {
+            Query::Or(queries)
+        })
+    }
+
+    fn term(&mut self, field: Option<&str>, depth: usize) -> Result<Query, CommandError> {
+        self.skip_spaces();
+        let start = self.offset;
+        if depth > MAX_DEPTH {
+            return Err(syntax_error(start));
+        }
+        // A run of negations cancels out in pairs, so it never nests.
+        let mut negated = false;
+        while self.eat('-') {
+            negated = !negated;
+        }
+        if negated {
+            return Ok(match self.term(field, depth + 1)? {
+                Query::Not(query) => *query,
+                query => Query::Not(Box::new(query)),
+            });
+        }
+        if self.eat('(') {
+            let query = self.intersection(field, depth + 1)?;
+            self.expect(')')?;
+            return Ok(query);
+        }
+        if self.eat('@') {
+            let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+            if name.is_empty() {
+                return Err(syntax_error(self.offset));
+            }
+            self.expect(':')?;
+            return self.field_term(name, depth);
+        }
+        if field.is_none() && self.eat('*') {
+            return Ok(Query::All);
+        }
+        let word = self.take_while(|c| c.is_alphanumeric() || c == '_');
+        if word.is_empty() {
+            return Err(syntax_error(start));
+        }
+        Ok(Query::Word {
+            field: field.map(str::to_string),
+            word: word.to_lowercase(),
+            prefix: self.eat_suffix('*'),
+        })
+    }
+
+    /// Like `eat`, but only right after the previous token.
+    fn eat_suffix(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.offset += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn field_term(&mut self, field: String, depth: usize) -> Result<Query, CommandError> {
+        if self.eat('{') {
+            let mut tags = Vec::new();
+            loop {
+                let tag = self.take_while(|c| c != '|' && c != '}');
+                let tag = tag.trim();
+                if tag.is_empty() {
+                    return Err(syntax_error(self.offset));
+                }
+                tags.push(tag.to_lowercase());
+                if !self.eat('|') {
+                    break;
+                }
+            }
+            self.expect('}')?;
+            return Ok(Query::Tags(field, tags));
+        }
+        if self.eat('[') {
+            let mut bound = || {
+                self.skip_spaces();
+                let offset = self.offset;
+                let bound = self.take_while(|c| !c.is_whitespace() && c != ',' && c != ']');
+                self.eat(',');
+                Bound::parse(&bound).ok_or_else(|| syntax_error(offset))
+            };
+            let (min, max) = (bound()?, bound()?);
+            self.expect(']')?;
+            return Ok(Query::Range(field, min, max));
+        }
+        self.term(Some(&field), depth + 1)
+    }
+}
+
+/// FT.SEARCH's query and options.
+#[derive(Debug, PartialEq)]
+pub struct Search {
+    query: Query,
+    no_content: bool,
+    /// Only these fields are returned, when given.
+    fields: Option<Vec<String>>,
+    /// The field to sort by, and whether descending.
+    sort_by: Option<(String, bool)>,
+    offset: usize,
+    limit: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SearchCommand {
+    Create {
+        index: String,
+        prefixes: Vec<String>,
+        fields: Vec<Field>,
+    },
+    /// FT.SEARCH with the index and the query with its options.
+    Search(String, Box<Search>),
+    /// FT.DROPINDEX, with whether to delete the indexed hashes too.
+    DropIndex(String, bool),
+}
+
+impl SearchCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<SearchCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "FT.CREATE" => {
+                let index = args.string()?;
+                if args.keyword("ON") && !args.keyword("HASH") {
+                    return Err(CommandError::Other(
+                        "ERR only HASH indexes are supported".into(),
+                    ));
+                }
+                let mut prefixes = Vec::new();
+                if args.keyword("PREFIX") {
+                    for _ in 0..args.positive()? {
+                        prefixes.push(args.string()?);
+                    }
+                }
+                if prefixes.is_empty() {
+                    prefixes.push(String::new());
+                }
+                if !args.keyword("SCHEMA") {
+                    return Err(CommandError::Syntax);
+                }
+                let mut fields = Vec::new();
+                while !args.is_empty() {
+                    let name = args.string()?;
+                    let alias = if args.keyword("AS") {
+                        args.string()?
+                    } else {
+                        name.clone()
+                    };
+                    let kind = if args.keyword("TEXT") {
+                        FieldType::Text
+                    } else if args.keyword("TAG") {
+                        let mut separator = ',';
+                        if args.keyword("SEPARATOR") {
+                            let given = args.string()?;
+                            let mut chars = given.chars();
+                            separator = match (chars.next(), chars.next()) {
+                                (Some(c), None) => c,
+                                _ => return Err(CommandError::Syntax),
+                            };
+                        }
+                        FieldType::Tag(separator)
+                    } else if args.keyword("NUMERIC") {
+                        FieldType::Numeric
+                    } else {
+                        return Err(CommandError::Other(format!(
+                            "ERR Invalid field type for field `{}`",
+                            name
+                        )));
+                    };
+                    // Any field can be sorted by, so SORTABLE changes nothing.
+                    args.keyword("SORTABLE");
+                    fields.push(Field { name, alias, kind });
+                }
+                if fields.is_empty() {
+                    return Err(args.arity_error());
+                }
+                SearchCommand::Create {
+                    index,
+                    prefixes,
+                    fields,
+                }
+            }
+            "FT.SEARCH" => {
+                let index = args.string()?;
+                let query = Parser::parse(&args.string()?)?;
+                let (mut no_content, mut fields, mut sort_by) = (false, None, None);
+                let (mut offset, mut limit) = (0, DEFAULT_LIMIT);
+                while !args.is_empty() {
+                    if args.keyword("NOCONTENT") {
+                        no_content = true;
+                    } else if args.keyword("RETURN") {
+                        let count = args.positive()?;
+                        fields = Some(
+                            (0..count)
+                                .map(|_| args.string())
+                                .collect::<Result<_, _>>()?,
+                        );
+                    } else if args.keyword("SORTBY") {
+                        let field = args.string()?;
+                        let descending = args.keyword("DESC");
+                        if !descending {
+                            args.keyword("ASC");
+                        }
+                        sort_by = Some((field, descending));
+                    } else if args.keyword("LIMIT") {
+                        offset = args.positive()?;
+                        limit = args.positive()?;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                SearchCommand::Search(
+                    index,
+                    Box::new(Search {
+                        query,
+                        no_content,
+                        fields,
+                        sort_by,
+                        offset,
+                        limit,
+                    }),
+                )
+            }
+            "FT.DROPINDEX" => {
+                let index = args.string()?;
+                let delete_documents = args.keyword("DD");
+                SearchCommand::DropIndex(index, delete_documents)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(self, SearchCommand::Search(..))
+    }
+}
+
+pub fn execute(db: &mut Db, command: SearchCommand) -> Result<RespData, StoreError> {
+    match command {
+        SearchCommand::Create {
+            index: name,
+            prefixes,
+            fields,
+        } => {
+            if db.indexes().contains_key(&name) {
+                return Err(StoreError::IndexExists);
+            }
+            let mut index = Index::new(prefixes, fields);
+            for (key, value) in db.iter() {
+                if let (Value::Hash(hash), true) = (value, index.covers(key)) {
+                    index.add(key, hash);
+                }
+            }
+            db.indexes_mut().insert(name, index);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        SearchCommand::Search(index, search) => {
+            let Search {
+                query,
+                no_content,
+                fields,
+                sort_by,
+                offset,
+                limit,
+            } = *search;
+            let index = db.indexes().get(&index).ok_or(StoreError::UnknownIndex)?;
+            // Expired hashes stay indexed until they are deleted.
+            let mut keys: Vec<String> = index
+                .search(&query)?
+                .into_iter()
+                .filter(|key| db.hash(key).ok().flatten().is_some())
+                .collect();
+            if let Some((field, descending)) = sort_by {
+                index.sort(&mut keys, &field, descending)?;
+            }
+
+            let mut reply = vec![RespData::Integer(keys.len() as i64)];
+            for key in keys.into_iter().skip(offset).take(limit) {
+                let hash = db.hash(&key)?.unwrap();
+                reply.push(RespData::BulkString(key));
+                if no_content {
+                    continue;
+                }
+                let mut content = Vec::new();
+                match &fields {
+                    Some(fields) => {
+                        for field in fields {
+                            if let Some(value) = hash.get(field) {
+                                content.push(RespData::BulkString(field.clone()));
+                                content.push(RespData::BulkString(value.clone()));
+                            }
+                        }
+                    }
+                    None => {
+                        for (field, value) in hash.iter() {
+                            content.push(RespData::BulkString(field.clone()));
+                            content.push(RespData::BulkString(value.clone()));
+                        }
+                    }
+                }
+                reply.push(RespData::Array(content));
+            }
+            Ok(RespData::Array(reply))
+        }
+        SearchCommand::DropIndex(name, delete_documents) => {
+            let index = db
+                .indexes_mut()
+                .remove(&name)
+                .ok_or(StoreError::UnknownIndex)?;
+            if delete_documents {
+                for key in index.documents.keys() {
+                    db.remove(key);
+                }
+            }
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> Index {
+        let field = |name: &str, kind| Field {
+            name: name.to_string(),
+            alias: name.to_string(),
+            kind,
+        };
+        let mut index = Index::new(
+            vec!["order:".to_string()],
+            vec![
+                field("title", FieldType::Text),
+                field("status", FieldType::Tag(',')),
+                field("price", FieldType::Numeric),
+            ],
+        );
+        for (key, title, status, price) in [
+            ("order:1", "Red running shoes", "pending", "50"),
+            ("order:2", "Blue shoes", "shipped,paid", "80.5"),
+            ("order:3", "Running shorts", "Pending", "20"),
+            ("other:4", "Red shoes", "pending", "10"),
+        ] {
+            let mut hash = Hash::default();
+            hash.insert("title".to_string(), title.to_string());
+            hash.insert("status".to_string(), status.to_string());
+            hash.insert("price".to_string(), price.to_string());
+            index.update(key, Some(&hash));
+        }
+        index
+    }
+
+    fn search(index: &Index, query: &str) -> Vec<String> {
+        let query = Parser::parse(query).unwrap();
+        index.search(&query).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn parses_queries() {
+        let word = |field: Option<&str>, word: &str| Query::Word {
+            field: field.map(str::to_string),
+            word: word.to_string(),
+            prefix: false,
+        };
+        assert_eq!(
+            Parser::parse("Hello @title:(big | small) -@tags:{a b|c}").unwrap(),
+            Query::And(vec![
+                word(None, "hello"),
+                Query::Or(vec![
+                    word(Some("title"), "big"),
+                    word(Some("title"), "small")
+                ]),
+                Query::Not(Box::new(Query::Tags(
+                    "tags".to_string(),
+                    vec!["a b".to_string(), "c".to_string()]
+                ))),
+            ])
+        );
+        assert_eq!(
+            Parser::parse("@price:[(10 +inf]").unwrap(),
+            Query::Range(
+                "price".to_string(),
+                Bound {
+                    value: 10.0,
+                    exclusive: true
+                },
+                Bound {
+                    value: f64::INFINITY,
+                    exclusive: false
+                }
+            )
+        );
+        assert!(Parser::parse("@price:[1]").is_err());
+        assert!(Parser::parse("(a").is_err());
+        assert!(Parser::parse("").is_err());
+    }
+
+    #[test]
+    fn folds_negations_and_caps_nesting() {
+        let x = Query::Word {
+            field: None,
+            word: "x".to_string(),
+            prefix: false,
+        };
+        let not_x = Query::Not(Box::new(x.clone()));
+        assert_eq!(
+            Parser::parse(&format!("{}x", "-".repeat(300_000))).unwrap(),
+            x
+        );
+        assert_eq!(
+            Parser::parse(&format!("{}x", "-".repeat(300_001))).unwrap(),
+            not_x
+        );
+        assert_eq!(Parser::parse("-(-(-x))").unwrap(), not_x);
+        for query in [
+            format!("{}x{}", "(".repeat(300_000), ")".repeat(300_000)),
+            format!("{}x{}", "-(".repeat(300_000), ")".repeat(300_000)),
+            format!("{}x", "@t:".repeat(300_000)),
+        ] {
+            assert!(Parser::parse(&query).is_err());
+        }
+    }
+
+    #[test]
+    fn searches_fields() {
+        let index = index();
+        assert_eq!(search(&index, "*"), ["order:1", "order:2", "order:3"]);
+        assert_eq!(search(&index, "@status:{pending}"), ["order:1", "order:3"]);
+        assert_eq!(search(&index, "@status:{paid|x}"), ["order:2"]);
+        assert_eq!(search(&index, "@price:[20 (50]"), ["order:3"]);
+        assert_eq!(search(&index, "shoes -red"), ["order:2"]);
+        assert_eq!(search(&index, "run*"), ["order:1", "order:3"]);
+        assert_eq!(search(&index, "@title:shoes @price:[-inf 60]"), ["order:1"]);
+        assert!(index
+            .search(&Parser::parse("@price:{50}").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn updates_documents() {
+        let mut index = index();
+        let mut hash = Hash::default();
+        hash.insert("status".to_string(), "shipped".to_string());
+        index.update("order:1", Some(&hash));
+        index.update("order:3", None);
+        assert_eq!(search(&index, "@status:{pending}"), Vec::<String>::new());
+        assert_eq!(search(&index, "shoes"), ["order:2"]);
+        assert_eq!(search(&index, "@status:{shipped}"), ["order:1", "order:2"]);
+
+        let mut keys = search(&index, "*");
+        index.sort(&mut keys, "price", true).unwrap();
+        assert_eq!(keys, ["order:2", "order:1"]);
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashSet;
+
+use crate::{
+    command::{Args, CommandError},
+    dict::Dict,
+    random,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SetCommand {
+    Add(String, Vec<String>),
+    Rem(String, Vec<String>),
+    IsMember(String, String),
+    MIsMember(String, Vec<String>),
+    Members(String),
+    Card(String),
+    Pop {
+        key: String,
+        count: Option<usize>,
+    },
+    RandMember {
+        key: String,
+        count: Option<i64>,
+    },
+    Move {
+        source: String,
+        destination: String,
+        member: String,
+    },
+    /// SINTER, SUNION and SDIFF, or their STORE variants when there is a
+    /// destination.
+    Combine {
+        op: SetOp,
+        keys: Vec<String>,
+        destination: Option<String>,
+    },
+    InterCard {
+        keys: Vec<String>,
+        limit: usize,
+    },
+}
+
+impl SetCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<SetCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "SADD" => SetCommand::Add(args.string()?, args.rest()?),
+            "SREM" => SetCommand::Rem(args.string()?, args.rest()?),
+            "SISMEMBER" => SetCommand::IsMember(args.string()?, args.string()?),
+            "SMISMEMBER" => SetCommand::MIsMember(args.string()?, args.rest()?),
+            "SMEMBERS" => SetCommand::Members(args.string()?),
+            "SCARD" => SetCommand::Card(args.string()?),
+            "SPOP" => SetCommand::Pop {
+                key: args.string()?,
+                count: if args.is_empty() {
+                    None
+                } else {
+                    Some(args.positive()?)
+                },
+            },
+            "SRANDMEMBER" => SetCommand::RandMember {
+                key: args.string()?,
+                count: if args.is_empty() {
+                    None
+                } else {
+                    Some(args.random_count()?)
+                },
+            },
+            "SMOVE" => SetCommand::Move {
+                source: args.string()?,
+                destination: args.string()?,
+                member: args.string()?,
+            },
+            "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+                let op = match &name[..name.len().min(6)] {
+                    "SINTER" => SetOp::Inter,
+                    "SUNION" => SetOp::Union,
+                    _ => SetOp::Diff,
+                };
+                let destination = if name.ends_with("STORE") {
+                    Some(args.string()?)
+                } else {
+                    None
+                };
+                SetCommand::Combine {
+                    op,
+                    keys: args.rest()?,
+                    destination,
+                }
+            }
+            "SINTERCARD" => {
+                let numkeys: i64 = args.integer()?;
+                if numkeys <= 0 {
+                    return Err(CommandError::Other(
+                        "ERR numkeys should be greater than 0".into(),
+                    ));
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::Other(
+                        "ERR Number of keys can't be greater than number of args".into(),
+                    ));
+                }
+                let keys = (0..numkeys)
+                    .map(|_| args.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let limit = if args.keyword("LIMIT") {
+                    args.integer::<i64>()?
+                        .try_into()
+                        .map_err(|_| CommandError::Other("ERR LIMIT can't be negative".into()))?
+                } else {
+                    0
+                };
+                if !args.is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                SetCommand::InterCard { keys, limit }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        match self {
+            SetCommand::Add(..)
+            | SetCommand::Rem(..)
+            | SetCommand::Pop { .. }
+            | SetCommand::Move { .. } => true,
+            SetCommand::Combine { destination, .. } => destination.is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            SetCommand::Add(key, _)
+            | SetCommand::Rem(key, _)
+            | SetCommand::IsMember(key, _)
+            | SetCommand::MIsMember(key, _)
+            | SetCommand::Members(key)
+            | SetCommand::Card(key)
+            | SetCommand::Pop { key, .. }
+            | SetCommand::RandMember { key, .. } => vec![key],
+            SetCommand::Move {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+            SetCommand::Combine {
+                keys, destination, ..
+            } => destination.iter().chain(keys).map(String::as_str).collect(),
+            SetCommand::InterCard { keys, .. } => keys.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+fn bulk_strings<'a>(members: impl Iterator<Item = &'a String>) -> RespData {
+    RespData::Array(members.cloned().map(RespData::BulkString).collect())
+}
+
+/// Picks `count` random members the way SRANDMEMBER does: distinct members
+/// for a positive count, possibly repeated ones for a negative count.
+fn random_members(set: &Dict<String, ()>, count: i64) -> Vec<&String> {
+    if count < 0 {
+        return (0..count.unsigned_abs())
+            .filter_map(|_| set.random_key())
+            .collect();
+    }
+    random::sample_distinct(
+        set.len(),
+        count as usize,
+        || set.iter().map(|(member, _)| member).collect(),
+        || set.random_key().unwrap(),
+    )
+}
+
+/// Looks up every set in `keys`, failing if any of them holds another type.
+/// Missing keys count as empty sets.
+fn sets<'a>(db: &'a Db, keys: &[String]) -> Result<Vec<Option<&'a Dict<String, ()>>>, StoreError> {
+    keys.iter().map(|key| db.set_members(key)).collect()
+}
+
+/// The intersection of `sets`, stopping after `limit` members unless it is 0.
+/// Walks the smallest set and probes the others.
+fn intersection<'a>(sets: &[Option<&'a Dict<String, ()>>], limit: usize) -> Vec<&'a String> {
+    let Some(mut sets) = sets.iter().copied().collect::<Option<Vec<_>>>() else {
+        return Vec::new();
+    };
+    sets.sort_by_key(|set| set.len());
+    let (smallest, others) = sets.split_first().unwrap();
+    let members = smallest
+        .iter()
+        .map(|(member, _)| member)
+        .filter(|member| others.iter().all(|set| set.get(*member).is_some()));
+    if limit == 0 {
+        members.collect()
+    } else {
+        members.take(limit).collect()
+    }
+}
+
+fn combine<'a>(op: SetOp, sets: &[Option<&'a Dict<String, ()>>]) -> Vec<&'a String> {
+    match op {
+        SetOp::Inter => intersection(sets, 0),
+        SetOp::Union => {
+            let mut seen = HashSet::new();
+            sets.iter()
+                .flatten()
+                .flat_map(|set| set.iter().map(|(member, _)| member))
+                .filter(|member| seen.insert(*member))
+                .collect()
+        }
+        SetOp::Diff => {
+            let (first, others) = sets.split_first().unwrap();
+            first
+                .iter()
+                .flat_map(|set| set.iter().map(|(member, _)| member))
+                .filter(|member| {
+                    !others
+                        .iter()
+                        .flatten()
+                        .any(|set| set.get(*member).is_some())
+                })
+                .collect()
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: SetCommand) -> Result<RespData, StoreError> {
+    match command {
+        SetCommand::Add(key, members) => {
+            let set = db.set_members_or_create(&key)?;
+            let added = members
+                .into_iter()
+                .filter(|member| set.insert(member.clone(), ()).is_none())
+                .count();
+            if added > 0 {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(added as i64))
+        }
+        SetCommand::Rem(key, members) => {
+            let Some(set) = db.set_members_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let removed = members
+                .iter()
+                .filter(|member| set.remove(member.as_str()).is_some())
+                .count();
+            if removed > 0 {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(RespData::Integer(removed as i64))
+        }
+        SetCommand::IsMember(key, member) => Ok(RespData::Integer(
+            db.set_members(&key)?
+                .is_some_and(|set| set.get(&member).is_some()) as i64,
+        )),
+        SetCommand::MIsMember(key, members) => {
+            let set = db.set_members(&key)?;
+            Ok(RespData::Array(
+                members
+                    .iter()
+                    .map(|member| {
+                        RespData::Integer(set.is_some_and(|set| set.get(member).is_some()) as i64)
+                    })
+                    .collect(),
+            ))
+        }
+        SetCommand::Members(key) => Ok(bulk_strings(
+            db.set_members(&key)?
+                .into_iter()
+                .flat_map(|set| set.iter().map(|(member, _)| member)),
+        )),
+        SetCommand::Card(key) => Ok(RespData::Integer(
+            db.set_members(&key)?.map_or(0, |set| set.len() as i64),
+        )),
+        SetCommand::Pop { key, count } => {
+            let Some(set) = db.set_members_mut(&key)? else {
+                return Ok(match count {
+                    Some(_) => RespData::Array(Vec::new()),
+                    None => RespData::BulkStringNull,
+                });
+            };
+            let popped: Vec<String> = random_members(set, count.unwrap_or(1) as i64)
+                .into_iter()
+                .cloned()
+                .collect();
+            for member in &popped {
+                set.remove(member);
+            }
+            if !popped.is_empty() {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(match count {
+                Some(_) => bulk_strings(popped.iter()),
+                None => RespData::BulkString(popped.into_iter().next().unwrap()),
+            })
+        }
+        SetCommand::RandMember { key, count } => {
+            let set = db.set_members(&key)?;
+            Ok(match (set, count) {
+                (set, None) => set
+                    .and_then(|set| set.random_key())
+                    .cloned()
+                    .map(RespData::BulkString)
+                    .unwrap_or(RespData::BulkStringNull),
+                (None, Some(_)) => RespData::Array(Vec::new()),
+                (Some(set), Some(count)) => bulk_strings(random_members(set, count).into_iter()),
+            })
+        }
+        SetCommand::Move {
+            source,
+            destination,
+            member,
+        } => {
+            // Check the destination's type before touching the source.
+            db.set_members(&destination)?;
+            let Some(set) = db.set_members_mut(&source)? else {
+                return Ok(RespData::Integer(0));
+            };
+            if source == destination {
+                return Ok(RespData::Integer(set.get(&member).is_some() as i64));
+            }
+            if set.remove(&member).is_none() {
+                return Ok(RespData::Integer(0));
+            }
+            db.touch(&source);
+            db.remove_if_empty(&source);
+            db.set_members_or_create(&destination)?.insert(member, ());
+            db.touch(&destination);
+            Ok(RespData::Integer(1))
+        }
+        SetCommand::Combine {
+            op,
+            keys,
+            destination: None,
+        } => Ok(bulk_strings(combine(op, &sets(db, &keys)?).into_iter())),
+        SetCommand::Combine {
+            op,
+            keys,
+            destination: Some(destination),
+        } => {
+            let mut result = Dict::new();
+            for member in combine(op, &sets(db, &keys)?) {
+                result.insert(member.clone(), ());
+            }
+            let len = result.len();
+            db.remove(&destination);
+            if len > 0 {
+                db.set(destination, Value::Set(result), None);
+            }
+            Ok(RespData::Integer(len as i64))
+        }
+        SetCommand::InterCard { keys, limit } => Ok(RespData::Integer(
+            intersection(&sets(db, &keys)?, limit).len() as i64,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&str]) -> Dict<String, ()> {
+        let mut set = Dict::new();
+        for member in members {
+            set.insert(member.to_string(), ());
+        }
+        set
+    }
+
+    fn sorted(members: Vec<&String>) -> Vec<&str> {
+        let mut members: Vec<&str> = members.into_iter().map(String::as_str).collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn algebra() {
+        let a = set(&["a", "b", "c", "d"]);
+        let b = set(&["c", "d", "e"]);
+        let sets = [Some(&a), Some(&b), None];
+
+        assert!(combine(SetOp::Inter, &sets).is_empty());
+        assert_eq!(sorted(combine(SetOp::Inter, &sets[..2])), ["c", "d"]);
+        assert_eq!(
+            sorted(combine(SetOp::Union, &sets)),
+            ["a", "b", "c", "d", "e"]
+        );
+        assert_eq!(sorted(combine(SetOp::Diff, &sets)), ["a", "b"]);
+        assert!(combine(SetOp::Diff, &[None, Some(&a)]).is_empty());
+    }
+
+    #[test]
+    fn intersection_stops_at_limit() {
+        let a = set(&["a", "b", "c"]);
+        assert_eq!(intersection(&[Some(&a), Some(&a)], 2).len(), 2);
+        assert_eq!(intersection(&[Some(&a), Some(&a)], 0).len(), 3);
+    }
+
+    #[test]
+    fn srandmember_refuses_huge_negative_counts() {
+        let parse = |count: &str| {
+            let args = ["s", count].map(|arg| RespData::BulkString(arg.to_string()));
+            SetCommand::parse("SRANDMEMBER", &args)
+        };
+        assert!(parse("-16777216").is_ok());
+        assert_eq!(
+            parse("-9223372036854775808"),
+            Err(CommandError::Other("ERR value is out of range".into()))
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use std::cmp::Ordering;
+
+use crate::random;
+
+const MAX_LEVEL: usize = 32;
+/// Chance, out of 65536, that a node gets one more level (Redis's p = 1/4).
+const LEVEL_UP_CHANCE: u64 = 65536 / 4;
+const HEAD: usize = 0;
+
+struct Level {
+    forward: Option<usize>,
+    /// How many elements `forward` skips over, which is what makes rank
+    /// lookups logarithmic.
+    span: usize,
+}
+
+struct Node {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// Redis's `zskiplist`: elements ordered by score and then member, where every
+/// link also records how many elements it jumps. Nodes live in an arena and
+/// refer to each other by index; slot 0 is the header.
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    len: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+fn compare(score: f64, member: &str, other_score: f64, other_member: &str) -> Ordering {
+    score
+        .partial_cmp(&other_score)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| member.cmp(other_member))
+}
+
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && random::next_u64() & 0xffff < LEVEL_UP_CHANCE {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let head = Node {
+            member: String::new(),
+            score: 0.0,
+            backward: None,
+            levels: (0..MAX_LEVEL)
+                .map(|_| Level {
+                    forward: None,
+                    span: 0,
+                })
+                .collect(),
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            len: 0,
+            level: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+
+    fn span(&self, node: usize, level: usize) -> usize {
+        self.nodes[node].levels[level].span
+    }
+
+    fn is_before(&self, node: usize, score: f64, member: &str) -> bool {
+        let node = &self.nodes[node];
+        compare(node.score, &node.member, score, member) == Ordering::Less
+    }
+
+    /// For every level, the last node that sorts before `(score, member)`,
+    /// along with its rank.
+    fn predecessors(&self, score: f64, member: &str) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(node, i) {
+                if !self.is_before(next, score, member) {
+                    break;
+                }
+                rank[i] += self.span(node, i);
+                node = next;
+            }
+            update[i] = node;
+        }
+        (update, rank)
+    }
+
+    /// Inserts an element, which must not be present yet.
+    pub fn insert(&mut self, score: f64, member: String) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            backward: (update[0] != HEAD).then_some(update[0]),
+            levels: (0..level)
+                .map(|_| Level {
+                    forward: None,
+                    span: 0,
+                })
+                .collect(),
+        };
+        let new = match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let prev = update[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[new].levels[i] = Level {
+                forward: self.nodes[prev].levels[i].forward,
+                span: self.nodes[prev].levels[i].span - skipped,
+            };
+            self.nodes[prev].levels[i] = Level {
+                forward: Some(new),
+                span: skipped + 1,
+            };
+        }
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        match self.forward(new, 0) {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+        self.len += 1;
+    }
+
+    /// Removes an element, returning whether it was present.
+    pub fn remove(&mut self, score: f64, member: &str) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let Some(node) = self.forward(update[0], 0) else {
+            return false;
+        };
+        if self.nodes[node].score != score || self.nodes[node].member != member {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if self.forward(prev, i) == Some(node) {
+                self.nodes[prev].levels[i] = Level {
+                    forward: self.forward(node, i),
+                    span: self.span(prev, i) + self.span(node, i) - 1,
+                };
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+
+        let backward = self.nodes[node].backward;
+        match self.forward(node, 0) {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.forward(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[node].member = String::new();
+        self.nodes[node].levels = Vec::new();
+        self.free.push(node);
+        self.len -= 1;
+        true
+    }
+
+    /// The 0-based rank of an element.
+    pub fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut node = HEAD;
+        let mut rank = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(node, i) {
+                let next_node = &self.nodes[next];
+                if compare(next_node.score, &next_node.member, score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.span(node, i);
+                node = next;
+            }
+            if node != HEAD && self.nodes[node].score == score && self.nodes[node].member == member
+            {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// How many leading elements satisfy `pred`, which has to hold for a
+    /// prefix of the list and then never again (e.g. "score is below 10").
+    pub fn count_while(&self, pred: impl Fn(f64, &str) -> bool) -> usize {
+        let mut node = HEAD;
+        let mut count = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(node, i) {
+                if !pred(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                count += self.span(node, i);
+                node = next;
+            }
+        }
+        count
+    }
+
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let mut node = HEAD;
+        let mut traversed = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(node, i) {
+                if traversed + self.span(node, i) > rank + 1 {
+                    break;
+                }
+                traversed += self.span(node, i);
+                node = next;
+            }
+            if traversed == rank + 1 {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Iterates from the element at `rank` towards the end.
+    pub fn iter_from(&self, rank: usize) -> Iter<'_> {
+        Iter {
+            list: self,
+            node: self.node_at(rank),
+            reverse: false,
+        }
+    }
+
+    /// Iterates from the element at `rank` back towards the start.
+    pub fn iter_rev_from(&self, rank: usize) -> Iter<'_> {
+        Iter {
+            list: self,
+            node: self.node_at(rank),
+            reverse: true,
+        }
+    }
+
+    pub fn last(&self) -> Option<(&String, f64)> {
+        self.tail
+            .map(|node| (&self.nodes[node].member, self.nodes[node].score))
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    node: Option<usize>,
+    reverse: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a String, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.list.nodes[self.node?];
+        self.node = if self.reverse {
+            node.backward
+        } else {
+            node.levels[0].forward
+        };
+        Some((&node.member, node.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(iter: Iter<'_>) -> Vec<&str> {
+        iter.map(|(member, _)| member.as_str()).collect()
+    }
+
+    #[test]
+    fn orders_by_score_then_member() {
+        let mut list = SkipList::new();
+        list.insert(2.0, "b".to_string());
+        list.insert(1.0, "z".to_string());
+        list.insert(2.0, "a".to_string());
+        assert_eq!(members(list.iter_from(0)), ["z", "a", "b"]);
+        assert_eq!(members(list.iter_rev_from(2)), ["b", "a", "z"]);
+        assert_eq!(list.last(), Some((&"b".to_string(), 2.0)));
+    }
+
+    #[test]
+    fn ranks_survive_inserts_and_removes() {
+        let mut list = SkipList::new();
+        for i in 0..1000 {
+            list.insert(i as f64, format!("m{}", i));
+        }
+        for i in (0..1000).step_by(3) {
+            assert!(list.remove(i as f64, &format!("m{}", i)));
+        }
+        assert!(!list.remove(0.0, "m0"));
+
+        let remaining: Vec<usize> = (0..1000).filter(|i| i % 3 != 0).collect();
+        assert_eq!(list.len(), remaining.len());
+        for (rank, i) in remaining.iter().enumerate() {
+            assert_eq!(list.rank(*i as f64, &format!("m{}", i)), Some(rank));
+            assert_eq!(
+                list.iter_from(rank)
+                    .next()
+                    .map(|(member, _)| member.clone()),
+                Some(format!("m{}", i))
+            );
+        }
+        assert_eq!(list.rank(3.0, "m3"), None);
+        assert!(list.iter_from(remaining.len()).next().is_none());
+    }
+
+    #[test]
+    fn count_while_counts_a_prefix() {
+        let mut list = SkipList::new();
+        for i in 0..100 {
+            list.insert(i as f64, format!("m{}", i));
+        }
+        assert_eq!(list.count_while(|score, _| score < 42.0), 42);
+        assert_eq!(list.count_while(|score, _| score <= 42.0), 43);
+        assert_eq!(list.count_while(|_, _| false), 0);
+        assert_eq!(list.count_while(|_, _| true), 100);
+    }
+}
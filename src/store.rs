@@ -1,41 +1,1120 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashSet, VecDeque},
+    net::SocketAddr,
     sync::{Arc, RwLock},
-    time::Instant,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use crate::{
+    bloom::BloomFilter, cms::CountMinSketch, cuckoo::CuckooFilter, dict::Dict, glob::glob_match,
+    hash::Hash, hyperloglog::HyperLogLog, json::Json, search::Index, stream::Stream,
+    tdigest::TDigest, timeseries::TimeSeries, topk::TopK, vector::VectorSet, zset::SortedSet,
+};
+
+/// Keys sampled per iteration of the active expire cycle.
+const ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP: usize = 20;
+/// Keep sampling while more than this percentage of a sample was expired.
+const ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE: usize = 25;
+/// Upper bound on the time a single cycle may take (25% of a 100ms tick).
+const ACTIVE_EXPIRE_CYCLE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+/// The current Unix time in milliseconds, for expiry times clients give us
+/// as timestamps.
+pub fn unix_time_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum StoreError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR index out of range")]
+    IndexOutOfRange,
+    #[error("ERR hash value is not an integer")]
+    HashValueNotInteger,
+    #[error("ERR hash value is not a float")]
+    HashValueNotFloat,
+    #[error("ERR increment or decrement would overflow")]
+    Overflow,
+    #[error("ERR increment would produce NaN or Infinity")]
+    NanOrInfinity,
+    #[error("ERR resulting score is not a number (NaN)")]
+    NotANumber,
+    #[error("ERR The ID specified in XADD is equal or smaller than the target stream top item")]
+    StreamIdTooSmall,
+    #[error("ERR The stream has exhausted the last possible ID, unable to add more items")]
+    StreamExhausted,
+    #[error("ERR could not decode requested zset member")]
+    UnknownGeoMember,
+    #[error("WRONGTYPE Key is not a valid HyperLogLog string value.")]
+    NotHyperLogLog,
+    #[error("ERR HLL encoding is not sparse")]
+    HllNotSparse,
+    #[error("ERR The specified key does not exist")]
+    MissingKey,
+    #[error("ERR Path '{0}' does not exist")]
+    JsonPathMissing(String),
+    #[error("WRONGTYPE wrong type of path value - expected {0} but found {1}")]
+    JsonWrongType(&'static str, &'static str),
+    #[error("ERR new objects must be created at the root")]
+    JsonNewAtRoot,
+    #[error("ERR Err: wrong static path")]
+    JsonWrongStaticPath,
+    #[error("ERR could not perform this operation on a key that doesn't exist")]
+    JsonMissingKey,
+    #[error("ERR TSDB: {0}")]
+    Tsdb(&'static str),
+    #[error("ERR item exists")]
+    BloomExists,
+    #[error("ERR not found")]
+    BloomNotFound,
+    #[error("ERR non scaling filter is full")]
+    BloomFull,
+    #[error("ERR filter would be too large")]
+    BloomTooLarge,
+    #[error("ERR Not found")]
+    CuckooNotFound,
+    #[error("ERR Filter is full")]
+    CuckooFull,
+    #[error("ERR CMS: {0}")]
+    Cms(&'static str),
+    #[error("ERR TopK: {0}")]
+    TopK(&'static str),
+    #[error("ERR T-Digest: {0}")]
+    TDigest(&'static str),
+    #[error("ERR Unknown index name")]
+    UnknownIndex,
+    #[error("ERR Index already exists")]
+    IndexExists,
+    #[error("ERR Unknown field `{0}`")]
+    UnknownField(String),
+    #[error("ERR Field `{0}` is not a {1} field")]
+    FieldType(String, &'static str),
+    #[error("ERR Vector dimension mismatch - got {0} but set has {1}")]
+    VectorDimension(usize, usize),
+    #[error("ERR the vector set uses a different metric")]
+    VectorMetric,
+    #[error("ERR element not found in set")]
+    ElementNotFound,
+    #[error("NOGROUP No such key '{0}' or consumer group '{1}'")]
+    NoGroup(String, String),
+    #[error("NOGROUP No such key '{0}' or consumer group '{1}' in XREADGROUP with GROUP option")]
+    NoGroupToRead(String, String),
+    #[error("NOGROUP No such consumer group '{1}' for key name '{0}'")]
+    NoSuchGroup(String, String),
+    #[error("BUSYGROUP Consumer Group name already exists")]
+    BusyGroup,
+    #[error(
+        "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want \
+         to use the MKSTREAM option to create an empty stream automatically."
+    )]
+    XGroupNoKey,
+}
+
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<String>),
+    Hash(Hash),
+    Set(Dict<String, ()>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+    HyperLogLog(HyperLogLog),
+    Json(Json),
+    TimeSeries(TimeSeries),
+    Bloom(BloomFilter),
+    Cuckoo(CuckooFilter),
+    Cms(CountMinSketch),
+    TopK(TopK),
+    TDigest(TDigest),
+    VectorSet(VectorSet),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Stream(_) => "stream",
+            // Redis keeps HyperLogLogs in strings.
+            Value::HyperLogLog(_) => "string",
+            Value::Json(_) => "ReJSON-RL",
+            Value::TimeSeries(_) => "TSDB-TYPE",
+            Value::Bloom(_) => "MBbloom--",
+            Value::Cuckoo(_) => "MBbloomCF",
+            Value::Cms(_) => "CMSk-TYPE",
+            Value::TopK(_) => "TopK-TYPE",
+            Value::TDigest(_) => "TDIS-TYPE",
+            Value::VectorSet(_) => "vectorset",
+        }
+    }
+
+    /// Collections are deleted as soon as their last element is removed.
+    fn is_empty(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.len() == 0,
+            Value::Set(set) => set.len() == 0,
+            Value::SortedSet(zset) => zset.len() == 0,
+            // Streams outlive their entries, keeping their last ID.
+            Value::Stream(_) => false,
+            Value::HyperLogLog(_) => false,
+            Value::Json(_) => false,
+            Value::TimeSeries(_) => false,
+            Value::Bloom(_) => false,
+            Value::Cuckoo(_) => false,
+            Value::Cms(_) => false,
+            Value::TopK(_) => false,
+            Value::TDigest(_) => false,
+            Value::VectorSet(set) => set.len() == 0,
+        }
+    }
+}
+
 struct Data {
-    value: String,
+    value: Value,
     expires_at: Option<Instant>,
 }
 
+impl Data {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+pub struct ScanResult {
+    pub cursor: u64,
+    pub keys: Vec<String>,
+}
+
+/// The clients WATCHing keys of a database, for EXEC to fail when a key
+/// one of them watches is modified.
+#[derive(Default)]
+struct Watches {
+    /// The clients watching each key, with whether it existed when they
+    /// started to, so that EXEC can tell it expired even if it wasn't
+    /// deleted yet.
+    keys: Dict<String, Vec<(SocketAddr, bool)>>,
+    /// Clients one of whose keys was modified since they watched it.
+    dirty: HashSet<SocketAddr>,
+}
+
+#[derive(Default)]
+pub struct Db {
+    entries: Dict<String, Data>,
+    /// Keys that carry a TTL, so the expire cycle can sample only those.
+    volatile: Dict<String, ()>,
+    /// Hashes that have (or recently had) fields with a TTL.
+    volatile_hashes: Dict<String, ()>,
+    /// Keys that clients may be blocked on and that just got something to
+    /// serve them, e.g. a list that was created by a push.
+    ready_keys: Vec<String>,
+    /// FT.CREATE indexes by name, kept in step with the hashes they cover.
+    indexes: BTreeMap<String, Index>,
+    watches: Watches,
+}
+
+impl Db {
+    fn remove_data(&mut self, key: &str) -> Option<Data> {
+        self.volatile.remove(key);
+        self.volatile_hashes.remove(key);
+        let data = self.entries.remove(key);
+        self.reindex(key);
+        self.touch(key);
+        data
+    }
+
+    fn insert_data(&mut self, key: String, data: Data) {
+        if matches!(data.value, Value::List(_) | Value::SortedSet(_)) {
+            self.ready_keys.push(key.clone());
+        }
+        if matches!(&data.value, Value::Hash(hash) if hash.has_volatile_fields()) {
+            self.volatile_hashes.insert(key.clone(), ());
+        }
+        if data.expires_at.is_some() {
+            self.volatile.insert(key.clone(), ());
+        } else {
+            self.volatile.remove(&key);
+        }
+        self.entries.insert(key.clone(), data);
+        self.reindex(&key);
+        self.touch(&key);
+    }
+
+    /// Flags the clients watching `key` after it was modified. Setting and
+    /// deleting keys does this already; commands that change a value in
+    /// place call it once they did change something, as Redis's
+    /// `signalModifiedKey`.
+    pub fn touch(&mut self, key: &str) {
+        if let Some(clients) = self.watches.keys.get(key) {
+            self.watches
+                .dirty
+                .extend(clients.iter().map(|(client, _)| *client));
+        }
+    }
+
+    /// Flags every client watching a key, for when the whole database
+    /// changes at once.
+    fn touch_all(&mut self) {
+        let clients = self.watches.keys.iter().flat_map(|(_, clients)| clients);
+        self.watches
+            .dirty
+            .extend(clients.map(|(client, _)| *client));
+    }
+
+    fn expire_if_needed(&mut self, key: &str, now: Instant) -> bool {
+        let expired = self
+            .entries
+            .get(key)
+            .is_some_and(|data| data.is_expired(now));
+        if expired {
+            self.remove_data(key);
+        }
+        expired
+    }
+
+    /// Looks up a live key; expired keys read as missing.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries
+            .get(key)
+            .filter(|data| !data.is_expired(Instant::now()))
+            .map(|data| &data.value)
+    }
+
+    /// Looks up a live key for writing. An expired key is dropped first, so
+    /// the caller sees it as missing and may create it afresh.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.expire_if_needed(key, Instant::now());
+        self.entries.get_mut(key).map(|data| &mut data.value)
+    }
+
+    /// The live keys and their values, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(move |(_, data)| !data.is_expired(now))
+            .map(|(key, data)| (key.as_str(), &data.value))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Stores `value` under `key`, replacing any previous value and TTL.
+    pub fn set(&mut self, key: String, value: Value, expires_at: Option<Instant>) {
+        self.insert_data(key, Data { value, expires_at });
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.remove_data(key).map(|data| data.value)
+    }
+
+    /// Lets clients blocked on `key` know it may have something for them,
+    /// for changes that don't create the key, such as XADD.
+    pub fn signal_ready(&mut self, key: &str) {
+        self.ready_keys.push(key.to_string());
+    }
+
+    /// Deletes `key` if it holds a collection that has become empty.
+    pub fn remove_if_empty(&mut self, key: &str) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|data| data.value.is_empty())
+        {
+            self.remove_data(key);
+        }
+    }
+
+    /// The bytes of the string at `key`. HyperLogLogs are strings too, as
+    /// far as clients can tell.
+    pub fn string(&self, key: &str) -> Result<Option<&[u8]>, StoreError> {
+        match self.get(key) {
+            Some(Value::String(string)) => Ok(Some(string)),
+            Some(Value::HyperLogLog(hll)) => Ok(Some(hll.as_bytes())),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `string`, for writing. A HyperLogLog written to this way becomes
+    /// a plain string.
+    pub fn string_mut(&mut self, key: &str) -> Result<Option<&mut Vec<u8>>, StoreError> {
+        if let Some(value) = self.get_mut(key) {
+            if let Value::HyperLogLog(hll) = value {
+                let bytes = std::mem::take(hll).into_bytes();
+                *value = Value::String(bytes);
+            }
+        }
+        match self.get_mut(key) {
+            Some(Value::String(string)) => Ok(Some(string)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `string_mut`, but creates an empty string when the key is
+    /// missing.
+    pub fn string_or_create(&mut self, key: &str) -> Result<&mut Vec<u8>, StoreError> {
+        if self.string_mut(key)?.is_none() {
+            self.set(key.to_string(), Value::String(Vec::new()), None);
+        }
+        Ok(self.string_mut(key)?.unwrap())
+    }
+
+    pub fn list(&self, key: &str) -> Result<Option<&VecDeque<String>>, StoreError> {
+        match self.get(key) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_mut(&mut self, key: &str) -> Result<Option<&mut VecDeque<String>>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `list_mut`, but creates an empty list when the key is missing.
+    pub fn list_or_create(&mut self, key: &str) -> Result<&mut VecDeque<String>, StoreError> {
+        if self.list_mut(key)?.is_none() {
+            self.set(key.to_string(), Value::List(VecDeque::new()), None);
+        }
+        Ok(self.list_mut(key)?.unwrap())
+    }
+
+    pub fn hash(&self, key: &str) -> Result<Option<&Hash>, StoreError> {
+        match self.get(key) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn hash_mut(&mut self, key: &str) -> Result<Option<&mut Hash>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Hash(hash)) => Ok(Some(hash)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `hash_mut`, but creates an empty hash when the key is missing.
+    pub fn hash_or_create(&mut self, key: &str) -> Result<&mut Hash, StoreError> {
+        if self.hash_mut(key)?.is_none() {
+            self.set(key.to_string(), Value::Hash(Hash::default()), None);
+        }
+        Ok(self.hash_mut(key)?.unwrap())
+    }
+
+    pub fn set_members(&self, key: &str) -> Result<Option<&Dict<String, ()>>, StoreError> {
+        match self.get(key) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_members_mut(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<&mut Dict<String, ()>>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `set_members_mut`, but creates an empty set when the key is
+    /// missing.
+    pub fn set_members_or_create(
+        &mut self,
+        key: &str,
+    ) -> Result<&mut Dict<String, ()>, StoreError> {
+        if self.set_members_mut(key)?.is_none() {
+            self.set(key.to_string(), Value::Set(Dict::new()), None);
+        }
+        Ok(self.set_members_mut(key)?.unwrap())
+    }
+
+    pub fn sorted_set(&self, key: &str) -> Result<Option<&SortedSet>, StoreError> {
+        match self.get(key) {
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn sorted_set_mut(&mut self, key: &str) -> Result<Option<&mut SortedSet>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `sorted_set_mut`, but creates an empty sorted set when the key is
+    /// missing.
+    pub fn sorted_set_or_create(&mut self, key: &str) -> Result<&mut SortedSet, StoreError> {
+        if self.sorted_set_mut(key)?.is_none() {
+            self.set(
+                key.to_string(),
+                Value::SortedSet(SortedSet::default()),
+                None,
+            );
+        }
+        Ok(self.sorted_set_mut(key)?.unwrap())
+    }
+
+    pub fn stream(&self, key: &str) -> Result<Option<&Stream>, StoreError> {
+        match self.get(key) {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn stream_mut(&mut self, key: &str) -> Result<Option<&mut Stream>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `stream_mut`, but creates an empty stream when the key is
+    /// missing.
+    pub fn stream_or_create(&mut self, key: &str) -> Result<&mut Stream, StoreError> {
+        if self.stream_mut(key)?.is_none() {
+            self.set(key.to_string(), Value::Stream(Stream::default()), None);
+        }
+        Ok(self.stream_mut(key)?.unwrap())
+    }
+
+    /// The HyperLogLog at `key`, which may also be a string holding one,
+    /// e.g. one that was written back with SET.
+    pub fn hyperloglog(&self, key: &str) -> Result<Option<Cow<'_, HyperLogLog>>, StoreError> {
+        match self.get(key) {
+            Some(Value::HyperLogLog(hll)) => Ok(Some(Cow::Borrowed(hll))),
+            Some(Value::String(bytes)) => HyperLogLog::from_bytes(bytes)
+                .map(|hll| Some(Cow::Owned(hll)))
+                .ok_or(StoreError::NotHyperLogLog),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `hyperloglog`, for writing. A string holding a HyperLogLog
+    /// becomes one again, the reverse of `string_mut`.
+    pub fn hyperloglog_mut(&mut self, key: &str) -> Result<Option<&mut HyperLogLog>, StoreError> {
+        if let Some(value) = self.get_mut(key) {
+            if let Value::String(bytes) = value {
+                let hll = HyperLogLog::from_bytes(bytes).ok_or(StoreError::NotHyperLogLog)?;
+                *value = Value::HyperLogLog(hll);
+            }
+        }
+        match self.get_mut(key) {
+            Some(Value::HyperLogLog(hll)) => Ok(Some(hll)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `hyperloglog_mut`, but creates an empty HyperLogLog when the key
+    /// is missing.
+    pub fn hyperloglog_or_create(&mut self, key: &str) -> Result<&mut HyperLogLog, StoreError> {
+        if self.hyperloglog_mut(key)?.is_none() {
+            self.set(
+                key.to_string(),
+                Value::HyperLogLog(HyperLogLog::default()),
+                None,
+            );
+        }
+        Ok(self.hyperloglog_mut(key)?.unwrap())
+    }
+
+    pub fn json(&self, key: &str) -> Result<Option<&Json>, StoreError> {
+        match self.get(key) {
+            Some(Value::Json(json)) => Ok(Some(json)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn json_mut(&mut self, key: &str) -> Result<Option<&mut Json>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Json(json)) => Ok(Some(json)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn timeseries(&self, key: &str) -> Result<Option<&TimeSeries>, StoreError> {
+        match self.get(key) {
+            Some(Value::TimeSeries(series)) => Ok(Some(series)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn timeseries_mut(&mut self, key: &str) -> Result<Option<&mut TimeSeries>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::TimeSeries(series)) => Ok(Some(series)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn bloom(&self, key: &str) -> Result<Option<&BloomFilter>, StoreError> {
+        match self.get(key) {
+            Some(Value::Bloom(filter)) => Ok(Some(filter)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn bloom_mut(&mut self, key: &str) -> Result<Option<&mut BloomFilter>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Bloom(filter)) => Ok(Some(filter)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn cuckoo(&self, key: &str) -> Result<Option<&CuckooFilter>, StoreError> {
+        match self.get(key) {
+            Some(Value::Cuckoo(filter)) => Ok(Some(filter)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn cuckoo_mut(&mut self, key: &str) -> Result<Option<&mut CuckooFilter>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Cuckoo(filter)) => Ok(Some(filter)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn cms(&self, key: &str) -> Result<Option<&CountMinSketch>, StoreError> {
+        match self.get(key) {
+            Some(Value::Cms(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn cms_mut(&mut self, key: &str) -> Result<Option<&mut CountMinSketch>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::Cms(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn topk(&self, key: &str) -> Result<Option<&TopK>, StoreError> {
+        match self.get(key) {
+            Some(Value::TopK(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn topk_mut(&mut self, key: &str) -> Result<Option<&mut TopK>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::TopK(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn tdigest(&self, key: &str) -> Result<Option<&TDigest>, StoreError> {
+        match self.get(key) {
+            Some(Value::TDigest(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn tdigest_mut(&mut self, key: &str) -> Result<Option<&mut TDigest>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::TDigest(sketch)) => Ok(Some(sketch)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn vector_set(&self, key: &str) -> Result<Option<&VectorSet>, StoreError> {
+        match self.get(key) {
+            Some(Value::VectorSet(set)) => Ok(Some(set)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn vector_set_mut(&mut self, key: &str) -> Result<Option<&mut VectorSet>, StoreError> {
+        match self.get_mut(key) {
+            Some(Value::VectorSet(set)) => Ok(Some(set)),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn indexes(&self) -> &BTreeMap<String, Index> {
+        &self.indexes
+    }
+
+    pub fn indexes_mut(&mut self) -> &mut BTreeMap<String, Index> {
+        &mut self.indexes
+    }
+
+    /// Updates the search indexes covering `key` after the hash there
+    /// changed, was created or went away.
+    pub fn reindex(&mut self, key: &str) {
+        if self.indexes.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let hash = match self.entries.get(key) {
+            Some(data) if data.is_expired(now) => None,
+            Some(Data {
+                value: Value::Hash(hash),
+                ..
+            }) => Some(hash),
+            _ => None,
+        };
+        for index in self.indexes.values_mut() {
+            index.update(key, hash);
+        }
+    }
+
+    /// Lets the expire cycle know that the hash at `key` has fields with a
+    /// TTL.
+    pub fn track_volatile_hash(&mut self, key: &str) {
+        self.volatile_hashes.insert(key.to_string(), ());
+    }
+
+    /// Deletes the expired fields of the hash at `key`, and the key itself if
+    /// none are left. Returns the deleted fields.
+    fn expire_hash_fields(&mut self, key: &str, now: i64) -> Vec<String> {
+        if self.volatile_hashes.get(key).is_none() {
+            return Vec::new();
+        }
+        let Some(Value::Hash(hash)) = self.entries.get_mut(key).map(|data| &mut data.value) else {
+            self.volatile_hashes.remove(key);
+            return Vec::new();
+        };
+
+        let fields = hash.remove_expired(now);
+        if !hash.has_volatile_fields() {
+            self.volatile_hashes.remove(key);
+        }
+        self.remove_if_empty(key);
+        if !fields.is_empty() {
+            self.reindex(key);
+            self.touch(key);
+        }
+        fields
+    }
+}
+
 #[derive(Clone)]
 pub struct Store {
-    data: Arc<RwLock<HashMap<String, Data>>>,
+    dbs: Arc<RwLock<Vec<Db>>>,
 }
 
 impl Store {
-    pub fn new() -> Self {
+    pub fn new(databases: usize) -> Self {
         Store {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            dbs: Arc::new(RwLock::new((0..databases).map(|_| Db::default()).collect())),
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        let data = self.data.read().unwrap();
-        data.get(key)
-            .and_then(|Data { value, expires_at }| match expires_at {
-                Some(expires_at) if expires_at.clone() <= Instant::now() => None,
-                _ => Some(value.clone()),
+    pub fn databases(&self) -> usize {
+        self.dbs.read().unwrap().len()
+    }
+
+    pub fn read<R>(&self, db: usize, f: impl FnOnce(&Db) -> R) -> R {
+        let dbs = self.dbs.read().unwrap();
+        f(&dbs[db])
+    }
+
+    pub fn write<R>(&self, db: usize, f: impl FnOnce(&mut Db) -> R) -> R {
+        let mut dbs = self.dbs.write().unwrap();
+        f(&mut dbs[db])
+    }
+
+    /// Deletes `key` if its TTL has passed. Returns true when the key was
+    /// removed, so the caller can propagate a `DEL` to replicas.
+    pub fn expire_if_needed(&self, db: usize, key: &str) -> bool {
+        let mut dbs = self.dbs.write().unwrap();
+        dbs[db].expire_if_needed(key, Instant::now())
+    }
+
+    pub fn expire_hash_fields(&self, db: usize, key: &str) -> Vec<String> {
+        let mut dbs = self.dbs.write().unwrap();
+        dbs[db].expire_hash_fields(key, unix_time_ms())
+    }
+
+    /// Moves `key` from database `src` to `dst`, keeping its TTL. Fails when
+    /// the key is missing from `src` or already present in `dst`.
+    pub fn move_key(&self, src: usize, dst: usize, key: &str) -> bool {
+        let mut dbs = self.dbs.write().unwrap();
+        if dbs[dst].contains(key) || !dbs[src].contains(key) {
+            return false;
+        }
+
+        let data = dbs[src].remove_data(key).unwrap();
+        dbs[dst].insert_data(key.to_string(), data);
+        true
+    }
+
+    /// Swaps the contents of two databases. Clients watching keys stay with
+    /// the database they picked, and all of them see their keys modified.
+    pub fn swap_dbs(&self, a: usize, b: usize) {
+        let mut dbs = self.dbs.write().unwrap();
+        dbs.swap(a, b);
+        let watches = std::mem::take(&mut dbs[a].watches);
+        dbs[a].watches = std::mem::replace(&mut dbs[b].watches, watches);
+        dbs[a].touch_all();
+        dbs[b].touch_all();
+    }
+
+    /// Deletes every key of every database. Search indexes stay defined but
+    /// empty.
+    pub fn flush_all(&self) {
+        let mut dbs = self.dbs.write().unwrap();
+        for db in dbs.iter_mut() {
+            let mut indexes = std::mem::take(&mut db.indexes);
+            indexes.values_mut().for_each(Index::clear);
+            let watches = std::mem::take(&mut db.watches);
+            *db = Db {
+                indexes,
+                watches,
+                ..Db::default()
+            };
+            db.touch_all();
+        }
+    }
+
+    /// Starts watching `keys` of database `db` on behalf of `client`.
+    pub fn watch(&self, db: usize, keys: &[String], client: SocketAddr) {
+        let mut dbs = self.dbs.write().unwrap();
+        let db = &mut dbs[db];
+        for key in keys {
+            let exists = db.contains(key);
+            match db.watches.keys.get_mut(key) {
+                Some(clients) => clients.push((client, exists)),
+                None => {
+                    db.watches.keys.insert(key.clone(), vec![(client, exists)]);
+                }
+            }
+        }
+    }
+
+    /// Whether any of the keys `client` watches, given as database and key,
+    /// was modified or expired since.
+    pub fn watched_keys_modified(&self, client: SocketAddr, keys: &[(usize, String)]) -> bool {
+        let dbs = self.dbs.read().unwrap();
+        keys.iter().any(|(db, key)| {
+            let db = &dbs[*db];
+            let existed = db.watches.keys.get(key).is_some_and(|clients| {
+                clients
+                    .iter()
+                    .any(|(watcher, existed)| *watcher == client && *existed)
+            });
+            db.watches.dirty.contains(&client) || (existed && !db.contains(key))
+        })
+    }
+
+    /// Stops `client` watching the keys it watches.
+    pub fn unwatch(&self, client: SocketAddr, keys: &[(usize, String)]) {
+        let mut dbs = self.dbs.write().unwrap();
+        for (db, key) in keys {
+            let watches = &mut dbs[*db].watches;
+            watches.dirty.remove(&client);
+            if let Some(clients) = watches.keys.get_mut(key) {
+                clients.retain(|(watcher, _)| *watcher != client);
+                if clients.is_empty() {
+                    watches.keys.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Drains the keys that became ready for blocked clients, across all
+    /// databases.
+    pub fn take_ready_keys(&self) -> Vec<(usize, String)> {
+        let mut dbs = self.dbs.write().unwrap();
+        dbs.iter_mut()
+            .enumerate()
+            .flat_map(|(index, db)| db.ready_keys.drain(..).map(move |key| (index, key)))
+            .collect()
+    }
+
+    /// One run of the active expire cycle, modelled on Redis's adaptive
+    /// sampling: for each database, pick random keys with a TTL, delete the
+    /// expired ones and try again while more than 25% of the sample had
+    /// expired. Hash fields with a TTL are reclaimed the same way, sampling
+    /// hashes instead of keys. Returns the deletions to feed replicas, as
+    /// `DEL` and `HDEL` commands along with their database.
+    pub fn active_expire_cycle(&self) -> Vec<(usize, Vec<String>)> {
+        let mut dbs = self.dbs.write().unwrap();
+        let started_at = Instant::now();
+        let mut deletions = Vec::new();
+
+        for (index, db) in dbs.iter_mut().enumerate() {
+            loop {
+                let sampled = db.volatile.len().min(ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP);
+                if sampled == 0 {
+                    break;
+                }
+
+                let now = Instant::now();
+                let mut expired = 0;
+                for _ in 0..sampled {
+                    let key = match db.volatile.random_key() {
+                        Some(key) => key.clone(),
+                        None => break,
+                    };
+                    if db.expire_if_needed(&key, now) {
+                        deletions.push((index, vec!["DEL".to_string(), key]));
+                        expired += 1;
+                    }
+                }
+
+                if expired * 100 <= sampled * ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE
+                    || started_at.elapsed() > ACTIVE_EXPIRE_CYCLE_TIME_LIMIT
+                {
+                    break;
+                }
+            }
+
+            loop {
+                let sampled = db
+                    .volatile_hashes
+                    .len()
+                    .min(ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP);
+                if sampled == 0 {
+                    break;
+                }
+
+                let now = unix_time_ms();
+                let mut expired = 0;
+                for _ in 0..sampled {
+                    let key = match db.volatile_hashes.random_key() {
+                        Some(key) => key.clone(),
+                        None => break,
+                    };
+                    let fields = db.expire_hash_fields(&key, now);
+                    if !fields.is_empty() {
+                        let mut args = vec!["HDEL".to_string(), key];
+                        args.extend(fields);
+                        deletions.push((index, args));
+                        expired += 1;
+                    }
+                }
+
+                if expired * 100 <= sampled * ACTIVE_EXPIRE_CYCLE_ACCEPTABLE_STALE
+                    || started_at.elapsed() > ACTIVE_EXPIRE_CYCLE_TIME_LIMIT
+                {
+                    break;
+                }
+            }
+
+            if started_at.elapsed() > ACTIVE_EXPIRE_CYCLE_TIME_LIMIT {
+                break;
+            }
+        }
+
+        deletions
+    }
+
+    pub fn keys(&self, db: usize, pattern: &str) -> Vec<String> {
+        let dbs = self.dbs.read().unwrap();
+        let now = Instant::now();
+        dbs[db]
+            .entries
+            .iter()
+            .filter(|(key, data)| {
+                !data.is_expired(now) && glob_match(pattern.as_bytes(), key.as_bytes(), false)
             })
+            .map(|(key, _)| key.clone())
+            .collect()
     }
 
-    pub fn set(&self, key: String, value: String, px: Option<u64>) {
-        let mut data = self.data.write().unwrap();
-        let value = Data {
-            value,
-            expires_at: px.map(|px| Instant::now() + std::time::Duration::from_millis(px)),
-        };
-        data.insert(key, value);
+    /// Walks the keyspace a few buckets at a time, like Redis's `SCAN`. Visits
+    /// buckets until `count` keys were collected (or ten times that many
+    /// buckets were tried), then hands back the cursor to resume from.
+    pub fn scan(
+        &self,
+        db: usize,
+        cursor: u64,
+        pattern: Option<&str>,
+        count: usize,
+        type_name: Option<&str>,
+    ) -> ScanResult {
+        let dbs = self.dbs.read().unwrap();
+        let now = Instant::now();
+        let mut keys = Vec::new();
+        let mut cursor = cursor;
+        let mut max_iterations = count * 10;
+
+        loop {
+            cursor = dbs[db].entries.scan(cursor, |key, data| {
+                if data.is_expired(now) {
+                    return;
+                }
+                if let Some(pattern) = pattern {
+                    if !glob_match(pattern.as_bytes(), key.as_bytes(), false) {
+                        return;
+                    }
+                }
+                if let Some(type_name) = type_name {
+                    if !data.value.type_name().eq_ignore_ascii_case(type_name) {
+                        return;
+                    }
+                }
+                keys.push(key.clone());
+            });
+
+            max_iterations -= 1;
+            if cursor == 0 || max_iterations == 0 || keys.len() >= count {
+                break;
+            }
+        }
+
+        ScanResult { cursor, keys }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing_until_removed() {
+        let store = Store::new(1);
+        let past = Some(Instant::now());
+        let future = Some(Instant::now() + Duration::from_secs(60));
+        store.write(0, |db| {
+            db.set("gone".to_string(), string("a"), past);
+            db.set("kept".to_string(), string("b"), future);
+        });
+
+        assert!(!store.read(0, |db| db.contains("gone")));
+        assert!(store.read(0, |db| db.contains("kept")));
+        assert!(store.expire_if_needed(0, "gone"));
+        assert!(!store.expire_if_needed(0, "gone"));
+        assert!(!store.expire_if_needed(0, "kept"));
+        assert_eq!(store.keys(0, "*"), ["kept"]);
+    }
+
+    #[test]
+    fn get_mut_drops_an_expired_key() {
+        let mut db = Db::default();
+        db.set("key".to_string(), string("a"), Some(Instant::now()));
+        assert!(db.get_mut("key").is_none());
+        assert_eq!(db.entries.len(), 0);
+        assert_eq!(db.volatile.len(), 0);
+    }
+
+    #[test]
+    fn active_expire_cycle_deletes_expired_keys_in_every_db() {
+        let store = Store::new(2);
+        let past = Some(Instant::now());
+        let future = Some(Instant::now() + Duration::from_secs(60));
+        store.write(0, |db| {
+            db.set("live".to_string(), string("a"), future);
+            db.set("persistent".to_string(), string("b"), None);
+        });
+        store.write(1, |db| {
+            for i in 0..50 {
+                db.set(format!("key:{i}"), string("c"), past);
+            }
+        });
+
+        let mut deletions = store.active_expire_cycle();
+        deletions.sort();
+        let mut expected: Vec<_> = (0..50)
+            .map(|i| (1, vec!["DEL".to_string(), format!("key:{i}")]))
+            .collect();
+        expected.sort();
+        assert_eq!(deletions, expected);
+        assert_eq!(store.read(1, |db| db.entries.len()), 0);
+        assert_eq!(store.read(0, |db| db.entries.len()), 2);
+        assert!(store.active_expire_cycle().is_empty());
+    }
+
+    fn client(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn writes_to_a_watched_key_flag_its_watchers() {
+        let store = Store::new(1);
+        let (watcher, other) = (client(1), client(2));
+        store.watch(0, &["a".to_string()], watcher);
+        store.watch(0, &["b".to_string()], other);
+        let watched = [(0, "a".to_string())];
+        assert!(!store.watched_keys_modified(watcher, &watched));
+
+        store.write(0, |db| db.set("b".to_string(), string("1"), None));
+        assert!(!store.watched_keys_modified(watcher, &watched));
+        store.write(0, |db| db.set("a".to_string(), string("1"), None));
+        assert!(store.watched_keys_modified(watcher, &watched));
+
+        // EXEC unwatches, so the next transaction starts afresh.
+        store.unwatch(watcher, &watched);
+        store.watch(0, &["a".to_string()], watcher);
+        assert!(!store.watched_keys_modified(watcher, &watched));
+        store.write(0, |db| db.remove("a"));
+        assert!(store.watched_keys_modified(watcher, &watched));
+    }
+
+    #[test]
+    fn a_watched_key_that_expires_counts_as_modified() {
+        let store = Store::new(1);
+        let soon = Instant::now() + Duration::from_millis(20);
+        store.write(0, |db| db.set("a".to_string(), string("1"), Some(soon)));
+        store.watch(0, &["a".to_string()], client(1));
+        let watched = [(0, "a".to_string())];
+        assert!(!store.watched_keys_modified(client(1), &watched));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(store.watched_keys_modified(client(1), &watched));
+    }
+
+    #[test]
+    fn swapdb_and_flushall_flag_every_watcher() {
+        let store = Store::new(3);
+        store.watch(0, &["a".to_string()], client(1));
+        store.watch(2, &["b".to_string()], client(2));
+        let (first, second) = ([(0, "a".to_string())], [(2, "b".to_string())]);
+
+        store.swap_dbs(0, 1);
+        assert!(store.watched_keys_modified(client(1), &first));
+        assert!(!store.watched_keys_modified(client(2), &second));
+
+        store.flush_all();
+        assert!(store.watched_keys_modified(client(2), &second));
     }
 }
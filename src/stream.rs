@@ -0,0 +1,1673 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt,
+    ops::Bound::{Excluded, Unbounded},
+};
+
+use crate::{
+    command::{Args, CommandError},
+    consumer_group::{
+        self, AutoClaim, Claim, ClaimOptions, ConsumerGroup, DeliveryTime, PendingRange,
+    },
+    executor::Rewritten,
+    listpack::ListPack,
+    resp_parser::RespData,
+    store::{unix_time_ms, Db, StoreError},
+};
+
+/// Entries per node before a new one is started (Redis's
+/// `stream-node-max-entries`).
+const NODE_MAX_ENTRIES: usize = 100;
+/// Size in bytes of a node before a new one is started
+/// (`stream-node-max-bytes`).
+const NODE_MAX_BYTES: usize = 4096;
+
+const LIVE: u8 = 0;
+const DELETED: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    fn prev(self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+
+    /// Parses `<ms>-<seq>`, or a bare `<ms>` with `missing_seq` as its
+    /// sequence number.
+    pub fn parse(id: &str, missing_seq: u64) -> Result<StreamId, CommandError> {
+        let id = match id.split_once('-') {
+            Some((ms, seq)) => ms
+                .parse()
+                .ok()
+                .zip(seq.parse().ok())
+                .map(|(ms, seq)| StreamId { ms, seq }),
+            None => id.parse().ok().map(|ms| StreamId {
+                ms,
+                seq: missing_seq,
+            }),
+        };
+        id.ok_or_else(|| {
+            CommandError::Other("ERR Invalid stream ID specified as stream command argument".into())
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    pub id: StreamId,
+    pub fields: Vec<(String, String)>,
+}
+
+/// An entry as laid out in a node, deleted or not.
+struct NodeEntry {
+    entry: Entry,
+    deleted: bool,
+    /// Where its deleted flag is in the node's listpack.
+    flag_pos: usize,
+}
+
+/// A run of consecutive entries packed into one listpack. Each entry is a
+/// deleted flag, its ID as a difference from the node's first ID, and its
+/// fields and values.
+#[derive(Default)]
+struct Node {
+    pack: ListPack,
+    /// Entries in the pack, deleted ones included.
+    count: usize,
+    live: usize,
+}
+
+impl Node {
+    fn push(&mut self, master: StreamId, id: StreamId, fields: &[(String, String)]) {
+        self.pack.push_int(LIVE.into());
+        self.pack.push_int(id.ms.wrapping_sub(master.ms));
+        self.pack.push_int(id.seq.wrapping_sub(master.seq));
+        self.pack.push_int(fields.len() as u64);
+        for (field, value) in fields {
+            self.pack.push_str(field);
+            self.pack.push_str(value);
+        }
+        self.count += 1;
+        self.live += 1;
+    }
+
+    fn entries(&self, master: StreamId) -> Vec<NodeEntry> {
+        let mut reader = self.pack.reader();
+        let mut entries = Vec::with_capacity(self.count);
+        while !reader.is_at_end() {
+            let flag_pos = reader.pos();
+            let deleted = reader.int() == u64::from(DELETED);
+            let id = StreamId {
+                ms: master.ms.wrapping_add(reader.int()),
+                seq: master.seq.wrapping_add(reader.int()),
+            };
+            let fields = (0..reader.int())
+                .map(|_| (reader.str().to_string(), reader.str().to_string()))
+                .collect();
+            entries.push(NodeEntry {
+                entry: Entry { id, fields },
+                deleted,
+                flag_pos,
+            });
+        }
+        entries
+    }
+
+    fn delete(&mut self, entry: &NodeEntry) {
+        self.pack.set_small_int(entry.flag_pos, DELETED);
+        self.live -= 1;
+    }
+}
+
+/// An append-only log of entries, stored like Redis does: nodes keyed by
+/// their first ID in an ordered map, each packing up to
+/// `NODE_MAX_ENTRIES` entries.
+#[derive(Default)]
+pub struct Stream {
+    nodes: BTreeMap<StreamId, Node>,
+    len: usize,
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    entries_added: u64,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+/// The ID XADD was given.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AddId {
+    /// `*`
+    Auto,
+    /// `<ms>-*`
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl AddId {
+    fn parse(id: &str) -> Result<AddId, CommandError> {
+        if id == "*" {
+            return Ok(AddId::Auto);
+        }
+        if let Some(ms) = id.strip_suffix("-*") {
+            return Ok(AddId::AutoSeq(StreamId::parse(ms, 0)?.ms));
+        }
+        let id = StreamId::parse(id, 0)?;
+        if id == StreamId::MIN {
+            return Err(CommandError::Other(
+                "ERR The ID specified in XADD must be greater than 0-0".into(),
+            ));
+        }
+        Ok(AddId::Explicit(id))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+/// The trimming XADD and XTRIM do.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Trim {
+    pub strategy: TrimStrategy,
+    /// With `~`, only whole nodes are removed.
+    pub approx: bool,
+    /// The most entries to remove, or 0 for no limit.
+    pub limit: usize,
+}
+
+impl Trim {
+    /// Parses `[= | ~] threshold [LIMIT count]`, following MAXLEN when
+    /// `max_len` is set and MINID otherwise.
+    fn parse(args: &mut Args, max_len: bool) -> Result<Trim, CommandError> {
+        let approx = args.keyword("~");
+        if !approx {
+            args.keyword("=");
+        }
+        let strategy = if max_len {
+            let max_len: i64 = args.integer()?;
+            let max_len = max_len
+                .try_into()
+                .map_err(|_| CommandError::Other("ERR The MAXLEN argument must be >= 0.".into()))?;
+            TrimStrategy::MaxLen(max_len)
+        } else {
+            TrimStrategy::MinId(StreamId::parse(&args.string()?, 0)?)
+        };
+        let limit = if args.keyword("LIMIT") {
+            if !approx {
+                return Err(CommandError::Other(
+                    "ERR syntax error, LIMIT cannot be used without the special ~ option".into(),
+                ));
+            }
+            let limit: i64 = args.integer()?;
+            limit
+                .try_into()
+                .map_err(|_| CommandError::Other("ERR The LIMIT argument must be >= 0.".into()))?
+        } else if approx {
+            100 * NODE_MAX_ENTRIES
+        } else {
+            0
+        };
+        Ok(Trim {
+            strategy,
+            approx,
+            limit,
+        })
+    }
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The ID an XADD with `id` would get, given the current Unix time.
+    pub fn next_id(&self, id: AddId, now: u64) -> Result<StreamId, StoreError> {
+        let last = self.last_id;
+        if last == StreamId::MAX {
+            return Err(StoreError::StreamExhausted);
+        }
+        match id {
+            AddId::Auto if now > last.ms => Ok(StreamId { ms: now, seq: 0 }),
+            AddId::Auto => last.next().ok_or(StoreError::StreamExhausted),
+            AddId::AutoSeq(ms) => match ms.cmp(&last.ms) {
+                Ordering::Greater => Ok(StreamId { ms, seq: 0 }),
+                Ordering::Equal => last.next().ok_or(StoreError::StreamIdTooSmall),
+                Ordering::Less => Err(StoreError::StreamIdTooSmall),
+            },
+            AddId::Explicit(id) if id > last => Ok(id),
+            AddId::Explicit(_) => Err(StoreError::StreamIdTooSmall),
+        }
+    }
+
+    /// Appends an entry, whose ID has to be greater than any so far.
+    pub fn add(&mut self, id: StreamId, fields: &[(String, String)]) {
+        let master = match self.nodes.last_key_value() {
+            Some((master, node))
+                if node.count < NODE_MAX_ENTRIES && node.pack.size() < NODE_MAX_BYTES =>
+            {
+                *master
+            }
+            _ => {
+                self.nodes.insert(id, Node::default());
+                id
+            }
+        };
+        self.nodes
+            .get_mut(&master)
+            .unwrap()
+            .push(master, id, fields);
+        self.len += 1;
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// The entries with IDs in `start..=end`, from the highest when `rev` is
+    /// set, and at most `count` of them.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<Entry> {
+        let count = count.unwrap_or(usize::MAX);
+        let mut entries = Vec::new();
+        if start > end || count == 0 {
+            return entries;
+        }
+        if rev {
+            for (master, node) in self.nodes.range(..=end).rev() {
+                for entry in node.entries(*master).into_iter().rev() {
+                    if entry.deleted || entry.entry.id > end {
+                        continue;
+                    }
+                    if entry.entry.id < start {
+                        return entries;
+                    }
+                    entries.push(entry.entry);
+                    if entries.len() == count {
+                        return entries;
+                    }
+                }
+            }
+        } else {
+            // The node holding `start` may begin before it.
+            let first = self
+                .nodes
+                .range(..=start)
+                .next_back()
+                .map_or(start, |(master, _)| *master);
+            for (master, node) in self.nodes.range(first..) {
+                for entry in node.entries(*master) {
+                    if entry.deleted || entry.entry.id < start {
+                        continue;
+                    }
+                    if entry.entry.id > end {
+                        return entries;
+                    }
+                    entries.push(entry.entry);
+                    if entries.len() == count {
+                        return entries;
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    pub fn first_entry(&self) -> Option<Entry> {
+        self.range(StreamId::MIN, StreamId::MAX, Some(1), false)
+            .pop()
+    }
+
+    pub fn last_entry(&self) -> Option<Entry> {
+        self.range(StreamId::MIN, StreamId::MAX, Some(1), true)
+            .pop()
+    }
+
+    pub fn entry(&self, id: StreamId) -> Option<Entry> {
+        self.range(id, id, Some(1), false).pop()
+    }
+
+    /// Whether an entry from `start` on may have been deleted, which makes
+    /// counting entries by their position in the stream unreliable.
+    fn has_tombstones_from(&self, start: StreamId) -> bool {
+        self.len > 0 && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /// How many entries were ever added up to and including `id`, when that
+    /// can be told.
+    fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        match id.cmp(&self.last_id) {
+            Ordering::Equal => return Some(self.entries_added),
+            Ordering::Greater => return None,
+            Ordering::Less if self.len == 0 => return Some(self.entries_added),
+            Ordering::Less => {}
+        }
+        let first = self.first_entry()?.id;
+        if self.max_deleted_id != StreamId::MIN && self.max_deleted_id >= first {
+            return None;
+        }
+        let before_first = self.entries_added - self.len as u64;
+        match id.cmp(&first) {
+            Ordering::Less => Some(before_first),
+            Ordering::Equal => Some(before_first + 1),
+            Ordering::Greater => None,
+        }
+    }
+
+    /// How many entries a group has yet to read, when that can be told.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_delivered) => read,
+            _ => self.entries_read_at(group.last_delivered)?,
+        };
+        Some(self.entries_added.saturating_sub(entries_read))
+    }
+
+    /// Hands a consumer, which must exist, the entries after its group's last
+    /// delivered ID, which moves past them. Unless `noack`, they become
+    /// pending.
+    fn deliver_new(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: Option<usize>,
+        noack: bool,
+        now: i64,
+    ) -> Vec<Entry> {
+        let last_delivered = self.groups[group].last_delivered;
+        let entries = last_delivered.next().map_or_else(Vec::new, |start| {
+            self.range(start, StreamId::MAX, count, false)
+        });
+        let mut entries_read = self.groups[group].entries_read;
+        for entry in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones_from(entry.id) => Some(read + 1),
+                _ => self.entries_read_at(entry.id),
+            };
+        }
+        let group = self.groups.get_mut(group).unwrap();
+        if let Some(last) = entries.last() {
+            group.last_delivered = last.id;
+            group.entries_read = entries_read;
+        }
+        if !noack {
+            for entry in &entries {
+                group.deliver(entry.id, consumer, now, 1);
+            }
+        }
+        entries
+    }
+
+    /// The entries pending for a consumer, which must exist, after `after`,
+    /// each counting as delivered again. Entries deleted since come back as
+    /// `None`.
+    fn deliver_history(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: Option<usize>,
+        now: i64,
+    ) -> Vec<(StreamId, Option<Entry>)> {
+        let ids: Vec<StreamId> = self.groups[group].consumers()[consumer]
+            .pending
+            .range((Excluded(after), Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .copied()
+            .collect();
+        ids.into_iter()
+            .map(|id| {
+                let entry = self.entry(id);
+                if entry.is_some() {
+                    self.groups.get_mut(group).unwrap().redeliver(id, now);
+                }
+                (id, entry)
+            })
+            .collect()
+    }
+
+    /// Deletes the entry with `id`, returning whether there was one.
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let Some((&master, node)) = self.nodes.range_mut(..=id).next_back() else {
+            return false;
+        };
+        let Some(entry) = node
+            .entries(master)
+            .into_iter()
+            .find(|entry| entry.entry.id == id && !entry.deleted)
+        else {
+            return false;
+        };
+        node.delete(&entry);
+        if node.live == 0 {
+            self.nodes.remove(&master);
+        }
+        self.len -= 1;
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Removes entries from the start of the stream, returning how many.
+    pub fn trim(&mut self, trim: Trim) -> usize {
+        let mut removed = 0;
+        while let Some(mut first) = self.nodes.first_entry() {
+            let master = *first.key();
+            let node = first.get_mut();
+            let entries: Vec<NodeEntry> = node
+                .entries(master)
+                .into_iter()
+                .filter(|entry| !entry.deleted)
+                .collect();
+            let whole_node = match trim.strategy {
+                TrimStrategy::MaxLen(max_len) => self.len - node.live >= max_len,
+                TrimStrategy::MinId(min_id) => entries.last().unwrap().entry.id < min_id,
+            };
+
+            if whole_node {
+                if trim.limit > 0 && removed + node.live > trim.limit {
+                    break;
+                }
+                removed += node.live;
+                self.len -= node.live;
+                first.remove();
+                continue;
+            }
+            if trim.approx {
+                break;
+            }
+            for entry in &entries {
+                let done = match trim.strategy {
+                    TrimStrategy::MaxLen(max_len) => self.len <= max_len,
+                    TrimStrategy::MinId(min_id) => entry.entry.id >= min_id,
+                };
+                if done {
+                    break;
+                }
+                node.delete(entry);
+                self.len -= 1;
+                removed += 1;
+            }
+            break;
+        }
+        removed
+    }
+}
+
+/// An ID XREAD reads after.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReadId {
+    After(StreamId),
+    /// `$`: only entries added from now on.
+    New,
+    /// `+`: the last entry.
+    Last,
+}
+
+/// The ID XGROUP CREATE and SETID set a group's last delivered ID to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GroupId {
+    Id(StreamId),
+    /// `$`: the stream's last ID.
+    Last,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StreamCommand {
+    Add {
+        key: String,
+        no_mkstream: bool,
+        trim: Option<Trim>,
+        id: AddId,
+        fields: Vec<(String, String)>,
+    },
+    Len(String),
+    /// XRANGE, or XREVRANGE when `rev` is set.
+    Range {
+        key: String,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    },
+    Del(String, Vec<StreamId>),
+    Trim(String, Trim),
+    Read {
+        count: Option<usize>,
+        /// BLOCK's timeout in seconds, 0 meaning forever.
+        block: Option<f64>,
+        streams: Vec<(String, ReadId)>,
+    },
+    GroupCreate {
+        key: String,
+        group: String,
+        id: GroupId,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    GroupSetId {
+        key: String,
+        group: String,
+        id: GroupId,
+        entries_read: Option<u64>,
+    },
+    /// XGROUP DESTROY with the key and group.
+    GroupDestroy(String, String),
+    /// XGROUP CREATECONSUMER with the key, group and consumer.
+    GroupCreateConsumer(String, String, String),
+    /// XGROUP DELCONSUMER with the key, group and consumer.
+    GroupDelConsumer(String, String, String),
+    ReadGroup {
+        group: String,
+        consumer: String,
+        count: Option<usize>,
+        block: Option<f64>,
+        noack: bool,
+        /// The ID to read the consumer's pending entries after, or `None`
+        /// for `>`: entries never delivered to the group.
+        streams: Vec<(String, Option<StreamId>)>,
+    },
+    /// XACK with the key, group and IDs.
+    Ack(String, String, Vec<StreamId>),
+    Pending {
+        key: String,
+        group: String,
+        range: Option<PendingRange>,
+    },
+    Claim(Box<Claim>),
+    AutoClaim(AutoClaim),
+    InfoStream(String),
+    InfoGroups(String),
+    /// XINFO CONSUMERS with the key and group.
+    InfoConsumers(String, String),
+}
+
+fn parse_group_id(id: &str) -> Result<GroupId, CommandError> {
+    match id {
+        "$" => Ok(GroupId::Last),
+        id => Ok(GroupId::Id(StreamId::parse(id, 0)?)),
+    }
+}
+
+/// Parses the value of ENTRIESREAD, where -1 means unknown.
+fn parse_entries_read(args: &mut Args) -> Result<Option<u64>, CommandError> {
+    let entries_read: i64 = args.integer()?;
+    match entries_read {
+        -1 => Ok(None),
+        n if n >= 0 => Ok(Some(n as u64)),
+        _ => Err(CommandError::Other(
+            "ERR value for ENTRIESREAD must be positive or -1".into(),
+        )),
+    }
+}
+
+/// Parses a min-idle-time of XCLAIM or XAUTOCLAIM, where a negative one is
+/// as good as 0.
+fn parse_min_idle(args: &mut Args, name: &str) -> Result<i64, CommandError> {
+    let min_idle: i64 = args.integer().map_err(|_| {
+        CommandError::Other(format!("ERR Invalid min-idle-time argument for {}", name))
+    })?;
+    Ok(min_idle.max(0))
+}
+
+/// Parses a range bound of XRANGE: `-`, `+`, an ID, or `(` and an ID for an
+/// exclusive bound. A bare `<ms>` covers that whole millisecond.
+fn parse_bound(bound: &str, start: bool) -> Result<StreamId, CommandError> {
+    match bound {
+        "-" => return Ok(StreamId::MIN),
+        "+" => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    let missing_seq = if start { 0 } else { u64::MAX };
+    let Some(bound) = bound.strip_prefix('(') else {
+        return StreamId::parse(bound, missing_seq);
+    };
+    let id = StreamId::parse(bound, missing_seq)?;
+    let id = if start { id.next() } else { id.prev() };
+    id.ok_or_else(|| {
+        CommandError::Other(format!(
+            "ERR invalid {} ID for the interval",
+            if start { "start" } else { "end" }
+        ))
+    })
+}
+
+impl StreamCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<StreamCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "XADD" => {
+                let key = args.string()?;
+                let mut no_mkstream = false;
+                let mut trim = None;
+                loop {
+                    let max_len = if args.keyword("NOMKSTREAM") {
+                        no_mkstream = true;
+                        continue;
+                    } else if args.keyword("MAXLEN") {
+                        true
+                    } else if args.keyword("MINID") {
+                        false
+                    } else {
+                        break;
+                    };
+                    if trim.is_some() {
+                        return Err(CommandError::Other(
+                            "ERR syntax error, MAXLEN and MINID options at the same time are \
+                             not compatible"
+                                .into(),
+                        ));
+                    }
+                    trim = Some(Trim::parse(&mut args, max_len)?);
+                }
+                let id = AddId::parse(&args.string()?)?;
+                if args.is_empty() || args.len() % 2 == 1 {
+                    return Err(args.arity_error());
+                }
+                let mut fields = Vec::new();
+                while !args.is_empty() {
+                    fields.push((args.string()?, args.string()?));
+                }
+                StreamCommand::Add {
+                    key,
+                    no_mkstream,
+                    trim,
+                    id,
+                    fields,
+                }
+            }
+            "XLEN" => StreamCommand::Len(args.string()?),
+            "XRANGE" | "XREVRANGE" => {
+                let key = args.string()?;
+                let rev = name == "XREVRANGE";
+                let (start, end) = if rev {
+                    let end = args.string()?;
+                    (args.string()?, end)
+                } else {
+                    (args.string()?, args.string()?)
+                };
+                let count = if args.keyword("COUNT") {
+                    // A negative count is as good as 0, which returns nothing.
+                    Some(args.integer::<i64>()?.max(0) as usize)
+                } else {
+                    None
+                };
+                StreamCommand::Range {
+                    key,
+                    start: parse_bound(&start, true)?,
+                    end: parse_bound(&end, false)?,
+                    count,
+                    rev,
+                }
+            }
+            "XDEL" => {
+                let key = args.string()?;
+                let ids = args
+                    .rest()?
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0))
+                    .collect::<Result<_, _>>()?;
+                StreamCommand::Del(key, ids)
+            }
+            "XTRIM" => {
+                let key = args.string()?;
+                let max_len = if args.keyword("MAXLEN") {
+                    true
+                } else if args.keyword("MINID") {
+                    false
+                } else {
+                    return Err(CommandError::Syntax);
+                };
+                StreamCommand::Trim(key, Trim::parse(&mut args, max_len)?)
+            }
+            "XREAD" | "XREADGROUP" => {
+                let mut group = None;
+                let mut count = None;
+                let mut block = None;
+                let mut noack = false;
+                loop {
+                    if args.keyword("COUNT") {
+                        // 0 or less means no limit.
+                        let n: i64 = args.integer()?;
+                        count = (n > 0).then_some(n as usize);
+                    } else if args.keyword("BLOCK") {
+                        let ms: i64 = args.integer().map_err(|_| {
+                            CommandError::Other(
+                                "ERR timeout is not an integer or out of range".into(),
+                            )
+                        })?;
+                        if ms < 0 {
+                            return Err(CommandError::Other("ERR timeout is negative".into()));
+                        }
+                        block = Some(ms as f64 / 1000.0);
+                    } else if args.keyword("STREAMS") {
+                        break;
+                    } else if name == "XREADGROUP" && args.keyword("GROUP") {
+                        group = Some((args.string()?, args.string()?));
+                    } else if name == "XREADGROUP" && args.keyword("NOACK") {
+                        noack = true;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                let rest = args.rest()?;
+                if rest.len() % 2 == 1 {
+                    return Err(CommandError::Other(format!(
+                        "ERR Unbalanced '{}' list of streams: for each stream key an ID or \
+                         '{}' must be specified.",
+                        name.to_lowercase(),
+                        if group.is_some() { ">" } else { "$" }
+                    )));
+                }
+                let (keys, ids) = rest.split_at(rest.len() / 2);
+                let Some((group, consumer)) = group else {
+                    if name == "XREADGROUP" {
+                        return Err(CommandError::Other(
+                            "ERR Missing GROUP option for XREADGROUP".into(),
+                        ));
+                    }
+                    let streams = keys
+                        .iter()
+                        .zip(ids)
+                        .map(|(key, id)| {
+                            let id = match id.as_str() {
+                                "$" => ReadId::New,
+                                "+" => ReadId::Last,
+                                id => ReadId::After(StreamId::parse(id, 0)?),
+                            };
+                            Ok((key.clone(), id))
+                        })
+                        .collect::<Result<_, CommandError>>()?;
+                    return Ok(StreamCommand::Read {
+                        count,
+                        block,
+                        streams,
+                    });
+                };
+                let streams =
+                    keys.iter()
+                        .zip(ids)
+                        .map(|(key, id)| {
+                            let id = match id.as_str() {
+                                ">" => None,
+                                "$" => return Err(CommandError::Other(
+                                    "ERR The $ ID is meaningless in the context of XREADGROUP: \
+                                     you want to read the history of this consumer by \
+                                     specifying a proper ID, or use the > ID to get new \
+                                     messages. The $ ID would just return an empty result set."
+                                        .into(),
+                                )),
+                                id => Some(StreamId::parse(id, 0)?),
+                            };
+                            Ok((key.clone(), id))
+                        })
+                        .collect::<Result<_, CommandError>>()?;
+                StreamCommand::ReadGroup {
+                    group,
+                    consumer,
+                    count,
+                    block,
+                    noack,
+                    streams,
+                }
+            }
+            "XGROUP" => {
+                let subcommand = args.string()?;
+                match subcommand.to_uppercase().as_str() {
+                    "CREATE" => {
+                        let key = args.string()?;
+                        let group = args.string()?;
+                        let id = parse_group_id(&args.string()?)?;
+                        let mut mkstream = false;
+                        let mut entries_read = None;
+                        while !args.is_empty() {
+                            if args.keyword("MKSTREAM") {
+                                mkstream = true;
+                            } else if args.keyword("ENTRIESREAD") {
+                                entries_read = parse_entries_read(&mut args)?;
+                            } else {
+                                return Err(CommandError::Syntax);
+                            }
+                        }
+                        StreamCommand::GroupCreate {
+                            key,
+                            group,
+                            id,
+                            mkstream,
+                            entries_read,
+                        }
+                    }
+                    "SETID" => {
+                        let key = args.string()?;
+                        let group = args.string()?;
+                        let id = parse_group_id(&args.string()?)?;
+                        let entries_read = if args.keyword("ENTRIESREAD") {
+                            parse_entries_read(&mut args)?
+                        } else {
+                            None
+                        };
+                        StreamCommand::GroupSetId {
+                            key,
+                            group,
+                            id,
+                            entries_read,
+                        }
+                    }
+                    "DESTROY" => StreamCommand::GroupDestroy(args.string()?, args.string()?),
+                    "CREATECONSUMER" => StreamCommand::GroupCreateConsumer(
+                        args.string()?,
+                        args.string()?,
+                        args.string()?,
+                    ),
+                    "DELCONSUMER" => StreamCommand::GroupDelConsumer(
+                        args.string()?,
+                        args.string()?,
+                        args.string()?,
+                    ),
+                    _ => {
+                        return Err(CommandError::Other(format!(
+                            "ERR unknown subcommand '{}'. Try XGROUP HELP.",
+                            subcommand
+                        )))
+                    }
+                }
+            }
+            "XACK" => {
+                let key = args.string()?;
+                let group = args.string()?;
+                let ids = args
+                    .rest()?
+                    .iter()
+                    .map(|id| StreamId::parse(id, 0))
+                    .collect::<Result<_, _>>()?;
+                StreamCommand::Ack(key, group, ids)
+            }
+            "XPENDING" => {
+                let key = args.string()?;
+                let group = args.string()?;
+                let range = if args.is_empty() {
+                    None
+                } else {
+                    let min_idle = if args.keyword("IDLE") {
+                        args.integer()?
+                    } else {
+                        0
+                    };
+                    let start = parse_bound(&args.string()?, true)?;
+                    let end = parse_bound(&args.string()?, false)?;
+                    // A negative count is as good as 0, which returns nothing.
+                    let count = args.integer::<i64>()?.max(0) as usize;
+                    let consumer = if args.is_empty() {
+                        None
+                    } else {
+                        Some(args.string()?)
+                    };
+                    Some(PendingRange {
+                        min_idle,
+                        start,
+                        end,
+                        count,
+                        consumer,
+                    })
+                };
+                StreamCommand::Pending { key, group, range }
+            }
+            "XCLAIM" => {
+                let key = args.string()?;
+                let group = args.string()?;
+                let consumer = args.string()?;
+                let min_idle = parse_min_idle(&mut args, name)?;
+                if args.is_empty() {
+                    return Err(args.arity_error());
+                }
+                // IDs go on until the first argument that isn't one, which
+                // starts the options.
+                let mut ids = Vec::new();
+                let mut options = ClaimOptions::default();
+                let mut in_options = false;
+                while !args.is_empty() {
+                    let arg = args.string()?;
+                    if !in_options {
+                        if let Ok(id) = StreamId::parse(&arg, 0) {
+                            ids.push(id);
+                            continue;
+                        }
+                        in_options = true;
+                    }
+                    match arg.to_uppercase().as_str() {
+                        "IDLE" => {
+                            let idle: i64 = args.integer()?;
+                            options.delivery_time = Some(DeliveryTime::Idle(idle));
+                        }
+                        "TIME" => options.delivery_time = Some(DeliveryTime::At(args.integer()?)),
+                        "RETRYCOUNT" => {
+                            let retry_count: i64 = args.integer()?;
+                            options.retry_count = Some(retry_count.max(0) as u64);
+                        }
+                        "FORCE" => options.force = true,
+                        "JUSTID" => options.just_id = true,
+                        "LASTID" => options.last_id = Some(StreamId::parse(&args.string()?, 0)?),
+                        _ => {
+                            return Err(CommandError::Other(format!(
+                                "ERR Unrecognized XCLAIM option '{}'",
+                                arg
+                            )))
+                        }
+                    }
+                }
+                StreamCommand::Claim(Box::new(Claim {
+                    key,
+                    group,
+                    consumer,
+                    min_idle,
+                    ids,
+                    options,
+                }))
+            }
+            "XAUTOCLAIM" => {
+                let key = args.string()?;
+                let group = args.string()?;
+                let consumer = args.string()?;
+                let min_idle = parse_min_idle(&mut args, name)?;
+                let start = parse_bound(&args.string()?, true)?;
+                let mut count = 100;
+                let mut just_id = false;
+                while !args.is_empty() {
+                    if args.keyword("COUNT") {
+                        let n: i64 = args.integer()?;
+                        if n < 1 {
+                            return Err(CommandError::Other("ERR COUNT must be > 0".into()));
+                        }
+                        count = n as usize;
+                    } else if args.keyword("JUSTID") {
+                        just_id = true;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                StreamCommand::AutoClaim(AutoClaim {
+                    key,
+                    group,
+                    consumer,
+                    min_idle,
+                    start,
+                    count,
+                    just_id,
+                })
+            }
+            "XINFO" => {
+                let subcommand = args.string()?;
+                match subcommand.to_uppercase().as_str() {
+                    "STREAM" => StreamCommand::InfoStream(args.string()?),
+                    "GROUPS" => StreamCommand::InfoGroups(args.string()?),
+                    "CONSUMERS" => StreamCommand::InfoConsumers(args.string()?, args.string()?),
+                    _ => {
+                        return Err(CommandError::Other(format!(
+                            "ERR unknown subcommand '{}'. Try XINFO HELP.",
+                            subcommand
+                        )))
+                    }
+                }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            StreamCommand::Add { .. }
+                | StreamCommand::Del(..)
+                | StreamCommand::Trim(..)
+                | StreamCommand::GroupCreate { .. }
+                | StreamCommand::GroupSetId { .. }
+                | StreamCommand::GroupDestroy(..)
+                | StreamCommand::GroupCreateConsumer(..)
+                | StreamCommand::GroupDelConsumer(..)
+                | StreamCommand::ReadGroup { .. }
+                | StreamCommand::Ack(..)
+                | StreamCommand::Claim(_)
+                | StreamCommand::AutoClaim(_)
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            StreamCommand::Add { key, .. }
+            | StreamCommand::Len(key)
+            | StreamCommand::Range { key, .. }
+            | StreamCommand::Del(key, _)
+            | StreamCommand::Trim(key, _)
+            | StreamCommand::GroupCreate { key, .. }
+            | StreamCommand::GroupSetId { key, .. }
+            | StreamCommand::GroupDestroy(key, _)
+            | StreamCommand::GroupCreateConsumer(key, ..)
+            | StreamCommand::GroupDelConsumer(key, ..)
+            | StreamCommand::Ack(key, ..)
+            | StreamCommand::Pending { key, .. }
+            | StreamCommand::AutoClaim(AutoClaim { key, .. })
+            | StreamCommand::InfoStream(key)
+            | StreamCommand::InfoGroups(key)
+            | StreamCommand::InfoConsumers(key, _) => vec![key],
+            StreamCommand::Claim(claim) => vec![&claim.key],
+            StreamCommand::Read { streams, .. } => {
+                streams.iter().map(|(key, _)| key.as_str()).collect()
+            }
+            StreamCommand::ReadGroup { streams, .. } => {
+                streams.iter().map(|(key, _)| key.as_str()).collect()
+            }
+        }
+    }
+
+    /// How long the command may block, in seconds (0 meaning forever), or
+    /// `None` for commands that never block.
+    pub fn timeout(&self) -> Option<f64> {
+        match self {
+            StreamCommand::Read { block, .. } | StreamCommand::ReadGroup { block, .. } => *block,
+            _ => None,
+        }
+    }
+
+    /// Pins down what `$` and `+` refer to for an XREAD about to block, so
+    /// that it waits for entries added after this point.
+    pub fn resolve_read_ids(&mut self, db: &Db) {
+        let StreamCommand::Read { streams, .. } = self else {
+            return;
+        };
+        for (key, id) in streams {
+            if let ReadId::New | ReadId::Last = id {
+                let last_id = db.stream(key).ok().flatten().map(|stream| stream.last_id);
+                *id = ReadId::After(last_id.unwrap_or(StreamId::MIN));
+            }
+        }
+    }
+
+    /// What replicas get in place of XADD and XTRIM, given the reply and the
+    /// stream's resulting length. An auto-generated ID depends on the
+    /// master's clock and approximate trimming on how entries fall into
+    /// nodes, so replicas are sent the ID and an exact trim instead.
+    pub fn replicated_form(&self, reply: &RespData, len: usize) -> Vec<Vec<String>> {
+        let exact_trim = |key: &String| {
+            vec![
+                "XTRIM".to_string(),
+                key.clone(),
+                "MAXLEN".to_string(),
+                "=".to_string(),
+                len.to_string(),
+            ]
+        };
+        match (self, reply) {
+            (
+                StreamCommand::Add {
+                    key, trim, fields, ..
+                },
+                RespData::BulkString(id),
+            ) => {
+                let mut add = vec!["XADD".to_string(), key.clone(), id.clone()];
+                for (field, value) in fields {
+                    add.push(field.clone());
+                    add.push(value.clone());
+                }
+                let mut commands = vec![add];
+                if trim.is_some() {
+                    commands.push(exact_trim(key));
+                }
+                commands
+            }
+            (StreamCommand::Trim(key, _), _) => vec![exact_trim(key)],
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub fn entry_reply(entry: &Entry) -> RespData {
+    RespData::Array(vec![
+        RespData::BulkString(entry.id.to_string()),
+        RespData::Array(
+            entry
+                .fields
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        RespData::BulkString(field.clone()),
+                        RespData::BulkString(value.clone()),
+                    ]
+                })
+                .collect(),
+        ),
+    ])
+}
+
+pub fn entries_reply(entries: &[Entry]) -> RespData {
+    RespData::Array(entries.iter().map(entry_reply).collect())
+}
+
+fn info_reply(stream: &Stream) -> RespData {
+    let field = |name: &str| RespData::BulkString(name.to_string());
+    let id = |id: StreamId| RespData::BulkString(id.to_string());
+    let entry = |entry: Option<Entry>| entry.as_ref().map_or(RespData::BulkStringNull, entry_reply);
+    let first = stream.first_entry();
+    RespData::Map(vec![
+        (field("length"), RespData::Integer(stream.len as i64)),
+        (
+            field("radix-tree-keys"),
+            RespData::Integer(stream.nodes.len() as i64),
+        ),
+        (
+            field("radix-tree-nodes"),
+            RespData::Integer(stream.nodes.len() as i64),
+        ),
+        (field("last-generated-id"), id(stream.last_id)),
+        (field("max-deleted-entry-id"), id(stream.max_deleted_id)),
+        (
+            field("entries-added"),
+            RespData::Integer(stream.entries_added as i64),
+        ),
+        (
+            field("recorded-first-entry-id"),
+            id(first.as_ref().map_or(StreamId::MIN, |entry| entry.id)),
+        ),
+        (
+            field("groups"),
+            RespData::Integer(stream.groups.len() as i64),
+        ),
+        (field("first-entry"), entry(first)),
+        (field("last-entry"), entry(stream.last_entry())),
+    ])
+}
+
+/// The entries XREAD returns for one stream.
+fn read(stream: &Stream, id: ReadId, count: Option<usize>) -> Vec<Entry> {
+    match id {
+        ReadId::After(id) => id.next().map_or_else(Vec::new, |start| {
+            stream.range(start, StreamId::MAX, count, false)
+        }),
+        ReadId::New => Vec::new(),
+        ReadId::Last => stream.last_entry().into_iter().collect(),
+    }
+}
+
+fn no_group(key: &str, group: &str) -> StoreError {
+    StoreError::NoGroupToRead(key.to_string(), group.to_string())
+}
+
+/// Makes sure every group XREADGROUP reads from exists, and adds the
+/// consumer to those that don't have it yet. Returns the commands creating
+/// consumers, to feed replicas.
+pub fn create_consumers(
+    db: &mut Db,
+    command: &StreamCommand,
+) -> Result<Vec<Vec<String>>, StoreError> {
+    let StreamCommand::ReadGroup {
+        group,
+        consumer,
+        streams,
+        ..
+    } = command
+    else {
+        return Ok(Vec::new());
+    };
+    for (key, _) in streams {
+        if !db
+            .stream(key)?
+            .is_some_and(|stream| stream.groups.contains_key(group))
+        {
+            return Err(no_group(key, group));
+        }
+    }
+    let now = unix_time_ms();
+    let mut created = Vec::new();
+    for (key, _) in streams {
+        let stream = db.stream_mut(key)?.unwrap();
+        if stream
+            .groups
+            .get_mut(group)
+            .unwrap()
+            .create_consumer(consumer, now)
+        {
+            created.push(
+                ["XGROUP", "CREATECONSUMER", key, group, consumer]
+                    .map(String::from)
+                    .to_vec(),
+            );
+        }
+    }
+    Ok(created)
+}
+
+/// Runs XREAD or XREADGROUP without blocking. Returns the reply, along with
+/// what to feed replicas as XREADGROUP moves groups along, or `None` when no
+/// stream has entries to serve.
+pub fn try_read(db: &mut Db, command: &StreamCommand) -> Result<Option<Rewritten>, StoreError> {
+    let stream_reply =
+        |key: &String, entries| RespData::Array(vec![RespData::BulkString(key.clone()), entries]);
+    match command {
+        StreamCommand::Read { count, streams, .. } => {
+            let mut replies = Vec::new();
+            for (key, id) in streams {
+                let Some(stream) = db.stream(key)? else {
+                    continue;
+                };
+                let entries = read(stream, *id, *count);
+                if !entries.is_empty() {
+                    replies.push(stream_reply(key, entries_reply(&entries)));
+                }
+            }
+            Ok((!replies.is_empty()).then(|| (RespData::Array(replies), Vec::new())))
+        }
+        StreamCommand::ReadGroup {
+            group,
+            consumer,
+            count,
+            noack,
+            streams,
+            ..
+        } => {
+            let now = unix_time_ms();
+            let mut replies = Vec::new();
+            let mut propagate = Vec::new();
+            for (key, id) in streams {
+                let Some(stream) = db
+                    .stream_mut(key)?
+                    .filter(|stream| stream.groups.contains_key(group))
+                else {
+                    return Err(no_group(key, group));
+                };
+                // The consumer may have been deleted while blocked.
+                if stream
+                    .groups
+                    .get_mut(group)
+                    .unwrap()
+                    .create_consumer(consumer, now)
+                {
+                    propagate.push(
+                        ["XGROUP", "CREATECONSUMER", key, group, consumer]
+                            .map(String::from)
+                            .to_vec(),
+                    );
+                }
+                let Some(after) = id else {
+                    let entries = stream.deliver_new(group, consumer, *count, *noack, now);
+                    stream
+                        .groups
+                        .get_mut(group)
+                        .unwrap()
+                        .see(consumer, now, !entries.is_empty());
+                    let group_state = &stream.groups[group];
+                    if entries.is_empty() {
+                        continue;
+                    }
+                    if !noack {
+                        for entry in &entries {
+                            propagate.push(consumer_group::claim_command(
+                                key,
+                                group,
+                                group_state,
+                                entry.id,
+                            ));
+                        }
+                    }
+                    propagate.push(consumer_group::set_id_command(key, group, group_state));
+                    replies.push(stream_reply(key, entries_reply(&entries)));
+                    continue;
+                };
+                // A read of the consumer's history never blocks, so the
+                // stream is in the reply even without entries.
+                let entries = stream
+                    .deliver_history(group, consumer, *after, *count, now)
+                    .into_iter()
+                    .map(|(id, entry)| match entry {
+                        Some(entry) => entry_reply(&entry),
+                        None => RespData::Array(vec![
+                            RespData::BulkString(id.to_string()),
+                            RespData::ArrayNull,
+                        ]),
+                    })
+                    .collect();
+                stream
+                    .groups
+                    .get_mut(group)
+                    .unwrap()
+                    .see(consumer, now, false);
+                replies.push(stream_reply(key, RespData::Array(entries)));
+            }
+            Ok((!replies.is_empty()).then_some((RespData::Array(replies), propagate)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Looks up a group for XGROUP, which needs the stream to exist.
+fn group_for_xgroup<'a>(
+    db: &'a mut Db,
+    key: &str,
+    group: &str,
+) -> Result<&'a mut ConsumerGroup, StoreError> {
+    db.stream_mut(key)?
+        .ok_or(StoreError::XGroupNoKey)?
+        .groups
+        .get_mut(group)
+        .ok_or_else(|| StoreError::NoSuchGroup(key.to_string(), group.to_string()))
+}
+
+/// Runs XCLAIM or XAUTOCLAIM. Returns the reply along with what to feed
+/// replicas in place of the command.
+pub fn claim(db: &mut Db, command: &StreamCommand) -> Result<Rewritten, StoreError> {
+    let now = unix_time_ms();
+    let no_group = |key: &String, group: &String| StoreError::NoGroup(key.clone(), group.clone());
+    match command {
+        StreamCommand::Claim(claim) => {
+            let stream = db
+                .stream_mut(&claim.key)?
+                .ok_or_else(|| no_group(&claim.key, &claim.group))?;
+            consumer_group::claim(stream, claim, now)
+        }
+        StreamCommand::AutoClaim(claim) => {
+            let stream = db
+                .stream_mut(&claim.key)?
+                .ok_or_else(|| no_group(&claim.key, &claim.group))?;
+            consumer_group::auto_claim(stream, claim, now)
+        }
+        _ => Ok((RespData::ArrayNull, Vec::new())),
+    }
+}
+
+pub fn execute(db: &mut Db, command: StreamCommand) -> Result<RespData, StoreError> {
+    match command {
+        StreamCommand::Add {
+            key,
+            no_mkstream,
+            trim,
+            id,
+            fields,
+        } => {
+            let now = unix_time_ms() as u64;
+            let id = match db.stream(&key)? {
+                Some(stream) => stream.next_id(id, now)?,
+                None if no_mkstream => return Ok(RespData::BulkStringNull),
+                None => Stream::default().next_id(id, now)?,
+            };
+            let stream = db.stream_or_create(&key)?;
+            stream.add(id, &fields);
+            if let Some(trim) = trim {
+                stream.trim(trim);
+            }
+            db.touch(&key);
+            db.signal_ready(&key);
+            Ok(RespData::BulkString(id.to_string()))
+        }
+        StreamCommand::Len(key) => Ok(RespData::Integer(
+            db.stream(&key)?.map_or(0, |stream| stream.len() as i64),
+        )),
+        StreamCommand::Range {
+            key,
+            start,
+            end,
+            count,
+            rev,
+        } => {
+            if count == Some(0) {
+                return Ok(RespData::ArrayNull);
+            }
+            Ok(entries_reply(
+                &db.stream(&key)?
+                    .map_or_else(Vec::new, |stream| stream.range(start, end, count, rev)),
+            ))
+        }
+        StreamCommand::Del(key, ids) => {
+            let Some(stream) = db.stream_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let deleted = ids.into_iter().filter(|id| stream.remove(*id)).count();
+            if deleted > 0 {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(deleted as i64))
+        }
+        StreamCommand::Trim(key, trim) => {
+            let trimmed = db.stream_mut(&key)?.map_or(0, |stream| stream.trim(trim));
+            if trimmed > 0 {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(trimmed as i64))
+        }
+        StreamCommand::Read { .. } | StreamCommand::ReadGroup { .. } => {
+            create_consumers(db, &command)?;
+            Ok(try_read(db, &command)?.map_or(RespData::ArrayNull, |(reply, _)| reply))
+        }
+        StreamCommand::GroupCreate {
+            key,
+            group,
+            id,
+            mkstream,
+            entries_read,
+        } => {
+            if !mkstream && db.stream(&key)?.is_none() {
+                return Err(StoreError::XGroupNoKey);
+            }
+            let stream = db.stream_or_create(&key)?;
+            if stream.groups.contains_key(&group) {
+                return Err(StoreError::BusyGroup);
+            }
+            let id = match id {
+                GroupId::Id(id) => id,
+                GroupId::Last => stream.last_id,
+            };
+            stream
+                .groups
+                .insert(group, ConsumerGroup::new(id, entries_read));
+            db.touch(&key);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        StreamCommand::GroupSetId {
+            key,
+            group,
+            id,
+            entries_read,
+        } => {
+            let last_id = db.stream(&key)?.map(|stream| stream.last_id);
+            let group = group_for_xgroup(db, &key, &group)?;
+            group.last_delivered = match id {
+                GroupId::Id(id) => id,
+                GroupId::Last => last_id.unwrap(),
+            };
+            group.entries_read = entries_read;
+            db.touch(&key);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        StreamCommand::GroupDestroy(key, group) => {
+            let stream = db.stream_mut(&key)?.ok_or(StoreError::XGroupNoKey)?;
+            let destroyed = stream.groups.remove(&group).is_some();
+            if destroyed {
+                // Consumers blocked on the group get told it's gone.
+                db.touch(&key);
+                db.signal_ready(&key);
+            }
+            Ok(RespData::Integer(destroyed as i64))
+        }
+        StreamCommand::GroupCreateConsumer(key, group, consumer) => {
+            let created =
+                group_for_xgroup(db, &key, &group)?.create_consumer(&consumer, unix_time_ms());
+            if created {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(created as i64))
+        }
+        StreamCommand::GroupDelConsumer(key, group, consumer) => {
+            let pending = group_for_xgroup(db, &key, &group)?.remove_consumer(&consumer);
+            if pending.is_some() {
+                db.touch(&key);
+            }
+            Ok(RespData::Integer(pending.unwrap_or(0) as i64))
+        }
+        // Like Redis, what reading does to a group (XREADGROUP, XCLAIM and
+        // XACK) doesn't count as modifying the stream for WATCH.
+        StreamCommand::Ack(key, group, ids) => {
+            let Some(group) = db
+                .stream_mut(&key)?
+                .and_then(|stream| stream.groups.get_mut(&group))
+            else {
+                return Ok(RespData::Integer(0));
+            };
+            let acked = ids.into_iter().filter(|id| group.ack(*id)).count();
+            Ok(RespData::Integer(acked as i64))
+        }
+        StreamCommand::Pending { key, group, range } => {
+            let Some(group_state) = db
+                .stream(&key)?
+                .and_then(|stream| stream.groups.get(&group))
+            else {
+                return Err(StoreError::NoGroup(key, group));
+            };
+            Ok(consumer_group::pending_reply(
+                group_state,
+                range.as_ref(),
+                unix_time_ms(),
+            ))
+        }
+        StreamCommand::Claim(_) | StreamCommand::AutoClaim(_) => Ok(claim(db, &command)?.0),
+        StreamCommand::InfoStream(key) => {
+            Ok(info_reply(db.stream(&key)?.ok_or(StoreError::NoSuchKey)?))
+        }
+        StreamCommand::InfoGroups(key) => Ok(consumer_group::groups_reply(
+            db.stream(&key)?.ok_or(StoreError::NoSuchKey)?,
+        )),
+        StreamCommand::InfoConsumers(key, group) => {
+            let stream = db.stream(&key)?.ok_or(StoreError::NoSuchKey)?;
+            let Some(group_state) = stream.groups.get(&group) else {
+                return Err(StoreError::NoSuchGroup(key, group));
+            };
+            Ok(consumer_group::consumers_reply(group_state, unix_time_ms()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn stream(len: u64) -> Stream {
+        let mut stream = Stream::default();
+        for i in 1..=len {
+            stream.add(id(i, 0), &[("n".to_string(), i.to_string())]);
+        }
+        stream
+    }
+
+    fn ids(entries: Vec<Entry>) -> Vec<u64> {
+        entries.iter().map(|entry| entry.id.ms).collect()
+    }
+
+    #[test]
+    fn generates_ids_after_the_last_one() {
+        let mut stream = Stream::default();
+        assert_eq!(stream.next_id(AddId::AutoSeq(0), 5), Ok(id(0, 1)));
+        stream.add(id(5, 3), &[]);
+        assert_eq!(stream.next_id(AddId::Auto, 4), Ok(id(5, 4)));
+        assert_eq!(stream.next_id(AddId::Auto, 9), Ok(id(9, 0)));
+        assert_eq!(stream.next_id(AddId::AutoSeq(5), 0), Ok(id(5, 4)));
+        assert_eq!(
+            stream.next_id(AddId::AutoSeq(4), 0),
+            Err(StoreError::StreamIdTooSmall)
+        );
+        assert_eq!(
+            stream.next_id(AddId::Explicit(id(5, 3)), 0),
+            Err(StoreError::StreamIdTooSmall)
+        );
+    }
+
+    #[test]
+    fn ranges_span_nodes() {
+        let mut stream = stream(250);
+        assert_eq!(stream.nodes.len(), 3);
+        assert_eq!(
+            ids(stream.range(id(99, 0), id(102, 0), None, false)),
+            [99, 100, 101, 102]
+        );
+        assert_eq!(
+            ids(stream.range(id(99, 0), StreamId::MAX, Some(3), true)),
+            [250, 249, 248]
+        );
+        assert!(stream.remove(id(100, 0)));
+        assert!(!stream.remove(id(100, 0)));
+        assert_eq!(
+            ids(stream.range(id(99, 0), id(102, 0), None, true)),
+            [102, 101, 99]
+        );
+        assert_eq!(stream.len(), 249);
+        assert_eq!(parse_bound("(5", true).map(|bound| bound.seq), Ok(1));
+    }
+
+    #[test]
+    fn trims_whole_nodes_unless_exact() {
+        let trim = |strategy, approx| Trim {
+            strategy,
+            approx,
+            limit: 0,
+        };
+        let mut approx = stream(250);
+        assert_eq!(approx.trim(trim(TrimStrategy::MaxLen(120), true)), 100);
+        assert_eq!(approx.len(), 150);
+
+        let mut exact = stream(250);
+        assert_eq!(exact.trim(trim(TrimStrategy::MaxLen(120), false)), 130);
+        assert_eq!(
+            ids(exact.range(StreamId::MIN, StreamId::MAX, Some(1), false)),
+            [131]
+        );
+
+        let mut min_id = stream(250);
+        assert_eq!(
+            min_id.trim(trim(TrimStrategy::MinId(id(205, 0)), false)),
+            204
+        );
+        assert_eq!(min_id.first_entry().map(|entry| entry.id), Some(id(205, 0)));
+    }
+}
@@ -3,8 +3,22 @@ use std::{
     net::TcpStream,
 };
 
-pub fn send_message_to_client(mut stream: &TcpStream, message: &str) -> Result<(), Error> {
-    stream.write(message.as_bytes())?;
+pub fn send_message_to_client(
+    mut stream: &TcpStream,
+    message: impl AsRef<[u8]>,
+) -> Result<(), Error> {
+    stream.write_all(message.as_ref())?;
     stream.flush()?;
     Ok(())
 }
+
+/// Whether the peer has closed its end of the connection. Used while a client
+/// is parked on a blocking command and we are not reading from it.
+pub fn peer_closed(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let closed = matches!(stream.peek(&mut [0]), Ok(0));
+    let _ = stream.set_nonblocking(false);
+    closed
+}
@@ -0,0 +1,465 @@
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+    zset::format_score,
+};
+
+/// What TDIGEST.CREATE defaults to, as in RedisBloom.
+const DEFAULT_COMPRESSION: usize = 100;
+/// Far more centroids than any use calls for, and little enough that a digest
+/// buffering values up to its compression stays a sane size.
+const MAX_COMPRESSION: usize = 1 << 20;
+/// How many values wait to be merged into the centroids, relative to the
+/// compression.
+const BUFFER_FACTOR: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// A merging t-digest. Values are grouped into centroids, small near the
+/// extremes and larger in the middle, so quantiles near 0 and 1 stay
+/// accurate. A larger compression keeps more centroids.
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    compression: usize,
+    /// Merged centroids, ordered by mean.
+    centroids: Vec<Centroid>,
+    /// Values added since the last merge.
+    buffer: Vec<Centroid>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    fn new(compression: usize) -> Self {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(Centroid {
+            mean: value,
+            weight: 1.0,
+        });
+        if self.buffer.len() >= self.compression.saturating_mul(BUFFER_FACTOR) {
+            self.compress();
+        }
+    }
+
+    /// Adds everything in `other`.
+    fn absorb(&mut self, other: &TDigest) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.buffer.extend(&other.centroids);
+        self.buffer.extend(&other.buffer);
+        self.compress();
+    }
+
+    /// Merges the buffered values into the centroids. Neighbouring centroids
+    /// are combined as long as the result stays within the size allowed at
+    /// its quantile, `4 n q (1 - q) / compression`.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = std::mem::take(&mut self.centroids);
+        all.append(&mut self.buffer);
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        let total: f64 = all.iter().map(|centroid| centroid.weight).sum();
+
+        let mut merged: Vec<Centroid> = Vec::new();
+        let mut before = 0.0;
+        for centroid in all {
+            if let Some(last) = merged.last_mut() {
+                let weight = last.weight + centroid.weight;
+                let size_at = |q: f64| 4.0 * total * q * (1.0 - q) / self.compression as f64;
+                let limit = size_at(before / total).min(size_at((before + weight) / total));
+                if weight <= limit {
+                    last.mean += (centroid.mean - last.mean) * centroid.weight / weight;
+                    last.weight = weight;
+                    continue;
+                }
+                before += last.weight;
+            }
+            merged.push(centroid);
+        }
+        self.centroids = merged;
+    }
+
+    /// The centroids with any buffered values merged in.
+    fn merged(&self) -> Vec<Centroid> {
+        let mut digest = self.clone();
+        digest.compress();
+        digest.centroids
+    }
+
+    /// The value below which a fraction `q` of the values fall.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let centroids = self.merged();
+        if centroids.is_empty() {
+            return f64::NAN;
+        }
+        if q <= 0.0 {
+            return self.min;
+        }
+        let total: f64 = centroids.iter().map(|centroid| centroid.weight).sum();
+        let index = q * total;
+        let mut before = 0.0;
+        for (i, centroid) in centroids.iter().enumerate() {
+            if before + centroid.weight > index {
+                if centroid.weight <= 1.0 {
+                    return centroid.mean;
+                }
+                // Spread a centroid's values evenly between the midpoints to
+                // its neighbours.
+                let low = match i {
+                    0 => self.min,
+                    _ => (centroids[i - 1].mean + centroid.mean) / 2.0,
+                };
+                let high = centroids
+                    .get(i + 1)
+                    .map_or(self.max, |next| (centroid.mean + next.mean) / 2.0);
+                return low + (high - low) * (index - before) / centroid.weight;
+            }
+            before += centroid.weight;
+        }
+        self.max
+    }
+
+    /// The fraction of values below `value`, counting those equal to it as
+    /// half below.
+    pub fn cdf(&self, value: f64) -> f64 {
+        let centroids = self.merged();
+        if centroids.is_empty() {
+            return f64::NAN;
+        }
+        if value < self.min {
+            return 0.0;
+        }
+        if value > self.max {
+            return 1.0;
+        }
+        let total: f64 = centroids.iter().map(|centroid| centroid.weight).sum();
+        let weight_where = |keep: fn(f64, f64) -> bool| -> f64 {
+            centroids
+                .iter()
+                .filter(|centroid| keep(centroid.mean, value))
+                .map(|centroid| centroid.weight)
+                .sum()
+        };
+        let below = weight_where(|mean, value| mean < value);
+        let equal = weight_where(|mean, value| mean == value);
+        if equal > 0.0 {
+            return (below + equal / 2.0) / total;
+        }
+
+        // Interpolate between the centres of the centroids around the value,
+        // or the extremes past the first and last.
+        let after = centroids.partition_point(|centroid| centroid.mean < value);
+        let (low_mean, low_position) = match after {
+            0 => (self.min, 0.0),
+            _ => (
+                centroids[after - 1].mean,
+                below - centroids[after - 1].weight / 2.0,
+            ),
+        };
+        let (high_mean, high_position) = match centroids.get(after) {
+            Some(next) => (next.mean, below + next.weight / 2.0),
+            None => (self.max, total),
+        };
+        let position = if high_mean > low_mean {
+            low_position
+                + (high_position - low_position) * (value - low_mean) / (high_mean - low_mean)
+        } else {
+            low_position
+        };
+        position / total
+    }
+}
+
+fn tdigest_error(message: &str) -> CommandError {
+    CommandError::Other(format!("ERR T-Digest: {}", message))
+}
+
+fn compression(args: &mut Args) -> Result<usize, CommandError> {
+    let compression = args
+        .positive()
+        .ok()
+        .filter(|compression| *compression > 0)
+        .ok_or_else(|| tdigest_error("error parsing compression parameter"))?;
+    if compression > MAX_COMPRESSION {
+        return Err(tdigest_error("compression parameter is too large"));
+    }
+    Ok(compression)
+}
+
+/// The values TDIGEST.ADD and TDIGEST.CDF take, all the rest of `args`.
+fn values(args: &mut Args, what: &str) -> Result<Vec<f64>, CommandError> {
+    let mut values = Vec::new();
+    while !args.is_empty() {
+        let value = args
+            .float()
+            .ok()
+            .filter(|value| !value.is_nan())
+            .ok_or_else(|| tdigest_error(&format!("error parsing {}", what)))?;
+        values.push(value);
+    }
+    if values.is_empty() {
+        return Err(args.arity_error());
+    }
+    Ok(values)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TDigestCommand {
+    Create(String, usize),
+    Add(String, Vec<f64>),
+    Quantile(String, Vec<f64>),
+    Cdf(String, Vec<f64>),
+    Merge {
+        destination: String,
+        sources: Vec<String>,
+        compression: Option<usize>,
+        /// Whether the destination's values are dropped rather than merged
+        /// with the sources'.
+        overwrite: bool,
+    },
+}
+
+impl TDigestCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<TDigestCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "TDIGEST.CREATE" => {
+                let key = args.string()?;
+                let compression = if args.keyword("COMPRESSION") {
+                    compression(&mut args)?
+                } else {
+                    DEFAULT_COMPRESSION
+                };
+                TDigestCommand::Create(key, compression)
+            }
+            "TDIGEST.ADD" => {
+                TDigestCommand::Add(args.string()?, values(&mut args, "val parameter")?)
+            }
+            "TDIGEST.QUANTILE" => {
+                let key = args.string()?;
+                let quantiles = values(&mut args, "quantile")?;
+                if quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                    return Err(tdigest_error("quantile should be in [0,1]"));
+                }
+                TDigestCommand::Quantile(key, quantiles)
+            }
+            "TDIGEST.CDF" => TDigestCommand::Cdf(args.string()?, values(&mut args, "value")?),
+            "TDIGEST.MERGE" => {
+                let destination = args.string()?;
+                let count = args
+                    .positive()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| tdigest_error("error parsing numkeys"))?;
+                let mut sources = Vec::new();
+                for _ in 0..count {
+                    sources.push(args.string()?);
+                }
+                let (mut compression_arg, mut overwrite) = (None, false);
+                while !args.is_empty() {
+                    if args.keyword("COMPRESSION") {
+                        compression_arg = Some(compression(&mut args)?);
+                    } else if args.keyword("OVERRIDE") {
+                        overwrite = true;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                TDigestCommand::Merge {
+                    destination,
+                    sources,
+                    compression: compression_arg,
+                    overwrite,
+                }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            TDigestCommand::Create(..) | TDigestCommand::Add(..) | TDigestCommand::Merge { .. }
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            TDigestCommand::Create(key, _)
+            | TDigestCommand::Add(key, _)
+            | TDigestCommand::Quantile(key, _)
+            | TDigestCommand::Cdf(key, _) => vec![key],
+            TDigestCommand::Merge {
+                destination,
+                sources,
+                ..
+            } => std::iter::once(destination)
+                .chain(sources)
+                .map(String::as_str)
+                .collect(),
+        }
+    }
+}
+
+fn missing_key() -> StoreError {
+    StoreError::TDigest("key does not exist")
+}
+
+fn doubles_reply(values: impl Iterator<Item = f64>) -> RespData {
+    RespData::Array(
+        values
+            .map(|value| RespData::BulkString(format_score(value)))
+            .collect(),
+    )
+}
+
+pub fn execute(db: &mut Db, command: TDigestCommand) -> Result<RespData, StoreError> {
+    match command {
+        TDigestCommand::Create(key, compression) => {
+            if db.contains(&key) {
+                return Err(StoreError::TDigest("key already exists"));
+            }
+            db.set(key, Value::TDigest(TDigest::new(compression)), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        TDigestCommand::Add(key, values) => {
+            let digest = db.tdigest_mut(&key)?.ok_or_else(missing_key)?;
+            for value in values {
+                digest.add(value);
+            }
+            db.touch(&key);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        TDigestCommand::Quantile(key, quantiles) => {
+            let digest = db.tdigest(&key)?.ok_or_else(missing_key)?;
+            Ok(doubles_reply(
+                quantiles.into_iter().map(|q| digest.quantile(q)),
+            ))
+        }
+        TDigestCommand::Cdf(key, values) => {
+            let digest = db.tdigest(&key)?.ok_or_else(missing_key)?;
+            Ok(doubles_reply(
+                values.into_iter().map(|value| digest.cdf(value)),
+            ))
+        }
+        TDigestCommand::Merge {
+            destination,
+            sources,
+            compression,
+            overwrite,
+        } => {
+            let mut inputs = Vec::new();
+            for key in &sources {
+                inputs.push(db.tdigest(key)?.ok_or_else(missing_key)?.clone());
+            }
+            let existing = match db.tdigest(&destination)? {
+                Some(digest) if !overwrite => Some(digest.clone()),
+                _ => None,
+            };
+            inputs.extend(existing);
+            // Without COMPRESSION, the result is as fine-grained as the finest
+            // of what went into it.
+            let compression = compression.unwrap_or_else(|| {
+                inputs
+                    .iter()
+                    .map(|digest| digest.compression)
+                    .max()
+                    .unwrap_or(DEFAULT_COMPRESSION)
+            });
+            let mut merged = TDigest::new(compression);
+            for input in &inputs {
+                merged.absorb(input);
+            }
+            db.set(destination, Value::TDigest(merged), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(values: &[f64]) -> TDigest {
+        let mut digest = TDigest::new(DEFAULT_COMPRESSION);
+        for &value in values {
+            digest.add(value);
+        }
+        digest
+    }
+
+    #[test]
+    fn small_digests_are_exact() {
+        // The example from the TDIGEST.QUANTILE docs.
+        let digest = digest(&[1., 2., 2., 3., 3., 3., 4., 4., 4., 4., 5., 5., 5., 5., 5.]);
+        let quantiles: Vec<f64> = (0..=10).map(|i| digest.quantile(i as f64 / 10.0)).collect();
+        assert_eq!(quantiles, [1., 2., 3., 3., 4., 4., 4., 5., 5., 5., 5.]);
+        let cdf: Vec<String> = (0..=6)
+            .map(|i| format!("{:.4}", digest.cdf(i as f64)))
+            .collect();
+        assert_eq!(
+            cdf,
+            ["0.0000", "0.0333", "0.1333", "0.3000", "0.5333", "0.8333", "1.0000"]
+        );
+        assert!(TDigest::new(100).quantile(0.5).is_nan());
+    }
+
+    #[test]
+    fn large_digests_stay_close() {
+        let values: Vec<f64> = (0..100_000)
+            .map(|i| ((i * 7919) % 100_000) as f64)
+            .collect();
+        let digest = digest(&values);
+        assert!(digest.centroids.len() < 1000, "{}", digest.centroids.len());
+        for q in [0.001, 0.01, 0.25, 0.5, 0.75, 0.99, 0.999] {
+            let error = (digest.quantile(q) - q * 100_000.0).abs();
+            assert!(error < 100_000.0 * 0.005, "q={} error={}", q, error);
+            let error = (digest.cdf(q * 100_000.0) - q).abs();
+            assert!(error < 0.005, "cdf at q={} error={}", q, error);
+        }
+        assert_eq!(digest.quantile(1.0), 99_999.0);
+        assert_eq!(digest.quantile(0.0), 0.0);
+    }
+
+    #[test]
+    fn merges() {
+        let mut merged = TDigest::new(100);
+        merged.absorb(&digest(&[1., 2., 3.]));
+        merged.absorb(&digest(&[4., 5.]));
+        assert_eq!(merged.quantile(0.5), 3.0);
+        assert_eq!((merged.min, merged.max), (1.0, 5.0));
+    }
+
+    #[test]
+    fn refuses_compressions_too_large() {
+        let parse = |compression: &str| {
+            let args =
+                ["td", "COMPRESSION", compression].map(|arg| RespData::BulkString(arg.to_string()));
+            TDigestCommand::parse("TDIGEST.CREATE", &args)
+        };
+        assert!(parse("1048576").is_ok());
+        let too_large = Err(tdigest_error("compression parameter is too large"));
+        assert_eq!(parse("1048577"), too_large);
+        assert_eq!(parse("9223372036854775807"), too_large);
+    }
+}
@@ -0,0 +1,964 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    command::{Args, CommandError},
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+    zset::format_score,
+};
+
+/// What to do with a sample for a timestamp that already has one.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DuplicatePolicy {
+    #[default]
+    Block,
+    First,
+    Last,
+    Min,
+    Max,
+    Sum,
+}
+
+impl DuplicatePolicy {
+    fn parse(name: &str) -> Result<Self, CommandError> {
+        match name.to_uppercase().as_str() {
+            "BLOCK" => Ok(DuplicatePolicy::Block),
+            "FIRST" => Ok(DuplicatePolicy::First),
+            "LAST" => Ok(DuplicatePolicy::Last),
+            "MIN" => Ok(DuplicatePolicy::Min),
+            "MAX" => Ok(DuplicatePolicy::Max),
+            "SUM" => Ok(DuplicatePolicy::Sum),
+            _ => Err(tsdb_error("Unknown DUPLICATE_POLICY")),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DuplicatePolicy::Block => "BLOCK",
+            DuplicatePolicy::First => "FIRST",
+            DuplicatePolicy::Last => "LAST",
+            DuplicatePolicy::Min => "MIN",
+            DuplicatePolicy::Max => "MAX",
+            DuplicatePolicy::Sum => "SUM",
+        }
+    }
+
+    /// The value to keep when `new` arrives for a timestamp holding `old`,
+    /// or `None` if that isn't allowed.
+    fn merge(self, old: f64, new: f64) -> Option<f64> {
+        match self {
+            DuplicatePolicy::Block => None,
+            DuplicatePolicy::First => Some(old),
+            DuplicatePolicy::Last => Some(new),
+            DuplicatePolicy::Min => Some(old.min(new)),
+            DuplicatePolicy::Max => Some(old.max(new)),
+            DuplicatePolicy::Sum => Some(old + new),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregation {
+    Avg,
+    Sum,
+    Min,
+    Max,
+    Count,
+}
+
+impl Aggregation {
+    fn parse(name: &str) -> Result<Self, CommandError> {
+        match name.to_uppercase().as_str() {
+            "AVG" => Ok(Aggregation::Avg),
+            "SUM" => Ok(Aggregation::Sum),
+            "MIN" => Ok(Aggregation::Min),
+            "MAX" => Ok(Aggregation::Max),
+            "COUNT" => Ok(Aggregation::Count),
+            _ => Err(tsdb_error("Unknown aggregation type")),
+        }
+    }
+
+    /// Aggregates the values of a bucket, which is never empty.
+    fn apply(self, values: &[f64]) -> f64 {
+        let sum = || values.iter().sum::<f64>();
+        match self {
+            Aggregation::Avg => sum() / values.len() as f64,
+            Aggregation::Sum => sum(),
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Count => values.len() as f64,
+        }
+    }
+}
+
+/// Groups samples into buckets of `duration` milliseconds, the first one
+/// starting at `align`, and aggregates each.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Bucketing {
+    aggregation: Aggregation,
+    duration: i64,
+    align: i64,
+}
+
+impl Bucketing {
+    /// Parses what follows `AGGREGATION`.
+    fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let aggregation = Aggregation::parse(&args.string()?)?;
+        let duration = args
+            .integer()
+            .ok()
+            .filter(|duration| *duration > 0)
+            .ok_or_else(|| tsdb_error("bucketDuration must be greater than zero"))?;
+        Ok(Bucketing {
+            aggregation,
+            duration,
+            align: 0,
+        })
+    }
+
+    fn start(&self, timestamp: i64) -> i64 {
+        timestamp - (timestamp - self.align).rem_euclid(self.duration)
+    }
+
+    /// Aggregates samples given in timestamp order, one sample per bucket
+    /// that has any.
+    fn aggregate(&self, samples: impl Iterator<Item = (i64, f64)>) -> Vec<(i64, f64)> {
+        let mut buckets: Vec<(i64, Vec<f64>)> = Vec::new();
+        for (timestamp, value) in samples {
+            let start = self.start(timestamp);
+            match buckets.last_mut() {
+                Some((last, values)) if *last == start => values.push(value),
+                _ => buckets.push((start, vec![value])),
+            }
+        }
+        buckets
+            .into_iter()
+            .map(|(start, values)| (start, self.aggregation.apply(&values)))
+            .collect()
+    }
+}
+
+/// Compacts the samples of a series into another series.
+#[derive(Debug)]
+struct Rule {
+    dest: String,
+    bucketing: Bucketing,
+    /// The latest bucket, which is written out once a sample lands past it.
+    open: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct TimeSeries {
+    samples: BTreeMap<i64, f64>,
+    /// How far back from the latest sample to keep samples, in milliseconds,
+    /// where 0 keeps them all.
+    retention: i64,
+    duplicate_policy: DuplicatePolicy,
+    labels: Vec<(String, String)>,
+    /// The series this one is compacted from.
+    source: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl TimeSeries {
+    fn last(&self) -> Option<(i64, f64)> {
+        self.samples
+            .last_key_value()
+            .map(|(timestamp, value)| (*timestamp, *value))
+    }
+
+    fn add(
+        &mut self,
+        timestamp: i64,
+        value: f64,
+        policy: DuplicatePolicy,
+    ) -> Result<(), StoreError> {
+        if let Some((last, _)) = self.last() {
+            if self.retention > 0 && timestamp < last - self.retention {
+                return Err(StoreError::Tsdb("Timestamp is older than retention"));
+            }
+        }
+        match self.samples.get_mut(&timestamp) {
+            Some(old) => {
+                *old = policy.merge(*old, value).ok_or(StoreError::Tsdb(
+                    "Error at upsert, update is not supported when DUPLICATE_POLICY is set to \
+                     BLOCK mode",
+                ))?;
+            }
+            None => {
+                self.samples.insert(timestamp, value);
+            }
+        }
+        if let (Some((last, _)), true) = (self.last(), self.retention > 0) {
+            self.samples = self.samples.split_off(&(last - self.retention));
+        }
+        Ok(())
+    }
+
+    /// The buckets to write to compacted series after a sample was added at
+    /// `timestamp`: the bucket it closed by starting a later one, or its own
+    /// bucket if it arrived late for it.
+    fn compact(&mut self, timestamp: i64) -> Vec<(String, i64, f64)> {
+        let mut writes = Vec::new();
+        for rule in &mut self.rules {
+            let start = rule.bucketing.start(timestamp);
+            let bucket = match rule.open {
+                Some(open) if start > open => {
+                    rule.open = Some(start);
+                    open
+                }
+                Some(open) if start < open => start,
+                Some(_) => continue,
+                None => {
+                    rule.open = Some(start);
+                    continue;
+                }
+            };
+            let values: Vec<f64> = self
+                .samples
+                .range(bucket..bucket + rule.bucketing.duration)
+                .map(|(_, value)| *value)
+                .collect();
+            if !values.is_empty() {
+                let value = rule.bucketing.aggregation.apply(&values);
+                writes.push((rule.dest.clone(), bucket, value));
+            }
+        }
+        writes
+    }
+}
+
+fn tsdb_error(message: &str) -> CommandError {
+    CommandError::Other(format!("ERR TSDB: {}", message))
+}
+
+/// A sample's timestamp, where `*` (`None`) stands for the server's clock.
+fn timestamp(args: &mut Args) -> Result<Option<i64>, CommandError> {
+    let timestamp = args.string()?;
+    if timestamp == "*" {
+        return Ok(None);
+    }
+    timestamp
+        .parse()
+        .ok()
+        .filter(|timestamp| *timestamp >= 0)
+        .map(Some)
+        .ok_or_else(|| tsdb_error("invalid timestamp"))
+}
+
+fn value(args: &mut Args) -> Result<f64, CommandError> {
+    args.float()
+        .ok()
+        .filter(|value| !value.is_nan())
+        .ok_or_else(|| tsdb_error("invalid value"))
+}
+
+/// A bound of TS.RANGE, where `-` and `+` stand for the earliest and latest
+/// possible timestamps.
+fn range_bound(args: &mut Args) -> Result<i64, CommandError> {
+    match args.string()?.as_str() {
+        "-" => Ok(0),
+        "+" => Ok(i64::MAX),
+        bound => bound
+            .parse()
+            .map_err(|_| tsdb_error("wrong fromTimestamp or toTimestamp")),
+    }
+}
+
+/// The options that apply when a command creates its series.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Options {
+    retention: Option<i64>,
+    duplicate_policy: Option<DuplicatePolicy>,
+    labels: Vec<(String, String)>,
+}
+
+impl Options {
+    /// Parses an option if `args` continues with one, returning whether it
+    /// did. `LABELS` takes the rest of the arguments.
+    fn parse(&mut self, args: &mut Args) -> Result<bool, CommandError> {
+        if args.keyword("RETENTION") {
+            let retention = args
+                .integer()
+                .ok()
+                .filter(|retention| *retention >= 0)
+                .ok_or_else(|| tsdb_error("Couldn't parse RETENTION"))?;
+            self.retention = Some(retention);
+        } else if args.keyword("DUPLICATE_POLICY") {
+            self.duplicate_policy = Some(DuplicatePolicy::parse(&args.string()?)?);
+        } else if args.keyword("LABELS") {
+            let labels = args.rest()?;
+            if labels.is_empty() || labels.len() % 2 == 1 {
+                return Err(CommandError::Syntax);
+            }
+            self.labels = labels
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(retention) = self.retention {
+            args.extend(["RETENTION".to_string(), retention.to_string()]);
+        }
+        if let Some(policy) = self.duplicate_policy {
+            args.extend(["DUPLICATE_POLICY".to_string(), policy.name().to_string()]);
+        }
+        if !self.labels.is_empty() {
+            args.push("LABELS".to_string());
+            for (label, value) in &self.labels {
+                args.extend([label.clone(), value.clone()]);
+            }
+        }
+        args
+    }
+
+    fn create(&self) -> TimeSeries {
+        TimeSeries {
+            retention: self.retention.unwrap_or(0),
+            duplicate_policy: self.duplicate_policy.unwrap_or_default(),
+            labels: self.labels.clone(),
+            ..TimeSeries::default()
+        }
+    }
+}
+
+/// The samples TS.RANGE and TS.MRANGE reply with.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Range {
+    from: i64,
+    to: i64,
+    count: Option<usize>,
+    bucketing: Option<Bucketing>,
+    reverse: bool,
+}
+
+impl Range {
+    fn parse(args: &mut Args, reverse: bool) -> Result<Self, CommandError> {
+        Ok(Range {
+            from: range_bound(args)?,
+            to: range_bound(args)?,
+            count: None,
+            bucketing: None,
+            reverse,
+        })
+    }
+
+    /// Parses an option if `args` continues with one, returning whether it
+    /// did.
+    fn parse_option(&mut self, args: &mut Args) -> Result<bool, CommandError> {
+        if args.keyword("COUNT") {
+            self.count = Some(args.positive()?);
+        } else if args.keyword("AGGREGATION") {
+            self.bucketing = Some(Bucketing::parse(args)?);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn query(&self, series: &TimeSeries) -> Vec<(i64, f64)> {
+        if self.from > self.to {
+            return Vec::new();
+        }
+        let samples = series
+            .samples
+            .range(self.from..=self.to)
+            .map(|(timestamp, value)| (*timestamp, *value));
+        let mut samples = match &self.bucketing {
+            Some(bucketing) => bucketing.aggregate(samples),
+            None => samples.collect(),
+        };
+        if self.reverse {
+            samples.reverse();
+        }
+        if let Some(count) = self.count {
+            samples.truncate(count);
+        }
+        samples
+    }
+}
+
+/// A TS.MRANGE filter on a label: `label=value` or `label!=value`, with
+/// `(a,b)` for any of several values. An empty value matches series without
+/// the label.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Filter {
+    label: String,
+    values: Vec<String>,
+    negated: bool,
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Self, CommandError> {
+        let (label, value, negated) = match filter.split_once("!=") {
+            Some((label, value)) => (label, value, true),
+            None => match filter.split_once('=') {
+                Some((label, value)) => (label, value, false),
+                None => return Err(tsdb_error("failed parsing labels")),
+            },
+        };
+        if label.is_empty() {
+            return Err(tsdb_error("failed parsing labels"));
+        }
+        let values = match value
+            .strip_prefix('(')
+            .and_then(|list| list.strip_suffix(')'))
+        {
+            Some(list) => list.split(',').map(str::to_string).collect(),
+            None => vec![value.to_string()],
+        };
+        Ok(Filter {
+            label: label.to_string(),
+            values,
+            negated,
+        })
+    }
+
+    /// Whether the filter picks series by a label they have, as at least one
+    /// filter has to.
+    fn is_matcher(&self) -> bool {
+        !self.negated && self.values.iter().any(|value| !value.is_empty())
+    }
+
+    fn matches(&self, labels: &[(String, String)]) -> bool {
+        let value = labels
+            .iter()
+            .find(|(label, _)| *label == self.label)
+            .map_or("", |(_, value)| value.as_str());
+        self.values.iter().any(|candidate| candidate == value) != self.negated
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TimeSeriesCommand {
+    Create(String, Options),
+    Add {
+        key: String,
+        timestamp: Option<i64>,
+        value: f64,
+        on_duplicate: Option<DuplicatePolicy>,
+        options: Options,
+    },
+    MAdd(Vec<(String, Option<i64>, f64)>),
+    IncrBy {
+        key: String,
+        value: f64,
+        timestamp: Option<i64>,
+        options: Options,
+    },
+    Get(String),
+    Range(String, Range),
+    MRange {
+        range: Range,
+        with_labels: bool,
+        filters: Vec<Filter>,
+    },
+    CreateRule {
+        source: String,
+        dest: String,
+        bucketing: Bucketing,
+    },
+}
+
+impl TimeSeriesCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<TimeSeriesCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "TS.CREATE" => {
+                let key = args.string()?;
+                let mut options = Options::default();
+                while !args.is_empty() {
+                    if !options.parse(&mut args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                TimeSeriesCommand::Create(key, options)
+            }
+            "TS.ADD" => {
+                let (key, timestamp, value) =
+                    (args.string()?, timestamp(&mut args)?, value(&mut args)?);
+                let mut options = Options::default();
+                let mut on_duplicate = None;
+                while !args.is_empty() {
+                    if args.keyword("ON_DUPLICATE") {
+                        on_duplicate = Some(DuplicatePolicy::parse(&args.string()?)?);
+                    } else if !options.parse(&mut args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                TimeSeriesCommand::Add {
+                    key,
+                    timestamp,
+                    value,
+                    on_duplicate,
+                    options,
+                }
+            }
+            "TS.MADD" => {
+                let samples = args.len() / 3;
+                if samples == 0 || samples * 3 != args.len() {
+                    return Err(args.arity_error());
+                }
+                let mut samples = Vec::new();
+                while !args.is_empty() {
+                    samples.push((args.string()?, timestamp(&mut args)?, value(&mut args)?));
+                }
+                TimeSeriesCommand::MAdd(samples)
+            }
+            "TS.INCRBY" => {
+                let (key, value) = (args.string()?, value(&mut args)?);
+                let mut options = Options::default();
+                let mut timestamp_arg = None;
+                while !args.is_empty() {
+                    if args.keyword("TIMESTAMP") {
+                        timestamp_arg = timestamp(&mut args)?;
+                    } else if !options.parse(&mut args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                TimeSeriesCommand::IncrBy {
+                    key,
+                    value,
+                    timestamp: timestamp_arg,
+                    options,
+                }
+            }
+            "TS.GET" => TimeSeriesCommand::Get(args.string()?),
+            "TS.RANGE" | "TS.REVRANGE" => {
+                let key = args.string()?;
+                let mut range = Range::parse(&mut args, name == "TS.REVRANGE")?;
+                while !args.is_empty() {
+                    if !range.parse_option(&mut args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                TimeSeriesCommand::Range(key, range)
+            }
+            "TS.MRANGE" => {
+                let mut range = Range::parse(&mut args, false)?;
+                let mut with_labels = false;
+                let mut filters = Vec::new();
+                while !args.is_empty() {
+                    if args.keyword("WITHLABELS") {
+                        with_labels = true;
+                    } else if args.keyword("FILTER") {
+                        for filter in args.rest()? {
+                            filters.push(Filter::parse(&filter)?);
+                        }
+                    } else if !range.parse_option(&mut args)? {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                if !filters.iter().any(Filter::is_matcher) {
+                    return Err(tsdb_error("please provide at least one matcher"));
+                }
+                TimeSeriesCommand::MRange {
+                    range,
+                    with_labels,
+                    filters,
+                }
+            }
+            "TS.CREATERULE" => {
+                let (source, dest) = (args.string()?, args.string()?);
+                if !args.keyword("AGGREGATION") {
+                    return Err(CommandError::Syntax);
+                }
+                let mut bucketing = Bucketing::parse(&mut args)?;
+                if !args.is_empty() {
+                    bucketing.align = args.integer()?;
+                }
+                TimeSeriesCommand::CreateRule {
+                    source,
+                    dest,
+                    bucketing,
+                }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(
+            self,
+            TimeSeriesCommand::Get(_)
+                | TimeSeriesCommand::Range(..)
+                | TimeSeriesCommand::MRange { .. }
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            TimeSeriesCommand::Create(key, _)
+            | TimeSeriesCommand::Add { key, .. }
+            | TimeSeriesCommand::IncrBy { key, .. }
+            | TimeSeriesCommand::Get(key)
+            | TimeSeriesCommand::Range(key, _) => vec![key],
+            TimeSeriesCommand::MAdd(samples) => {
+                samples.iter().map(|(key, ..)| key.as_str()).collect()
+            }
+            TimeSeriesCommand::MRange { .. } => Vec::new(),
+            TimeSeriesCommand::CreateRule { source, dest, .. } => vec![source, dest],
+        }
+    }
+
+    /// Stamps samples left to the server's clock with `now`.
+    pub fn resolve_timestamps(&mut self, now: i64) {
+        match self {
+            TimeSeriesCommand::Add { timestamp, .. }
+            | TimeSeriesCommand::IncrBy { timestamp, .. } => {
+                timestamp.get_or_insert(now);
+            }
+            TimeSeriesCommand::MAdd(samples) => {
+                for (_, timestamp, _) in samples {
+                    timestamp.get_or_insert(now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The command to feed replicas in place of one that adds samples, with
+    /// explicit timestamps only. Meant to be called after
+    /// `resolve_timestamps`.
+    pub fn replicated_form(&self) -> Option<Vec<String>> {
+        let timestamp = |timestamp: &Option<i64>| timestamp.unwrap_or_default().to_string();
+        match self {
+            TimeSeriesCommand::Add {
+                key,
+                timestamp: at,
+                value,
+                on_duplicate,
+                options,
+            } => {
+                let mut args = vec![
+                    "TS.ADD".to_string(),
+                    key.clone(),
+                    timestamp(at),
+                    value.to_string(),
+                ];
+                if let Some(policy) = on_duplicate {
+                    args.extend(["ON_DUPLICATE".to_string(), policy.name().to_string()]);
+                }
+                args.extend(options.to_args());
+                Some(args)
+            }
+            TimeSeriesCommand::MAdd(samples) => {
+                let mut args = vec!["TS.MADD".to_string()];
+                for (key, at, value) in samples {
+                    args.extend([key.clone(), timestamp(at), value.to_string()]);
+                }
+                Some(args)
+            }
+            TimeSeriesCommand::IncrBy {
+                key,
+                value,
+                timestamp: at,
+                options,
+            } => {
+                let mut args = vec![
+                    "TS.INCRBY".to_string(),
+                    key.clone(),
+                    value.to_string(),
+                    "TIMESTAMP".to_string(),
+                    timestamp(at),
+                ];
+                args.extend(options.to_args());
+                Some(args)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn missing_key() -> StoreError {
+    StoreError::Tsdb("the key does not exist")
+}
+
+/// Adds a sample to the series at `key`, then brings the series it is
+/// compacted into up to date.
+fn add_sample(
+    db: &mut Db,
+    key: &str,
+    timestamp: i64,
+    value: f64,
+    policy: Option<DuplicatePolicy>,
+) -> Result<(), StoreError> {
+    let series = db.timeseries_mut(key)?.ok_or_else(missing_key)?;
+    series.add(timestamp, value, policy.unwrap_or(series.duplicate_policy))?;
+
+    let compacted_samples = series.compact(timestamp);
+    db.touch(key);
+
+    let mut stale = Vec::new();
+    for (dest, bucket, value) in compacted_samples {
+        match db.timeseries_mut(&dest) {
+            Ok(Some(compacted)) if compacted.source.as_deref() == Some(key) => {
+                // Samples too old for the compacted series' retention are dropped.
+                if compacted.add(bucket, value, DuplicatePolicy::Last).is_ok() {
+                    db.touch(&dest);
+                }
+            }
+            // The compacted series was deleted or replaced since.
+            _ => stale.push(dest),
+        }
+    }
+    if !stale.is_empty() {
+        let series = db.timeseries_mut(key)?.ok_or_else(missing_key)?;
+        series.rules.retain(|rule| !stale.contains(&rule.dest));
+    }
+    Ok(())
+}
+
+fn create_if_missing(db: &mut Db, key: &str, options: &Options) -> Result<(), StoreError> {
+    if db.timeseries(key)?.is_none() {
+        db.set(key.to_string(), Value::TimeSeries(options.create()), None);
+    }
+    Ok(())
+}
+
+fn sample_reply((timestamp, value): (i64, f64)) -> RespData {
+    RespData::Array(vec![
+        RespData::Integer(timestamp),
+        RespData::SimpleString(format_score(value)),
+    ])
+}
+
+fn samples_reply(samples: Vec<(i64, f64)>) -> RespData {
+    RespData::Array(samples.into_iter().map(sample_reply).collect())
+}
+
+pub fn execute(db: &mut Db, command: TimeSeriesCommand) -> Result<RespData, StoreError> {
+    match command {
+        TimeSeriesCommand::Create(key, options) => {
+            if db.contains(&key) {
+                return Err(StoreError::Tsdb("key already exists"));
+            }
+            db.set(key, Value::TimeSeries(options.create()), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        TimeSeriesCommand::Add {
+            key,
+            timestamp,
+            value,
+            on_duplicate,
+            options,
+        } => {
+            create_if_missing(db, &key, &options)?;
+            let timestamp = timestamp.unwrap_or_default();
+            add_sample(db, &key, timestamp, value, on_duplicate)?;
+            Ok(RespData::Integer(timestamp))
+        }
+        TimeSeriesCommand::MAdd(samples) => Ok(RespData::Array(
+            samples
+                .into_iter()
+                .map(|(key, timestamp, value)| {
+                    let timestamp = timestamp.unwrap_or_default();
+                    match add_sample(db, &key, timestamp, value, None) {
+                        Ok(()) => RespData::Integer(timestamp),
+                        Err(e) => RespData::Error(e.to_string()),
+                    }
+                })
+                .collect(),
+        )),
+        TimeSeriesCommand::IncrBy {
+            key,
+            value,
+            timestamp,
+            options,
+        } => {
+            create_if_missing(db, &key, &options)?;
+            let timestamp = timestamp.unwrap_or_default();
+            let last = db.timeseries(&key)?.and_then(TimeSeries::last);
+            let (last_timestamp, last_value) = last.unwrap_or((0, 0.0));
+            if timestamp < last_timestamp {
+                return Err(StoreError::Tsdb(
+                    "timestamp must be equal to or higher than the maximum existing timestamp",
+                ));
+            }
+            add_sample(
+                db,
+                &key,
+                timestamp,
+                last_value + value,
+                Some(DuplicatePolicy::Last),
+            )?;
+            Ok(RespData::Integer(timestamp))
+        }
+        TimeSeriesCommand::Get(key) => {
+            let series = db.timeseries(&key)?.ok_or_else(missing_key)?;
+            Ok(series
+                .last()
+                .map_or(RespData::Array(Vec::new()), sample_reply))
+        }
+        TimeSeriesCommand::Range(key, range) => {
+            let series = db.timeseries(&key)?.ok_or_else(missing_key)?;
+            Ok(samples_reply(range.query(series)))
+        }
+        TimeSeriesCommand::MRange {
+            range,
+            with_labels,
+            filters,
+        } => {
+            let mut matches: Vec<(&str, &TimeSeries)> = db
+                .iter()
+                .filter_map(|(key, value)| match value {
+                    Value::TimeSeries(series) => Some((key, series)),
+                    _ => None,
+                })
+                .filter(|(_, series)| filters.iter().all(|filter| filter.matches(&series.labels)))
+                .collect();
+            matches.sort_by_key(|(key, _)| *key);
+            Ok(RespData::Array(
+                matches
+                    .into_iter()
+                    .map(|(key, series)| {
+                        let labels = if with_labels {
+                            series
+                                .labels
+                                .iter()
+                                .map(|(label, value)| {
+                                    RespData::Array(vec![
+                                        RespData::BulkString(label.clone()),
+                                        RespData::BulkString(value.clone()),
+                                    ])
+                                })
+                                .collect()
+                        } else {
+                            Vec::new()
+                        };
+                        RespData::Array(vec![
+                            RespData::BulkString(key.to_string()),
+                            RespData::Array(labels),
+                            samples_reply(range.query(series)),
+                        ])
+                    })
+                    .collect(),
+            ))
+        }
+        TimeSeriesCommand::CreateRule {
+            source,
+            dest,
+            bucketing,
+        } => {
+            if source == dest {
+                return Err(StoreError::Tsdb(
+                    "the source key and destination key should be different",
+                ));
+            }
+            let compacted = db.timeseries(&dest)?.ok_or_else(missing_key)?;
+            if compacted.source.is_some() {
+                return Err(StoreError::Tsdb(
+                    "the destination key already has a src rule",
+                ));
+            }
+            if !compacted.rules.is_empty() {
+                return Err(StoreError::Tsdb(
+                    "the destination key already has a dst rule",
+                ));
+            }
+            let series = db.timeseries(&source)?.ok_or_else(missing_key)?;
+            if series.source.is_some() {
+                return Err(StoreError::Tsdb("the source key already has a source rule"));
+            }
+
+            db.timeseries_mut(&dest)?.unwrap().source = Some(source.clone());
+            let series = db.timeseries_mut(&source)?.unwrap();
+            // Only samples added from now on are compacted.
+            let open = series
+                .last()
+                .map(|(timestamp, _)| bucketing.start(timestamp));
+            series.rules.push(Rule {
+                dest: dest.clone(),
+                bucketing,
+                open,
+            });
+            db.touch(&source);
+            db.touch(&dest);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(samples: &[(i64, f64)]) -> TimeSeries {
+        let mut series = TimeSeries::default();
+        for &(timestamp, value) in samples {
+            series
+                .add(timestamp, value, DuplicatePolicy::Block)
+                .unwrap();
+        }
+        series
+    }
+
+    #[test]
+    fn duplicates_follow_the_policy() {
+        let mut series = series(&[(10, 1.0)]);
+        assert!(series.add(10, 2.0, DuplicatePolicy::Block).is_err());
+        series.add(10, 5.0, DuplicatePolicy::Sum).unwrap();
+        series.add(10, 3.0, DuplicatePolicy::Max).unwrap();
+        series.add(10, 4.0, DuplicatePolicy::First).unwrap();
+        assert_eq!(series.last(), Some((10, 6.0)));
+    }
+
+    #[test]
+    fn retention_trims_old_samples() {
+        let mut series = series(&[(10, 1.0), (20, 2.0)]);
+        series.retention = 15;
+        series.add(30, 3.0, DuplicatePolicy::Block).unwrap();
+        assert_eq!(series.samples.keys().collect::<Vec<_>>(), [&20, &30]);
+        assert!(series.add(14, 0.0, DuplicatePolicy::Block).is_err());
+    }
+
+    #[test]
+    fn aggregates_into_buckets() {
+        let series = series(&[(1, 1.0), (5, 2.0), (10, 6.0), (21, 4.0), (29, 8.0)]);
+        let range = |aggregation, reverse, count| Range {
+            from: 0,
+            to: i64::MAX,
+            count,
+            bucketing: Some(Bucketing {
+                aggregation,
+                duration: 10,
+                align: 0,
+            }),
+            reverse,
+        };
+        assert_eq!(
+            range(Aggregation::Avg, false, None).query(&series),
+            [(0, 1.5), (10, 6.0), (20, 6.0)]
+        );
+        assert_eq!(
+            range(Aggregation::Count, true, Some(2)).query(&series),
+            [(20, 2.0), (10, 1.0)]
+        );
+        assert_eq!(
+            range(Aggregation::Min, false, Some(1)).query(&series),
+            [(0, 1.0)]
+        );
+    }
+
+    #[test]
+    fn filters_match_labels() {
+        let labels = [("area".to_string(), "east".to_string())];
+        let matches = |filter: &str| Filter::parse(filter).unwrap().matches(&labels);
+        assert!(matches("area=east"));
+        assert!(matches("area=(west,east)"));
+        assert!(!matches("area!=(west,east)"));
+        assert!(matches("sensor="));
+        assert!(!matches("area="));
+        assert!(matches("area!="));
+        assert!(!Filter::parse("sensor=").unwrap().is_matcher());
+        assert!(Filter::parse("=x").is_err());
+    }
+}
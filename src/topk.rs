@@ -0,0 +1,309 @@
+use crate::{
+    command::{Args, CommandError},
+    hyperloglog::murmur_hash_64a,
+    random::Seeded,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+};
+
+/// What TOPK.RESERVE defaults to, as in RedisBloom.
+const DEFAULT_WIDTH: usize = 8;
+const DEFAULT_DEPTH: usize = 7;
+const DEFAULT_DECAY: f64 = 0.9;
+/// The most buckets a sketch may have, 512MB worth.
+const MAX_BUCKETS: usize = 32 << 20;
+/// Seeds every sketch's generator the same, so replicas decay alike.
+const SEED: u64 = 0x5eed_70b4;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    fingerprint: u32,
+    count: u64,
+}
+
+/// A HeavyKeeper sketch that keeps track of the `k` most frequent items. An
+/// item in a bucket another one hashes to decays with a probability of
+/// `decay` to the power of its count, so that only heavy hitters hold on to
+/// their buckets.
+#[derive(Debug)]
+pub struct TopK {
+    k: usize,
+    width: usize,
+    decay: f64,
+    buckets: Vec<Bucket>,
+    /// The top items and their counts, most frequent first.
+    top: Vec<(String, u64)>,
+    random: Seeded,
+}
+
+impl TopK {
+    fn new(k: usize, width: usize, depth: usize, decay: f64) -> Self {
+        TopK {
+            k,
+            width,
+            decay,
+            buckets: vec![Bucket::default(); width * depth],
+            top: Vec::new(),
+            random: Seeded::new(SEED),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.buckets.len() / self.width
+    }
+
+    fn fingerprint(item: &str) -> u32 {
+        murmur_hash_64a(item.as_bytes(), 0x9747b28c) as u32
+    }
+
+    fn bucket_index(&self, item: &str, row: usize) -> usize {
+        let column = murmur_hash_64a(item.as_bytes(), row as u64) % self.width as u64;
+        row * self.width + column as usize
+    }
+
+    /// Counts one more `item`, returning the item it pushed out of the top
+    /// items, if any.
+    pub fn add(&mut self, item: &str) -> Option<String> {
+        let fingerprint = Self::fingerprint(item);
+        let mut count = 0;
+        for row in 0..self.depth() {
+            let index = self.bucket_index(item, row);
+            let bucket = &mut self.buckets[index];
+            if bucket.count > 0 && bucket.fingerprint != fingerprint {
+                let chance = self.decay.powf(bucket.count as f64);
+                if self.random.next_f64() < chance {
+                    bucket.count -= 1;
+                }
+            }
+            if bucket.count == 0 {
+                bucket.fingerprint = fingerprint;
+            }
+            if bucket.fingerprint == fingerprint {
+                bucket.count += 1;
+                count = count.max(bucket.count);
+            }
+        }
+        self.update_top(item, count)
+    }
+
+    fn update_top(&mut self, item: &str, count: u64) -> Option<String> {
+        let mut expelled = None;
+        if let Some(entry) = self.top.iter_mut().find(|(name, _)| name == item) {
+            entry.1 = count;
+        } else if self.top.len() < self.k {
+            self.top.push((item.to_string(), count));
+        } else if self.top.last().is_some_and(|(_, least)| count > *least) {
+            expelled = self.top.pop().map(|(name, _)| name);
+            self.top.push((item.to_string(), count));
+        } else {
+            return None;
+        }
+        // Stable, so ties keep their order.
+        self.top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        expelled
+    }
+
+    /// The estimated count of `item`: the highest of the buckets it holds.
+    pub fn count(&self, item: &str) -> u64 {
+        let fingerprint = Self::fingerprint(item);
+        (0..self.depth())
+            .map(|row| self.buckets[self.bucket_index(item, row)])
+            .filter(|bucket| bucket.fingerprint == fingerprint)
+            .map(|bucket| bucket.count)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+fn topk_error(message: &str) -> CommandError {
+    CommandError::Other(format!("ERR TopK: {}", message))
+}
+
+fn dimension(args: &mut Args, what: &str) -> Result<usize, CommandError> {
+    args.positive()
+        .ok()
+        .filter(|value| *value > 0)
+        .ok_or_else(|| topk_error(&format!("invalid {}", what)))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TopKCommand {
+    Reserve {
+        key: String,
+        k: usize,
+        width: usize,
+        depth: usize,
+        decay: f64,
+    },
+    Add(String, Vec<String>),
+    /// TOPK.LIST with whether to include counts.
+    List(String, bool),
+    Count(String, Vec<String>),
+}
+
+impl TopKCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<TopKCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "TOPK.RESERVE" => {
+                let key = args.string()?;
+                let k = dimension(&mut args, "k")?;
+                let (width, depth, decay) = if args.is_empty() {
+                    (DEFAULT_WIDTH, DEFAULT_DEPTH, DEFAULT_DECAY)
+                } else {
+                    let width = dimension(&mut args, "width")?;
+                    let depth = dimension(&mut args, "depth")?;
+                    let decay = args
+                        .float()
+                        .ok()
+                        .filter(|decay| *decay > 0.0 && *decay <= 1.0)
+                        .ok_or_else(|| topk_error("invalid decay value. must be '<= 1' & '> 0'"))?;
+                    (width, depth, decay)
+                };
+                if width
+                    .checked_mul(depth)
+                    .filter(|buckets| *buckets <= MAX_BUCKETS)
+                    .is_none()
+                {
+                    return Err(topk_error("width/depth is too large"));
+                }
+                TopKCommand::Reserve {
+                    key,
+                    k,
+                    width,
+                    depth,
+                    decay,
+                }
+            }
+            "TOPK.ADD" | "TOPK.COUNT" => {
+                let key = args.string()?;
+                let items = args.rest()?;
+                if items.is_empty() {
+                    return Err(args.arity_error());
+                }
+                if name == "TOPK.ADD" {
+                    TopKCommand::Add(key, items)
+                } else {
+                    TopKCommand::Count(key, items)
+                }
+            }
+            "TOPK.LIST" => {
+                let key = args.string()?;
+                let with_count = args.keyword("WITHCOUNT");
+                TopKCommand::List(key, with_count)
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, TopKCommand::Reserve { .. } | TopKCommand::Add(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            TopKCommand::Reserve { key, .. }
+            | TopKCommand::Add(key, _)
+            | TopKCommand::List(key, _)
+            | TopKCommand::Count(key, _) => vec![key],
+        }
+    }
+}
+
+fn missing_key() -> StoreError {
+    StoreError::TopK("key does not exist")
+}
+
+pub fn execute(db: &mut Db, command: TopKCommand) -> Result<RespData, StoreError> {
+    match command {
+        TopKCommand::Reserve {
+            key,
+            k,
+            width,
+            depth,
+            decay,
+        } => {
+            if db.contains(&key) {
+                return Err(StoreError::TopK("key already exists"));
+            }
+            db.set(key, Value::TopK(TopK::new(k, width, depth, decay)), None);
+            Ok(RespData::SimpleString("OK".to_string()))
+        }
+        TopKCommand::Add(key, items) => {
+            let topk = db.topk_mut(&key)?.ok_or_else(missing_key)?;
+            let expelled = items
+                .iter()
+                .map(|item| {
+                    topk.add(item)
+                        .map_or(RespData::BulkStringNull, RespData::BulkString)
+                })
+                .collect();
+            db.touch(&key);
+            Ok(RespData::Array(expelled))
+        }
+        TopKCommand::List(key, with_count) => {
+            let topk = db.topk(&key)?.ok_or_else(missing_key)?;
+            let mut reply = Vec::new();
+            for (item, count) in &topk.top {
+                reply.push(RespData::BulkString(item.clone()));
+                if with_count {
+                    reply.push(RespData::Integer(*count as i64));
+                }
+            }
+            Ok(RespData::Array(reply))
+        }
+        TopKCommand::Count(key, items) => {
+            let topk = db.topk(&key)?.ok_or_else(missing_key)?;
+            Ok(RespData::Array(
+                items
+                    .iter()
+                    .map(|item| RespData::Integer(topk.count(item) as i64))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_heavy_hitters() {
+        let mut topk = TopK::new(3, 50, 4, DEFAULT_DECAY);
+        for round in 0..100 {
+            for heavy in ["a", "b", "c"] {
+                topk.add(heavy);
+            }
+            topk.add(&format!("noise{}", round));
+        }
+        let mut top: Vec<&str> = topk.top.iter().map(|(item, _)| item.as_str()).collect();
+        top.sort();
+        assert_eq!(top, ["a", "b", "c"]);
+        assert!(topk.count("a") >= 90, "{}", topk.count("a"));
+    }
+
+    #[test]
+    fn reports_expelled_items() {
+        let mut topk = TopK::new(1, 8, 7, DEFAULT_DECAY);
+        assert_eq!(topk.add("a"), None);
+        assert_eq!(topk.add("b"), None);
+        assert_eq!(topk.add("b"), Some("a".to_string()));
+        assert_eq!(topk.top, [("b".to_string(), 2)]);
+    }
+
+    #[test]
+    fn refuses_sketches_too_large_to_allocate() {
+        let parse = |width: &str, depth: &str| {
+            let args = ["topk", "10", width, depth, "0.9"]
+                .map(|arg| RespData::BulkString(arg.to_string()));
+            TopKCommand::parse("TOPK.RESERVE", &args)
+        };
+        assert!(parse("1048576", "32").is_ok());
+        let too_large = Err(topk_error("width/depth is too large"));
+        assert_eq!(parse("1048576", "33"), too_large);
+        assert_eq!(parse("9223372036854775807", "4"), too_large);
+    }
+}
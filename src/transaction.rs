@@ -0,0 +1,117 @@
+use crate::{
+    command::{CommandArgs, RedisCommand},
+    resp_parser::Protocol,
+};
+
+/// The commands a client queued after MULTI, to run all at once on EXEC.
+#[derive(Default)]
+pub struct Transaction {
+    /// Each command with its arguments as sent, for feeding replicas.
+    pub commands: Vec<(RedisCommand, CommandArgs)>,
+    /// Set when a command could not be queued, e.g. for a syntax error, so
+    /// that EXEC discards the whole transaction.
+    pub aborted: bool,
+}
+
+impl Transaction {
+    /// Whether a command may be queued. Those about replication or the
+    /// server rather than the keyspace can't be.
+    fn can_queue(command: &RedisCommand) -> bool {
+        !matches!(
+            command,
+            RedisCommand::Info
+                | RedisCommand::ReplConf(_)
+                | RedisCommand::PSync
+                | RedisCommand::Wait(..)
+                | RedisCommand::ConfigGet(_)
+        )
+    }
+
+    /// Queues a command if it may be, else marks the transaction for EXEC to
+    /// discard. Returns what to reply. WATCH is refused without aborting,
+    /// as in Redis.
+    pub fn queue(&mut self, command: RedisCommand, args: CommandArgs) -> &'static str {
+        if let RedisCommand::Watch(_) = command {
+            "-ERR WATCH inside MULTI is not allowed\r\n"
+        } else if Self::can_queue(&command) {
+            self.commands.push((command, args));
+            "+QUEUED\r\n"
+        } else {
+            self.aborted = true;
+            "-ERR Command not allowed inside a transaction\r\n"
+        }
+    }
+
+    /// The database the client ends up in once the transaction ran, if any
+    /// of its SELECTs succeeded.
+    pub fn selected_db(&self, databases: usize) -> Option<usize> {
+        self.commands
+            .iter()
+            .rev()
+            .find_map(|(command, _)| match command {
+                RedisCommand::Select(index) => usize::try_from(*index)
+                    .ok()
+                    .filter(|index| *index < databases),
+                _ => None,
+            })
+    }
+
+    /// The protocol the client speaks once the transaction ran, if any of
+    /// its HELLOs switched it.
+    pub fn selected_protocol(&self) -> Option<Protocol> {
+        self.commands
+            .iter()
+            .rev()
+            .find_map(|(command, _)| match command {
+                RedisCommand::Hello(Some(2)) => Some(Protocol::Resp2),
+                RedisCommand::Hello(Some(3)) => Some(Protocol::Resp3),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_valid_select_wins() {
+        let mut transaction = Transaction::default();
+        assert_eq!(transaction.selected_db(16), None);
+        for index in [3, 5, 99, -1] {
+            let args = vec![b"SELECT".to_vec(), index.to_string().into_bytes()];
+            transaction
+                .commands
+                .push((RedisCommand::Select(index), args));
+        }
+        assert_eq!(transaction.selected_db(16), Some(5));
+        assert_eq!(transaction.selected_db(4), Some(3));
+    }
+
+    #[test]
+    fn commands_that_cannot_be_queued_abort_the_transaction() {
+        let mut transaction = Transaction::default();
+        let hello = vec![b"HELLO".to_vec(), b"3".to_vec()];
+        assert_eq!(
+            transaction.queue(RedisCommand::Hello(Some(3)), hello),
+            "+QUEUED\r\n"
+        );
+        assert!(!transaction.aborted);
+        assert_eq!(transaction.selected_protocol(), Some(Protocol::Resp3));
+
+        let watch = vec![b"WATCH".to_vec(), b"k".to_vec()];
+        assert_eq!(
+            transaction.queue(RedisCommand::Watch(vec!["k".to_string()]), watch),
+            "-ERR WATCH inside MULTI is not allowed\r\n"
+        );
+        assert!(!transaction.aborted);
+
+        let info = vec![b"INFO".to_vec()];
+        assert_eq!(
+            transaction.queue(RedisCommand::Info, info),
+            "-ERR Command not allowed inside a transaction\r\n"
+        );
+        assert!(transaction.aborted);
+        assert_eq!(transaction.commands.len(), 1);
+    }
+}
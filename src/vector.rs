@@ -0,0 +1,670 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use crate::{
+    command::{Args, CommandError},
+    random::Seeded,
+    resp_parser::RespData,
+    store::{Db, StoreError, Value},
+    zset::format_score,
+};
+
+/// Links per node above level 0, unless VADD's M says otherwise. Level 0
+/// gets twice as many.
+const DEFAULT_M: usize = 16;
+/// Candidates kept while looking for a new node's neighbors.
+const DEFAULT_BUILD_EF: usize = 200;
+/// Candidates kept while searching, unless VSIM's EF says otherwise.
+const DEFAULT_SEARCH_EF: usize = 100;
+const DEFAULT_COUNT: usize = 10;
+const MAX_LEVEL: usize = 16;
+/// Seeds every set's generator the same, so replicas build the same graph.
+const SEED: u64 = 0x4e5f_7a11;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Metric {
+    Cosine,
+    L2,
+    InnerProduct,
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl Metric {
+    fn parse(name: &str) -> Option<Metric> {
+        match name.to_uppercase().as_str() {
+            "COSINE" => Some(Metric::Cosine),
+            "L2" => Some(Metric::L2),
+            "IP" => Some(Metric::InnerProduct),
+            _ => None,
+        }
+    }
+
+    /// Brings a vector into the form distances are taken on: unit length for
+    /// cosine, so that it comes down to a dot product.
+    fn prepare(self, mut vector: Vec<f32>) -> Vec<f32> {
+        if self == Metric::Cosine {
+            let norm = dot(&vector, &vector).sqrt();
+            if norm > 0.0 {
+                vector.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+        vector
+    }
+
+    /// How far apart two prepared vectors are; lower is closer.
+    fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => 1.0 - dot(a, b),
+            Metric::L2 => a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum(),
+            Metric::InnerProduct => -dot(a, b),
+        }
+    }
+
+    /// What VSIM WITHSCORES reports for a distance: a similarity from 0 to 1
+    /// for cosine as in Redis, the Euclidean distance for L2 and the inner
+    /// product for IP.
+    fn score(self, distance: f32) -> f64 {
+        let distance = f64::from(distance);
+        match self {
+            Metric::Cosine => 1.0 - distance / 2.0,
+            Metric::L2 => distance.sqrt(),
+            Metric::InnerProduct => -distance,
+        }
+    }
+}
+
+/// A node being compared to some vector, ordered by distance and then by
+/// ID so that ties always break the same way.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Candidate {
+    distance: f32,
+    id: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    element: String,
+    vector: Vec<f32>,
+    /// The nodes this one links to on each of its levels, from level 0 up.
+    links: Vec<Vec<usize>>,
+}
+
+/// A vector set: named vectors of one dimension, linked into an HNSW graph
+/// for approximate nearest neighbor search. Each node is on a random number
+/// of levels; searches descend greedily through the sparse upper levels and
+/// explore the dense bottom one.
+#[derive(Debug)]
+pub struct VectorSet {
+    dimension: usize,
+    metric: Metric,
+    m: usize,
+    /// Nodes by ID, with holes where nodes were removed.
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    ids: HashMap<String, usize>,
+    /// The node on the highest level, where searches start.
+    entry: Option<usize>,
+    random: Seeded,
+}
+
+impl VectorSet {
+    fn new(dimension: usize, metric: Metric, m: usize) -> Self {
+        VectorSet {
+            dimension,
+            metric,
+            m,
+            nodes: Vec::new(),
+            free: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            random: Seeded::new(SEED),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn node(&self, id: usize) -> &Node {
+        self.nodes[id].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, id: usize) -> &mut Node {
+        self.nodes[id].as_mut().unwrap()
+    }
+
+    fn top_level(&self, id: usize) -> usize {
+        self.node(id).links.len() - 1
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 {
+            self.m * 2
+        } else {
+            self.m
+        }
+    }
+
+    fn candidate(&self, vector: &[f32], id: usize) -> Candidate {
+        Candidate {
+            distance: self.metric.distance(vector, &self.node(id).vector),
+            id,
+        }
+    }
+
+    /// A level drawn from an exponential distribution, so that each level
+    /// has about `m` times fewer nodes than the one below.
+    fn random_level(&mut self) -> usize {
+        let uniform = 1.0 - self.random.next_f64();
+        let level = -uniform.ln() / (self.m as f64).ln();
+        (level as usize).min(MAX_LEVEL)
+    }
+
+    /// The `ef` nodes closest to `vector` on `level` that a best-first walk
+    /// from `entries` finds, closest first.
+    fn search_level(
+        &self,
+        vector: &[f32],
+        entries: &[usize],
+        ef: usize,
+        level: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        let mut found: BinaryHeap<Candidate> = BinaryHeap::new();
+        for &id in entries {
+            let candidate = self.candidate(vector, id);
+            candidates.push(Reverse(candidate));
+            found.push(candidate);
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|worst| closest > *worst) {
+                break;
+            }
+            for &neighbor in &self.node(closest.id).links[level] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let candidate = self.candidate(vector, neighbor);
+                if found.len() < ef || found.peek().is_some_and(|worst| candidate < *worst) {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// Walks down from the entry point, keeping only the closest node on each
+    /// level above `level`.
+    fn descend(&self, vector: &[f32], level: usize) -> Option<usize> {
+        let mut closest = self.entry?;
+        for upper in (level + 1..=self.top_level(closest)).rev() {
+            closest = self.search_level(vector, &[closest], 1, upper)[0].id;
+        }
+        Some(closest)
+    }
+
+    /// Keeps the `max_links` closest of a node's links on a level.
+    fn prune(&mut self, id: usize, level: usize) {
+        let max_links = self.max_links(level);
+        let node = self.node(id);
+        let mut links: Vec<Candidate> = node.links[level]
+            .iter()
+            .map(|&link| self.candidate(&node.vector, link))
+            .collect();
+        links.sort();
+        links.truncate(max_links);
+        self.node_mut(id).links[level] = links.into_iter().map(|link| link.id).collect();
+    }
+
+    /// Adds an element, or moves it to a new vector. Returns whether it is
+    /// new.
+    fn insert(&mut self, element: String, vector: Vec<f32>, ef: usize) -> bool {
+        let vector = self.metric.prepare(vector);
+        let added = match self.ids.get(&element) {
+            Some(&id) if self.node(id).vector == vector => return false,
+            Some(_) => {
+                self.remove(&element);
+                false
+            }
+            None => true,
+        };
+
+        let level = self.random_level();
+        let node = Node {
+            element: element.clone(),
+            vector,
+            links: vec![Vec::new(); level + 1],
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = Some(node);
+                id
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        self.ids.insert(element, id);
+
+        let Some(entry) = self.entry else {
+            self.entry = Some(id);
+            return added;
+        };
+        let top_level = self.top_level(entry);
+        let vector = self.node(id).vector.clone();
+        let mut entries = vec![self.descend(&vector, level).unwrap()];
+        for level in (0..=level.min(top_level)).rev() {
+            let found = self.search_level(&vector, &entries, ef.max(self.m), level);
+            let neighbors: Vec<usize> = found.iter().take(self.m).map(|c| c.id).collect();
+            for &neighbor in &neighbors {
+                self.node_mut(neighbor).links[level].push(id);
+                if self.node(neighbor).links[level].len() > self.max_links(level) {
+                    self.prune(neighbor, level);
+                }
+            }
+            self.node_mut(id).links[level] = neighbors;
+            entries = found.into_iter().map(|c| c.id).collect();
+        }
+        if level > top_level {
+            self.entry = Some(id);
+        }
+        added
+    }
+
+    /// Removes an element, relinking the nodes that pointed at it to its
+    /// other neighbors so the graph stays connected.
+    fn remove(&mut self, element: &str) -> bool {
+        let Some(id) = self.ids.remove(element) else {
+            return false;
+        };
+        let removed = self.nodes[id].take().unwrap();
+        self.free.push(id);
+
+        // Links only go one way, so any node on the removed one's levels may
+        // point at it.
+        for other in 0..self.nodes.len() {
+            let levels = match &self.nodes[other] {
+                Some(node) => node.links.len().min(removed.links.len()),
+                None => continue,
+            };
+            for level in 0..levels {
+                let links = &mut self.node_mut(other).links[level];
+                let before = links.len();
+                links.retain(|&link| link != id);
+                if links.len() == before {
+                    continue;
+                }
+                let node = self.node(other);
+                let mut replacements: Vec<Candidate> = removed.links[level]
+                    .iter()
+                    .filter(|&&link| link != other && !node.links[level].contains(&link))
+                    .map(|&link| self.candidate(&node.vector, link))
+                    .collect();
+                replacements.sort();
+                replacements.truncate(self.max_links(level) - node.links[level].len());
+                let links = &mut self.node_mut(other).links[level];
+                links.extend(replacements.into_iter().map(|c| c.id));
+            }
+        }
+
+        if self.entry == Some(id) {
+            self.entry = (0..self.nodes.len())
+                .filter(|&id| self.nodes[id].is_some())
+                .max_by_key(|&id| (self.top_level(id), Reverse(id)));
+        }
+        true
+    }
+
+    /// The `count` elements nearest to `vector`, using the graph with `ef`
+    /// candidates, or checking every element when `exact` is set.
+    fn search(&self, vector: &[f32], count: usize, ef: usize, exact: bool) -> Vec<Candidate> {
+        let vector = self.metric.prepare(vector.to_vec());
+        let mut found = if exact {
+            let mut all: Vec<Candidate> = self
+                .ids
+                .values()
+                .map(|&id| self.candidate(&vector, id))
+                .collect();
+            all.sort();
+            all
+        } else {
+            match self.descend(&vector, 0) {
+                Some(entry) => self.search_level(&vector, &[entry], ef.max(count), 0),
+                None => Vec::new(),
+            }
+        };
+        found.truncate(count);
+        found
+    }
+}
+
+/// What VSIM looks for neighbors of.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    Element(String),
+    Vector(Vec<f32>),
+}
+
+/// VSIM's target and options.
+#[derive(Debug, PartialEq)]
+pub struct Sim {
+    target: Target,
+    with_scores: bool,
+    count: usize,
+    ef: usize,
+    /// TRUTH: compare against every element instead of walking the graph.
+    exact: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VectorCommand {
+    Add {
+        key: String,
+        element: String,
+        vector: Vec<f32>,
+        metric: Option<Metric>,
+        ef: usize,
+        m: usize,
+    },
+    Sim(String, Box<Sim>),
+    Rem(String, String),
+}
+
+/// `VALUES count x1 x2 ...`.
+fn parse_values(args: &mut Args) -> Result<Vec<f32>, CommandError> {
+    let count = args
+        .positive()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| CommandError::Other("ERR invalid vector specification".into()))?;
+    (0..count)
+        .map(|_| {
+            args.float()
+                .ok()
+                .map(|x| x as f32)
+                .filter(|x| x.is_finite())
+                .ok_or(CommandError::NotFloat)
+        })
+        .collect()
+}
+
+fn positive_option(args: &mut Args, name: &str) -> Result<usize, CommandError> {
+    args.positive()
+        .ok()
+        .filter(|value| *value > 0)
+        .ok_or_else(|| CommandError::Other(format!("ERR invalid {} value", name)))
+}
+
+impl VectorCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<VectorCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "VADD" => {
+                let key = args.string()?;
+                if !args.keyword("VALUES") {
+                    return Err(CommandError::Syntax);
+                }
+                let vector = parse_values(&mut args)?;
+                let element = args.string()?;
+                let (mut metric, mut ef, mut m) = (None, DEFAULT_BUILD_EF, DEFAULT_M);
+                while !args.is_empty() {
+                    if args.keyword("EF") {
+                        ef = positive_option(&mut args, "EF")?;
+                    } else if args.keyword("M") {
+                        m = positive_option(&mut args, "M")?.max(2);
+                    } else if args.keyword("METRIC") {
+                        metric = Some(Metric::parse(&args.string()?).ok_or_else(|| {
+                            CommandError::Other("ERR METRIC must be COSINE, L2 or IP".into())
+                        })?);
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                VectorCommand::Add {
+                    key,
+                    element,
+                    vector,
+                    metric,
+                    ef,
+                    m,
+                }
+            }
+            "VSIM" => {
+                let key = args.string()?;
+                let target = if args.keyword("ELE") {
+                    Target::Element(args.string()?)
+                } else if args.keyword("VALUES") {
+                    Target::Vector(parse_values(&mut args)?)
+                } else {
+                    return Err(CommandError::Syntax);
+                };
+                let mut sim = Sim {
+                    target,
+                    with_scores: false,
+                    count: DEFAULT_COUNT,
+                    ef: DEFAULT_SEARCH_EF,
+                    exact: false,
+                };
+                while !args.is_empty() {
+                    if args.keyword("WITHSCORES") {
+                        sim.with_scores = true;
+                    } else if args.keyword("COUNT") {
+                        sim.count = positive_option(&mut args, "COUNT")?;
+                    } else if args.keyword("EF") {
+                        sim.ef = positive_option(&mut args, "EF")?;
+                    } else if args.keyword("TRUTH") {
+                        sim.exact = true;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                VectorCommand::Sim(key, Box::new(sim))
+            }
+            "VREM" => VectorCommand::Rem(args.string()?, args.string()?),
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        !matches!(self, VectorCommand::Sim(..))
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            VectorCommand::Add { key, .. }
+            | VectorCommand::Sim(key, _)
+            | VectorCommand::Rem(key, _) => vec![key],
+        }
+    }
+}
+
+pub fn execute(db: &mut Db, command: VectorCommand) -> Result<RespData, StoreError> {
+    match command {
+        VectorCommand::Add {
+            key,
+            element,
+            vector,
+            metric,
+            ef,
+            m,
+        } => {
+            if db.vector_set(&key)?.is_none() {
+                let set = VectorSet::new(vector.len(), metric.unwrap_or(Metric::Cosine), m);
+                db.set(key.clone(), Value::VectorSet(set), None);
+            }
+            let set = db.vector_set_mut(&key)?.unwrap();
+            if vector.len() != set.dimension {
+                return Err(StoreError::VectorDimension(vector.len(), set.dimension));
+            }
+            if metric.is_some_and(|metric| metric != set.metric) {
+                return Err(StoreError::VectorMetric);
+            }
+            let added = set.insert(element, vector, ef);
+            db.touch(&key);
+            Ok(RespData::Integer(added.into()))
+        }
+        VectorCommand::Sim(key, sim) => {
+            let Some(set) = db.vector_set(&key)? else {
+                return Ok(RespData::Array(Vec::new()));
+            };
+            let vector = match &sim.target {
+                Target::Element(element) => {
+                    let id = *set.ids.get(element).ok_or(StoreError::ElementNotFound)?;
+                    set.node(id).vector.clone()
+                }
+                Target::Vector(vector) if vector.len() != set.dimension => {
+                    return Err(StoreError::VectorDimension(vector.len(), set.dimension));
+                }
+                Target::Vector(vector) => vector.clone(),
+            };
+            let mut reply = Vec::new();
+            for found in set.search(&vector, sim.count, sim.ef, sim.exact) {
+                reply.push(RespData::BulkString(set.node(found.id).element.clone()));
+                if sim.with_scores {
+                    let score = set.metric.score(found.distance);
+                    reply.push(RespData::BulkString(format_score(score)));
+                }
+            }
+            Ok(RespData::Array(reply))
+        }
+        VectorCommand::Rem(key, element) => {
+            let Some(set) = db.vector_set_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let removed = set.remove(&element);
+            if removed {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(RespData::Integer(removed.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_vectors(count: usize, dimension: usize) -> Vec<Vec<f32>> {
+        let mut random = Seeded::new(7);
+        (0..count)
+            .map(|_| {
+                (0..dimension)
+                    .map(|_| random.next_f64() as f32 * 2.0 - 1.0)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn recall(set: &VectorSet, queries: &[Vec<f32>]) -> f64 {
+        let mut hits = 0;
+        for query in queries {
+            let exact = set.search(query, 10, 0, true);
+            let approximate = set.search(query, 10, DEFAULT_SEARCH_EF, false);
+            hits += approximate
+                .iter()
+                .filter(|found| exact.contains(found))
+                .count();
+        }
+        hits as f64 / (queries.len() * 10) as f64
+    }
+
+    #[test]
+    fn refuses_components_out_of_f32_range() {
+        let parse = |values: &[&str]| {
+            let args: Vec<RespData> = ["v", "VALUES"]
+                .iter()
+                .chain(values)
+                .chain(&["a"])
+                .map(|arg| RespData::BulkString(arg.to_string()))
+                .collect();
+            VectorCommand::parse("VADD", &args)
+        };
+        assert!(parse(&["2", "3e38", "-1"]).is_ok());
+        assert!(parse(&["2", "1e39", "1"]).is_err());
+        assert!(parse(&["2", "1", "-1e39"]).is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let (a, b) = (vec![1.0, 0.0], vec![0.0, 2.0]);
+        let cosine = Metric::Cosine;
+        let distance = cosine.distance(&cosine.prepare(a.clone()), &cosine.prepare(b.clone()));
+        assert_eq!(cosine.score(distance), 0.5);
+        assert_eq!(Metric::L2.score(Metric::L2.distance(&a, &b)), 5f64.sqrt());
+        let inner = Metric::InnerProduct;
+        assert_eq!(inner.score(inner.distance(&[1.0, 2.0], &[3.0, 4.0])), 11.0);
+    }
+
+    #[test]
+    fn approximate_search_finds_nearest_neighbors() {
+        for metric in [Metric::Cosine, Metric::L2, Metric::InnerProduct] {
+            let mut set = VectorSet::new(8, metric, 8);
+            for (i, vector) in random_vectors(500, 8).into_iter().enumerate() {
+                assert!(set.insert(i.to_string(), vector, DEFAULT_BUILD_EF));
+            }
+            let recall = recall(&set, &random_vectors(520, 8)[500..]);
+            assert!(recall > 0.9, "{:?}: {}", metric, recall);
+        }
+    }
+
+    #[test]
+    fn survives_removals() {
+        let vectors = random_vectors(500, 4);
+        let mut set = VectorSet::new(4, Metric::L2, 4);
+        for (i, vector) in vectors.iter().enumerate() {
+            set.insert(i.to_string(), vector.clone(), DEFAULT_BUILD_EF);
+        }
+        for i in (0..500).step_by(2) {
+            assert!(set.remove(&i.to_string()));
+        }
+        assert!(!set.remove("0"));
+        assert_eq!(set.len(), 250);
+        assert!(set.nodes.iter().flatten().all(|node| node
+            .links
+            .iter()
+            .flatten()
+            .all(|&link| set.nodes[link].is_some())));
+
+        let nearest = set.search(&vectors[1], 1, DEFAULT_SEARCH_EF, false);
+        assert_eq!(set.node(nearest[0].id).element, "1");
+        assert!(recall(&set, &vectors[..20]) > 0.9);
+
+        assert!(!set.insert("1".to_string(), vectors[1].clone(), DEFAULT_BUILD_EF));
+        assert!(!set.insert("1".to_string(), vectors[0].clone(), DEFAULT_BUILD_EF));
+        assert_eq!(set.search(&vectors[0], 1, 10, true)[0].distance, 0.0);
+    }
+}
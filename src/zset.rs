@@ -0,0 +1,1388 @@
+use crate::{
+    command::{Args, CommandError},
+    dict::Dict,
+    executor::Rewritten,
+    list, random,
+    resp_parser::RespData,
+    set::SetOp,
+    skiplist::{Iter, SkipList},
+    store::{Db, StoreError, Value},
+};
+
+/// A sorted set: a dict from member to score for O(1) lookups, and a skiplist
+/// ordered by score for ranks and ranges.
+#[derive(Default)]
+pub struct SortedSet {
+    scores: Dict<String, f64>,
+    list: SkipList,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member`, or moves it to `score` if it is already present.
+    pub fn insert(&mut self, member: String, score: f64) {
+        if let Some(current) = self.score(&member) {
+            if current == score {
+                return;
+            }
+            self.list.remove(current, &member);
+        }
+        self.list.insert(score, member.clone());
+        self.scores.insert(member, score);
+    }
+
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.list.remove(score, member);
+        Some(score)
+    }
+
+    /// The 0-based rank of `member`, counting from the highest score when
+    /// `reverse` is set.
+    pub fn rank(&self, member: &str, reverse: bool) -> Option<usize> {
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if reverse { self.len() - 1 - rank } else { rank })
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.list.iter_from(0)
+    }
+
+    /// Members scored from `min` up to but excluding `max`, in order.
+    pub fn score_range(&self, min: f64, max: f64) -> impl Iterator<Item = (&String, f64)> {
+        let first = self.list.count_while(|score, _| score < min);
+        self.list
+            .iter_from(first)
+            .take_while(move |(_, score)| *score < max)
+    }
+
+    /// Removes the member with the lowest score, or the highest when `max`
+    /// is set.
+    pub fn pop(&mut self, max: bool) -> Option<(String, f64)> {
+        let (member, score) = if max {
+            self.list.last()
+        } else {
+            self.list.iter_from(0).next()
+        }?;
+        let member = member.clone();
+        self.remove(&member);
+        Some((member, score))
+    }
+
+    pub fn random_member(&self) -> Option<&String> {
+        self.scores.random_key()
+    }
+}
+
+/// Formats a score the way Redis's `%.17g` does, e.g. `1.5`, `3`, `1e+20`
+/// and `inf`.
+pub fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        return if score > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let exponent = if score == 0.0 {
+        0
+    } else {
+        score.abs().log10().floor() as i32
+    };
+    if (-4..17).contains(&exponent) {
+        return score.to_string();
+    }
+    let formatted = format!("{:e}", score);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    format!(
+        "{}e{}{:02}",
+        mantissa,
+        if exponent < 0 { '-' } else { '+' },
+        exponent.abs()
+    )
+}
+
+fn parse_score(args: &mut Args) -> Result<f64, CommandError> {
+    let score = args.float()?;
+    if score.is_nan() {
+        return Err(CommandError::NotFloat);
+    }
+    Ok(score)
+}
+
+/// One end of a BYSCORE range, e.g. `5`, `(5` or `-inf`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(args: &mut Args) -> Result<ScoreBound, CommandError> {
+        let bound = args.string()?;
+        let (value, exclusive) = match bound.strip_prefix('(') {
+            Some(value) => (value, true),
+            None => (bound.as_str(), false),
+        };
+        value
+            .parse()
+            .ok()
+            .filter(|value: &f64| !value.is_nan())
+            .map(|value| ScoreBound { value, exclusive })
+            .ok_or_else(|| CommandError::Other("ERR min or max is not a float".into()))
+    }
+
+    fn is_above(self, score: f64) -> bool {
+        score < self.value || (self.exclusive && score == self.value)
+    }
+
+    fn is_at_least(self, score: f64) -> bool {
+        score < self.value || (!self.exclusive && score == self.value)
+    }
+}
+
+/// One end of a BYLEX range: `-`, `+`, `[member` or `(member`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn parse(args: &mut Args) -> Result<LexBound, CommandError> {
+        let bound = args.string()?;
+        match bound.as_bytes().first() {
+            Some(b'-') if bound.len() == 1 => Ok(LexBound::Min),
+            Some(b'+') if bound.len() == 1 => Ok(LexBound::Max),
+            Some(b'[') => Ok(LexBound::Inclusive(bound[1..].to_string())),
+            Some(b'(') => Ok(LexBound::Exclusive(bound[1..].to_string())),
+            _ => Err(CommandError::Other(
+                "ERR min or max not valid string range item".into(),
+            )),
+        }
+    }
+
+    /// Whether `member` sorts before this bound taken as a minimum.
+    fn is_above(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member < bound.as_str(),
+            LexBound::Exclusive(bound) => member <= bound.as_str(),
+        }
+    }
+
+    /// Whether `member` is within this bound taken as a maximum.
+    fn is_at_least(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= bound.as_str(),
+            LexBound::Exclusive(bound) => member < bound.as_str(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RangeBy {
+    Rank(i64, i64),
+    /// Minimum and maximum, whatever order the client gave them in.
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+/// The selection of a ZRANGE or ZRANGESTORE.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Range {
+    pub by: RangeBy,
+    pub rev: bool,
+    /// LIMIT's offset and count, where a negative count means all.
+    pub limit: Option<(i64, i64)>,
+}
+
+impl Range {
+    /// Parses `start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count]`,
+    /// leaving any other options to the caller.
+    fn parse(args: &mut Args, with_scores: &mut bool) -> Result<Range, CommandError> {
+        let start = args.string()?;
+        let stop = args.string()?;
+        let mut by_score = false;
+        let mut by_lex = false;
+        let mut rev = false;
+        let mut limit = None;
+        while !args.is_empty() {
+            if args.keyword("BYSCORE") {
+                by_score = true;
+            } else if args.keyword("BYLEX") {
+                by_lex = true;
+            } else if args.keyword("REV") {
+                rev = true;
+            } else if args.keyword("LIMIT") {
+                limit = Some((args.integer()?, args.integer()?));
+            } else if args.keyword("WITHSCORES") {
+                *with_scores = true;
+            } else {
+                return Err(CommandError::Syntax);
+            }
+        }
+
+        if by_score && by_lex {
+            return Err(CommandError::Syntax);
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err(CommandError::Other(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .into(),
+            ));
+        }
+        if *with_scores && by_lex {
+            return Err(CommandError::Other(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX".into(),
+            ));
+        }
+
+        let bounds = [RespData::BulkString(start), RespData::BulkString(stop)];
+        let mut bounds = Args::new("zrange", &bounds);
+        let by = if by_score {
+            let (start, stop) = (
+                ScoreBound::parse(&mut bounds)?,
+                ScoreBound::parse(&mut bounds)?,
+            );
+            if rev {
+                RangeBy::Score(stop, start)
+            } else {
+                RangeBy::Score(start, stop)
+            }
+        } else if by_lex {
+            let (start, stop) = (LexBound::parse(&mut bounds)?, LexBound::parse(&mut bounds)?);
+            if rev {
+                RangeBy::Lex(stop, start)
+            } else {
+                RangeBy::Lex(start, stop)
+            }
+        } else {
+            RangeBy::Rank(bounds.integer()?, bounds.integer()?)
+        };
+        Ok(Range { by, rev, limit })
+    }
+
+    /// The members in the range, in the order the client asked for.
+    fn select<'a>(&self, zset: &'a SortedSet) -> Vec<(&'a String, f64)> {
+        let len = zset.len();
+        // The range as forward ranks `first..end`.
+        let (first, end) = match &self.by {
+            RangeBy::Rank(start, stop) => match list::range(len, *start, *stop) {
+                Some((start, stop)) if self.rev => (len - 1 - stop, len - start),
+                Some((start, stop)) => (start, stop + 1),
+                None => (0, 0),
+            },
+            RangeBy::Score(min, max) => (
+                zset.list.count_while(|score, _| min.is_above(score)),
+                zset.list.count_while(|score, _| max.is_at_least(score)),
+            ),
+            RangeBy::Lex(min, max) => (
+                zset.list.count_while(|_, member| min.is_above(member)),
+                zset.list.count_while(|_, member| max.is_at_least(member)),
+            ),
+        };
+
+        let (offset, count) = self.limit.unwrap_or((0, -1));
+        let Ok(offset) = usize::try_from(offset) else {
+            return Vec::new();
+        };
+        if first >= end || offset >= end - first {
+            return Vec::new();
+        }
+        let available = end - first - offset;
+        let count = usize::try_from(count).map_or(available, |count| count.min(available));
+        if self.rev {
+            zset.list
+                .iter_rev_from(end - 1 - offset)
+                .take(count)
+                .collect()
+        } else {
+            zset.list.iter_from(first + offset).take(count).collect()
+        }
+    }
+}
+
+/// The options of ZADD.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct AddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SortedSetCommand {
+    Add {
+        key: String,
+        options: AddOptions,
+        elements: Vec<(f64, String)>,
+    },
+    Rem(String, Vec<String>),
+    Score(String, String),
+    MScore(String, Vec<String>),
+    IncrBy(String, f64, String),
+    Card(String),
+    Count(String, ScoreBound, ScoreBound),
+    /// ZRANK, or ZREVRANK when `reverse` is set.
+    Rank {
+        key: String,
+        member: String,
+        reverse: bool,
+        with_score: bool,
+    },
+    Range {
+        key: String,
+        range: Range,
+        with_scores: bool,
+    },
+    RangeStore {
+        destination: String,
+        source: String,
+        range: Range,
+    },
+    /// ZPOPMIN, or ZPOPMAX when `max` is set.
+    Pop {
+        key: String,
+        max: bool,
+        count: Option<usize>,
+    },
+    RandMember {
+        key: String,
+        count: Option<i64>,
+        with_scores: bool,
+    },
+    /// BZPOPMIN, or BZPOPMAX when `max` is set.
+    BlockingPop {
+        keys: Vec<String>,
+        max: bool,
+        timeout: f64,
+    },
+    /// ZMPOP, or BZMPOP when there is a timeout.
+    MPop {
+        keys: Vec<String>,
+        max: bool,
+        count: usize,
+        timeout: Option<f64>,
+    },
+    /// ZUNION, ZINTER and ZDIFF, and their STORE variants when there is a
+    /// destination.
+    Combine {
+        op: SetOp,
+        keys: Vec<String>,
+        weights: Vec<f64>,
+        aggregate: Aggregate,
+        with_scores: bool,
+        destination: Option<String>,
+    },
+    InterCard {
+        keys: Vec<String>,
+        limit: usize,
+    },
+}
+
+impl SortedSetCommand {
+    pub fn parse(name: &str, args: &[RespData]) -> Result<SortedSetCommand, CommandError> {
+        let mut args = Args::new(name, args);
+        let command = match name {
+            "ZADD" => {
+                let key = args.string()?;
+                let mut options = AddOptions::default();
+                loop {
+                    if args.keyword("NX") {
+                        options.nx = true;
+                    } else if args.keyword("XX") {
+                        options.xx = true;
+                    } else if args.keyword("GT") {
+                        options.gt = true;
+                    } else if args.keyword("LT") {
+                        options.lt = true;
+                    } else if args.keyword("CH") {
+                        options.ch = true;
+                    } else if args.keyword("INCR") {
+                        options.incr = true;
+                    } else {
+                        break;
+                    }
+                }
+                if options.nx && options.xx {
+                    return Err(CommandError::Other(
+                        "ERR XX and NX options at the same time are not compatible".into(),
+                    ));
+                }
+                if [options.nx, options.gt, options.lt]
+                    .iter()
+                    .filter(|set| **set)
+                    .count()
+                    > 1
+                {
+                    return Err(CommandError::Other(
+                        "ERR GT, LT, and/or NX options at the same time are not compatible".into(),
+                    ));
+                }
+                if args.is_empty() || args.len() % 2 == 1 {
+                    return Err(CommandError::Syntax);
+                }
+                if options.incr && args.len() > 2 {
+                    return Err(CommandError::Other(
+                        "ERR INCR option supports a single increment-element pair".into(),
+                    ));
+                }
+                let mut elements = Vec::new();
+                while !args.is_empty() {
+                    elements.push((parse_score(&mut args)?, args.string()?));
+                }
+                SortedSetCommand::Add {
+                    key,
+                    options,
+                    elements,
+                }
+            }
+            "ZREM" => SortedSetCommand::Rem(args.string()?, args.rest()?),
+            "ZSCORE" => SortedSetCommand::Score(args.string()?, args.string()?),
+            "ZMSCORE" => SortedSetCommand::MScore(args.string()?, args.rest()?),
+            "ZINCRBY" => {
+                SortedSetCommand::IncrBy(args.string()?, parse_score(&mut args)?, args.string()?)
+            }
+            "ZCARD" => SortedSetCommand::Card(args.string()?),
+            "ZCOUNT" => SortedSetCommand::Count(
+                args.string()?,
+                ScoreBound::parse(&mut args)?,
+                ScoreBound::parse(&mut args)?,
+            ),
+            "ZRANK" | "ZREVRANK" => SortedSetCommand::Rank {
+                key: args.string()?,
+                member: args.string()?,
+                reverse: name == "ZREVRANK",
+                with_score: args.keyword("WITHSCORE"),
+            },
+            "ZRANGE" => {
+                let key = args.string()?;
+                let mut with_scores = false;
+                let range = Range::parse(&mut args, &mut with_scores)?;
+                SortedSetCommand::Range {
+                    key,
+                    range,
+                    with_scores,
+                }
+            }
+            "ZRANGESTORE" => {
+                let destination = args.string()?;
+                let source = args.string()?;
+                let mut with_scores = false;
+                let range = Range::parse(&mut args, &mut with_scores)?;
+                if with_scores {
+                    return Err(CommandError::Syntax);
+                }
+                SortedSetCommand::RangeStore {
+                    destination,
+                    source,
+                    range,
+                }
+            }
+            "ZPOPMIN" | "ZPOPMAX" => SortedSetCommand::Pop {
+                key: args.string()?,
+                max: name == "ZPOPMAX",
+                count: if args.is_empty() {
+                    None
+                } else {
+                    Some(args.positive()?)
+                },
+            },
+            "ZRANDMEMBER" => {
+                let key = args.string()?;
+                let count = if args.is_empty() {
+                    None
+                } else {
+                    Some(args.random_count()?)
+                };
+                SortedSetCommand::RandMember {
+                    key,
+                    count,
+                    with_scores: count.is_some() && args.keyword("WITHSCORES"),
+                }
+            }
+            "ZUNION" | "ZINTER" | "ZDIFF" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                let op = match name.trim_end_matches("STORE") {
+                    "ZINTER" => SetOp::Inter,
+                    "ZUNION" => SetOp::Union,
+                    _ => SetOp::Diff,
+                };
+                let destination = if name.ends_with("STORE") {
+                    Some(args.string()?)
+                } else {
+                    None
+                };
+                let numkeys: i64 = args.integer()?;
+                if numkeys <= 0 {
+                    return Err(CommandError::Other(format!(
+                        "ERR at least 1 input key is needed for '{}' command",
+                        name.to_lowercase()
+                    )));
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::Syntax);
+                }
+                let keys = (0..numkeys)
+                    .map(|_| args.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut weights = vec![1.0; keys.len()];
+                let mut aggregate = Aggregate::Sum;
+                let mut with_scores = false;
+                while !args.is_empty() {
+                    if op != SetOp::Diff && args.keyword("WEIGHTS") {
+                        if args.len() < keys.len() {
+                            return Err(CommandError::Syntax);
+                        }
+                        for weight in &mut weights {
+                            *weight = args.float().map_err(|_| {
+                                CommandError::Other("ERR weight value is not a float".into())
+                            })?;
+                        }
+                    } else if op != SetOp::Diff && args.keyword("AGGREGATE") {
+                        aggregate = if args.keyword("SUM") {
+                            Aggregate::Sum
+                        } else if args.keyword("MIN") {
+                            Aggregate::Min
+                        } else if args.keyword("MAX") {
+                            Aggregate::Max
+                        } else {
+                            return Err(CommandError::Syntax);
+                        };
+                    } else if destination.is_none() && args.keyword("WITHSCORES") {
+                        with_scores = true;
+                    } else {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                SortedSetCommand::Combine {
+                    op,
+                    keys,
+                    weights,
+                    aggregate,
+                    with_scores,
+                    destination,
+                }
+            }
+            "BZPOPMIN" | "BZPOPMAX" => {
+                if args.len() < 2 {
+                    return Err(args.arity_error());
+                }
+                let keys = (1..args.len())
+                    .map(|_| args.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                SortedSetCommand::BlockingPop {
+                    keys,
+                    max: name == "BZPOPMAX",
+                    timeout: args.timeout()?,
+                }
+            }
+            "ZMPOP" => parse_mpop(&mut args, None)?,
+            "BZMPOP" => {
+                let timeout = args.timeout()?;
+                parse_mpop(&mut args, Some(timeout))?
+            }
+            "ZINTERCARD" => {
+                let numkeys: i64 = args.integer()?;
+                if numkeys <= 0 {
+                    return Err(CommandError::Other(
+                        "ERR numkeys should be greater than 0".into(),
+                    ));
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::Other(
+                        "ERR Number of keys can't be greater than number of args".into(),
+                    ));
+                }
+                let keys = (0..numkeys)
+                    .map(|_| args.string())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let limit = if args.keyword("LIMIT") {
+                    args.integer::<i64>()?
+                        .try_into()
+                        .map_err(|_| CommandError::Other("ERR LIMIT can't be negative".into()))?
+                } else {
+                    0
+                };
+                SortedSetCommand::InterCard { keys, limit }
+            }
+            _ => return Err(CommandError::UnknownCommand(name.to_string())),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            SortedSetCommand::Add { .. }
+                | SortedSetCommand::Rem(..)
+                | SortedSetCommand::IncrBy(..)
+                | SortedSetCommand::RangeStore { .. }
+                | SortedSetCommand::Pop { .. }
+                | SortedSetCommand::BlockingPop { .. }
+                | SortedSetCommand::MPop { .. }
+                | SortedSetCommand::Combine {
+                    destination: Some(_),
+                    ..
+                }
+        )
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            SortedSetCommand::Add { key, .. }
+            | SortedSetCommand::Rem(key, _)
+            | SortedSetCommand::Score(key, _)
+            | SortedSetCommand::MScore(key, _)
+            | SortedSetCommand::IncrBy(key, ..)
+            | SortedSetCommand::Card(key)
+            | SortedSetCommand::Count(key, ..)
+            | SortedSetCommand::Rank { key, .. }
+            | SortedSetCommand::Range { key, .. }
+            | SortedSetCommand::Pop { key, .. }
+            | SortedSetCommand::RandMember { key, .. } => vec![key],
+            SortedSetCommand::RangeStore {
+                destination,
+                source,
+                ..
+            } => vec![destination, source],
+            SortedSetCommand::Combine {
+                keys, destination, ..
+            } => destination.iter().chain(keys).map(String::as_str).collect(),
+            SortedSetCommand::BlockingPop { keys, .. }
+            | SortedSetCommand::MPop { keys, .. }
+            | SortedSetCommand::InterCard { keys, .. } => keys.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// How long the command may block, in seconds (0 meaning forever), or
+    /// `None` for commands that never block.
+    pub fn timeout(&self) -> Option<f64> {
+        match self {
+            SortedSetCommand::BlockingPop { timeout, .. } => Some(*timeout),
+            SortedSetCommand::MPop { timeout, .. } => *timeout,
+            _ => None,
+        }
+    }
+}
+
+/// Parses `numkeys key [key ...] MIN | MAX [COUNT count]`, the arguments
+/// ZMPOP and BZMPOP share.
+fn parse_mpop(args: &mut Args, timeout: Option<f64>) -> Result<SortedSetCommand, CommandError> {
+    let numkeys: i64 = args.integer()?;
+    if numkeys <= 0 {
+        return Err(CommandError::Other(
+            "ERR numkeys should be greater than 0".into(),
+        ));
+    }
+    let keys = (0..numkeys)
+        .map(|_| args.string())
+        .collect::<Result<Vec<_>, _>>()?;
+    let max = if args.keyword("MIN") {
+        false
+    } else if args.keyword("MAX") {
+        true
+    } else {
+        return Err(CommandError::Syntax);
+    };
+    let count = if args.keyword("COUNT") {
+        let count: i64 = args.integer()?;
+        if count <= 0 {
+            return Err(CommandError::Other(
+                "ERR count should be greater than 0".into(),
+            ));
+        }
+        count as usize
+    } else {
+        1
+    };
+    Ok(SortedSetCommand::MPop {
+        keys,
+        max,
+        count,
+        timeout,
+    })
+}
+
+/// How ZUNION and ZINTER merge the scores a member has in several inputs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which Redis turns into 0.
+            Aggregate::Sum => zero_if_nan(a + b),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// An input of ZUNION, ZINTER and ZDIFF: a sorted set, or a plain set whose
+/// members all score 1.
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    Sorted(&'a SortedSet),
+    Plain(&'a Dict<String, ()>),
+}
+
+impl<'a> Input<'a> {
+    fn len(self) -> usize {
+        match self {
+            Input::Sorted(zset) => zset.len(),
+            Input::Plain(set) => set.len(),
+        }
+    }
+
+    fn score(self, member: &str) -> Option<f64> {
+        match self {
+            Input::Sorted(zset) => zset.score(member),
+            Input::Plain(set) => set.get(member).map(|_| 1.0),
+        }
+    }
+
+    fn iter(self) -> Box<dyn Iterator<Item = (&'a String, f64)> + 'a> {
+        match self {
+            Input::Sorted(zset) => Box::new(zset.iter()),
+            Input::Plain(set) => Box::new(set.iter().map(|(member, _)| (member, 1.0))),
+        }
+    }
+}
+
+fn inputs<'a>(db: &'a Db, keys: &[String]) -> Result<Vec<Option<Input<'a>>>, StoreError> {
+    keys.iter()
+        .map(|key| match db.get(key) {
+            Some(Value::SortedSet(zset)) => Ok(Some(Input::Sorted(zset))),
+            Some(Value::Set(set)) => Ok(Some(Input::Plain(set))),
+            Some(_) => Err(StoreError::WrongType),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// The members in every input, checking from the smallest one and stopping
+/// after `limit` members unless it is 0.
+fn intersection<'a>(inputs: &[Option<Input<'a>>], limit: usize) -> Vec<&'a String> {
+    let Some(inputs) = inputs.iter().copied().collect::<Option<Vec<_>>>() else {
+        return Vec::new();
+    };
+    let (smallest, input) = inputs
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, input)| input.len())
+        .unwrap();
+    let members = input.iter().filter(|(member, _)| {
+        inputs
+            .iter()
+            .enumerate()
+            .all(|(i, input)| i == smallest || input.score(member).is_some())
+    });
+    let members = members.map(|(member, _)| member);
+    if limit == 0 {
+        members.collect()
+    } else {
+        members.take(limit).collect()
+    }
+}
+
+fn combine(
+    op: SetOp,
+    inputs: &[Option<Input>],
+    weights: &[f64],
+    aggregate: Aggregate,
+) -> SortedSet {
+    let weighted = |i: usize, score: f64| zero_if_nan(score * weights[i]);
+    let mut scores: Dict<String, f64> = Dict::new();
+    match op {
+        SetOp::Union => {
+            for (i, input) in inputs.iter().enumerate() {
+                for (member, score) in input.iter().flat_map(|input| input.iter()) {
+                    let score = weighted(i, score);
+                    match scores.get_mut(member) {
+                        Some(total) => *total = aggregate.apply(*total, score),
+                        None => {
+                            scores.insert(member.clone(), score);
+                        }
+                    }
+                }
+            }
+        }
+        SetOp::Inter => {
+            for member in intersection(inputs, 0) {
+                let score = inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, input)| weighted(i, input.unwrap().score(member).unwrap()))
+                    .reduce(|total, score| aggregate.apply(total, score))
+                    .unwrap();
+                scores.insert(member.clone(), score);
+            }
+        }
+        SetOp::Diff => {
+            let (first, others) = inputs.split_first().unwrap();
+            for (member, score) in first.iter().flat_map(|input| input.iter()) {
+                if others
+                    .iter()
+                    .flatten()
+                    .all(|input| input.score(member).is_none())
+                {
+                    scores.insert(member.clone(), score);
+                }
+            }
+        }
+    }
+
+    let mut result = SortedSet::default();
+    for (member, score) in scores.iter() {
+        result.insert(member.clone(), *score);
+    }
+    result
+}
+
+fn score_or_null(score: Option<f64>) -> RespData {
+    score
+        .map(|score| RespData::BulkString(format_score(score)))
+        .unwrap_or(RespData::BulkStringNull)
+}
+
+/// Members, each followed by its score if `with_scores` is set.
+fn members_reply<'a>(
+    members: impl IntoIterator<Item = (&'a String, f64)>,
+    with_scores: bool,
+) -> RespData {
+    RespData::Array(
+        members
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let score = with_scores.then(|| RespData::BulkString(format_score(score)));
+                std::iter::once(RespData::BulkString(member.clone())).chain(score)
+            })
+            .collect(),
+    )
+}
+
+/// Adds or updates one element under ZADD's rules. Returns the resulting
+/// score, or `None` when the options ruled the update out, and whether the
+/// member was added or its score changed.
+fn add(
+    zset: &mut SortedSet,
+    options: AddOptions,
+    score: f64,
+    member: String,
+) -> Result<(Option<f64>, bool, bool), StoreError> {
+    let Some(current) = zset.score(&member) else {
+        if options.xx {
+            return Ok((None, false, false));
+        }
+        zset.insert(member, score);
+        return Ok((Some(score), true, false));
+    };
+    if options.nx {
+        return Ok((None, false, false));
+    }
+
+    let score = if options.incr { current + score } else { score };
+    if score.is_nan() {
+        return Err(StoreError::NotANumber);
+    }
+    if (options.gt && score <= current) || (options.lt && score >= current) {
+        return Ok((None, false, false));
+    }
+    zset.insert(member, score);
+    Ok((Some(score), false, score != current))
+}
+
+/// Pops up to `count` members with the lowest scores, or the highest when
+/// `max` is set. Returns `None` when the key doesn't exist.
+fn pop_from(
+    db: &mut Db,
+    key: &str,
+    max: bool,
+    count: usize,
+) -> Result<Option<Vec<(String, f64)>>, StoreError> {
+    let Some(zset) = db.sorted_set_mut(key)? else {
+        return Ok(None);
+    };
+    let popped: Vec<(String, f64)> = (0..count).map_while(|_| zset.pop(max)).collect();
+    if !popped.is_empty() {
+        db.touch(key);
+    }
+    db.remove_if_empty(key);
+    Ok(Some(popped))
+}
+
+fn pop_command(max: bool) -> &'static str {
+    if max {
+        "ZPOPMAX"
+    } else {
+        "ZPOPMIN"
+    }
+}
+
+/// Runs one of the pops that may block (BZPOPMIN, ZMPOP and friends) without
+/// blocking. Returns the reply together with the non-blocking commands to feed
+/// replicas in its place, or `None` when every key is empty.
+pub fn try_pop(db: &mut Db, command: &SortedSetCommand) -> Result<Option<Rewritten>, StoreError> {
+    match command {
+        SortedSetCommand::BlockingPop { keys, max, .. } => {
+            for key in keys {
+                if let Some(mut popped) = pop_from(db, key, *max, 1)? {
+                    let (member, score) = popped.pop().unwrap();
+                    return Ok(Some((
+                        RespData::Array(vec![
+                            RespData::BulkString(key.clone()),
+                            RespData::BulkString(member),
+                            RespData::BulkString(format_score(score)),
+                        ]),
+                        vec![vec![pop_command(*max).to_string(), key.clone()]],
+                    )));
+                }
+            }
+            Ok(None)
+        }
+        SortedSetCommand::MPop {
+            keys, max, count, ..
+        } => {
+            for key in keys {
+                if let Some(popped) = pop_from(db, key, *max, *count)? {
+                    let propagate = vec![vec![
+                        pop_command(*max).to_string(),
+                        key.clone(),
+                        popped.len().to_string(),
+                    ]];
+                    let popped = popped
+                        .into_iter()
+                        .map(|(member, score)| {
+                            RespData::Array(vec![
+                                RespData::BulkString(member),
+                                RespData::BulkString(format_score(score)),
+                            ])
+                        })
+                        .collect();
+                    return Ok(Some((
+                        RespData::Array(vec![
+                            RespData::BulkString(key.clone()),
+                            RespData::Array(popped),
+                        ]),
+                        propagate,
+                    )));
+                }
+            }
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn execute(db: &mut Db, command: SortedSetCommand) -> Result<RespData, StoreError> {
+    match command {
+        SortedSetCommand::Add {
+            key,
+            options,
+            elements,
+        } => {
+            if options.xx && db.sorted_set(&key)?.is_none() {
+                return Ok(if options.incr {
+                    score_or_null(None)
+                } else {
+                    RespData::Integer(0)
+                });
+            }
+            let zset = db.sorted_set_or_create(&key)?;
+            let mut added = 0;
+            let mut changed = 0;
+            let mut result = None;
+            for (score, member) in elements {
+                let outcome = add(zset, options, score, member);
+                let (score, was_added, was_changed) = match outcome {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        db.remove_if_empty(&key);
+                        return Err(e);
+                    }
+                };
+                added += was_added as i64;
+                changed += was_changed as i64;
+                result = score;
+            }
+            if added + changed > 0 {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(if options.incr {
+                score_or_null(result)
+            } else if options.ch {
+                RespData::Integer(added + changed)
+            } else {
+                RespData::Integer(added)
+            })
+        }
+        SortedSetCommand::IncrBy(key, increment, member) => {
+            let zset = db.sorted_set_or_create(&key)?;
+            let options = AddOptions {
+                incr: true,
+                ..AddOptions::default()
+            };
+            let outcome = add(zset, options, increment, member);
+            if outcome.is_ok() {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(score_or_null(outcome?.0))
+        }
+        SortedSetCommand::Rem(key, members) => {
+            let Some(zset) = db.sorted_set_mut(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let removed = members
+                .iter()
+                .filter(|member| zset.remove(member).is_some())
+                .count();
+            if removed > 0 {
+                db.touch(&key);
+            }
+            db.remove_if_empty(&key);
+            Ok(RespData::Integer(removed as i64))
+        }
+        SortedSetCommand::Score(key, member) => Ok(score_or_null(
+            db.sorted_set(&key)?.and_then(|zset| zset.score(&member)),
+        )),
+        SortedSetCommand::MScore(key, members) => {
+            let zset = db.sorted_set(&key)?;
+            Ok(RespData::Array(
+                members
+                    .iter()
+                    .map(|member| score_or_null(zset.and_then(|zset| zset.score(member))))
+                    .collect(),
+            ))
+        }
+        SortedSetCommand::Card(key) => Ok(RespData::Integer(
+            db.sorted_set(&key)?.map_or(0, |zset| zset.len() as i64),
+        )),
+        SortedSetCommand::Count(key, min, max) => {
+            let Some(zset) = db.sorted_set(&key)? else {
+                return Ok(RespData::Integer(0));
+            };
+            let first = zset.list.count_while(|score, _| min.is_above(score));
+            let end = zset.list.count_while(|score, _| max.is_at_least(score));
+            Ok(RespData::Integer(end.saturating_sub(first) as i64))
+        }
+        SortedSetCommand::Rank {
+            key,
+            member,
+            reverse,
+            with_score,
+        } => {
+            let zset = db.sorted_set(&key)?;
+            let rank =
+                zset.and_then(|zset| Some((zset.rank(&member, reverse)?, zset.score(&member)?)));
+            Ok(match rank {
+                Some((rank, score)) if with_score => RespData::Array(vec![
+                    RespData::Integer(rank as i64),
+                    RespData::BulkString(format_score(score)),
+                ]),
+                Some((rank, _)) => RespData::Integer(rank as i64),
+                None if with_score => RespData::ArrayNull,
+                None => RespData::BulkStringNull,
+            })
+        }
+        SortedSetCommand::Range {
+            key,
+            range,
+            with_scores,
+        } => Ok(members_reply(
+            db.sorted_set(&key)?
+                .map(|zset| range.select(zset))
+                .unwrap_or_default(),
+            with_scores,
+        )),
+        SortedSetCommand::RangeStore {
+            destination,
+            source,
+            range,
+        } => {
+            let mut result = SortedSet::default();
+            if let Some(zset) = db.sorted_set(&source)? {
+                for (member, score) in range.select(zset) {
+                    result.insert(member.clone(), score);
+                }
+            }
+            let len = result.len();
+            db.remove(&destination);
+            if len > 0 {
+                db.set(destination, Value::SortedSet(result), None);
+            }
+            Ok(RespData::Integer(len as i64))
+        }
+        SortedSetCommand::Pop { key, max, count } => {
+            let popped = pop_from(db, &key, max, count.unwrap_or(1))?.unwrap_or_default();
+            Ok(members_reply(
+                popped.iter().map(|(member, score)| (member, *score)),
+                true,
+            ))
+        }
+        SortedSetCommand::BlockingPop { .. } | SortedSetCommand::MPop { .. } => {
+            Ok(try_pop(db, &command)?.map_or(RespData::ArrayNull, |(reply, _)| reply))
+        }
+        SortedSetCommand::RandMember {
+            key,
+            count,
+            with_scores,
+        } => {
+            let zset = db.sorted_set(&key)?;
+            let (zset, count) = match (zset, count) {
+                (zset, None) => {
+                    return Ok(zset
+                        .and_then(|zset| zset.random_member())
+                        .cloned()
+                        .map(RespData::BulkString)
+                        .unwrap_or(RespData::BulkStringNull))
+                }
+                (None, Some(_)) => return Ok(RespData::Array(Vec::new())),
+                (Some(zset), Some(count)) => (zset, count),
+            };
+            let members: Vec<&String> = if count < 0 {
+                (0..count.unsigned_abs())
+                    .filter_map(|_| zset.random_member())
+                    .collect()
+            } else {
+                random::sample_distinct(
+                    zset.len(),
+                    count as usize,
+                    || zset.iter().map(|(member, _)| member).collect(),
+                    || zset.random_member().unwrap(),
+                )
+            };
+            Ok(members_reply(
+                members
+                    .into_iter()
+                    .map(|member| (member, zset.score(member).unwrap())),
+                with_scores,
+            ))
+        }
+        SortedSetCommand::Combine {
+            op,
+            keys,
+            weights,
+            aggregate,
+            with_scores,
+            destination: None,
+        } => Ok(members_reply(
+            combine(op, &inputs(db, &keys)?, &weights, aggregate).iter(),
+            with_scores,
+        )),
+        SortedSetCommand::Combine {
+            op,
+            keys,
+            weights,
+            aggregate,
+            destination: Some(destination),
+            ..
+        } => {
+            let result = combine(op, &inputs(db, &keys)?, &weights, aggregate);
+            let len = result.len();
+            db.remove(&destination);
+            if len > 0 {
+                db.set(destination, Value::SortedSet(result), None);
+            }
+            Ok(RespData::Integer(len as i64))
+        }
+        SortedSetCommand::InterCard { keys, limit } => Ok(RespData::Integer(
+            intersection(&inputs(db, &keys)?, limit).len() as i64,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zset(elements: &[(&str, f64)]) -> SortedSet {
+        let mut zset = SortedSet::default();
+        for (member, score) in elements {
+            zset.insert(member.to_string(), *score);
+        }
+        zset
+    }
+
+    fn selected(zset: &SortedSet, range: Range) -> Vec<&str> {
+        range
+            .select(zset)
+            .into_iter()
+            .map(|(member, _)| member.as_str())
+            .collect()
+    }
+
+    fn score(value: f64, exclusive: bool) -> ScoreBound {
+        ScoreBound { value, exclusive }
+    }
+
+    #[test]
+    fn formats_scores_like_redis() {
+        assert_eq!(format_score(1.5), "1.5");
+        assert_eq!(format_score(3.0), "3");
+        assert_eq!(format_score(-0.25), "-0.25");
+        assert_eq!(format_score(1e20), "1e+20");
+        assert_eq!(format_score(1.5e-7), "1.5e-07");
+        assert_eq!(format_score(f64::NEG_INFINITY), "-inf");
+    }
+
+    #[test]
+    fn ranges_by_rank_score_and_lex() {
+        let zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        let range = |by, rev, limit| Range { by, rev, limit };
+
+        assert_eq!(
+            selected(&zset, range(RangeBy::Rank(1, -1), false, None)),
+            ["b", "c", "d"]
+        );
+        assert_eq!(
+            selected(&zset, range(RangeBy::Rank(0, 1), true, None)),
+            ["d", "c"]
+        );
+        assert_eq!(
+            selected(
+                &zset,
+                range(
+                    RangeBy::Score(score(1.0, true), score(4.0, false)),
+                    false,
+                    None
+                )
+            ),
+            ["b", "c", "d"]
+        );
+        assert_eq!(
+            selected(
+                &zset,
+                range(
+                    RangeBy::Score(score(f64::NEG_INFINITY, false), score(3.0, false)),
+                    true,
+                    Some((1, 1))
+                )
+            ),
+            ["b"]
+        );
+        assert_eq!(
+            selected(
+                &zset,
+                range(
+                    RangeBy::Lex(LexBound::Exclusive("a".into()), LexBound::Max),
+                    false,
+                    Some((0, 2))
+                )
+            ),
+            ["b", "c"]
+        );
+        assert!(selected(
+            &zset,
+            range(
+                RangeBy::Score(score(3.0, false), score(2.0, false)),
+                false,
+                None
+            )
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn combines_with_weights_and_plain_sets() {
+        let a = zset(&[("x", 1.0), ("y", 2.0), ("z", f64::INFINITY)]);
+        let b = zset(&[("y", 10.0), ("z", f64::NEG_INFINITY)]);
+        let mut plain = Dict::new();
+        plain.insert("x".to_string(), ());
+        plain.insert("w".to_string(), ());
+        let inputs = [
+            Some(Input::Sorted(&a)),
+            Some(Input::Sorted(&b)),
+            Some(Input::Plain(&plain)),
+        ];
+        let scores = |zset: SortedSet| -> Vec<(String, f64)> {
+            zset.iter()
+                .map(|(member, score)| (member.clone(), score))
+                .collect()
+        };
+
+        assert_eq!(
+            scores(combine(
+                SetOp::Union,
+                &inputs,
+                &[2.0, 1.0, 5.0],
+                Aggregate::Sum
+            )),
+            [
+                ("z".to_string(), 0.0),
+                ("w".to_string(), 5.0),
+                ("x".to_string(), 7.0),
+                ("y".to_string(), 14.0)
+            ]
+        );
+        assert_eq!(
+            scores(combine(
+                SetOp::Inter,
+                &inputs[..2],
+                &[1.0, 1.0],
+                Aggregate::Max
+            )),
+            [("y".to_string(), 10.0), ("z".to_string(), f64::INFINITY)]
+        );
+        assert_eq!(
+            scores(combine(SetOp::Diff, &inputs, &[1.0; 3], Aggregate::Sum)),
+            Vec::new()
+        );
+        assert_eq!(
+            combine(SetOp::Inter, &[inputs[0], None], &[1.0; 2], Aggregate::Sum).len(),
+            0
+        );
+        assert_eq!(intersection(&inputs[..2], 1).len(), 1);
+    }
+
+    #[test]
+    fn rank_and_pop() {
+        let mut zset = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        zset.insert("a".to_string(), 5.0);
+        assert_eq!(zset.rank("a", false), Some(2));
+        assert_eq!(zset.rank("a", true), Some(0));
+        assert_eq!(zset.pop(false), Some(("b".to_string(), 2.0)));
+        assert_eq!(zset.pop(true), Some(("a".to_string(), 5.0)));
+        assert_eq!(zset.len(), 1);
+    }
+
+    #[test]
+    fn zrandmember_refuses_huge_negative_counts() {
+        let parse = |count: &str| {
+            let args = ["z", count].map(|arg| RespData::BulkString(arg.to_string()));
+            SortedSetCommand::parse("ZRANDMEMBER", &args)
+        };
+        assert!(parse("-16777216").is_ok());
+        assert_eq!(
+            parse("-9223372036854775808"),
+            Err(CommandError::Other("ERR value is out of range".into()))
+        );
+    }
+}